@@ -0,0 +1,123 @@
+//! Packs the directory `initrd`, or the one `WALLY_INITRD` points to, into a newc cpio archive
+//! that the kernel embeds and unpacks into its root filesystem at boot.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// the kind of file is in the upper bits of the mode
+const KIND: u32 = 0o170000;
+const FILE: u32 = 0o100000;
+const DIRECTORY: u32 = 0o040000;
+const SYMLINK: u32 = 0o120000;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=WALLY_INITRD");
+    let source = match env::var_os("WALLY_INITRD") {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("initrd"),
+    };
+    // also when the directory doesn't exist, so creating it later is noticed
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let mut archive = Archive::default();
+    // without the directory the archive is empty
+    if source.is_dir() {
+        archive
+            .pack(&source, "")
+            .unwrap_or_else(|err| panic!("can't pack {}: {err}", source.display()));
+    }
+    archive.entry("TRAILER!!!", 0, &[]);
+    let out = Path::new(&env::var_os("OUT_DIR").unwrap()).join("initrd.cpio");
+    fs::write(out, archive.data).expect("can't write the initrd");
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+// other hosts don't have mode bits, so everything gets the usual ones
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, false) => 0o644,
+        (false, true) => 0o444,
+    }
+}
+
+#[derive(Default)]
+struct Archive {
+    data: Vec<u8>,
+    inodes: u32,
+}
+
+impl Archive {
+    /// Adds the contents of a directory, sorted so the archive is the same on every build.
+    fn pack(&mut self, directory: &Path, prefix: &str) -> io::Result<()> {
+        let mut children = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let name = child.file_name().into_string().map_err(|name| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{name:?} isn't UTF-8"))
+            })?;
+            let path = format!("{prefix}{name}");
+            let metadata = fs::symlink_metadata(child.path())?;
+            let permissions = permissions(&metadata);
+            if metadata.is_dir() {
+                self.entry(&path, DIRECTORY | permissions, &[]);
+                self.pack(&child.path(), &format!("{path}/"))?;
+            } else if metadata.is_file() {
+                self.entry(&path, FILE | permissions, &fs::read(child.path())?);
+            } else if metadata.is_symlink() {
+                let target = fs::read_link(child.path())?;
+                let target = target.to_str().unwrap_or_default();
+                self.entry(&path, SYMLINK | 0o777, target.as_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends an entry: a header of hexadecimal fields, the name and the data, which both
+    /// start at multiples of 4 bytes. Everything belongs to root and has no time, so builds
+    /// are reproducible.
+    fn entry(&mut self, name: &str, mode: u32, data: &[u8]) {
+        self.inodes += 1;
+        let links = match mode & KIND {
+            DIRECTORY => 2,
+            _ => 1,
+        };
+        let fields = [
+            self.inodes,
+            mode,
+            0,
+            0,
+            links,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        self.data.extend_from_slice(b"070701");
+        for field in fields {
+            self.data.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.align();
+        self.data.extend_from_slice(data);
+        self.align();
+    }
+
+    fn align(&mut self) {
+        self.data.resize(self.data.len().next_multiple_of(4), 0);
+    }
+}
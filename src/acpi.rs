@@ -0,0 +1,99 @@
+//! Just enough ACPI to find the tables other modules need.
+//!
+//! See <https://wiki.osdev.org/RSDP> and <https://wiki.osdev.org/RSDT>.
+
+use crate::memory::phys_to_virt;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The size of the header every system description table starts with.
+pub const SDT_HEADER_SIZE: usize = 36;
+
+// the BIOS stores the segment of the extended BIOS data area at this address
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+/// Looks up the ACPI table with the given `signature`, e.g. `b"MCFG"`, and returns its
+/// physical address if it exists and its checksum is valid.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+    let revision = unsafe { read::<u8>(rsdp + 15u64) };
+    // ACPI 2.0 and later have a table with 64 bit pointers
+    let (root, entry_size) = if revision >= 2 {
+        (PhysAddr::new(unsafe { read::<u64>(rsdp + 24u64) }), 8)
+    } else {
+        (
+            PhysAddr::new(unsafe { read::<u32>(rsdp + 16u64) }.into()),
+            4,
+        )
+    };
+    if !valid_table(root) {
+        return None;
+    }
+
+    let entries = (table_length(root) - SDT_HEADER_SIZE) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + (SDT_HEADER_SIZE + i * entry_size) as u64;
+            let addr = match entry_size {
+                8 => unsafe { read::<u64>(entry) },
+                _ => unsafe { read::<u32>(entry) }.into(),
+            };
+            PhysAddr::new(addr)
+        })
+        .find(|&table| unsafe { read::<[u8; 4]>(table) } == *signature && valid_table(table))
+}
+
+/// Returns the length of a table in bytes, including the header.
+pub fn table_length(table: PhysAddr) -> usize {
+    unsafe { read::<u32>(table + 4u64) as usize }
+}
+
+/// Reads a value from a physical address.
+///
+/// # Safety
+/// The address has to belong to firmware tables or something else that's safe to read.
+pub unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    phys_to_virt(addr).as_ptr::<T>().read_unaligned()
+}
+
+/// Searches the places the firmware could have put the root system description pointer.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(EBDA_POINTER)) }) << 4;
+    let ebda = (ebda != 0).then_some(ebda..ebda + 1024);
+    ebda.into_iter()
+        .chain(core::iter::once(BIOS_AREA_START..BIOS_AREA_END))
+        // the pointer is always 16 byte aligned
+        .flat_map(|area| area.step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            let signature = unsafe { read::<[u8; 8]>(addr) };
+            signature == *RSDP_SIGNATURE && checksum(addr, 20) == 0
+        })
+}
+
+fn valid_table(table: PhysAddr) -> bool {
+    let length = table_length(table);
+    length >= SDT_HEADER_SIZE && checksum(table, length) == 0
+}
+
+/// All bytes of a table add up to 0 if it is intact.
+fn checksum(start: PhysAddr, length: usize) -> u8 {
+    (0..length as u64).fold(0u8, |sum, i| {
+        sum.wrapping_add(unsafe { read::<u8>(start + i) })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn finds_tables() {
+        // every ACPI system has a FADT, its signature is a historical accident
+        let fadt = find_table(b"FACP").expect("no FADT");
+        assert!(table_length(fadt) > SDT_HEADER_SIZE);
+        assert_eq!(find_table(b"NOPE"), None);
+    }
+}
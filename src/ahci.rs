@@ -0,0 +1,627 @@
+//! Driver for SATA disks behind an AHCI controller, which moves the data with DMA and signals
+//! finished commands with an interrupt.
+//!
+//! See <https://wiki.osdev.org/AHCI>.
+
+use crate::ata::Identify;
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::interrupts;
+use crate::memory::{phys_to_virt, DmaBuffer};
+use crate::pci::{msi, Bar, PciDevice};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+// the generic host control registers
+const HBA_CAPABILITIES: u64 = 0x00;
+const HBA_GLOBAL_CONTROL: u64 = 0x04;
+const HBA_INTERRUPT_STATUS: u64 = 0x08;
+const HBA_PORTS_IMPLEMENTED: u64 = 0x0c;
+
+// bits of the global host control register
+const GLOBAL_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GLOBAL_AHCI_ENABLE: u32 = 1 << 31;
+
+// the registers of a port, relative to its own base
+const PORTS_START: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PORT_COMMAND_LIST: u64 = 0x00;
+const PORT_COMMAND_LIST_HIGH: u64 = 0x04;
+const PORT_FIS: u64 = 0x08;
+const PORT_FIS_HIGH: u64 = 0x0c;
+const PORT_INTERRUPT_STATUS: u64 = 0x10;
+const PORT_INTERRUPT_ENABLE: u64 = 0x14;
+const PORT_COMMAND: u64 = 0x18;
+const PORT_TASK_FILE: u64 = 0x20;
+const PORT_SIGNATURE: u64 = 0x24;
+const PORT_SATA_STATUS: u64 = 0x28;
+const PORT_SATA_ERROR: u64 = 0x30;
+const PORT_COMMAND_ISSUE: u64 = 0x38;
+
+// bits of the port command register
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+const COMMAND_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+// bits of the port interrupt registers
+const INTERRUPT_D2H_REGISTER: u32 = 1 << 0;
+const INTERRUPT_PIO_SETUP: u32 = 1 << 1;
+const INTERRUPT_DMA_SETUP: u32 = 1 << 2;
+const INTERRUPT_SET_DEVICE_BITS: u32 = 1 << 3;
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+// bits of the status byte in the task file register
+const STATUS_ERROR: u32 = 1 << 0;
+const STATUS_DATA_REQUEST: u32 = 1 << 3;
+const STATUS_BUSY: u32 = 1 << 7;
+
+/// A device is present and the link is up.
+const SATA_STATUS_ESTABLISHED: u32 = 3;
+/// The signature of a plain SATA disk, as opposed to ATAPI drives or port multipliers.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+// where the structures of a port live in its DMA memory, they all fit in a single frame
+const COMMAND_LIST_OFFSET: usize = 0;
+const FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x800;
+// the command FIS is followed by the ATAPI command, the PRDT starts behind that
+const PRDT_OFFSET: usize = COMMAND_TABLE_OFFSET + 0x80;
+
+/// A register FIS that goes from the host to the device.
+const FIS_TYPE_H2D: u8 = 0x27;
+/// The length of a host to device FIS in dwords.
+const FIS_H2D_LENGTH: u32 = 5;
+/// Marks the FIS as a command rather than a write to the device control register.
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+
+// bits of the first dword of a command header
+const HEADER_WRITE: u32 = 1 << 6;
+// bit of a PRDT entry that asks for an interrupt when the entry has been transferred
+const PRDT_INTERRUPT: u32 = 1 << 31;
+
+// commands
+const READ_DMA: u8 = 0xc8;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA: u8 = 0xca;
+const WRITE_DMA_EXT: u8 = 0x35;
+const CACHE_FLUSH: u8 = 0xe7;
+const CACHE_FLUSH_EXT: u8 = 0xea;
+const IDENTIFY: u8 = 0xec;
+
+/// The size of the buffer every port copies its data through, which is the most a single
+/// command transfers.
+const BUFFER_SECTORS: u64 = 128;
+/// How often the registers are looked at before giving up on a command.
+const TIMEOUT: u32 = 100_000_000;
+
+// the number of disks found so far, which decides the name of the next one
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+// how many interrupts the controllers have raised
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+pub static DRIVER: AhciDriver = AhciDriver;
+
+/// The memory mapped registers of a controller, the "host bus adapter".
+#[derive(Debug, Clone, Copy)]
+struct Hba {
+    base: VirtAddr,
+}
+
+impl Hba {
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+
+    fn read_port(&self, port: usize, offset: u64) -> u32 {
+        self.read(PORTS_START + port as u64 * PORT_SIZE + offset)
+    }
+
+    fn write_port(&self, port: usize, offset: u64, value: u32) {
+        self.write(PORTS_START + port as u64 * PORT_SIZE + offset, value)
+    }
+
+    /// Waits until the bits in `mask` of a port register are cleared.
+    fn wait_port(&self, port: usize, offset: u64, mask: u32) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT {
+            if self.read_port(port, offset) & mask == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+}
+
+/// Builds a host to device register FIS that sends `command`.
+fn command_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let mut fis = [0; 20];
+    fis[0] = FIS_TYPE_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4] = lba as u8;
+    fis[5] = (lba >> 8) as u8;
+    fis[6] = (lba >> 16) as u8;
+    fis[7] = DEVICE_LBA;
+    fis[8] = (lba >> 24) as u8;
+    fis[9] = (lba >> 32) as u8;
+    fis[10] = (lba >> 40) as u8;
+    fis[12] = count as u8;
+    fis[13] = (count >> 8) as u8;
+    fis
+}
+
+/// A port with a disk attached to it.
+struct Port {
+    hba: Hba,
+    number: usize,
+    // the command list, received FISes and the command table
+    memory: DmaBuffer,
+    // the data of every command goes through here
+    buffer: DmaBuffer,
+    // the interrupt status bits the interrupt handler collected
+    completed: Arc<AtomicU32>,
+}
+
+impl Port {
+    fn new(hba: Hba, number: usize) -> Result<Self, BlockError> {
+        let no_memory = BlockError::Io("out of DMA memory");
+        let port = Self {
+            hba,
+            number,
+            memory: DmaBuffer::new(4096).ok_or(no_memory)?,
+            buffer: DmaBuffer::new(BUFFER_SECTORS as usize * SECTOR_SIZE).ok_or(no_memory)?,
+            completed: Arc::new(AtomicU32::new(0)),
+        };
+        port.start()?;
+        Ok(port)
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        self.hba.read_port(self.number, offset)
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        self.hba.write_port(self.number, offset, value)
+    }
+
+    /// Stops the port from processing commands and receiving FISes, after which it doesn't
+    /// touch our memory anymore.
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_START);
+        self.hba
+            .wait_port(self.number, PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        self.write(
+            PORT_COMMAND,
+            self.read(PORT_COMMAND) & !COMMAND_FIS_RECEIVE_ENABLE,
+        );
+        self.hba
+            .wait_port(self.number, PORT_COMMAND, COMMAND_FIS_RECEIVE_RUNNING)
+    }
+
+    /// Points the port at our memory and makes it process commands.
+    fn start(&self) -> Result<(), BlockError> {
+        // the addresses can only be changed while the port is stopped
+        self.stop()?;
+
+        let memory = self.memory.phys_addr().as_u64();
+        let command_list = memory + COMMAND_LIST_OFFSET as u64;
+        let fis = memory + FIS_OFFSET as u64;
+        self.write(PORT_COMMAND_LIST, command_list as u32);
+        self.write(PORT_COMMAND_LIST_HIGH, (command_list >> 32) as u32);
+        self.write(PORT_FIS, fis as u32);
+        self.write(PORT_FIS_HIGH, (fis >> 32) as u32);
+
+        // both registers are cleared by writing ones
+        self.write(PORT_SATA_ERROR, u32::MAX);
+        self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+        self.write(
+            PORT_INTERRUPT_ENABLE,
+            INTERRUPT_D2H_REGISTER
+                | INTERRUPT_PIO_SETUP
+                | INTERRUPT_DMA_SETUP
+                | INTERRUPT_SET_DEVICE_BITS
+                | INTERRUPT_TASK_FILE_ERROR,
+        );
+
+        self.write(
+            PORT_COMMAND,
+            self.read(PORT_COMMAND) | COMMAND_FIS_RECEIVE_ENABLE,
+        );
+        self.hba.wait_port(
+            self.number,
+            PORT_TASK_FILE,
+            STATUS_BUSY | STATUS_DATA_REQUEST,
+        )?;
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_START);
+        Ok(())
+    }
+
+    /// Runs a command in the first slot, transferring `bytes` bytes of the buffer.
+    fn run(&self, fis: [u8; 20], bytes: usize, write: bool) -> Result<(), BlockError> {
+        self.hba.wait_port(
+            self.number,
+            PORT_TASK_FILE,
+            STATUS_BUSY | STATUS_DATA_REQUEST,
+        )?;
+
+        let table = self.memory.phys_addr().as_u64() + COMMAND_TABLE_OFFSET as u64;
+        let prdt_length = u32::from(bytes > 0);
+        let flags = if write { HEADER_WRITE } else { 0 };
+        let header = [
+            FIS_H2D_LENGTH | flags | prdt_length << 16,
+            0,
+            table as u32,
+            (table >> 32) as u32,
+        ];
+        for (i, dword) in header.into_iter().enumerate() {
+            unsafe {
+                ptr::write_volatile(self.memory.as_mut_ptr(COMMAND_LIST_OFFSET + i * 4), dword)
+            };
+        }
+        for (i, byte) in fis.into_iter().enumerate() {
+            unsafe { ptr::write_volatile(self.memory.as_mut_ptr(COMMAND_TABLE_OFFSET + i), byte) };
+        }
+        if bytes > 0 {
+            // the whole buffer is contiguous, so a single entry describes it
+            let buffer = self.buffer.phys_addr().as_u64();
+            let entry = [
+                buffer as u32,
+                (buffer >> 32) as u32,
+                0,
+                (bytes as u32 - 1) | PRDT_INTERRUPT,
+            ];
+            for (i, dword) in entry.into_iter().enumerate() {
+                unsafe { ptr::write_volatile(self.memory.as_mut_ptr(PRDT_OFFSET + i * 4), dword) };
+            }
+        }
+
+        self.completed.store(0, Ordering::SeqCst);
+        self.write(PORT_COMMAND_ISSUE, 1);
+        let result = self.wait_for_completion().and_then(|()| {
+            match self.read(PORT_TASK_FILE) & STATUS_ERROR {
+                0 => Ok(()),
+                _ => Err(BlockError::Io("the disk reported an error")),
+            }
+        });
+        if let Err(BlockError::Io(_)) = result {
+            self.recover()?;
+        }
+        result
+    }
+
+    /// Gets the port going again after the disk reported an error, which makes the port stop
+    /// processing commands until it is restarted.
+    fn recover(&self) -> Result<(), BlockError> {
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_START);
+        self.hba
+            .wait_port(self.number, PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        // both registers are cleared by writing ones
+        self.write(PORT_SATA_ERROR, u32::MAX);
+        self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+        self.hba.wait_port(
+            self.number,
+            PORT_TASK_FILE,
+            STATUS_BUSY | STATUS_DATA_REQUEST,
+        )?;
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_START);
+        Ok(())
+    }
+
+    fn wait_for_completion(&self) -> Result<(), BlockError> {
+        // without interrupts nobody tells us that the command is done, so we have to look
+        let polling = !x86_64::instructions::interrupts::are_enabled();
+        for _ in 0..TIMEOUT {
+            let status = match polling {
+                true => self.read(PORT_INTERRUPT_STATUS),
+                false => self.completed.load(Ordering::SeqCst),
+            };
+            if status & INTERRUPT_TASK_FILE_ERROR != 0 {
+                self.write(PORT_INTERRUPT_STATUS, status);
+                return Err(BlockError::Io("the disk reported an error"));
+            }
+            if status != 0 && self.read(PORT_COMMAND_ISSUE) & 1 == 0 {
+                if polling {
+                    self.write(PORT_INTERRUPT_STATUS, status);
+                }
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn identify(&self) -> Result<Identify, BlockError> {
+        self.run(command_fis(IDENTIFY, 0, 0), SECTOR_SIZE, false)?;
+        let mut sector = [0; SECTOR_SIZE];
+        self.buffer.read_at(0, &mut sector);
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(sector.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(Identify::parse(&words))
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        // a port that doesn't stop might still write into the memory, so it has to stay ours
+        if self.stop().is_err() {
+            self.memory.leak();
+            self.buffer.leak();
+        }
+    }
+}
+
+/// A disk attached to an AHCI controller.
+pub struct AhciDisk {
+    name: String,
+    port: Mutex<Port>,
+    info: Identify,
+}
+
+impl AhciDisk {
+    pub fn info(&self) -> &Identify {
+        &self.info
+    }
+
+    /// Transfers the sectors starting at `start` in pieces that fit the DMA buffer.
+    fn transfer(
+        &self,
+        start: u64,
+        length: usize,
+        mut copy: impl FnMut(usize, &mut [u8]),
+        write: bool,
+    ) -> Result<(), BlockError> {
+        let count = block::check_request(self, start, length)?;
+        let mut port = self.port.lock();
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(BUFFER_SECTORS);
+            let lba = start + done;
+            let bytes = chunk as usize * SECTOR_SIZE;
+            let offset = done as usize * SECTOR_SIZE;
+            let command = match (write, self.info.lba48) {
+                (false, true) => READ_DMA_EXT,
+                (false, false) => READ_DMA,
+                (true, true) => WRITE_DMA_EXT,
+                (true, false) => WRITE_DMA,
+            };
+            if write {
+                copy(offset, &mut port.buffer.as_mut_slice()[..bytes]);
+            }
+            port.run(command_fis(command, lba, chunk as u16), bytes, write)?;
+            if !write {
+                copy(offset, &mut port.buffer.as_mut_slice()[..bytes]);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let length = buffer.len();
+        self.transfer(
+            start,
+            length,
+            |offset, dma| buffer[offset..][..dma.len()].copy_from_slice(dma),
+            false,
+        )
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.transfer(
+            start,
+            buffer.len(),
+            |offset, dma| dma.copy_from_slice(&buffer[offset..][..dma.len()]),
+            true,
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = if self.info.lba48 {
+            CACHE_FLUSH_EXT
+        } else {
+            CACHE_FLUSH
+        };
+        self.port.lock().run(command_fis(command, 0, 0), 0, false)
+    }
+}
+
+/// Returns the interrupt handler of a controller, which hands the interrupt status of each
+/// port to whoever waits for a command on it.
+fn interrupt_handler(hba: Hba, ports: Vec<(usize, Arc<AtomicU32>)>) -> impl Fn() + Send + Sync {
+    move || {
+        INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        let pending = hba.read(HBA_INTERRUPT_STATUS);
+        for (number, completed) in &ports {
+            if pending & 1 << number != 0 {
+                let status = hba.read_port(*number, PORT_INTERRUPT_STATUS);
+                hba.write_port(*number, PORT_INTERRUPT_STATUS, status);
+                completed.fetch_or(status, Ordering::SeqCst);
+            }
+        }
+        hba.write(HBA_INTERRUPT_STATUS, pending);
+    }
+}
+
+/// The driver for AHCI controllers, which registers a block device for every SATA disk.
+///
+/// The disks are named `sata0`, `sata1` and so on, in the order they are found.
+pub struct AhciDriver;
+
+impl AhciDriver {
+    fn init_controller(&self, pci: PciDevice) -> Result<(), DriverError> {
+        // the registers are in the sixth BAR, the others are for legacy IDE emulation
+        let Some(Bar::Memory { address, .. }) = pci.bar(5) else {
+            return Err(DriverError::Hardware("AHCI registers not found"));
+        };
+        pci.enable();
+        let hba = Hba {
+            base: phys_to_virt(address),
+        };
+        hba.write(
+            HBA_GLOBAL_CONTROL,
+            hba.read(HBA_GLOBAL_CONTROL) | GLOBAL_AHCI_ENABLE,
+        );
+        // the number of ports is in the lowest bits, but not all of them have to be there
+        let port_count = (hba.read(HBA_CAPABILITIES) & 0x1f) as usize + 1;
+        let implemented = hba.read(HBA_PORTS_IMPLEMENTED);
+
+        let mut ports = Vec::new();
+        for number in (0..port_count.min(32)).filter(|number| implemented & 1 << number != 0) {
+            if hba.read_port(number, PORT_SATA_STATUS) & 0xf != SATA_STATUS_ESTABLISHED
+                || hba.read_port(number, PORT_SIGNATURE) != SIGNATURE_ATA
+            {
+                continue;
+            }
+            match Port::new(hba, number) {
+                Ok(port) => ports.push(port),
+                Err(err) => {
+                    serial_println!("ahci: port {} doesn't start: {:?}", number, err);
+                }
+            }
+        }
+        if ports.is_empty() {
+            return Err(DriverError::NoDevice);
+        }
+
+        let completions: Vec<_> = ports
+            .iter()
+            .map(|port| (port.number, port.completed.clone()))
+            .collect();
+        if msi::enable_interrupt(pci, interrupt_handler(hba, completions.clone())).is_err() {
+            interrupts::register_irq_closure(
+                pci.interrupt_line(),
+                interrupt_handler(hba, completions),
+            )
+            .map_err(|_| DriverError::Hardware("no interrupt for the AHCI controller"))?;
+        }
+        hba.write(HBA_INTERRUPT_STATUS, u32::MAX);
+        hba.write(
+            HBA_GLOBAL_CONTROL,
+            hba.read(HBA_GLOBAL_CONTROL) | GLOBAL_INTERRUPT_ENABLE,
+        );
+
+        for port in ports {
+            let info = match port.identify() {
+                Ok(info) => info,
+                Err(err) => {
+                    serial_println!("ahci: port {} doesn't identify: {:?}", port.number, err);
+                    continue;
+                }
+            };
+            let number = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+            block::register(Arc::new(AhciDisk {
+                name: format!("sata{number}"),
+                port: Mutex::new(port),
+                info,
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl Driver for AhciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn match_table(&self) -> &'static [Match] {
+        &[Match::PciClass {
+            class: 0x01,
+            subclass: 0x06,
+            prog_if: Some(0x01),
+        }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        match device {
+            Device::Pci(pci) => self.init_controller(*pci),
+            _ => Err(DriverError::Unsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The test image is attached to the first port of the AHCI controller.
+    fn test_disk() -> Arc<dyn BlockDevice> {
+        block::get("sata0").expect("no SATA disk attached")
+    }
+
+    #[test_case]
+    fn fis_layout() {
+        let fis = command_fis(READ_DMA_EXT, 0x0605_0403_0201, 0x0807);
+        assert_eq!(fis[..4], [FIS_TYPE_H2D, FIS_COMMAND, READ_DMA_EXT, 0]);
+        assert_eq!(fis[4..8], [0x01, 0x02, 0x03, DEVICE_LBA]);
+        assert_eq!(fis[8..11], [0x04, 0x05, 0x06]);
+        assert_eq!(fis[12..14], [0x07, 0x08]);
+    }
+
+    #[test_case]
+    fn read_sectors() {
+        // every sector of the image starts with its own number
+        let disk = test_disk();
+        assert_eq!(disk.block_count(), 2048);
+        let interrupts = INTERRUPTS.load(Ordering::Relaxed);
+
+        let mut buffer = vec![0; 4 * SECTOR_SIZE];
+        disk.read_blocks(700, &mut buffer).unwrap();
+        for (i, sector) in buffer.chunks(SECTOR_SIZE).enumerate() {
+            let number = u64::from_le_bytes(sector[..8].try_into().unwrap());
+            assert_eq!(number, 700 + i as u64);
+        }
+        assert!(INTERRUPTS.load(Ordering::Relaxed) > interrupts);
+    }
+
+    #[test_case]
+    fn write_sectors() {
+        let disk = test_disk();
+        // more than fits in the DMA buffer at once
+        let data: Vec<u8> = (0..200 * SECTOR_SIZE).map(|i| (i % 253) as u8).collect();
+        disk.write_blocks(1200, &data).unwrap();
+        disk.flush().unwrap();
+
+        let mut buffer = vec![0; data.len()];
+        disk.read_blocks(1200, &mut buffer).unwrap();
+        assert!(buffer == data);
+    }
+
+    #[test_case]
+    fn polls_without_interrupts() {
+        let disk = test_disk();
+        let mut buffer = vec![0; SECTOR_SIZE];
+        x86_64::instructions::interrupts::without_interrupts(|| disk.read_blocks(5, &mut buffer))
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(buffer[..8].try_into().unwrap()), 5);
+    }
+}
@@ -0,0 +1,48 @@
+use linked_list_allocator::LockedHeap;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// The virtual address the kernel heap starts at.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// The size of the kernel heap, large enough to hold a back buffer for a 1024x768 screen.
+pub const HEAP_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
+
+// tell the compiler to use this allocator for everything in `alloc`.
+// the heap starts out empty and is only usable after `init_heap` has been called.
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// Maps the pages of the heap to physical frames and hands them to the allocator.
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        // this is safe since the frame allocator only hands out unused frames
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    // this is unsafe because the heap memory has to be mapped and unused,
+    // which we just made sure of above.
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+    }
+
+    Ok(())
+}
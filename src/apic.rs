@@ -0,0 +1,59 @@
+//! The local APIC of the CPU we're running on.
+//!
+//! The 8259 PICs still handle the legacy IRQs, the local APIC is only used to receive
+//! message signalled interrupts. See <https://wiki.osdev.org/APIC>.
+
+use crate::interrupts::APIC_SPURIOUS_VECTOR;
+use crate::memory::phys_to_virt;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// register offsets
+const ID: u64 = 0x20;
+const END_OF_INTERRUPT: u64 = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0xf0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Where MSI messages have to be written to, the target APIC id goes into bits 12..20.
+pub const MSI_ADDRESS: u64 = 0xfee0_0000;
+
+/// Enables the local APIC without touching the interrupts the firmware routed through it,
+/// so the PICs keep working.
+///
+/// Has to be called after [`crate::memory::init`].
+pub fn init() {
+    let mut base = Msr::new(IA32_APIC_BASE);
+    unsafe { base.write(base.read() | APIC_BASE_ENABLE) };
+    write(
+        SPURIOUS_INTERRUPT_VECTOR,
+        SOFTWARE_ENABLE | u32::from(APIC_SPURIOUS_VECTOR),
+    );
+}
+
+/// The id of the local APIC, which is what MSI messages use to address the CPU.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Tells the APIC that we're done handling an interrupt it delivered.
+pub fn end_of_interrupt() {
+    write(END_OF_INTERRUPT, 0);
+}
+
+fn register(offset: u64) -> *mut u32 {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & !0xfff;
+    // the registers are in the range the bootloader mapped along with the physical memory
+    phys_to_virt(PhysAddr::new(base + offset)).as_mut_ptr()
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { register(offset).write_volatile(value) }
+}
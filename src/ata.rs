@@ -0,0 +1,504 @@
+//! Driver for ATA disks on the legacy IDE channels, using programmed I/O.
+//!
+//! See <https://wiki.osdev.org/ATA_PIO_Mode>.
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::pci::Bar;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// the I/O ports of the channels when the controller is in compatibility mode
+const PRIMARY_PORTS: (u16, u16) = (0x1f0, 0x3f6);
+const SECONDARY_PORTS: (u16, u16) = (0x170, 0x376);
+
+// registers relative to the base port
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_SELECT: u16 = 6;
+// reading gives the status, writing sends a command
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// bits of the status register
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+// bits of the device control register
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+// bits of the drive select register
+const SELECT_LBA: u8 = 1 << 6;
+const SELECT_SLAVE: u8 = 1 << 4;
+// always set for compatibility with ancient drives
+const SELECT_OBSOLETE: u8 = 0b1010_0000;
+
+// commands
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const CACHE_FLUSH: u8 = 0xe7;
+const CACHE_FLUSH_EXT: u8 = 0xea;
+const IDENTIFY: u8 = 0xec;
+
+/// Drives that don't support LBA48 can only address this many sectors.
+const LBA28_LIMIT: u64 = 1 << 28;
+/// The most sectors we transfer with a single command, which is what LBA28 allows.
+const MAX_SECTORS_PER_COMMAND: u64 = 256;
+/// How often the status register is polled before giving up on the drive.
+const TIMEOUT: u32 = 1_000_000;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CHANNEL: Mutex<Channel> = Mutex::new(Channel::new(0, 0));
+static CHANNELS: [Mutex<Channel>; 2] = [NO_CHANNEL; 2];
+
+pub static DRIVER: AtaDriver = AtaDriver;
+
+/// One of the two IDE channels, each of which can have a master and a slave drive.
+struct Channel {
+    base: u16,
+    control: u16,
+}
+
+impl Channel {
+    const fn new(base: u16, control: u16) -> Self {
+        Self { base, control }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn alternate_status(&self) -> u8 {
+        // reading the alternate status doesn't acknowledge interrupts
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Selects a drive and gives it the 400ns it needs to put its status on the bus.
+    fn select(&self, value: u8) {
+        self.write(DRIVE_SELECT, value);
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits until the drive is ready to transfer data.
+    fn wait_data_request(&self) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+                return Err(BlockError::Io("the drive reported an error"));
+            }
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Sends the IDENTIFY command and returns what the drive answered, if there is an ATA drive.
+    fn identify(&self, slave: bool) -> Option<Identify> {
+        // all ones means nobody is driving the bus, so there's no channel at all
+        if self.read(STATUS) == 0xff {
+            return None;
+        }
+        self.select(SELECT_OBSOLETE | if slave { SELECT_SLAVE } else { 0 });
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.write(COMMAND, IDENTIFY);
+        if self.read(STATUS) == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA devices identify themselves by putting a signature here
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data_request().ok()?;
+
+        let mut words = [0; 256];
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for word in words.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        Some(Identify::parse(&words))
+    }
+
+    /// Transfers `count` sectors starting at `lba` with a single command.
+    fn transfer(
+        &self,
+        slave: bool,
+        lba: u64,
+        count: u64,
+        lba48: bool,
+        mut buffer: Transfer,
+    ) -> Result<(), BlockError> {
+        debug_assert!(count > 0 && count <= MAX_SECTORS_PER_COMMAND);
+        self.wait_not_busy()?;
+        let slave = if slave { SELECT_SLAVE } else { 0 };
+        let write = matches!(buffer, Transfer::Write(_));
+        if lba48 {
+            self.select(SELECT_OBSOLETE | SELECT_LBA | slave);
+            // the high bytes go first, the registers are two bytes deep
+            self.write(SECTOR_COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, (lba >> 24) as u8);
+            self.write(LBA_MID, (lba >> 32) as u8);
+            self.write(LBA_HIGH, (lba >> 40) as u8);
+            self.write(SECTOR_COUNT, count as u8);
+            self.write(LBA_LOW, lba as u8);
+            self.write(LBA_MID, (lba >> 8) as u8);
+            self.write(LBA_HIGH, (lba >> 16) as u8);
+            self.write(
+                COMMAND,
+                if write {
+                    WRITE_SECTORS_EXT
+                } else {
+                    READ_SECTORS_EXT
+                },
+            );
+        } else {
+            // the top 4 bits of the address go into the drive select register
+            self.select(SELECT_OBSOLETE | SELECT_LBA | slave | (lba >> 24) as u8 & 0xf);
+            // a count of 0 means 256 sectors
+            self.write(SECTOR_COUNT, count as u8);
+            self.write(LBA_LOW, lba as u8);
+            self.write(LBA_MID, (lba >> 8) as u8);
+            self.write(LBA_HIGH, (lba >> 16) as u8);
+            self.write(COMMAND, if write { WRITE_SECTORS } else { READ_SECTORS });
+        }
+
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for sector in 0..count as usize {
+            self.wait_data_request()?;
+            let range = sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE;
+            match &mut buffer {
+                Transfer::Read(buffer) => {
+                    for word in buffer[range].chunks_exact_mut(2) {
+                        word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+                    }
+                }
+                Transfer::Write(buffer) => {
+                    for word in buffer[range].chunks_exact(2) {
+                        unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+                    }
+                }
+            }
+        }
+        // the drive is busy until the last sector has been written
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            return Err(BlockError::Io("the drive reported an error"));
+        }
+        Ok(())
+    }
+
+    fn flush(&self, slave: bool, lba48: bool) -> Result<(), BlockError> {
+        self.wait_not_busy()?;
+        self.select(SELECT_OBSOLETE | if slave { SELECT_SLAVE } else { 0 });
+        self.write(COMMAND, if lba48 { CACHE_FLUSH_EXT } else { CACHE_FLUSH });
+        let status = self.wait_not_busy()?;
+        if status & STATUS_ERROR != 0 {
+            let _ = self.read(ERROR);
+            return Err(BlockError::Io("flushing the cache failed"));
+        }
+        Ok(())
+    }
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// The interesting parts of what a drive answers to IDENTIFY.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identify {
+    pub model: String,
+    pub sectors: u64,
+    pub lba48: bool,
+}
+
+impl Identify {
+    pub(crate) fn parse(words: &[u16; 256]) -> Self {
+        // every word of the model string has its two characters swapped
+        let model: String = words[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(char::from)
+            .collect();
+        let lba48 = words[83] & 1 << 10 != 0;
+        let sectors = if lba48 {
+            words[100..104]
+                .iter()
+                .rev()
+                .fold(0, |sectors, &word| sectors << 16 | u64::from(word))
+        } else {
+            u64::from(words[61]) << 16 | u64::from(words[60])
+        };
+        Self {
+            model: String::from(model.trim_end()),
+            sectors,
+            lba48,
+        }
+    }
+}
+
+/// A drive attached to one of the IDE channels.
+pub struct AtaDrive {
+    name: String,
+    channel: &'static Mutex<Channel>,
+    slave: bool,
+    info: Identify,
+}
+
+impl AtaDrive {
+    pub fn info(&self) -> &Identify {
+        &self.info
+    }
+
+    fn transfer_all(
+        &self,
+        start: u64,
+        length: usize,
+        mut buffer: Transfer,
+    ) -> Result<(), BlockError> {
+        use x86_64::instructions::interrupts;
+
+        let count = block::check_request(self, start, length)?;
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(MAX_SECTORS_PER_COMMAND);
+            let lba = start + done;
+            let range = done as usize * SECTOR_SIZE..(done + chunk) as usize * SECTOR_SIZE;
+            let part = match &mut buffer {
+                Transfer::Read(buffer) => Transfer::Read(&mut buffer[range]),
+                Transfer::Write(buffer) => Transfer::Write(&buffer[range]),
+            };
+            // LBA28 needs less port writes, so we only use LBA48 when we have to
+            let lba48 = lba + chunk > LBA28_LIMIT;
+            interrupts::without_interrupts(|| {
+                self.channel
+                    .lock()
+                    .transfer(self.slave, lba, chunk, lba48, part)
+            })?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let length = buffer.len();
+        self.transfer_all(start, length, Transfer::Read(buffer))
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.transfer_all(start, buffer.len(), Transfer::Write(buffer))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| self.channel.lock().flush(self.slave, self.info.lba48))
+    }
+}
+
+/// The driver for IDE controllers, which registers a block device for every ATA drive.
+///
+/// The drives are named `ata0` to `ata3`: primary master, primary slave, secondary master
+/// and secondary slave.
+pub struct AtaDriver;
+
+impl Driver for AtaDriver {
+    fn name(&self) -> &'static str {
+        "ata"
+    }
+
+    fn match_table(&self) -> &'static [Match] {
+        &[Match::PciClass {
+            class: 0x01,
+            subclass: 0x01,
+            prog_if: None,
+        }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        let Device::Pci(pci) = device else {
+            return Err(DriverError::Unsupported);
+        };
+
+        let mut found = false;
+        for (index, legacy_ports) in [PRIMARY_PORTS, SECONDARY_PORTS].into_iter().enumerate() {
+            // a channel in native mode gets its ports from the BARs instead of the legacy ones
+            let native = pci.prog_if & 1 << (index * 2) != 0;
+            let (base, control) = if native {
+                match (pci.bar(index * 2), pci.bar(index * 2 + 1)) {
+                    (Some(Bar::Io { port: base, .. }), Some(Bar::Io { port: control, .. })) => {
+                        (base, control + 2)
+                    }
+                    _ => continue,
+                }
+            } else {
+                legacy_ports
+            };
+
+            let channel = &CHANNELS[index];
+            *channel.lock() = Channel::new(base, control);
+            // we poll the drives, so they don't need to raise interrupts
+            unsafe { Port::new(control).write(CONTROL_NO_INTERRUPTS) };
+
+            for slave in [false, true] {
+                let Some(info) = channel.lock().identify(slave) else {
+                    continue;
+                };
+                let number = index * 2 + usize::from(slave);
+                block::register(Arc::new(AtaDrive {
+                    name: format!("ata{number}"),
+                    channel,
+                    slave,
+                    info,
+                }));
+                found = true;
+            }
+        }
+        match found {
+            true => Ok(()),
+            false => Err(DriverError::NoDevice),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The test image is attached as the primary slave, the primary master is the boot disk.
+    fn test_disk() -> Arc<dyn BlockDevice> {
+        block::get("ata1").expect("test disk not attached")
+    }
+
+    #[test_case]
+    fn parse_identify() {
+        let mut words = [0; 256];
+        // "QEMU HARDDISK" with the bytes of every word swapped
+        for (word, pair) in words[27..47]
+            .iter_mut()
+            .zip(b"QEMU HARDDISK       ".chunks(2))
+        {
+            *word = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        words[60] = 0x1000;
+        let info = Identify::parse(&words);
+        assert_eq!(info.model, "QEMU HARDDISK");
+        assert_eq!(info.sectors, 0x1000);
+        assert!(!info.lba48);
+
+        words[83] = 1 << 10;
+        words[100] = 0x0000;
+        words[101] = 0x0001;
+        let info = Identify::parse(&words);
+        assert!(info.lba48);
+        assert_eq!(info.sectors, 0x1_0000);
+    }
+
+    #[test_case]
+    fn identifies_test_disk() {
+        let disk = test_disk();
+        // the image is 1 MiB large
+        assert_eq!(disk.block_count(), 2048);
+        assert_eq!(disk.block_size(), SECTOR_SIZE);
+    }
+
+    #[test_case]
+    fn read_sectors() {
+        // every sector of the image starts with its own number
+        let disk = test_disk();
+        let mut buffer = vec![0; 3 * SECTOR_SIZE];
+        disk.read_blocks(1000, &mut buffer).unwrap();
+        for (i, sector) in buffer.chunks(SECTOR_SIZE).enumerate() {
+            let number = u64::from_le_bytes(sector[..8].try_into().unwrap());
+            assert_eq!(number, 1000 + i as u64);
+        }
+
+        assert_eq!(
+            disk.read_blocks(2047, &mut buffer),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            disk.read_blocks(0, &mut buffer[..100]),
+            Err(BlockError::BadBufferSize)
+        );
+    }
+
+    #[test_case]
+    fn write_sectors() {
+        let disk = test_disk();
+        // more than a single command can transfer
+        let data: alloc::vec::Vec<u8> = (0..300 * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+        disk.write_blocks(1500, &data).unwrap();
+        disk.flush().unwrap();
+
+        let mut buffer = vec![0; data.len()];
+        disk.read_blocks(1500, &mut buffer).unwrap();
+        assert!(buffer == data);
+    }
+
+    #[test_case]
+    fn lba48() {
+        // the test disk is too small to need LBA48, so we use it directly
+        let mut buffer = vec![0; 2 * SECTOR_SIZE];
+        let channel = &CHANNELS[0];
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            channel
+                .lock()
+                .transfer(true, 42, 2, true, Transfer::Read(&mut buffer))
+        })
+        .unwrap();
+        assert_eq!(u64::from_le_bytes(buffer[..8].try_into().unwrap()), 42);
+        assert_eq!(
+            u64::from_le_bytes(buffer[SECTOR_SIZE..][..8].try_into().unwrap()),
+            43
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Block devices, i.e. disks that are read and written in fixed size blocks.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub mod cache;
+pub mod gpt;
+pub mod mbr;
+pub mod partition;
+pub mod queue;
+pub mod ram;
+
+pub use cache::CachedDevice;
+pub use partition::Partition;
+pub use ram::RamDisk;
+
+/// The block size of pretty much every disk.
+pub const SECTOR_SIZE: usize = 512;
+
+// every block device the drivers found
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a multiple of the block size.
+    BadBufferSize,
+    /// The device doesn't respond.
+    Timeout,
+    /// The device reported an error.
+    Io(&'static str),
+}
+
+/// A disk or something that behaves like one.
+///
+/// The methods take `&self` so devices can be shared, drivers have to serialize
+/// the requests themselves.
+pub trait BlockDevice: Send + Sync {
+    /// A short name to tell the devices apart, like `ata0`.
+    fn name(&self) -> &str;
+
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// The number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Reads the blocks starting at `start` into `buffer`, whose length has to be
+    /// a multiple of the block size.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer` to the blocks starting at `start`.
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure everything written so far actually ended up on the disk.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// The device this one is a part of and the block it starts at, for partitions.
+    ///
+    /// The buffer cache uses this so a partition and its disk share the cached blocks.
+    fn parent(&self) -> Option<(Arc<dyn BlockDevice>, u64)> {
+        None
+    }
+}
+
+/// Checks that a request for `length` bytes starting at block `start` fits the device and
+/// returns the number of blocks it covers.
+pub fn check_request(
+    device: &(impl BlockDevice + ?Sized),
+    start: u64,
+    length: usize,
+) -> Result<u64, BlockError> {
+    let count = length / device.block_size();
+    if count * device.block_size() != length {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = count as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Makes a block device available to the rest of the kernel, along with its partitions.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().push(device.clone());
+    if device.parent().is_some() {
+        return;
+    }
+    match partition::scan(&device) {
+        Ok(partitions) => {
+            for partition in partitions {
+                DEVICES.lock().push(partition);
+            }
+        }
+        Err(err) => {
+            serial_println!("{}: bad partition table: {:?}", device.name(), err);
+        }
+    }
+}
+
+/// Returns all block devices.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// Looks up a block device by its name.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
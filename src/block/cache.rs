@@ -0,0 +1,391 @@
+//! The buffer cache, which keeps recently used blocks in memory so filesystems don't have to
+//! go to the disk for every access.
+//!
+//! Writes only change the cached copy, they reach the disk when the block gets evicted or the
+//! device is synced.
+
+use super::queue::{Request, RequestQueue};
+use super::{check_request, BlockDevice, BlockError};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// How many blocks the global cache holds, which is 512 KiB with the usual sector size.
+pub const CACHE_BLOCKS: usize = 1024;
+
+static CACHE: Mutex<BufferCache> = Mutex::new(BufferCache::new(CACHE_BLOCKS));
+
+// a device is identified by the address of its data, which stays put as long as it's alive
+type Key = (usize, u64);
+
+fn device_id(device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(device) as *const () as usize
+}
+
+/// Follows partitions down to the disk they are on, so both share the cached blocks.
+fn resolve(device: &Arc<dyn BlockDevice>, block: u64) -> (Arc<dyn BlockDevice>, u64) {
+    let mut device = device.clone();
+    let mut block = block;
+    while let Some((parent, start)) = device.parent() {
+        device = parent;
+        block += start;
+    }
+    (device, block)
+}
+
+struct Entry {
+    device: Arc<dyn BlockDevice>,
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// A least recently used cache of blocks, keyed by device and block number.
+pub struct BufferCache {
+    capacity: usize,
+    entries: BTreeMap<Key, Entry>,
+    // the keys of the entries by the time they were used last
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl BufferCache {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The number of blocks that were found in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of blocks that had to be read from the device.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Marks an entry as the most recently used one.
+    fn touch(&mut self, key: Key) -> Option<&mut Entry> {
+        let entry = self.entries.get_mut(&key)?;
+        self.lru.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.lru.insert(self.clock, key);
+        Some(entry)
+    }
+
+    /// Puts a block into the cache, making room for it if necessary.
+    fn insert(
+        &mut self,
+        key: Key,
+        device: &Arc<dyn BlockDevice>,
+        data: &[u8],
+        dirty: bool,
+    ) -> Result<(), BlockError> {
+        if let Some(entry) = self.touch(key) {
+            entry.data.copy_from_slice(data);
+            entry.dirty |= dirty;
+            return Ok(());
+        }
+        while self.entries.len() >= self.capacity {
+            self.evict()?;
+        }
+        self.clock += 1;
+        self.lru.insert(self.clock, key);
+        self.entries.insert(
+            key,
+            Entry {
+                device: device.clone(),
+                data: data.to_vec(),
+                dirty,
+                last_used: self.clock,
+            },
+        );
+        Ok(())
+    }
+
+    /// Throws out the least recently used block, writing it back first if it was changed.
+    fn evict(&mut self) -> Result<(), BlockError> {
+        let Some((&time, &key)) = self.lru.iter().next() else {
+            return Ok(());
+        };
+        let entry = &self.entries[&key];
+        if entry.dirty {
+            entry.device.write_blocks(key.1, &entry.data)?;
+        }
+        self.lru.remove(&time);
+        self.entries.remove(&key);
+        Ok(())
+    }
+
+    /// Reads the blocks starting at `start`, going to the device only for those that aren't
+    /// cached.
+    pub fn read(
+        &mut self,
+        device: &Arc<dyn BlockDevice>,
+        start: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockError> {
+        let count = check_request(device.as_ref(), start, buffer.len())?;
+        let block_size = device.block_size();
+        let (root, first) = resolve(device, start);
+        let id = device_id(&root);
+
+        let mut queue = RequestQueue::new();
+        for i in 0..count {
+            let target = &mut buffer[i as usize * block_size..][..block_size];
+            match self.touch((id, first + i)) {
+                Some(entry) => {
+                    target.copy_from_slice(&entry.data);
+                    self.hits += 1;
+                }
+                None => {
+                    queue.submit(Request::read(first + i, 1, block_size));
+                    self.misses += 1;
+                }
+            }
+        }
+        // neighbouring misses get read with a single transfer
+        let (_, requests) = queue.run(root.as_ref())?;
+        for request in requests {
+            let offset = (request.block - first) as usize * block_size;
+            buffer[offset..][..block_size].copy_from_slice(&request.data);
+            self.insert((id, request.block), &root, &request.data, false)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the blocks starting at `start` into the cache, they reach the device later.
+    pub fn write(
+        &mut self,
+        device: &Arc<dyn BlockDevice>,
+        start: u64,
+        buffer: &[u8],
+    ) -> Result<(), BlockError> {
+        check_request(device.as_ref(), start, buffer.len())?;
+        let (root, first) = resolve(device, start);
+        let id = device_id(&root);
+        for (i, data) in buffer.chunks(device.block_size()).enumerate() {
+            self.insert((id, first + i as u64), &root, data, true)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the changed blocks of a device back and flushes it. For partitions that's the
+    /// whole disk they are on.
+    pub fn sync(&mut self, device: &Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+        let (root, _) = resolve(device, 0);
+        let id = device_id(&root);
+        let mut queue = RequestQueue::new();
+        for (&(_, block), entry) in self.entries.range((id, 0)..=(id, u64::MAX)) {
+            if entry.dirty {
+                queue.submit(Request::write(block, entry.data.clone()));
+            }
+        }
+        let (_, written) = queue.run(root.as_ref())?;
+        for request in written {
+            if let Some(entry) = self.entries.get_mut(&(id, request.block)) {
+                entry.dirty = false;
+            }
+        }
+        root.flush()
+    }
+
+    /// Syncs every device that has changed blocks in the cache.
+    pub fn sync_all(&mut self) -> Result<(), BlockError> {
+        let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+        for entry in self.entries.values().filter(|entry| entry.dirty) {
+            if !devices
+                .iter()
+                .any(|device| Arc::ptr_eq(device, &entry.device))
+            {
+                devices.push(entry.device.clone());
+            }
+        }
+        for device in devices {
+            self.sync(&device)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the cached blocks of a device without writing them back.
+    pub fn invalidate(&mut self, device: &Arc<dyn BlockDevice>) {
+        let (root, _) = resolve(device, 0);
+        let id = device_id(&root);
+        let lru = &mut self.lru;
+        self.entries.retain(|&(entry_id, _), entry| {
+            let keep = entry_id != id;
+            if !keep {
+                lru.remove(&entry.last_used);
+            }
+            keep
+        });
+    }
+}
+
+/// Reads blocks through the global cache.
+pub fn read(
+    device: &Arc<dyn BlockDevice>,
+    start: u64,
+    buffer: &mut [u8],
+) -> Result<(), BlockError> {
+    CACHE.lock().read(device, start, buffer)
+}
+
+/// Writes blocks into the global cache.
+pub fn write(device: &Arc<dyn BlockDevice>, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+    CACHE.lock().write(device, start, buffer)
+}
+
+/// Writes the changed blocks of a device in the global cache back.
+pub fn sync(device: &Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    CACHE.lock().sync(device)
+}
+
+/// Writes everything that changed in the global cache back.
+pub fn sync_all() -> Result<(), BlockError> {
+    CACHE.lock().sync_all()
+}
+
+/// A block device that goes through the global buffer cache, which is what filesystems should
+/// be mounted on.
+pub struct CachedDevice {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self { device }
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        read(&self.device, start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        write(&self.device, start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        sync(&self.device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Partition, RamDisk};
+    use alloc::string::String;
+    use alloc::vec;
+
+    fn ram_disk(blocks: u64) -> (Arc<RamDisk>, Arc<dyn BlockDevice>) {
+        let disk = Arc::new(RamDisk::new("ram", 512, blocks));
+        (disk.clone(), disk)
+    }
+
+    #[test_case]
+    fn caches_reads() {
+        let (ram, disk) = ram_disk(16);
+        let mut cache = BufferCache::new(8);
+        let mut buffer = vec![0; 4 * 512];
+        cache.read(&disk, 2, &mut buffer).unwrap();
+        // the four misses are read with a single transfer
+        assert_eq!((cache.misses(), cache.hits(), ram.reads()), (4, 0, 1));
+        cache.read(&disk, 2, &mut buffer).unwrap();
+        assert_eq!((cache.misses(), cache.hits(), ram.reads()), (4, 4, 1));
+    }
+
+    #[test_case]
+    fn evicts_least_recently_used() {
+        let (ram, disk) = ram_disk(16);
+        let mut cache = BufferCache::new(2);
+        let mut buffer = vec![0; 512];
+        cache.read(&disk, 0, &mut buffer).unwrap();
+        cache.read(&disk, 1, &mut buffer).unwrap();
+        cache.read(&disk, 0, &mut buffer).unwrap();
+        // block 1 was used longer ago, so it has to go
+        cache.read(&disk, 2, &mut buffer).unwrap();
+        assert_eq!(cache.len(), 2);
+        cache.read(&disk, 0, &mut buffer).unwrap();
+        assert_eq!(ram.reads(), 3);
+        cache.read(&disk, 1, &mut buffer).unwrap();
+        assert_eq!(ram.reads(), 4);
+    }
+
+    #[test_case]
+    fn writes_back() {
+        let (ram, disk) = ram_disk(16);
+        let mut cache = BufferCache::new(4);
+        cache.write(&disk, 3, &[1; 1024]).unwrap();
+        cache.write(&disk, 5, &[2; 512]).unwrap();
+        assert_eq!(ram.writes(), 0);
+
+        // blocks 3 to 5 are next to each other and go out together
+        cache.sync(&disk).unwrap();
+        assert_eq!(ram.writes(), 1);
+        let mut buffer = vec![0; 512];
+        ram.read_blocks(5, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&byte| byte == 2));
+
+        // clean blocks don't have to be written again
+        cache.sync(&disk).unwrap();
+        assert_eq!(ram.writes(), 1);
+
+        // evicting a changed block writes it
+        cache.write(&disk, 0, &[3; 512]).unwrap();
+        for block in 8..12 {
+            cache.read(&disk, block, &mut buffer).unwrap();
+        }
+        assert_eq!(ram.writes(), 2);
+    }
+
+    #[test_case]
+    fn partitions_share_blocks() {
+        let (ram, disk) = ram_disk(16);
+        let partition: Arc<dyn BlockDevice> =
+            Arc::new(Partition::new(String::from("ram1"), disk.clone(), 8, 8).unwrap());
+        let mut cache = BufferCache::new(8);
+        cache.write(&partition, 1, &[9; 512]).unwrap();
+
+        let mut buffer = vec![0; 512];
+        cache.read(&disk, 9, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&byte| byte == 9));
+        assert_eq!(ram.reads(), 0);
+
+        cache.sync_all().unwrap();
+        ram.read_blocks(9, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&byte| byte == 9));
+    }
+}
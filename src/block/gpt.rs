@@ -0,0 +1,278 @@
+//! The GUID partition table.
+//!
+//! See <https://wiki.osdev.org/GPT>.
+
+use super::partition::{PartitionError, PartitionInfo, PartitionType};
+use super::BlockDevice;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+// fields of the header
+const HEADER_SIZE: usize = 12;
+const HEADER_CRC: usize = 16;
+const MY_LBA: usize = 24;
+const ALTERNATE_LBA: usize = 32;
+const ENTRIES_LBA: usize = 72;
+const ENTRY_COUNT: usize = 80;
+const ENTRY_SIZE: usize = 84;
+const ENTRIES_CRC: usize = 88;
+/// The header has to be at least this large to hold all of the fields above.
+const MIN_HEADER_SIZE: usize = 92;
+
+// fields of a partition entry
+const ENTRY_TYPE: usize = 0;
+const ENTRY_FIRST_LBA: usize = 32;
+const ENTRY_LAST_LBA: usize = 40;
+const ENTRY_NAME: usize = 56;
+const NAME_LENGTH: usize = 36;
+
+/// Keeps a corrupted header from making us read half the disk.
+const MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// A globally unique identifier, in the mixed endian layout GPT stores them in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The type of unused entries.
+    pub const UNUSED: Guid = Guid([0; 16]);
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        // the first three groups are little endian, the rest is stored as it's printed
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+/// The CRC-32 GPT uses, which is the one of zlib and Ethernet.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..][..8].try_into().unwrap())
+}
+
+/// Reads the header at `lba` and checks its CRC.
+fn read_header(device: &dyn BlockDevice, lba: u64) -> Result<Vec<u8>, PartitionError> {
+    let mut header = vec![0; device.block_size()];
+    device.read_blocks(lba, &mut header)?;
+    if &header[..8] != SIGNATURE {
+        return Err(PartitionError::Invalid("no GPT header"));
+    }
+    let size = read_u32(&header, HEADER_SIZE) as usize;
+    if !(MIN_HEADER_SIZE..=header.len()).contains(&size) || read_u64(&header, MY_LBA) != lba {
+        return Err(PartitionError::Invalid("broken GPT header"));
+    }
+    // the CRC is calculated with its own field zeroed
+    let expected = read_u32(&header, HEADER_CRC);
+    let mut copy = header[..size].to_vec();
+    copy[HEADER_CRC..][..4].fill(0);
+    if crc32(&copy) != expected {
+        return Err(PartitionError::BadChecksum("GPT header"));
+    }
+    Ok(header)
+}
+
+/// Reads the partition entries a header points to and checks their CRC.
+fn read_entries(
+    device: &dyn BlockDevice,
+    header: &[u8],
+) -> Result<Vec<PartitionInfo>, PartitionError> {
+    let count = read_u32(header, ENTRY_COUNT) as usize;
+    let entry_size = read_u32(header, ENTRY_SIZE) as usize;
+    let size = count * entry_size;
+    if entry_size < ENTRY_NAME + NAME_LENGTH * 2 || size > MAX_ENTRIES_SIZE {
+        return Err(PartitionError::Invalid("broken GPT header"));
+    }
+    let block_size = device.block_size();
+    let mut entries = vec![0; size.div_ceil(block_size) * block_size];
+    device.read_blocks(read_u64(header, ENTRIES_LBA), &mut entries)?;
+    if crc32(&entries[..size]) != read_u32(header, ENTRIES_CRC) {
+        return Err(PartitionError::BadChecksum("GPT partition entries"));
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries[..size].chunks(entry_size).enumerate() {
+        let kind = Guid(entry[ENTRY_TYPE..][..16].try_into().unwrap());
+        if kind == Guid::UNUSED {
+            continue;
+        }
+        let first = read_u64(entry, ENTRY_FIRST_LBA);
+        let last = read_u64(entry, ENTRY_LAST_LBA);
+        if last < first {
+            return Err(PartitionError::Invalid(
+                "GPT partition ends before it starts",
+            ));
+        }
+        let name = char::decode_utf16(
+            entry[ENTRY_NAME..][..NAME_LENGTH * 2]
+                .chunks(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .take_while(|&unit| unit != 0),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect::<String>();
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start: first,
+            // the last block belongs to the partition
+            count: last - first + 1,
+            kind: PartitionType::Gpt { kind, name },
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads the partitions of a GPT disk. If the primary header or its entries are damaged, the
+/// backup copy at the end of the disk is used.
+pub fn read_partitions(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, PartitionError> {
+    let primary = read_header(device, 1).and_then(|header| read_entries(device, &header));
+    match primary {
+        Ok(partitions) => Ok(partitions),
+        Err(PartitionError::Block(err)) => Err(PartitionError::Block(err)),
+        Err(err) => {
+            let last = device.block_count() - 1;
+            // the primary header knows where the backup is, but it might be the broken part
+            let backup = read_header(device, 1)
+                .map(|header| read_u64(&header, ALTERNATE_LBA))
+                .unwrap_or(last)
+                .min(last);
+            read_header(device, backup)
+                .and_then(|header| read_entries(device, &header))
+                .map_err(|_| err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mbr::tests::set_entry;
+    use crate::block::mbr::TYPE_GPT_PROTECTIVE;
+    use crate::block::partition;
+    use crate::block::RamDisk;
+
+    const LINUX_FILESYSTEM: [u8; 16] = [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ];
+    const BLOCKS: u64 = 2048;
+
+    /// Writes a header at `lba` whose entries are at `entries_lba`.
+    fn write_header(disk: &RamDisk, lba: u64, alternate: u64, entries_lba: u64, entries: &[u8]) {
+        let mut header = vec![0; 512];
+        header[..8].copy_from_slice(SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[HEADER_SIZE..][..4].copy_from_slice(&92u32.to_le_bytes());
+        header[MY_LBA..][..8].copy_from_slice(&lba.to_le_bytes());
+        header[ALTERNATE_LBA..][..8].copy_from_slice(&alternate.to_le_bytes());
+        header[ENTRIES_LBA..][..8].copy_from_slice(&entries_lba.to_le_bytes());
+        header[ENTRY_COUNT..][..4].copy_from_slice(&128u32.to_le_bytes());
+        header[ENTRY_SIZE..][..4].copy_from_slice(&128u32.to_le_bytes());
+        header[ENTRIES_CRC..][..4].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[HEADER_CRC..][..4].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(lba, &header).unwrap();
+        disk.write_blocks(entries_lba, entries).unwrap();
+    }
+
+    fn gpt_disk() -> RamDisk {
+        let disk = RamDisk::new("ram", 512, BLOCKS);
+        let mut mbr = vec![0; 512];
+        set_entry(&mut mbr, 0, TYPE_GPT_PROTECTIVE, 1, BLOCKS as u32 - 1);
+        disk.write_blocks(0, &mbr).unwrap();
+
+        let mut entries = vec![0; 128 * 128];
+        for (i, (first, last, name)) in [(64u64, 1023u64, "boot"), (1024, 2000, "root")]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = &mut entries[i * 128..][..128];
+            entry[..16].copy_from_slice(&LINUX_FILESYSTEM);
+            entry[ENTRY_FIRST_LBA..][..8].copy_from_slice(&first.to_le_bytes());
+            entry[ENTRY_LAST_LBA..][..8].copy_from_slice(&last.to_le_bytes());
+            for (j, unit) in name.encode_utf16().enumerate() {
+                entry[ENTRY_NAME + j * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        write_header(&disk, 1, BLOCKS - 1, 2, &entries);
+        write_header(&disk, BLOCKS - 1, 1, BLOCKS - 33, &entries);
+        disk
+    }
+
+    #[test_case]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test_case]
+    fn guid_display() {
+        assert_eq!(
+            alloc::format!("{}", Guid(LINUX_FILESYSTEM)),
+            "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+        );
+    }
+
+    #[test_case]
+    fn partitions() {
+        let disk = gpt_disk();
+        let partitions = partition::read_table(&disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!((partitions[0].start, partitions[0].count), (64, 960));
+        assert_eq!((partitions[1].start, partitions[1].count), (1024, 977));
+        assert_eq!(
+            partitions[1].kind,
+            PartitionType::Gpt {
+                kind: Guid(LINUX_FILESYSTEM),
+                name: String::from("root"),
+            }
+        );
+    }
+
+    #[test_case]
+    fn falls_back_to_backup() {
+        let disk = gpt_disk();
+        // damage the primary entries, which the CRC of the partition entries catches
+        disk.write_blocks(2, &[0xff; 512]).unwrap();
+        assert_eq!(partition::read_table(&disk).unwrap().len(), 2);
+
+        // with the backup gone too there's nothing left to trust
+        disk.write_blocks(BLOCKS - 33, &[0xff; 512]).unwrap();
+        assert_eq!(
+            partition::read_table(&disk),
+            Err(PartitionError::BadChecksum("GPT partition entries"))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! The partition table of the master boot record, including the chain of extended boot
+//! records that holds the logical partitions.
+//!
+//! See <https://wiki.osdev.org/MBR_(x86)> and <https://en.wikipedia.org/wiki/Extended_boot_record>.
+
+use super::partition::{PartitionError, PartitionInfo, PartitionType};
+use super::BlockDevice;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const SIGNATURE_OFFSET: usize = 510;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// The type of the protective partition that covers a GPT disk.
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+// the types of extended partitions, with CHS addressing, LBA addressing and the Linux one
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Logical partitions are numbered from here on.
+const FIRST_LOGICAL: usize = 5;
+/// Protects against extended boot records that point at each other.
+const MAX_LOGICAL: usize = 128;
+
+/// An entry of a partition table in a boot record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    kind: u8,
+    start: u64,
+    count: u64,
+}
+
+/// Returns the four entries of the boot record in `sector`, or `None` if it isn't one.
+fn entries(sector: &[u8]) -> Option<[Entry; 4]> {
+    if sector[SIGNATURE_OFFSET..][..2] != SIGNATURE {
+        return None;
+    }
+    Some(core::array::from_fn(|i| {
+        let entry = &sector[TABLE_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        Entry {
+            kind: entry[4],
+            start: u64::from(u32::from_le_bytes(entry[8..12].try_into().unwrap())),
+            count: u64::from(u32::from_le_bytes(entry[12..16].try_into().unwrap())),
+        }
+    }))
+}
+
+/// Reads the first sector and returns the type of the primary partitions that are used, or
+/// `None` if the disk has no master boot record.
+pub fn primary_types(device: &dyn BlockDevice) -> Result<Option<Vec<u8>>, PartitionError> {
+    let mut sector = vec![0; device.block_size()];
+    device.read_blocks(0, &mut sector)?;
+    Ok(entries(&sector).map(|entries| {
+        entries
+            .iter()
+            .filter(|entry| entry.kind != 0)
+            .map(|entry| entry.kind)
+            .collect()
+    }))
+}
+
+/// Reads the partitions of a disk with a master boot record. Returns `None` if it has none.
+pub fn read_partitions(
+    device: &dyn BlockDevice,
+) -> Result<Option<Vec<PartitionInfo>>, PartitionError> {
+    let mut sector = vec![0; device.block_size()];
+    device.read_blocks(0, &mut sector)?;
+    let Some(primary) = entries(&sector) else {
+        return Ok(None);
+    };
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (i, entry) in primary.iter().enumerate() {
+        if entry.kind == 0 || entry.count == 0 {
+            continue;
+        }
+        // the extended partition only holds the logical ones, it isn't used on its own
+        if EXTENDED_TYPES.contains(&entry.kind) {
+            extended = Some(entry.start);
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start: entry.start,
+            count: entry.count,
+            kind: PartitionType::Mbr(entry.kind),
+        });
+    }
+
+    // every extended boot record describes one logical partition relative to itself, and
+    // where the next record is relative to the start of the extended partition
+    if let Some(extended_start) = extended {
+        let mut record = extended_start;
+        for number in FIRST_LOGICAL.. {
+            if number - FIRST_LOGICAL == MAX_LOGICAL {
+                return Err(PartitionError::Invalid("too many logical partitions"));
+            }
+            device.read_blocks(record, &mut sector)?;
+            let Some([logical, next, ..]) = entries(&sector) else {
+                return Err(PartitionError::Invalid("broken extended boot record"));
+            };
+            if logical.kind != 0 && logical.count != 0 {
+                partitions.push(PartitionInfo {
+                    number,
+                    start: record + logical.start,
+                    count: logical.count,
+                    kind: PartitionType::Mbr(logical.kind),
+                });
+            }
+            if next.kind == 0 || next.start == 0 {
+                break;
+            }
+            record = extended_start + next.start;
+        }
+    }
+    Ok(Some(partitions))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    /// Writes a partition table entry into a boot record.
+    pub fn set_entry(sector: &mut [u8], index: usize, kind: u8, start: u32, count: u32) {
+        let entry = &mut sector[TABLE_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&count.to_le_bytes());
+        sector[SIGNATURE_OFFSET..][..2].copy_from_slice(&SIGNATURE);
+    }
+
+    fn set_record(disk: &RamDisk, sector: u64, entries: &[(u8, u32, u32)]) {
+        let mut record = vec![0; 512];
+        for (index, &(kind, start, count)) in entries.iter().enumerate() {
+            set_entry(&mut record, index, kind, start, count);
+        }
+        disk.write_blocks(sector, &record).unwrap();
+    }
+
+    #[test_case]
+    fn primary_and_logical_partitions() {
+        let disk = RamDisk::new("ram", 512, 4096);
+        set_record(
+            &disk,
+            0,
+            &[(0x83, 64, 512), (0x00, 0, 0), (0x0f, 1024, 2048)],
+        );
+        // two logical partitions, each behind its own record
+        set_record(&disk, 1024, &[(0x83, 32, 256), (0x05, 512, 512)]);
+        set_record(&disk, 1536, &[(0x0c, 32, 128)]);
+
+        let partitions = read_partitions(&disk).unwrap().unwrap();
+        let summary: Vec<_> = partitions
+            .iter()
+            .map(|partition| (partition.number, partition.start, partition.count))
+            .collect();
+        assert_eq!(summary, [(1, 64, 512), (5, 1056, 256), (6, 1568, 128)]);
+        assert_eq!(partitions[2].kind, PartitionType::Mbr(0x0c));
+    }
+
+    #[test_case]
+    fn no_boot_record() {
+        let disk = RamDisk::new("ram", 512, 16);
+        assert_eq!(read_partitions(&disk), Ok(None));
+        assert_eq!(primary_types(&disk), Ok(None));
+    }
+}
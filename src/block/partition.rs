@@ -0,0 +1,172 @@
+//! Partitions, ranges of blocks of a disk that act as devices of their own, and reading them
+//! from the partition table.
+
+use super::gpt::{self, Guid};
+use super::mbr::{self, TYPE_GPT_PROTECTIVE};
+use super::{check_request, BlockDevice, BlockError};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionError {
+    Block(BlockError),
+    /// The CRC of a part of the table doesn't match.
+    BadChecksum(&'static str),
+    /// The table doesn't make sense.
+    Invalid(&'static str),
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(err: BlockError) -> Self {
+        PartitionError::Block(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionType {
+    /// The type byte of an MBR partition.
+    Mbr(u8),
+    Gpt {
+        kind: Guid,
+        name: String,
+    },
+}
+
+/// A partition as the partition table describes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// The number of the partition, counting from 1. Logical MBR partitions start at 5.
+    pub number: usize,
+    pub start: u64,
+    pub count: u64,
+    pub kind: PartitionType,
+}
+
+/// Reads the partition table of a disk, which is empty if there is none.
+pub fn read_table(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, PartitionError> {
+    // GPT disks have a single MBR partition that covers the whole disk
+    match mbr::primary_types(device)? {
+        Some(types) if types.contains(&TYPE_GPT_PROTECTIVE) => gpt::read_partitions(device),
+        Some(_) => Ok(mbr::read_partitions(device)?.unwrap_or_default()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Creates a block device for every partition of a disk. They are named after the disk with
+/// the partition number appended, like `ata0p1`.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> Result<Vec<Arc<Partition>>, PartitionError> {
+    read_table(device.as_ref())?
+        .into_iter()
+        .map(|info| {
+            let name = format!("{}p{}", device.name(), info.number);
+            Partition::new(name, device.clone(), info.start, info.count)
+                .map(Arc::new)
+                .map_err(|_| PartitionError::Invalid("partition doesn't fit on the disk"))
+        })
+        .collect()
+}
+
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl Partition {
+    /// Creates a partition of `count` blocks starting at block `start` of `device`.
+    pub fn new(
+        name: String,
+        device: Arc<dyn BlockDevice>,
+        start: u64,
+        count: u64,
+    ) -> Result<Self, BlockError> {
+        match start.checked_add(count) {
+            Some(end) if end <= device.block_count() => Ok(Self {
+                name,
+                device,
+                start,
+                count,
+            }),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// The first block of the partition on its device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.device.read_blocks(self.start + start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.device.write_blocks(self.start + start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+
+    fn parent(&self) -> Option<(Arc<dyn BlockDevice>, u64)> {
+        Some((self.device.clone(), self.start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use alloc::vec;
+
+    #[test_case]
+    fn partitions_of_test_disks() {
+        // the images built by `tests/images/build.py`, see `Cargo.toml`
+        let mbr = crate::block::get("ata2p6").expect("no MBR test disk");
+        assert_eq!(mbr.block_count(), 128);
+        let gpt = crate::block::get("ata3p2").expect("no GPT test disk");
+        assert_eq!(gpt.block_count(), 977);
+        assert!(crate::block::get("ata1p1").is_none());
+    }
+
+    #[test_case]
+    fn stays_inside() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("ram", 512, 16));
+        let partition = Partition::new(String::from("ram1"), disk.clone(), 4, 8).unwrap();
+        assert_eq!(partition.block_count(), 8);
+
+        partition.write_blocks(0, &[7; 512]).unwrap();
+        let mut buffer = vec![0; 512];
+        disk.read_blocks(4, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&byte| byte == 7));
+
+        assert_eq!(
+            partition.write_blocks(7, &[0; 1024]),
+            Err(BlockError::OutOfRange)
+        );
+        assert!(Partition::new(String::from("ram2"), disk, 10, 8).is_err());
+    }
+}
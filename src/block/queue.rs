@@ -0,0 +1,158 @@
+//! Request queues, which collect the requests for a device so they can be sorted and merged
+//! into as few transfers as possible before they are sent to it.
+
+use super::{BlockDevice, BlockError};
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// A transfer of whole blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub operation: Operation,
+    pub block: u64,
+    /// What gets written, or where the blocks that are read end up.
+    pub data: Vec<u8>,
+}
+
+impl Request {
+    /// A request to read `count` blocks of `block_size` bytes.
+    pub fn read(block: u64, count: usize, block_size: usize) -> Self {
+        Self {
+            operation: Operation::Read,
+            block,
+            data: alloc::vec![0; count * block_size],
+        }
+    }
+
+    pub fn write(block: u64, data: Vec<u8>) -> Self {
+        Self {
+            operation: Operation::Write,
+            block,
+            data,
+        }
+    }
+}
+
+/// The requests waiting for a device.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    requests: Vec<Request>,
+}
+
+impl RequestQueue {
+    pub const fn new() -> Self {
+        Self {
+            requests: Vec::new(),
+        }
+    }
+
+    pub fn submit(&mut self, request: Request) {
+        self.requests.push(request);
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends all queued requests to `device` and returns them once they are done.
+    ///
+    /// The requests are sorted by block, like an elevator sweeping across the disk, and
+    /// requests for adjacent blocks are merged into a single transfer. Returns how many
+    /// transfers that took along with the finished requests.
+    pub fn run(&mut self, device: &dyn BlockDevice) -> Result<(usize, Vec<Request>), BlockError> {
+        let block_size = device.block_size() as u64;
+        let mut requests = core::mem::take(&mut self.requests);
+        // stable, so two writes to the same block happen in the order they were submitted
+        requests.sort_by_key(|request| request.block);
+
+        let mut transfers = 0;
+        let mut start = 0;
+        while start < requests.len() {
+            // find the requests that continue where the previous one stopped
+            let mut end = start + 1;
+            let mut next = requests[start].block + requests[start].data.len() as u64 / block_size;
+            while end < requests.len()
+                && requests[end].operation == requests[start].operation
+                && requests[end].block == next
+            {
+                next += requests[end].data.len() as u64 / block_size;
+                end += 1;
+            }
+
+            let batch = &mut requests[start..end];
+            let first = batch[0].block;
+            match batch[0].operation {
+                Operation::Write if batch.len() == 1 => {
+                    device.write_blocks(first, &batch[0].data)?
+                }
+                Operation::Read if batch.len() == 1 => {
+                    device.read_blocks(first, &mut batch[0].data)?
+                }
+                Operation::Write => {
+                    let data: Vec<u8> = batch
+                        .iter()
+                        .flat_map(|request| request.data.iter().copied())
+                        .collect();
+                    device.write_blocks(first, &data)?;
+                }
+                Operation::Read => {
+                    let mut data =
+                        alloc::vec![0; batch.iter().map(|request| request.data.len()).sum()];
+                    device.read_blocks(first, &mut data)?;
+                    let mut offset = 0;
+                    for request in batch.iter_mut() {
+                        let length = request.data.len();
+                        request.data.copy_from_slice(&data[offset..][..length]);
+                        offset += length;
+                    }
+                }
+            }
+            transfers += 1;
+            start = end;
+        }
+        Ok((transfers, requests))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use alloc::vec;
+
+    #[test_case]
+    fn merges_adjacent_requests() {
+        let disk = RamDisk::new("ram", 512, 32);
+        let mut queue = RequestQueue::new();
+        queue.submit(Request::write(5, vec![5; 512]));
+        queue.submit(Request::write(3, vec![3; 1024]));
+        queue.submit(Request::write(20, vec![20; 512]));
+        let (transfers, done) = queue.run(&disk).unwrap();
+        // blocks 3 to 5 in one go, then block 20
+        assert_eq!(transfers, 2);
+        assert_eq!(disk.writes(), 2);
+        assert_eq!(
+            done.iter().map(|request| request.block).collect::<Vec<_>>(),
+            [3, 5, 20]
+        );
+        assert!(queue.is_empty());
+
+        queue.submit(Request::read(4, 1, 512));
+        queue.submit(Request::read(5, 1, 512));
+        queue.submit(Request::write(6, vec![6; 512]));
+        let (transfers, done) = queue.run(&disk).unwrap();
+        // reads and writes are never merged with each other
+        assert_eq!(transfers, 2);
+        assert!(done[0].data.iter().all(|&byte| byte == 3));
+        assert!(done[1].data.iter().all(|&byte| byte == 5));
+    }
+}
@@ -0,0 +1,77 @@
+//! A block device in memory, for tests and scratch space.
+
+use super::{check_request, BlockDevice, BlockError};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// A disk that only lives in memory.
+pub struct RamDisk {
+    name: String,
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+    // the number of calls, so tests can see how often the device was hit
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk with `block_count` blocks.
+    pub fn new(name: &str, block_size: usize, block_count: u64) -> Self {
+        Self::from_vec(name, block_size, vec![0; block_size * block_count as usize])
+    }
+
+    /// Creates a disk with the given contents, which have to be a multiple of the block size.
+    pub fn from_vec(name: &str, block_size: usize, data: Vec<u8>) -> Self {
+        assert_eq!(data.len() % block_size, 0, "partial block");
+        Self {
+            name: String::from(name),
+            block_size,
+            data: Mutex::new(data),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        }
+    }
+
+    /// How often the blocks were read.
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// How often the blocks were written.
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let offset = start as usize * self.block_size;
+        buffer.copy_from_slice(&self.data.lock()[offset..][..buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        let offset = start as usize * self.block_size;
+        self.data.lock()[offset..][..buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! Matches drivers to the devices we find and manages their lifecycle.
+//!
+//! Devices come from two places: the PCI bus, which can be enumerated, and a fixed list of
+//! legacy ISA devices that every PC is expected to have. Drivers describe the devices they
+//! handle with a match table and get probed for every device that matches it.
+
+use crate::pci::{self, PciDevice};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// The legacy devices we assume to exist, since there is no way to ask the ISA bus.
+pub const ISA_DEVICES: [IsaDevice; 3] = [
+    IsaDevice {
+        id: "PNP0303",
+        name: "PS/2 keyboard",
+        io_base: 0x60,
+        irq: 1,
+    },
+    IsaDevice {
+        id: "PNP0F13",
+        name: "PS/2 mouse",
+        io_base: 0x60,
+        irq: 12,
+    },
+    IsaDevice {
+        id: "PNP0501",
+        name: "16550A serial port (COM1)",
+        io_base: 0x3f8,
+        irq: 4,
+    },
+];
+
+// every driver that has been registered
+static DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new());
+// every device we know about and which driver it is bound to
+static DEVICES: Mutex<Vec<DeviceEntry>> = Mutex::new(Vec::new());
+
+struct DeviceEntry {
+    device: Device,
+    driver: Option<&'static dyn Driver>,
+}
+
+/// A legacy device with fixed resources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaDevice {
+    /// The plug and play id of the device, which is what ACPI would call it.
+    pub id: &'static str,
+    pub name: &'static str,
+    pub io_base: u16,
+    pub irq: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Pci(PciDevice),
+    Isa(IsaDevice),
+}
+
+/// An entry in the match table of a driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// A PCI function with the given vendor and device id.
+    PciId { vendor_id: u16, device_id: u16 },
+    /// Any PCI function of the given class, for drivers of standardized interfaces.
+    /// `prog_if` is ignored if it's `None`.
+    PciClass {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+    /// A legacy device with the given plug and play id.
+    Isa(&'static str),
+}
+
+impl Match {
+    pub fn matches(&self, device: &Device) -> bool {
+        match (*self, device) {
+            (
+                Match::PciId {
+                    vendor_id,
+                    device_id,
+                },
+                Device::Pci(pci),
+            ) => pci.vendor_id == vendor_id && pci.device_id == device_id,
+            (
+                Match::PciClass {
+                    class,
+                    subclass,
+                    prog_if,
+                },
+                Device::Pci(pci),
+            ) => {
+                // without a programming interface any of them will do
+                pci.class == class
+                    && pci.subclass == subclass
+                    && prog_if.unwrap_or(pci.prog_if) == pci.prog_if
+            }
+            (Match::Isa(id), Device::Isa(isa)) => isa.id == id,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    /// The device matched, but isn't actually there or doesn't respond.
+    NoDevice,
+    /// The driver doesn't support this variant of the device.
+    Unsupported,
+    /// The device misbehaved while it was being set up.
+    Hardware(&'static str),
+}
+
+/// A driver for one or more kinds of devices.
+///
+/// Drivers are usually unit structs in a `static`, any state they need lives in the driver's module.
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The devices the driver could handle.
+    fn match_table(&self) -> &'static [Match];
+
+    /// Sets up a matching device. The driver gets bound to the device if this succeeds.
+    fn probe(&self, device: &Device) -> Result<(), DriverError>;
+
+    /// Stops using a device, called when the driver gets unregistered.
+    fn remove(&self, _device: &Device) {}
+
+    /// Puts a device into a state where it doesn't need the driver.
+    fn suspend(&self, _device: &Device) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    /// Undoes [`Driver::suspend`].
+    fn resume(&self, _device: &Device) -> Result<(), DriverError> {
+        Ok(())
+    }
+}
+
+/// Finds all devices and binds the built in drivers to them.
+///
+/// Has to be called after the heap and [`pci::init`] have been set up.
+pub fn init() {
+    for device in ISA_DEVICES {
+        add_device(Device::Isa(device));
+    }
+    for device in pci::scan() {
+        add_device(Device::Pci(device));
+    }
+
+    register(&crate::serial::DRIVER);
+    register(&crate::ps2::DRIVER);
+    register(&crate::mouse::DRIVER);
+    register(&crate::ata::DRIVER);
+    register(&crate::ahci::DRIVER);
+    register(&crate::virtio::block::DRIVER);
+}
+
+/// Adds a driver and probes it for every matching device that doesn't have a driver yet.
+pub fn register(driver: &'static dyn Driver) {
+    DRIVERS.lock().push(driver);
+    for device in unbound_devices() {
+        try_bind(device, driver);
+    }
+}
+
+/// Removes a driver, calling [`Driver::remove`] for every device it's bound to.
+pub fn unregister(name: &str) {
+    DRIVERS.lock().retain(|driver| driver.name() != name);
+    for (device, driver) in bound_devices() {
+        if driver.name() == name {
+            driver.remove(&device);
+            set_driver(&device, None);
+        }
+    }
+}
+
+/// Makes a newly found device known and probes the drivers that match it.
+pub fn add_device(device: Device) {
+    {
+        let mut devices = DEVICES.lock();
+        if devices.iter().any(|entry| entry.device == device) {
+            return;
+        }
+        devices.push(DeviceEntry {
+            device,
+            driver: None,
+        });
+    }
+
+    // drivers are probed without holding any locks, since they might want to add devices themselves
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        if try_bind(device, driver) {
+            break;
+        }
+    }
+}
+
+/// Returns all known devices.
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().iter().map(|entry| entry.device).collect()
+}
+
+/// Returns the devices that have a driver, along with the driver.
+pub fn bound_devices() -> Vec<(Device, &'static dyn Driver)> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter_map(|entry| Some((entry.device, entry.driver?)))
+        .collect()
+}
+
+/// Suspends all devices that have a driver, stopping at the first one that fails.
+pub fn suspend_all() -> Result<(), DriverError> {
+    bound_devices()
+        .iter()
+        .rev()
+        .try_for_each(|(device, driver)| driver.suspend(device))
+}
+
+/// Resumes all devices that have a driver, in the order they were found.
+pub fn resume_all() -> Result<(), DriverError> {
+    bound_devices()
+        .iter()
+        .try_for_each(|(device, driver)| driver.resume(device))
+}
+
+fn unbound_devices() -> Vec<Device> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|entry| entry.driver.is_none())
+        .map(|entry| entry.device)
+        .collect()
+}
+
+/// Probes `driver` for `device` if its match table allows it and returns whether it got bound.
+fn try_bind(device: Device, driver: &'static dyn Driver) -> bool {
+    if !driver.match_table().iter().any(|m| m.matches(&device)) {
+        return false;
+    }
+    match driver.probe(&device) {
+        Ok(()) => {
+            set_driver(&device, Some(driver));
+            true
+        }
+        // there being no device behind the match is normal, e.g. for a missing mouse
+        Err(DriverError::NoDevice) => false,
+        Err(err) => {
+            serial_println!("{}: probing {:?} failed: {:?}", driver.name(), device, err);
+            false
+        }
+    }
+}
+
+fn set_driver(device: &Device, driver: Option<&'static dyn Driver>) {
+    if let Some(entry) = DEVICES
+        .lock()
+        .iter_mut()
+        .find(|entry| entry.device == *device)
+    {
+        entry.driver = driver;
+    }
+}
+
+/// Prints every device along with the driver it's bound to.
+pub fn dump() {
+    let bound = bound_devices();
+    for device in devices() {
+        let driver = bound
+            .iter()
+            .find(|(bound, _)| *bound == device)
+            .map_or("-", |(_, driver)| driver.name());
+        match device {
+            Device::Pci(pci) => println!("{pci}\n\tdriver: {driver}"),
+            Device::Isa(isa) => println!(
+                "isa {:04x} {} [{}]\n\tdriver: {driver}",
+                isa.io_base, isa.name, isa.id
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const TEST_DEVICE: IsaDevice = IsaDevice {
+        id: "TST0001",
+        name: "test device",
+        io_base: 0,
+        irq: 0,
+    };
+
+    static PROBED: AtomicUsize = AtomicUsize::new(0);
+    static REMOVED: AtomicUsize = AtomicUsize::new(0);
+    static SUSPENDED: AtomicUsize = AtomicUsize::new(0);
+
+    struct TestDriver;
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn match_table(&self) -> &'static [Match] {
+            &[Match::Isa("TST0001")]
+        }
+
+        fn probe(&self, _device: &Device) -> Result<(), DriverError> {
+            PROBED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn remove(&self, _device: &Device) {
+            REMOVED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn suspend(&self, _device: &Device) -> Result<(), DriverError> {
+            SUSPENDED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    static TEST_DRIVER: TestDriver = TestDriver;
+
+    #[test_case]
+    fn match_tables() {
+        let isa = Device::Isa(TEST_DEVICE);
+        assert!(Match::Isa("TST0001").matches(&isa));
+        assert!(!Match::Isa("PNP0303").matches(&isa));
+        assert!(!Match::PciClass {
+            class: 0,
+            subclass: 0,
+            prog_if: None
+        }
+        .matches(&isa));
+
+        // qemu's IDE controller
+        let ide = pci::find(0x8086, 0x7010).expect("no IDE controller");
+        let ide = Device::Pci(ide);
+        assert!(Match::PciId {
+            vendor_id: 0x8086,
+            device_id: 0x7010
+        }
+        .matches(&ide));
+        assert!(Match::PciClass {
+            class: 0x01,
+            subclass: 0x01,
+            prog_if: None
+        }
+        .matches(&ide));
+    }
+
+    #[test_case]
+    fn driver_lifecycle() {
+        register(&TEST_DRIVER);
+        assert_eq!(PROBED.load(Ordering::SeqCst), 0);
+
+        // the driver gets probed as soon as a matching device turns up
+        add_device(Device::Isa(TEST_DEVICE));
+        assert_eq!(PROBED.load(Ordering::SeqCst), 1);
+        assert!(
+            bound_devices()
+                .iter()
+                .any(|(device, driver)| *device == Device::Isa(TEST_DEVICE)
+                    && driver.name() == "test")
+        );
+
+        assert_eq!(suspend_all(), Ok(()));
+        assert_eq!(SUSPENDED.load(Ordering::SeqCst), 1);
+        assert_eq!(resume_all(), Ok(()));
+
+        unregister("test");
+        assert_eq!(REMOVED.load(Ordering::SeqCst), 1);
+        assert!(bound_devices()
+            .iter()
+            .all(|(_, driver)| driver.name() != "test"));
+    }
+}
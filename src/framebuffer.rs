@@ -0,0 +1,294 @@
+// https://wiki.osdev.org/Drawing_In_a_Linear_Framebuffer
+use crate::pci::{self, Bar};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod console;
+pub mod font;
+
+pub use console::FrameBufferWriter;
+pub use font::Font;
+
+/// The virtual address the framebuffer gets mapped to.
+pub const FRAMEBUFFER_START: usize = 0x_5555_5555_0000;
+
+/// The resolution the Bochs VBE adapter is set up with by [`init`].
+pub const DEFAULT_WIDTH: usize = 1024;
+pub const DEFAULT_HEIGHT: usize = 768;
+
+// the Bochs VBE ("DISPI") interface, emulated by the standard VGA card of QEMU and Bochs.
+// https://wiki.osdev.org/Bochs_VBE_Extensions
+const VBE_DISPI_IOPORT_INDEX: u16 = 0x01ce;
+const VBE_DISPI_IOPORT_DATA: u16 = 0x01cf;
+const VBE_DISPI_INDEX_ID: u16 = 0x0;
+const VBE_DISPI_INDEX_XRES: u16 = 0x1;
+const VBE_DISPI_INDEX_YRES: u16 = 0x2;
+const VBE_DISPI_INDEX_BPP: u16 = 0x3;
+const VBE_DISPI_INDEX_ENABLE: u16 = 0x4;
+/// The oldest version of the interface that supports 32 bits per pixel.
+const VBE_DISPI_ID4: u16 = 0xb0c4;
+const VBE_DISPI_ENABLED: u16 = 0x01;
+const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+
+/// The PCI vendor and device id of the Bochs/QEMU standard VGA card.
+const BOCHS_VGA_ID: (u16, u16) = (0x1234, 0x1111);
+
+/// The console the virtual terminals are drawn on once a framebuffer has been set up.
+pub static CONSOLE: Mutex<Option<FrameBufferWriter<'static>>> = Mutex::new(None);
+
+/// The order of the color channels of a pixel in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+}
+
+/// Describes the layout of a framebuffer in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    /// The visible width in pixels.
+    pub width: usize,
+    /// The visible height in pixels.
+    pub height: usize,
+    /// The number of pixels between the start of two rows, which can be more than `width`.
+    pub stride: usize,
+    /// Either 3 or 4, in the latter case the fourth byte is unused.
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+impl FrameBufferInfo {
+    /// The number of bytes needed to hold the whole framebuffer.
+    pub fn size(&self) -> usize {
+        self.stride * self.height * self.bytes_per_pixel
+    }
+}
+
+/// A 24 bit color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+    pub const LIGHT_GRAY: Rgb = Rgb::new(0xaa, 0xaa, 0xaa);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameBufferError {
+    /// There is no supported graphics adapter.
+    NotFound,
+    /// The framebuffer could not be mapped into the address space.
+    MapFailed(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for FrameBufferError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        FrameBufferError::MapFailed(err)
+    }
+}
+
+/// A linear framebuffer, i.e. a region of memory where each pixel of the screen is
+/// stored one row after the other.
+pub struct FrameBuffer<'a> {
+    memory: &'a mut [u8],
+    info: FrameBufferInfo,
+}
+
+impl<'a> FrameBuffer<'a> {
+    /// Wraps `memory` laid out as described by `info`.
+    ///
+    /// Panics if `memory` is too small for the layout.
+    pub fn new(memory: &'a mut [u8], info: FrameBufferInfo) -> Self {
+        assert!(memory.len() >= info.size(), "framebuffer memory too small");
+        FrameBuffer { memory, info }
+    }
+
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    /// Sets the pixel at `(x, y)`, ignoring pixels outside of the visible area.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        let bytes = self.color_bytes(color);
+        self.memory[offset..offset + 3].copy_from_slice(&bytes);
+    }
+
+    /// Returns the color of the pixel at `(x, y)`, or `None` if it's outside of the visible area.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        let bytes = &self.memory[offset..offset + 3];
+        Some(match self.info.format {
+            PixelFormat::Rgb => Rgb::new(bytes[0], bytes[1], bytes[2]),
+            PixelFormat::Bgr => Rgb::new(bytes[2], bytes[1], bytes[0]),
+        })
+    }
+
+    /// Sets every pixel of the framebuffer to `color`.
+    pub fn fill(&mut self, color: Rgb) {
+        self.fill_rows(0, self.info.height, color);
+    }
+
+    /// Moves the whole image up by `rows` pixel rows, filling the rows at the bottom with `color`.
+    pub fn scroll_up(&mut self, rows: usize, color: Rgb) {
+        let rows = rows.min(self.info.height);
+        let row_size = self.info.stride * self.info.bytes_per_pixel;
+        let size = self.info.height * row_size;
+        self.memory.copy_within(rows * row_size..size, 0);
+        self.fill_rows(self.info.height - rows, rows, color);
+    }
+
+    // the framebuffer is uncached device memory, so it is written a whole row at a time
+    fn fill_rows(&mut self, first: usize, count: usize, color: Rgb) {
+        let count = count.min(self.info.height.saturating_sub(first));
+        if count == 0 {
+            return;
+        }
+        let row_size = self.info.stride * self.info.bytes_per_pixel;
+        let bytes = self.color_bytes(color);
+        let rows = &mut self.memory[first * row_size..(first + count) * row_size];
+        // black, white and the grays are the same byte over and over
+        if bytes.iter().all(|&byte| byte == bytes[0]) {
+            rows.fill(bytes[0]);
+            return;
+        }
+        for pixel in rows[..row_size].chunks_exact_mut(self.info.bytes_per_pixel) {
+            pixel[..3].copy_from_slice(&bytes);
+        }
+        for row in 1..count {
+            rows.copy_within(..row_size, row * row_size);
+        }
+    }
+
+    fn color_bytes(&self, color: Rgb) -> [u8; 3] {
+        match self.info.format {
+            PixelFormat::Rgb => [color.r, color.g, color.b],
+            PixelFormat::Bgr => [color.b, color.g, color.r],
+        }
+    }
+}
+
+/// Switches the screen over to a framebuffer console if a supported graphics adapter is present.
+///
+/// From then on the virtual terminals are drawn to the framebuffer instead of the VGA text buffer.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), FrameBufferError> {
+    let framebuffer = init_bochs_vbe(DEFAULT_WIDTH, DEFAULT_HEIGHT, mapper, frame_allocator)?;
+    let writer = FrameBufferWriter::new(framebuffer, Font::default());
+    let (rows, columns) = writer.size();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *CONSOLE.lock() = Some(writer);
+        // give every terminal the size of the console, which also shows what the active
+        // one had on the text buffer
+        for terminal in crate::terminal::TERMINALS.iter() {
+            terminal.writer().resize(rows, columns);
+        }
+    });
+    Ok(())
+}
+
+/// Sets the Bochs VBE adapter to a `width`x`height` mode with 32 bits per pixel and maps
+/// its linear framebuffer at [`FRAMEBUFFER_START`].
+pub fn init_bochs_vbe(
+    width: usize,
+    height: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<FrameBuffer<'static>, FrameBufferError> {
+    if vbe_read(VBE_DISPI_INDEX_ID) < VBE_DISPI_ID4 {
+        return Err(FrameBufferError::NotFound);
+    }
+    let lfb = find_bochs_lfb().ok_or(FrameBufferError::NotFound)?;
+
+    // the mode can only be changed while the display is disabled
+    vbe_write(VBE_DISPI_INDEX_ENABLE, 0);
+    vbe_write(VBE_DISPI_INDEX_XRES, width as u16);
+    vbe_write(VBE_DISPI_INDEX_YRES, height as u16);
+    vbe_write(VBE_DISPI_INDEX_BPP, 32);
+    vbe_write(
+        VBE_DISPI_INDEX_ENABLE,
+        VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED,
+    );
+
+    let info = FrameBufferInfo {
+        width,
+        height,
+        stride: width,
+        bytes_per_pixel: 4,
+        // pixels are stored as little endian 0x00RRGGBB
+        format: PixelFormat::Bgr,
+    };
+    let memory = map_framebuffer(lfb, info.size(), mapper, frame_allocator)?;
+    Ok(FrameBuffer::new(memory, info))
+}
+
+/// Maps `size` bytes of physical memory starting at `start` to [`FRAMEBUFFER_START`].
+fn map_framebuffer(
+    start: PhysAddr,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<&'static mut [u8], MapToError<Size4KiB>> {
+    let page_range = {
+        let first = Page::containing_address(VirtAddr::new(FRAMEBUFFER_START as u64));
+        let last = Page::containing_address(VirtAddr::new((FRAMEBUFFER_START + size - 1) as u64));
+        Page::range_inclusive(first, last)
+    };
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(start);
+
+    // the framebuffer is device memory so we don't want the CPU to cache it
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    for (i, page) in page_range.enumerate() {
+        let frame = first_frame + i as u64;
+        // this is safe since the frames belong to the graphics adapter and not to any
+        // memory the frame allocator could hand out
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(unsafe { core::slice::from_raw_parts_mut(FRAMEBUFFER_START as *mut u8, size) })
+}
+
+fn vbe_read(index: u16) -> u16 {
+    unsafe {
+        Port::new(VBE_DISPI_IOPORT_INDEX).write(index);
+        Port::new(VBE_DISPI_IOPORT_DATA).read()
+    }
+}
+
+fn vbe_write(index: u16, value: u16) {
+    unsafe {
+        Port::new(VBE_DISPI_IOPORT_INDEX).write(index);
+        Port::new(VBE_DISPI_IOPORT_DATA).write(value);
+    }
+}
+
+/// Looks for the Bochs/QEMU VGA card and returns the physical address of its linear framebuffer.
+fn find_bochs_lfb() -> Option<PhysAddr> {
+    let (vendor, device) = BOCHS_VGA_ID;
+    match pci::find(vendor, device)?.bar(0)? {
+        Bar::Memory { address, .. } => Some(address),
+        Bar::Io { .. } => None,
+    }
+}
@@ -0,0 +1,236 @@
+use super::{Font, FrameBuffer, Rgb};
+use core::fmt;
+
+/// The width of a tab stop in columns.
+const TAB_WIDTH: usize = 8;
+
+/// A text console drawing glyphs of a bitmap font into a framebuffer.
+///
+/// It understands the same control characters as the [`VGAWriter`](crate::vga_buffer::VGAWriter),
+/// but the number of rows and columns depends on the resolution and font.
+pub struct FrameBufferWriter<'a> {
+    framebuffer: FrameBuffer<'a>,
+    font: Font,
+    row_position: usize,
+    column_position: usize,
+    columns: usize,
+    rows: usize,
+    fg: Rgb,
+    bg: Rgb,
+}
+
+impl<'a> FrameBufferWriter<'a> {
+    /// Creates a console covering the whole framebuffer and clears the screen.
+    pub fn new(framebuffer: FrameBuffer<'a>, font: Font) -> Self {
+        let info = framebuffer.info();
+        let mut writer = Self {
+            framebuffer,
+            font,
+            row_position: 0,
+            column_position: 0,
+            columns: info.width / font.width(),
+            rows: info.height / font.height(),
+            fg: Rgb::LIGHT_GRAY,
+            bg: Rgb::BLACK,
+        };
+        writer.clear_screen();
+        writer
+    }
+
+    /// Returns the size of the console as `(rows, columns)`.
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    /// Returns the current cursor position as `(row, col)`.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /// Moves the cursor to `(row, col)`, clamping it to the bounds of the console.
+    pub fn set_cursor_position(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(self.rows.saturating_sub(1));
+        self.column_position = col.min(self.columns.saturating_sub(1));
+    }
+
+    /// Sets the foreground and background color used for subsequent writes.
+    pub fn set_color(&mut self, fg: Rgb, bg: Rgb) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// Blanks the whole screen and moves the cursor to the top left corner.
+    pub fn clear_screen(&mut self) {
+        self.framebuffer.fill(self.bg);
+        self.set_cursor_position(0, 0);
+    }
+
+    /// Returns the framebuffer the console draws into.
+    pub fn framebuffer(&self) -> &FrameBuffer<'a> {
+        &self.framebuffer
+    }
+
+    /// Write a string to the console
+    pub fn write_string(&mut self, s: &str) {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+    }
+
+    /// Write a single character to the console
+    pub fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column_position = 0,
+            '\t' => {
+                // pad with spaces up to the next tab stop
+                let next_stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < next_stop.min(self.columns) {
+                    self.write_char(' ');
+                }
+            }
+            // backspace
+            '\u{8}' => self.backspace(),
+            c => {
+                if self.column_position >= self.columns {
+                    self.new_line();
+                }
+                self.draw_char(self.row_position, self.column_position, c);
+                self.column_position += 1;
+            }
+        }
+    }
+
+    /// Draws the glyph for `c` in the given colors into the cell at `(row, col)`, without
+    /// moving the cursor. Cells outside of the console are ignored.
+    pub fn draw_cell(&mut self, row: usize, col: usize, c: char, fg: Rgb, bg: Rgb) {
+        if row >= self.rows || col >= self.columns {
+            return;
+        }
+        let glyph = self.font.glyph_for(c);
+        let (x0, y0) = (col * self.font.width(), row * self.font.height());
+        for y in 0..self.font.height() {
+            for x in 0..self.font.width() {
+                let color = if self.font.is_set(glyph, x, y) {
+                    fg
+                } else {
+                    bg
+                };
+                self.framebuffer.set_pixel(x0 + x, y0 + y, color);
+            }
+        }
+    }
+
+    /// Draws the glyph for `c` into the cell at `(row, col)`.
+    fn draw_char(&mut self, row: usize, col: usize, c: char) {
+        self.draw_cell(row, col, c, self.fg, self.bg);
+    }
+
+    /// Moves the cursor one step back and erases the character there.
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+        } else if self.row_position > 0 {
+            // wrap around to the end of the previous row
+            self.row_position -= 1;
+            self.column_position = self.columns - 1;
+        } else {
+            return;
+        }
+        self.draw_char(self.row_position, self.column_position, ' ');
+    }
+
+    /// Moves the contents of the screen `lines` rows up, without moving the cursor. The rows
+    /// that become free at the bottom are filled with `bg`.
+    pub fn scroll_up(&mut self, lines: usize, bg: Rgb) {
+        self.framebuffer.scroll_up(lines * self.font.height(), bg);
+    }
+
+    /// Moves the cursor to the start of the next line, scrolling the screen if needed
+    fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position + 1 < self.rows {
+            self.row_position += 1;
+            return;
+        }
+        self.scroll_up(1, self.bg);
+    }
+}
+
+impl fmt::Write for FrameBufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::{FrameBufferInfo, PixelFormat};
+
+    const INFO: FrameBufferInfo = FrameBufferInfo {
+        width: 32,
+        height: 32,
+        stride: 32,
+        bytes_per_pixel: 4,
+        format: PixelFormat::Bgr,
+    };
+
+    #[test_case]
+    fn draws_glyphs() {
+        let mut memory = [0u8; 32 * 32 * 4];
+        let mut writer =
+            FrameBufferWriter::new(FrameBuffer::new(&mut memory, INFO), Font::default());
+        assert_eq!(writer.size(), (2, 4));
+
+        writer.write_string("█ ");
+        let fb = writer.framebuffer();
+        // the full block fills its cell with the foreground color, the space doesn't
+        assert_eq!(fb.pixel(0, 0), Some(Rgb::LIGHT_GRAY));
+        assert_eq!(fb.pixel(7, 15), Some(Rgb::LIGHT_GRAY));
+        assert_eq!(fb.pixel(8, 0), Some(Rgb::BLACK));
+        assert_eq!(writer.cursor_position(), (0, 2));
+    }
+
+    #[test_case]
+    fn wraps_and_scrolls() {
+        let mut memory = [0u8; 32 * 32 * 4];
+        let mut writer =
+            FrameBufferWriter::new(FrameBuffer::new(&mut memory, INFO), Font::default());
+
+        // fill the first row and wrap onto the second one
+        writer.write_string("█████");
+        assert_eq!(writer.cursor_position(), (1, 1));
+
+        // the next new line scrolls the second row up into the first
+        writer.write_string("\n");
+        assert_eq!(writer.cursor_position(), (1, 0));
+        let fb = writer.framebuffer();
+        assert_eq!(fb.pixel(0, 0), Some(Rgb::LIGHT_GRAY));
+        assert_eq!(fb.pixel(8, 0), Some(Rgb::BLACK));
+        assert_eq!(fb.pixel(0, 16), Some(Rgb::BLACK));
+    }
+
+    #[test_case]
+    fn colored_cells() {
+        let mut memory = [0u8; 32 * 32 * 4];
+        let mut writer =
+            FrameBufferWriter::new(FrameBuffer::new(&mut memory, INFO), Font::default());
+        let blue = Rgb::new(0, 0, 0xaa);
+        writer.set_color(Rgb::WHITE, blue);
+        writer.clear_screen();
+        let fb = writer.framebuffer();
+        assert_eq!(fb.pixel(0, 0), Some(blue));
+        assert_eq!(fb.pixel(31, 31), Some(blue));
+
+        let red = Rgb::new(0xaa, 0, 0);
+        writer.draw_cell(1, 3, '█', red, Rgb::BLACK);
+        // off the console
+        writer.draw_cell(2, 0, '█', red, Rgb::BLACK);
+        let fb = writer.framebuffer();
+        assert_eq!(fb.pixel(24, 16), Some(red));
+        assert_eq!(fb.pixel(23, 16), Some(blue));
+        assert_eq!(writer.cursor_position(), (0, 0));
+    }
+}
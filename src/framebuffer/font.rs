@@ -0,0 +1,204 @@
+// https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+//! A renderer for [PC Screen Fonts](https://en.wikipedia.org/wiki/PC_Screen_Font), the bitmap font
+//! format used by the Linux console.
+
+use crate::vga_buffer::cp437;
+
+/// The font used by the framebuffer console.
+///
+/// It is an 8x16 font with its glyphs in code page 437 order, made from the public domain
+/// X11 "misc-fixed" 8x13 font with the box drawing and block characters drawn to fill the cell.
+static DEFAULT_FONT: &[u8] = include_bytes!("font.psf");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+/// Set in the PSF1 mode byte when the font has 512 instead of 256 glyphs.
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_HEADER_SIZE: usize = 4;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+
+/// A parsed PSF1 or PSF2 font.
+///
+/// Glyphs are looked up by their code page 437 byte, so the font is expected to use that order.
+#[derive(Debug, Clone, Copy)]
+pub struct Font {
+    glyphs: &'static [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Font {
+    /// Parses a PSF1 or PSF2 font, returning `None` if `data` isn't a valid font.
+    pub fn parse(data: &'static [u8]) -> Option<Font> {
+        if data.starts_with(&PSF1_MAGIC) {
+            let mode = *data.get(2)?;
+            let height = *data.get(3)? as usize;
+            let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            // PSF1 glyphs are always 8 pixels, i.e. one byte, wide
+            return Self::new(&data[PSF1_HEADER_SIZE..], glyph_count, height, 8, height);
+        }
+
+        if data.starts_with(&PSF2_MAGIC) {
+            let field = |index: usize| -> Option<usize> {
+                let bytes = data.get(index * 4..index * 4 + 4)?;
+                Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+            };
+            // the fields following the magic and version, in order
+            let header_size = field(2)?;
+            let glyph_count = field(4)?;
+            let bytes_per_glyph = field(5)?;
+            let height = field(6)?;
+            let width = field(7)?;
+            if header_size < PSF2_HEADER_SIZE {
+                return None;
+            }
+            return Self::new(
+                data.get(header_size..)?,
+                glyph_count,
+                bytes_per_glyph,
+                width,
+                height,
+            );
+        }
+
+        None
+    }
+
+    fn new(
+        glyphs: &'static [u8],
+        glyph_count: usize,
+        bytes_per_glyph: usize,
+        width: usize,
+        height: usize,
+    ) -> Option<Font> {
+        // make sure every glyph row fits into the glyph and that all glyphs are actually there
+        if width == 0 || height == 0 || width.div_ceil(8) * height > bytes_per_glyph {
+            return None;
+        }
+        let glyphs = glyphs.get(..glyph_count.checked_mul(bytes_per_glyph)?)?;
+        Some(Font {
+            glyphs,
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+        })
+    }
+
+    /// The width of a glyph in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The height of a glyph in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the bitmap of the glyph at `index`, one row after the other with each row
+    /// padded to a whole byte and the leftmost pixel in the most significant bit.
+    pub fn glyph(&self, index: usize) -> Option<&'static [u8]> {
+        if index >= self.glyph_count {
+            return None;
+        }
+        let start = index * self.bytes_per_glyph;
+        Some(&self.glyphs[start..start + self.bytes_per_glyph])
+    }
+
+    /// Returns the glyph for `c`, or the replacement glyph if the font has no glyph for it.
+    pub fn glyph_for(&self, c: char) -> &'static [u8] {
+        self.glyph(cp437::from_char_lossy(c) as usize)
+            .or_else(|| self.glyph(cp437::REPLACEMENT as usize))
+            .unwrap_or(&[])
+    }
+
+    /// Returns whether the pixel at `(x, y)` of `glyph` is set.
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let bytes_per_row = self.width.div_ceil(8);
+        glyph
+            .get(y * bytes_per_row + x / 8)
+            .is_some_and(|byte| byte & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Font::parse(DEFAULT_FONT).expect("the built-in font is a valid PSF font")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn default_font() {
+        let font = Font::default();
+        assert_eq!(font.width(), 8);
+        assert_eq!(font.height(), 16);
+        assert!(font.glyph(255).is_some());
+        assert!(font.glyph(256).is_none());
+        // a space has no pixels set, a full block has all of them set
+        assert!(font.glyph_for(' ').iter().all(|&row| row == 0));
+        assert!(font.glyph_for('█').iter().all(|&row| row == 0xff));
+    }
+
+    #[test_case]
+    fn parse_psf2() {
+        static DATA: [u8; 36] = [
+            0x72,
+            0xb5,
+            0x4a,
+            0x86, // magic
+            0,
+            0,
+            0,
+            0, // version
+            32,
+            0,
+            0,
+            0, // header size
+            0,
+            0,
+            0,
+            0, // flags
+            2,
+            0,
+            0,
+            0, // glyph count
+            2,
+            0,
+            0,
+            0, // bytes per glyph
+            2,
+            0,
+            0,
+            0, // height
+            3,
+            0,
+            0,
+            0, // width
+            0b1010_0000,
+            0b0100_0000, // glyph 0
+            0b1110_0000,
+            0, // glyph 1
+        ];
+        let font = Font::parse(&DATA).unwrap();
+        assert_eq!((font.width(), font.height()), (3, 2));
+        let glyph = font.glyph(0).unwrap();
+        assert!(font.is_set(glyph, 0, 0));
+        assert!(!font.is_set(glyph, 1, 0));
+        assert!(font.is_set(glyph, 1, 1));
+    }
+
+    #[test_case]
+    fn reject_invalid_fonts() {
+        assert!(Font::parse(&[]).is_none());
+        assert!(Font::parse(b"not a font").is_none());
+        // a PSF1 header announcing glyphs that aren't there
+        assert!(Font::parse(&[0x36, 0x04, 0x00, 0x10, 0xff]).is_none());
+    }
+}
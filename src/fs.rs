@@ -0,0 +1,89 @@
+//! The filesystems that can be mounted into the VFS.
+
+use crate::block::{BlockDevice, BlockError};
+use alloc::vec;
+
+pub mod ext2;
+pub mod fat;
+pub mod tmpfs;
+
+pub use ext2::Ext2Fs;
+pub use fat::FatFs;
+pub use tmpfs::TmpFs;
+
+/// Reads bytes from a device, which don't have to line up with its blocks.
+pub(crate) fn read_bytes(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let mut block = vec![0; block_size];
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let index = position / block_size as u64;
+        let in_block = (position % block_size as u64) as usize;
+        let remaining = buffer.len() - done;
+        if in_block == 0 && remaining >= block_size {
+            // whole blocks go straight into the buffer
+            let length = remaining / block_size * block_size;
+            device.read_blocks(index, &mut buffer[done..][..length])?;
+            done += length;
+        } else {
+            let length = (block_size - in_block).min(remaining);
+            device.read_blocks(index, &mut block)?;
+            buffer[done..][..length].copy_from_slice(&block[in_block..][..length]);
+            done += length;
+        }
+    }
+    Ok(())
+}
+
+/// Writes bytes to a device, reading the blocks that are only partially overwritten first.
+pub(crate) fn write_bytes(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buffer: &[u8],
+) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let mut block = vec![0; block_size];
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let index = position / block_size as u64;
+        let in_block = (position % block_size as u64) as usize;
+        let remaining = buffer.len() - done;
+        if in_block == 0 && remaining >= block_size {
+            let length = remaining / block_size * block_size;
+            device.write_blocks(index, &buffer[done..][..length])?;
+            done += length;
+        } else {
+            let length = (block_size - in_block).min(remaining);
+            device.read_blocks(index, &mut block)?;
+            block[in_block..][..length].copy_from_slice(&buffer[done..][..length]);
+            device.write_blocks(index, &block)?;
+            done += length;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn unaligned_bytes() {
+        let disk = RamDisk::new("ram", 512, 8);
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        write_bytes(&disk, 300, &data).unwrap();
+        let mut buffer = vec![0; 1600];
+        read_bytes(&disk, 250, &mut buffer).unwrap();
+        assert!(buffer[..50].iter().all(|&byte| byte == 0));
+        assert_eq!(buffer[50..1550], data);
+        assert!(buffer[1550..].iter().all(|&byte| byte == 0));
+    }
+}
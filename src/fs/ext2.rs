@@ -0,0 +1,897 @@
+//! The second extended filesystem, with Unix permissions and owners.
+//!
+//! The disk is split into block groups, which each have a bitmap of their free blocks and
+//! inodes and a part of the inode table. Files find their data through 12 direct block
+//! pointers in their inode and three levels of indirect blocks, and blocks that were never
+//! written are holes that read as zeros. Directories are files that hold a list of entries.
+//!
+//! Only features that don't change how the disk is read are supported. Filesystems with
+//! features we can't keep up to date, like huge files, are mounted read-only.
+
+mod dir;
+mod inode;
+
+use crate::block::{BlockDevice, CachedDevice};
+use crate::fs::{read_bytes, write_bytes};
+use crate::vfs::{FileSystem, FsError, Inode};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use inode::{Ext2Inode, RawInode};
+use spin::Mutex;
+
+// the superblock is always 1024 bytes into the disk, whatever the size of the blocks
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const ROOT_INODE: u32 = 2;
+// the first revision has fixed inodes and no features
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+
+// features we have to understand to read the disk at all
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+// features that only old drivers that ignore them could break
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_BTREE_DIR: u32 = 0x4;
+const SUPPORTED_RO_COMPAT: u32 =
+    RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+/// What the superblock says about the filesystem.
+#[derive(Debug, Clone)]
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    first_data_block: u32,
+    block_size: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    first_inode: u32,
+    inode_size: u32,
+    // when the filesystem was last written by a system with a clock
+    write_time: u32,
+    incompat: u32,
+    ro_compat: u32,
+    label: String,
+}
+
+impl Superblock {
+    fn parse(raw: &[u8]) -> Result<Self, FsError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+        if u16_at(56) != MAGIC {
+            return Err(FsError::Corrupted("no ext2 superblock"));
+        }
+        let log_block_size = u32_at(24);
+        // blocks are 1 KiB to 64 KiB
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted("bad block size"));
+        }
+        let block_size = 1024 << log_block_size;
+        let (first_inode, inode_size, incompat, ro_compat) = match u32_at(76) {
+            GOOD_OLD_REVISION => (GOOD_OLD_FIRST_INODE, GOOD_OLD_INODE_SIZE, 0, 0),
+            _ => (u32_at(84), u32::from(u16_at(88)), u32_at(96), u32_at(100)),
+        };
+        let superblock = Self {
+            inodes_count: u32_at(0),
+            blocks_count: u32_at(4),
+            free_blocks: u32_at(12),
+            free_inodes: u32_at(16),
+            first_data_block: u32_at(20),
+            block_size,
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            first_inode,
+            inode_size,
+            write_time: u32_at(48),
+            incompat,
+            ro_compat,
+            label: String::from_utf8_lossy(&raw[120..136])
+                .trim_end_matches('\0')
+                .into(),
+        };
+        if superblock.blocks_per_group == 0
+            || superblock.blocks_per_group > block_size * 8
+            || superblock.inodes_per_group == 0
+            || superblock.inodes_per_group > block_size * 8
+            || superblock.first_data_block >= superblock.blocks_count
+            || !superblock.inode_size.is_power_of_two()
+            || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&superblock.inode_size)
+            || superblock.first_inode <= ROOT_INODE
+            || u64::from(superblock.group_count()) * u64::from(superblock.inodes_per_group)
+                != u64::from(superblock.inodes_count)
+        {
+            return Err(FsError::Corrupted("bad ext2 superblock"));
+        }
+        Ok(superblock)
+    }
+
+    fn group_count(&self) -> u32 {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+
+    /// How many blocks a group has, the last one may be shorter than the others.
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = group * self.blocks_per_group;
+        (self.blocks_count - self.first_data_block - start).min(self.blocks_per_group)
+    }
+}
+
+/// Where the bitmaps and the inode table of a block group are.
+#[derive(Debug, Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+}
+
+/// The free counts of a block group, which change with every allocation.
+#[derive(Debug, Clone, Copy)]
+struct GroupCounts {
+    free_blocks: u16,
+    free_inodes: u16,
+    directories: u16,
+}
+
+struct Allocation {
+    counts: Vec<GroupCounts>,
+    free_blocks: u32,
+    free_inodes: u32,
+}
+
+/// An ext2 filesystem on a block device.
+pub struct Ext2Fs {
+    device: CachedDevice,
+    superblock: Superblock,
+    groups: Vec<Group>,
+    this: Weak<Ext2Fs>,
+    // the inodes in use by their number
+    inodes: Mutex<BTreeMap<u32, Weak<Ext2Inode>>>,
+    allocation: Mutex<Allocation>,
+    // held while directories change
+    directories: Mutex<()>,
+    read_only: bool,
+}
+
+impl Ext2Fs {
+    /// Reads the filesystem from a device, which fails if it doesn't have one or one with
+    /// features we don't know.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let device = CachedDevice::new(device);
+        let mut raw = [0; SUPERBLOCK_SIZE];
+        read_bytes(&device, SUPERBLOCK_OFFSET, &mut raw)?;
+        let superblock = Superblock::parse(&raw)?;
+        if superblock.incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let size = u64::from(superblock.blocks_count) * u64::from(superblock.block_size);
+        if size > device.block_count() * device.block_size() as u64 {
+            return Err(FsError::Corrupted("filesystem is larger than the device"));
+        }
+
+        // the group descriptors follow the block with the superblock
+        let group_count = superblock.group_count();
+        let mut descriptors = vec![0; (u64::from(group_count) * GROUP_DESCRIPTOR_SIZE) as usize];
+        let start = u64::from(superblock.first_data_block + 1) * u64::from(superblock.block_size);
+        read_bytes(&device, start, &mut descriptors)?;
+        let mut groups = Vec::new();
+        let mut counts = Vec::new();
+        for raw in descriptors.chunks_exact(GROUP_DESCRIPTOR_SIZE as usize) {
+            let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+            let u32_at =
+                |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+            let group = Group {
+                block_bitmap: u32_at(0),
+                inode_bitmap: u32_at(4),
+                inode_table: u32_at(8),
+            };
+            let table_blocks = (u64::from(superblock.inodes_per_group)
+                * u64::from(superblock.inode_size))
+            .div_ceil(u64::from(superblock.block_size));
+            if [group.block_bitmap, group.inode_bitmap]
+                .into_iter()
+                .chain([group.inode_table])
+                .any(|block| block >= superblock.blocks_count)
+                || u64::from(group.inode_table) + table_blocks > u64::from(superblock.blocks_count)
+            {
+                return Err(FsError::Corrupted("bad block group descriptor"));
+            }
+            groups.push(group);
+            counts.push(GroupCounts {
+                free_blocks: u16_at(12),
+                free_inodes: u16_at(14),
+                directories: u16_at(16),
+            });
+        }
+
+        let allocation = Allocation {
+            counts,
+            free_blocks: superblock.free_blocks,
+            free_inodes: superblock.free_inodes,
+        };
+        Ok(Arc::new_cyclic(|this| Self {
+            device,
+            read_only: superblock.ro_compat & !SUPPORTED_RO_COMPAT != 0,
+            superblock,
+            groups,
+            this: this.clone(),
+            inodes: Mutex::new(BTreeMap::new()),
+            allocation: Mutex::new(allocation),
+            directories: Mutex::new(()),
+        }))
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.superblock.block_size
+    }
+
+    pub fn group_count(&self) -> u32 {
+        self.superblock.group_count()
+    }
+
+    /// The name of the volume, like `e2label` shows it.
+    pub fn label(&self) -> &str {
+        &self.superblock.label
+    }
+
+    /// Whether the filesystem has features we can read but not write.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        self.allocation.lock().free_blocks
+    }
+
+    pub fn free_inodes(&self) -> u32 {
+        self.allocation.lock().free_inodes
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        match self.read_only {
+            true => Err(FsError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether directory entries have the type of their file.
+    fn file_types(&self) -> bool {
+        self.superblock.incompat & INCOMPAT_FILETYPE != 0
+    }
+
+    /// The largest file the block map can address, or that fits into 31 bits without
+    /// large files.
+    fn max_file_size(&self) -> u64 {
+        let per_block = u64::from(self.block_size() / 4);
+        let blocks = 12 + per_block + per_block.pow(2) + per_block.pow(3);
+        let size = blocks * u64::from(self.block_size());
+        match self.superblock.ro_compat & RO_COMPAT_LARGE_FILE {
+            0 => size.min(i32::MAX as u64),
+            _ => size,
+        }
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        u64::from(block) * u64::from(self.block_size())
+    }
+
+    /// Makes sure a block number from the disk points into the filesystem.
+    fn check_block(&self, block: u32) -> Result<u32, FsError> {
+        match (self.superblock.first_data_block..self.superblock.blocks_count).contains(&block) {
+            true => Ok(block),
+            false => Err(FsError::Corrupted("block number out of range")),
+        }
+    }
+
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        read_bytes(&self.device, self.block_offset(block), buffer)?;
+        Ok(())
+    }
+
+    fn write_block(&self, block: u32, buffer: &[u8]) -> Result<(), FsError> {
+        write_bytes(&self.device, self.block_offset(block), buffer)?;
+        Ok(())
+    }
+
+    /// Where an inode is in the inode table of its group.
+    fn inode_offset(&self, number: u32) -> Result<u64, FsError> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(FsError::Corrupted("inode number out of range"));
+        }
+        let group = (number - 1) / self.superblock.inodes_per_group;
+        let index = (number - 1) % self.superblock.inodes_per_group;
+        let table = self.block_offset(self.groups[group as usize].inode_table);
+        Ok(table + u64::from(index) * u64::from(self.superblock.inode_size))
+    }
+
+    fn read_inode(&self, number: u32) -> Result<RawInode, FsError> {
+        let mut raw = RawInode::new(self.superblock.inode_size as usize);
+        read_bytes(&self.device, self.inode_offset(number)?, raw.bytes_mut())?;
+        Ok(raw)
+    }
+
+    fn write_inode(&self, number: u32, raw: &RawInode) -> Result<(), FsError> {
+        write_bytes(&self.device, self.inode_offset(number)?, raw.bytes())?;
+        Ok(())
+    }
+
+    /// Returns the inode with a number, which is the one that is already in use if there is
+    /// one.
+    fn inode(&self, number: u32) -> Result<Arc<Ext2Inode>, FsError> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&number).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let raw = self.read_inode(number)?;
+        if raw.links() == 0 {
+            return Err(FsError::Corrupted("entry of a deleted inode"));
+        }
+        let inode = Arc::new(Ext2Inode::new(self.this.upgrade().unwrap(), number, raw));
+        inodes.insert(number, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// Writes a new inode to the disk and starts using it.
+    fn new_inode(&self, number: u32, raw: RawInode) -> Result<Arc<Ext2Inode>, FsError> {
+        self.write_inode(number, &raw)?;
+        let inode = Arc::new(Ext2Inode::new(self.this.upgrade().unwrap(), number, raw));
+        self.inodes.lock().insert(number, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// Writes the free counts of a group back, into its descriptor and the superblock.
+    fn write_counts(&self, allocation: &Allocation, group: u32) -> Result<(), FsError> {
+        let counts = allocation.counts[group as usize];
+        let mut raw = [0; 6];
+        raw[..2].copy_from_slice(&counts.free_blocks.to_le_bytes());
+        raw[2..4].copy_from_slice(&counts.free_inodes.to_le_bytes());
+        raw[4..].copy_from_slice(&counts.directories.to_le_bytes());
+        let descriptors = self.block_offset(self.superblock.first_data_block + 1);
+        let offset = descriptors + u64::from(group) * GROUP_DESCRIPTOR_SIZE + 12;
+        write_bytes(&self.device, offset, &raw)?;
+
+        let mut raw = [0; 8];
+        raw[..4].copy_from_slice(&allocation.free_blocks.to_le_bytes());
+        raw[4..].copy_from_slice(&allocation.free_inodes.to_le_bytes());
+        write_bytes(&self.device, SUPERBLOCK_OFFSET + 12, &raw)?;
+        Ok(())
+    }
+
+    /// Finds a free block, preferably in `group`, and fills it with zeros.
+    fn allocate_block(&self, group: u32) -> Result<u32, FsError> {
+        let mut allocation = self.allocation.lock();
+        let mut bitmap = vec![0; self.block_size() as usize];
+        for i in 0..self.group_count() {
+            let group = (group + i) % self.group_count();
+            if allocation.counts[group as usize].free_blocks == 0 {
+                continue;
+            }
+            let bitmap_block = self.groups[group as usize].block_bitmap;
+            self.read_block(bitmap_block, &mut bitmap)?;
+            let blocks = self.superblock.blocks_in_group(group) as usize;
+            let Some(bit) = find_zero(&bitmap, 0, blocks) else {
+                continue;
+            };
+            let block = self.superblock.first_data_block
+                + group * self.superblock.blocks_per_group
+                + bit as u32;
+            // directories end at zeros, and the parts of new blocks that aren't written
+            // have to read as zeros
+            self.write_block(block, &vec![0; self.block_size() as usize])?;
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            write_bytes(
+                &self.device,
+                self.block_offset(bitmap_block) + (bit / 8) as u64,
+                &bitmap[bit / 8..][..1],
+            )?;
+            allocation.counts[group as usize].free_blocks -= 1;
+            allocation.free_blocks = allocation.free_blocks.saturating_sub(1);
+            self.write_counts(&allocation, group)?;
+            return Ok(block);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), FsError> {
+        let block = self.check_block(block)?;
+        let index = block - self.superblock.first_data_block;
+        let group = index / self.superblock.blocks_per_group;
+        let bit = index % self.superblock.blocks_per_group;
+        let offset =
+            self.block_offset(self.groups[group as usize].block_bitmap) + u64::from(bit / 8);
+        let mut allocation = self.allocation.lock();
+        clear_bit(&self.device, offset, bit % 8)?;
+        allocation.counts[group as usize].free_blocks += 1;
+        allocation.free_blocks += 1;
+        self.write_counts(&allocation, group)
+    }
+
+    /// Finds a free inode, preferably in `group`.
+    fn allocate_inode(&self, group: u32, directory: bool) -> Result<u32, FsError> {
+        let mut allocation = self.allocation.lock();
+        let mut bitmap = vec![0; self.block_size() as usize];
+        let per_group = self.superblock.inodes_per_group;
+        for i in 0..self.group_count() {
+            let group = (group + i) % self.group_count();
+            if allocation.counts[group as usize].free_inodes == 0 {
+                continue;
+            }
+            let bitmap_block = self.groups[group as usize].inode_bitmap;
+            self.read_block(bitmap_block, &mut bitmap)?;
+            // the first inodes are reserved, like the root directory
+            let start = match group {
+                0 => (self.superblock.first_inode - 1).min(per_group),
+                _ => 0,
+            };
+            let Some(bit) = find_zero(&bitmap, start as usize, per_group as usize) else {
+                continue;
+            };
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            write_bytes(
+                &self.device,
+                self.block_offset(bitmap_block) + (bit / 8) as u64,
+                &bitmap[bit / 8..][..1],
+            )?;
+            let counts = &mut allocation.counts[group as usize];
+            counts.free_inodes -= 1;
+            if directory {
+                counts.directories += 1;
+            }
+            allocation.free_inodes = allocation.free_inodes.saturating_sub(1);
+            self.write_counts(&allocation, group)?;
+            return Ok(group * per_group + bit as u32 + 1);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, number: u32, directory: bool) -> Result<(), FsError> {
+        let group = (number - 1) / self.superblock.inodes_per_group;
+        let bit = (number - 1) % self.superblock.inodes_per_group;
+        let offset =
+            self.block_offset(self.groups[group as usize].inode_bitmap) + u64::from(bit / 8);
+        let mut allocation = self.allocation.lock();
+        clear_bit(&self.device, offset, bit % 8)?;
+        let counts = &mut allocation.counts[group as usize];
+        counts.free_inodes += 1;
+        if directory {
+            counts.directories = counts.directories.saturating_sub(1);
+        }
+        allocation.free_inodes += 1;
+        self.write_counts(&allocation, group)
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        // there's nothing to mount without a root directory
+        self.inode(ROOT_INODE)
+            .expect("ext2 root directory can't be read")
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.device.flush()?;
+        Ok(())
+    }
+}
+
+/// Finds the first bit of a bitmap in `start..end` that is clear.
+fn find_zero(bitmap: &[u8], start: usize, end: usize) -> Option<usize> {
+    let mut bit = start;
+    while bit < end {
+        let byte = bitmap[bit / 8];
+        // full bytes are skipped all at once
+        if byte == 0xff && bit & 7 == 0 {
+            bit += 8;
+            continue;
+        }
+        if byte & 1 << (bit % 8) == 0 {
+            return Some(bit);
+        }
+        bit += 1;
+    }
+    None
+}
+
+/// Clears a bit of a bitmap on the disk, which is an error if it wasn't set.
+fn clear_bit(device: &CachedDevice, offset: u64, bit: u32) -> Result<(), FsError> {
+    let mut byte = [0];
+    read_bytes(device, offset, &mut byte)?;
+    if byte[0] & 1 << bit == 0 {
+        return Err(FsError::Corrupted("freeing something that is free"));
+    }
+    byte[0] &= !(1 << bit);
+    write_bytes(device, offset, &byte)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block;
+    use crate::vfs::{self, FileType};
+    use alloc::format;
+
+    // the images from `tests/images/build.py`, with blocks of 1 KiB and 4 KiB
+    const DISKS: [&str; 2] = ["sata4", "sata5"];
+
+    fn mount(disk: &str) -> Arc<Ext2Fs> {
+        Ext2Fs::new(block::get(disk).expect("ext2 test image not attached")).unwrap()
+    }
+
+    fn filesystems() -> [Arc<Ext2Fs>; 2] {
+        DISKS.map(mount)
+    }
+
+    fn lookup(fs: &Ext2Fs, path: &str) -> Result<Arc<dyn Inode>, FsError> {
+        path.split('/')
+            .try_fold(fs.root(), |inode, name| inode.lookup(name))
+    }
+
+    fn read_all(inode: &Arc<dyn Inode>) -> Vec<u8> {
+        let mut data = vec![0; inode.metadata().unwrap().size as usize];
+        assert_eq!(inode.read_at(0, &mut data).unwrap(), data.len());
+        data
+    }
+
+    fn names(inode: &Arc<dyn Inode>) -> Vec<String> {
+        inode
+            .readdir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    fn sectors(inode: &Arc<dyn Inode>) -> u32 {
+        let inode = inode.as_any().downcast_ref::<Ext2Inode>().unwrap();
+        inode.raw.lock().sectors()
+    }
+
+    #[test_case]
+    fn superblocks() {
+        let [small, large] = filesystems();
+        assert_eq!((small.block_size(), small.group_count()), (1024, 4));
+        assert_eq!((large.block_size(), large.group_count()), (4096, 2));
+        assert_eq!(small.label(), "wally");
+        assert!(!small.is_read_only());
+        // not every disk has a filesystem
+        let disk = block::get("sata1").unwrap();
+        assert!(matches!(Ext2Fs::new(disk), Err(FsError::Corrupted(_))));
+    }
+
+    #[test_case]
+    fn bitmaps() {
+        let bitmap = [0xff, 0xff, 0b1110_1111, 0];
+        assert_eq!(find_zero(&bitmap, 0, 32), Some(20));
+        assert_eq!(find_zero(&bitmap, 21, 32), Some(24));
+        assert_eq!(find_zero(&bitmap, 0, 20), None);
+    }
+
+    #[test_case]
+    fn reads_host_files() {
+        for fs in filesystems() {
+            let root = fs.root();
+            assert_eq!(
+                names(&root),
+                [
+                    "lost+found",
+                    "big.bin",
+                    "docs",
+                    "hello.txt",
+                    "secret.txt",
+                    "sparse.bin"
+                ]
+            );
+            let hello = lookup(&fs, "hello.txt").unwrap();
+            assert_eq!(read_all(&hello), b"Hello from ext2!\n");
+            let metadata = hello.metadata().unwrap();
+            assert_eq!(
+                (metadata.permissions, metadata.uid, metadata.links),
+                (0o644, 0, 1)
+            );
+            let secret = lookup(&fs, "secret.txt").unwrap();
+            assert_eq!(secret.metadata().unwrap().permissions, 0o600);
+            let deep = lookup(&fs, "docs/deep").unwrap();
+            let metadata = deep.metadata().unwrap();
+            assert!(metadata.is_dir());
+            assert_eq!((metadata.permissions, metadata.links), (0o700, 2));
+            assert_eq!(
+                read_all(&lookup(&fs, "docs/readme.md").unwrap()),
+                b"# docs\n"
+            );
+            // `docs` is linked from the root, itself and `deep`
+            assert_eq!(lookup(&fs, "docs").unwrap().metadata().unwrap().links, 3);
+
+            // through the indirect blocks
+            let big = lookup(&fs, "big.bin").unwrap();
+            let expected: Vec<u8> = (0..300 * 1024).map(|i| (i % 253) as u8).collect();
+            assert_eq!(read_all(&big), expected);
+            let mut buffer = [0; 8];
+            assert_eq!(big.read_at(300 * 1024 - 3, &mut buffer).unwrap(), 3);
+
+            // the hole takes no blocks
+            let sparse = lookup(&fs, "sparse.bin").unwrap();
+            assert_eq!(sparse.metadata().unwrap().size, 1024 * 1024 + 1);
+            assert!(sectors(&sparse) <= 3 * fs.block_size() / 512);
+            let contents = read_all(&sparse);
+            assert!(contents[..1024 * 1024].iter().all(|&byte| byte == 0));
+            assert_eq!(contents[1024 * 1024], b'x');
+
+            assert_eq!(lookup(&fs, "docs/missing").err(), Some(FsError::NotFound));
+            assert_eq!(hello.lookup("x").err(), Some(FsError::NotADirectory));
+            assert_eq!(root.read_at(0, &mut buffer), Err(FsError::IsADirectory));
+        }
+    }
+
+    #[test_case]
+    fn writes_files() {
+        for disk in DISKS {
+            let fs = mount(disk);
+            let (free_blocks, free_inodes) = (fs.free_blocks(), fs.free_inodes());
+            let block_size = fs.block_size() as usize;
+            let file = fs.root().create("written.bin", FileType::File).unwrap();
+            assert_eq!(fs.free_inodes(), free_inodes - 1);
+            let metadata = file.metadata().unwrap();
+            assert_eq!((metadata.size, metadata.permissions), (0, 0o644));
+
+            // past the direct blocks into the first indirect block
+            let data: Vec<u8> = (0..20 * block_size).map(|i| (i * 7) as u8).collect();
+            file.write_at(0, &data).unwrap();
+            assert_eq!(read_all(&file), data);
+            assert_eq!(fs.free_blocks(), free_blocks - 21);
+            assert_eq!(sectors(&file) as usize, 21 * block_size / 512);
+
+            // what is cut off doesn't come back when the file grows again
+            file.truncate(100).unwrap();
+            assert_eq!(fs.free_blocks(), free_blocks - 1);
+            file.truncate(600).unwrap();
+            let contents = read_all(&file);
+            assert_eq!(contents[..100], data[..100]);
+            assert!(contents[100..].iter().all(|&byte| byte == 0));
+
+            // writing far behind the end only takes the blocks that are written and the
+            // indirect blocks that lead to them
+            let offset = 64 * 1024 * 1024;
+            file.write_at(offset, b"far away").unwrap();
+            assert_eq!(file.metadata().unwrap().size, offset + 8);
+            let used = free_blocks - fs.free_blocks();
+            assert!(used <= 4, "{used} blocks for a sparse file");
+            let mut buffer = [1; 16];
+            assert_eq!(file.read_at(offset - 8, &mut buffer).unwrap(), 16);
+            assert_eq!(buffer, *b"\0\0\0\0\0\0\0\0far away");
+            assert_eq!(file.write_at(u64::MAX, b"x"), Err(FsError::NoSpace));
+
+            // the file is still there for a new instance of the filesystem
+            fs.sync().unwrap();
+            let again = mount(disk);
+            let reread = again.root().lookup("written.bin").unwrap();
+            let mut start = [0; 600];
+            reread.read_at(0, &mut start).unwrap();
+            assert_eq!(start[..], contents[..]);
+            assert_eq!(reread.metadata().unwrap().size, offset + 8);
+            drop(reread);
+
+            assert_eq!(
+                fs.root().create("written.bin", FileType::File).err(),
+                Some(FsError::AlreadyExists)
+            );
+            fs.root().unlink("written.bin").unwrap();
+            drop(file);
+            assert_eq!(
+                (fs.free_blocks(), fs.free_inodes()),
+                (free_blocks, free_inodes)
+            );
+        }
+    }
+
+    #[test_case]
+    fn unlinked_files_stay_readable() {
+        let [fs, _] = filesystems();
+        let free = fs.free_blocks();
+        let file = fs.root().create("doomed", FileType::File).unwrap();
+        file.write_at(0, b"still here").unwrap();
+        fs.root().unlink("doomed").unwrap();
+        assert_eq!(file.metadata().unwrap().links, 0);
+        assert_eq!(read_all(&file), b"still here");
+        // the blocks and the inode are freed with the last reference
+        assert_eq!(fs.free_blocks(), free - 1);
+        drop(file);
+        assert_eq!(fs.free_blocks(), free);
+    }
+
+    #[test_case]
+    fn permissions() {
+        let [fs, _] = filesystems();
+        let secret = lookup(&fs, "secret.txt").unwrap();
+        secret.set_permissions(0o640).unwrap();
+        drop(secret);
+        // the inode isn't cached anymore, so this comes from the disk
+        let secret = lookup(&fs, "secret.txt").unwrap();
+        assert_eq!(secret.metadata().unwrap().permissions, 0o640);
+        secret.set_permissions(0o600).unwrap();
+    }
+
+    #[test_case]
+    fn directories() {
+        for fs in filesystems() {
+            let (free_blocks, free_inodes) = (fs.free_blocks(), fs.free_inodes());
+            let root = fs.root();
+            let links = root.metadata().unwrap().links;
+            let directory = root.create("new directory", FileType::Directory).unwrap();
+            assert_eq!(root.metadata().unwrap().links, links + 1);
+            let metadata = directory.metadata().unwrap();
+            assert_eq!((metadata.permissions, metadata.links), (0o755, 2));
+            let sub = directory.create("sub", FileType::Directory).unwrap();
+            assert_eq!(directory.metadata().unwrap().links, 3);
+
+            // enough long names that the directory needs more than one block
+            let count = fs.block_size() as usize / 24 + 10;
+            for i in 0..count {
+                let name = format!("file number {i}.txt");
+                directory.create(&name, FileType::File).unwrap();
+            }
+            assert_eq!(directory.readdir().unwrap().len(), count + 1);
+            assert!(directory.metadata().unwrap().size > u64::from(fs.block_size()));
+            assert!(directory.lookup("file number 7.txt").is_ok());
+            assert_eq!(root.unlink("new directory"), Err(FsError::NotEmpty));
+
+            // `..` is a real entry on the disk
+            let sub_inode = sub.as_any().downcast_ref::<Ext2Inode>().unwrap();
+            let raw = sub_inode.raw.lock();
+            let dot_dot = sub_inode.find(&raw, "..").unwrap().unwrap();
+            assert_eq!(
+                u64::from(dot_dot.inode),
+                directory.metadata().unwrap().inode
+            );
+            drop(raw);
+
+            for i in 0..count {
+                directory.unlink(&format!("file number {i}.txt")).unwrap();
+            }
+            directory.unlink("sub").unwrap();
+            // removed directories can't get new entries
+            assert_eq!(
+                sub.create("late", FileType::File).err(),
+                Some(FsError::NotFound)
+            );
+            root.unlink("new directory").unwrap();
+            assert_eq!(root.metadata().unwrap().links, links);
+            drop((directory, sub));
+            assert_eq!(
+                (fs.free_blocks(), fs.free_inodes()),
+                (free_blocks, free_inodes)
+            );
+        }
+    }
+
+    #[test_case]
+    fn renames() {
+        for fs in filesystems() {
+            let root = fs.root();
+            let docs = root.lookup("docs").unwrap();
+            let file = root.create("a.txt", FileType::File).unwrap();
+            file.write_at(0, b"moved").unwrap();
+
+            root.rename("a.txt", &docs, "renamed.txt").unwrap();
+            assert_eq!(root.lookup("a.txt").err(), Some(FsError::NotFound));
+            let renamed = docs.lookup("renamed.txt").unwrap();
+            assert_eq!(
+                renamed.metadata().unwrap().inode,
+                file.metadata().unwrap().inode
+            );
+            assert_eq!(read_all(&renamed), b"moved");
+            drop(renamed);
+
+            // replacing files, but not directories with files
+            docs.create("other", FileType::File).unwrap();
+            docs.rename("other", &docs, "renamed.txt").unwrap();
+            assert_eq!(file.metadata().unwrap().links, 0);
+            assert_eq!(
+                docs.rename("renamed.txt", &docs, "deep"),
+                Err(FsError::IsADirectory)
+            );
+            assert_eq!(
+                docs.rename("deep", &docs, "renamed.txt"),
+                Err(FsError::NotADirectory)
+            );
+            docs.unlink("renamed.txt").unwrap();
+            drop(file);
+
+            // directories take their `..` and a link of their parent along
+            let (root_links, docs_links) = (
+                root.metadata().unwrap().links,
+                docs.metadata().unwrap().links,
+            );
+            docs.rename("deep", &root, "moved").unwrap();
+            assert_eq!(root.metadata().unwrap().links, root_links + 1);
+            assert_eq!(docs.metadata().unwrap().links, docs_links - 1);
+            let moved = root.lookup("moved").unwrap();
+            let moved_inode = moved.as_any().downcast_ref::<Ext2Inode>().unwrap();
+            let raw = moved_inode.raw.lock();
+            let dot_dot = moved_inode.find(&raw, "..").unwrap().unwrap();
+            assert_eq!(dot_dot.inode, ROOT_INODE);
+            drop(raw);
+            assert_eq!(moved.metadata().unwrap().permissions, 0o700);
+            root.rename("moved", &docs, "deep").unwrap();
+            // the entry goes into whatever room was left, not where it was before
+            let mut entries = names(&docs);
+            entries.sort();
+            assert_eq!(entries, ["deep", "readme.md"]);
+            assert_eq!(docs.metadata().unwrap().links, docs_links);
+        }
+    }
+
+    #[test_case]
+    fn read_only_features() {
+        let disk = block::get("sata4").unwrap();
+        let device = CachedDevice::new(disk.clone());
+        let mut raw = [0; 4];
+        read_bytes(&device, SUPERBLOCK_OFFSET + 100, &mut raw).unwrap();
+        let ro_compat = u32::from_le_bytes(raw);
+
+        // a feature we don't know only keeps us from writing
+        write_bytes(
+            &device,
+            SUPERBLOCK_OFFSET + 100,
+            &(ro_compat | 0x8000).to_le_bytes(),
+        )
+        .unwrap();
+        let fs = Ext2Fs::new(disk.clone()).unwrap();
+        assert!(fs.is_read_only());
+        assert_eq!(
+            read_all(&fs.root().lookup("hello.txt").unwrap()),
+            b"Hello from ext2!\n"
+        );
+        assert_eq!(
+            fs.root().create("new", FileType::File).err(),
+            Some(FsError::ReadOnly)
+        );
+        write_bytes(&device, SUPERBLOCK_OFFSET + 100, &raw).unwrap();
+
+        // like extents, which change how files are found
+        read_bytes(&device, SUPERBLOCK_OFFSET + 96, &mut raw).unwrap();
+        let incompat = u32::from_le_bytes(raw);
+        write_bytes(
+            &device,
+            SUPERBLOCK_OFFSET + 96,
+            &(incompat | 0x40).to_le_bytes(),
+        )
+        .unwrap();
+        assert!(matches!(
+            Ext2Fs::new(disk.clone()),
+            Err(FsError::Unsupported)
+        ));
+        write_bytes(&device, SUPERBLOCK_OFFSET + 96, &raw).unwrap();
+        assert!(!mount("sata4").is_read_only());
+    }
+
+    #[test_case]
+    fn through_the_vfs() {
+        let [_, fs] = filesystems();
+        vfs::mkdir("/ext2").unwrap();
+        vfs::mount("/ext2", fs).unwrap();
+        assert_eq!(vfs::read("/ext2/docs/readme.md").unwrap(), b"# docs\n");
+        vfs::write("/ext2/docs/from the kernel.txt", b"hello host").unwrap();
+        vfs::rename("/ext2/docs/from the kernel.txt", "/ext2/kernel.txt").unwrap();
+        assert_eq!(vfs::read("/ext2/kernel.txt").unwrap(), b"hello host");
+        vfs::set_permissions("/ext2/kernel.txt", 0o600).unwrap();
+        assert_eq!(vfs::stat("/ext2/kernel.txt").unwrap().permissions, 0o600);
+        assert_eq!(vfs::stat("/ext2/docs/deep").unwrap().permissions, 0o700);
+        vfs::remove("/ext2/kernel.txt").unwrap();
+        vfs::unmount("/ext2").unwrap();
+        vfs::remove("/ext2").unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Directory entries, which fill the blocks of a directory as records of varying length.
+
+use crate::vfs::FsError;
+use alloc::vec::Vec;
+
+// the inode, the length of the record and of the name, and the type
+const HEADER_SIZE: usize = 8;
+
+/// The type of a file in its directory entry, which saves reading its inode.
+pub(super) const TYPE_UNKNOWN: u8 = 0;
+pub(super) const TYPE_FILE: u8 = 1;
+pub(super) const TYPE_DIRECTORY: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Entry {
+    /// Where the record starts in its block.
+    pub offset: usize,
+    pub record_length: usize,
+    /// 0 for records that are unused.
+    pub inode: u32,
+    pub kind: u8,
+    pub name: Vec<u8>,
+}
+
+impl Entry {
+    /// How much of its record the entry needs, the rest can take another entry.
+    pub fn used(&self) -> usize {
+        match self.inode {
+            0 => 0,
+            _ => record_size(self.name.len()),
+        }
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.name == b"." || self.name == b".."
+    }
+}
+
+/// How long the record of an entry with a name of this length has to be.
+pub(super) fn record_size(name_length: usize) -> usize {
+    (HEADER_SIZE + name_length).next_multiple_of(4)
+}
+
+/// Reads the records of a directory block, the unused ones included. Without `file_types`
+/// the length of the name has 16 bits and there is no type.
+pub(super) fn parse(block: &[u8], file_types: bool) -> Result<Vec<Entry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        let header = block
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(FsError::Corrupted("broken directory entry"))?;
+        let inode = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let record_length = usize::from(u16::from_le_bytes([header[4], header[5]]));
+        let (name_length, kind) = match file_types {
+            true => (usize::from(header[6]), header[7]),
+            false => (
+                usize::from(u16::from_le_bytes([header[6], header[7]])),
+                TYPE_UNKNOWN,
+            ),
+        };
+        // records are aligned to 4 bytes and never cross a block
+        if record_length < HEADER_SIZE
+            || record_length & 3 != 0
+            || offset + record_length > block.len()
+            || HEADER_SIZE + name_length > record_length
+        {
+            return Err(FsError::Corrupted("broken directory entry"));
+        }
+        entries.push(Entry {
+            offset,
+            record_length,
+            inode,
+            kind,
+            name: block[offset + HEADER_SIZE..][..name_length].to_vec(),
+        });
+        offset += record_length;
+    }
+    Ok(entries)
+}
+
+/// Writes an entry into a directory block.
+pub(super) fn write(
+    block: &mut [u8],
+    offset: usize,
+    record_length: usize,
+    inode: u32,
+    kind: u8,
+    name: &[u8],
+    file_types: bool,
+) {
+    let record = &mut block[offset..offset + record_length];
+    record[..4].copy_from_slice(&inode.to_le_bytes());
+    record[4..6].copy_from_slice(&(record_length as u16).to_le_bytes());
+    if file_types {
+        record[6] = name.len() as u8;
+        record[7] = kind;
+    } else {
+        record[6..8].copy_from_slice(&(name.len() as u16).to_le_bytes());
+    }
+    record[HEADER_SIZE..][..name.len()].copy_from_slice(name);
+}
+
+/// Changes the length of a record, which is how entries are split and merged.
+pub(super) fn set_record_length(block: &mut [u8], offset: usize, record_length: usize) {
+    block[offset + 4..offset + 6].copy_from_slice(&(record_length as u16).to_le_bytes());
+}
+
+/// Finds room for an entry with a name of this length, as the record that has to be split
+/// for it.
+pub(super) fn find_room(entries: &[Entry], name_length: usize) -> Option<&Entry> {
+    let needed = record_size(name_length);
+    entries
+        .iter()
+        .find(|entry| entry.record_length - entry.used() >= needed)
+}
+
+/// Puts an entry into the room `find_room` found.
+pub(super) fn insert(
+    block: &mut [u8],
+    room: &Entry,
+    inode: u32,
+    kind: u8,
+    name: &[u8],
+    file_types: bool,
+) {
+    let used = room.used();
+    if used != 0 {
+        set_record_length(block, room.offset, used);
+    }
+    let length = room.record_length - used;
+    write(
+        block,
+        room.offset + used,
+        length,
+        inode,
+        kind,
+        name,
+        file_types,
+    );
+}
+
+/// Takes an entry out of its block, the record before it gets its room.
+pub(super) fn remove(block: &mut [u8], entries: &[Entry], index: usize) {
+    let entry = &entries[index];
+    match index.checked_sub(1).map(|previous| &entries[previous]) {
+        Some(previous) => {
+            let length = previous.record_length + entry.record_length;
+            set_record_length(block, previous.offset, length);
+        }
+        // the first record of a block can only be marked as unused
+        None => block[entry.offset..entry.offset + 4].fill(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn records() {
+        let mut block = vec![0; 64];
+        write(&mut block, 0, 12, 2, TYPE_DIRECTORY, b".", true);
+        write(&mut block, 12, 52, 2, TYPE_DIRECTORY, b"..", true);
+        let entries = parse(&block, true).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(Entry::is_dot));
+
+        // `..` only needs 12 of its 52 bytes
+        let room = find_room(&entries, 20).unwrap().clone();
+        assert_eq!(room.offset, 12);
+        insert(
+            &mut block,
+            &room,
+            12,
+            TYPE_FILE,
+            b"a file with a name",
+            true,
+        );
+        let entries = parse(&block, true).unwrap();
+        assert_eq!(entries[1].record_length, 12);
+        assert_eq!((entries[2].offset, entries[2].record_length), (24, 40));
+        assert_eq!(entries[2].name, b"a file with a name");
+        assert_eq!(find_room(&entries, 20), None);
+
+        remove(&mut block, &entries, 2);
+        let entries = parse(&block, true).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].record_length, 52);
+
+        // a record that runs over the end of the block
+        set_record_length(&mut block, 12, 56);
+        assert!(parse(&block, true).is_err());
+    }
+
+    #[test_case]
+    fn without_file_types() {
+        let mut block = vec![0; 16];
+        write(&mut block, 0, 16, 11, TYPE_FILE, b"name", false);
+        let entries = parse(&block, false).unwrap();
+        assert_eq!((entries[0].inode, entries[0].kind), (11, TYPE_UNKNOWN));
+        assert_eq!(entries[0].name, b"name");
+
+        // unused first records keep their room
+        remove(&mut block, &entries, 0);
+        let entries = parse(&block, false).unwrap();
+        assert_eq!((entries[0].inode, entries[0].used()), (0, 0));
+        assert_eq!(find_room(&entries, 8).unwrap().offset, 0);
+    }
+}
@@ -0,0 +1,917 @@
+//! Inodes, which hold the type, permissions, size and block map of a file.
+
+use super::dir::{self, Entry, TYPE_DIRECTORY, TYPE_FILE};
+use super::Ext2Fs;
+use crate::block::BlockDevice;
+use crate::fs::{read_bytes, write_bytes};
+use crate::vfs::{DirEntry, FileType, FsError, Inode, Metadata, MAX_NAME_LENGTH};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use spin::{Mutex, MutexGuard};
+
+// the kind of file is in the upper bits of the mode
+const MODE_TYPE: u16 = 0xf000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xa000;
+const MODE_PERMISSIONS: u16 = 0o7777;
+// the directory has a hash tree that we don't keep up to date
+const FLAG_INDEX: u32 = 0x1000;
+// the block map: 12 direct blocks, then a singly, doubly and triply indirect block
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT: usize = 12;
+// how much the inode has beyond the first 128 bytes, like new inodes of `mke2fs`
+const EXTRA_SIZE: u16 = 32;
+
+/// An inode as it is on the disk.
+pub(super) struct RawInode(Vec<u8>);
+
+impl RawInode {
+    pub fn new(size: usize) -> Self {
+        Self(vec![0; size])
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.0[offset], self.0[offset + 1]])
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.u16_at(0)
+    }
+
+    fn set_mode(&mut self, mode: u16) {
+        self.set_u16(0, mode);
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_DIRECTORY
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_FILE
+    }
+
+    /// Whether the block map points to data, which it doesn't for devices and for symlinks
+    /// that are short enough to be stored in place of the map.
+    fn has_blocks(&self, block_size: u32) -> bool {
+        match self.mode() & MODE_TYPE {
+            MODE_FILE | MODE_DIRECTORY => true,
+            // the block with the extended attributes counts too
+            MODE_SYMLINK => match self.u32_at(104) {
+                0 => self.sectors() != 0,
+                _ => self.sectors() != block_size / 512,
+            },
+            _ => false,
+        }
+    }
+
+    // the upper halves of the owners are in the part for the operating system
+    fn uid(&self) -> u32 {
+        u32::from(self.u16_at(2)) | u32::from(self.u16_at(120)) << 16
+    }
+
+    fn gid(&self) -> u32 {
+        u32::from(self.u16_at(24)) | u32::from(self.u16_at(122)) << 16
+    }
+
+    /// The size, whose upper half is only there for regular files.
+    pub fn size(&self) -> u64 {
+        let high = match self.is_file() {
+            true => u64::from(self.u32_at(108)),
+            false => 0,
+        };
+        high << 32 | u64::from(self.u32_at(4))
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set_u32(4, size as u32);
+        if self.is_file() {
+            self.set_u32(108, (size >> 32) as u32);
+        }
+    }
+
+    pub fn links(&self) -> u16 {
+        self.u16_at(26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.set_u16(26, links);
+    }
+
+    fn add_link(&mut self) {
+        self.set_links(self.links() + 1);
+    }
+
+    fn remove_link(&mut self) {
+        self.set_links(self.links().saturating_sub(1));
+    }
+
+    /// How much space the file takes on the disk, in units of 512 bytes.
+    pub fn sectors(&self) -> u32 {
+        self.u32_at(28)
+    }
+
+    fn set_sectors(&mut self, sectors: u32) {
+        self.set_u32(28, sectors);
+    }
+
+    fn flags(&self) -> u32 {
+        self.u32_at(32)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.set_u32(32, flags);
+    }
+
+    /// One of the 15 pointers of the block map.
+    fn block(&self, index: usize) -> u32 {
+        self.u32_at(40 + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        self.set_u32(40 + index * 4, block);
+    }
+}
+
+/// Finds the way through the block map to a block of a file: the pointer of the inode, then
+/// the index in each indirect block. `None` if the file can't be that large.
+fn block_path(index: u64, per_block: u64) -> Option<(usize, Vec<usize>)> {
+    if index < DIRECT_BLOCKS {
+        return Some((index as usize, Vec::new()));
+    }
+    let mut index = index - DIRECT_BLOCKS;
+    let mut span = per_block;
+    for level in 0..3 {
+        if index < span {
+            let mut indices = Vec::new();
+            let mut below = span;
+            for _ in 0..=level {
+                below /= per_block;
+                indices.push((index / below) as usize);
+                index %= below;
+            }
+            return Some((INDIRECT + level, indices));
+        }
+        index -= span;
+        span *= per_block;
+    }
+    None
+}
+
+pub(super) struct Ext2Inode {
+    fs: Arc<Ext2Fs>,
+    number: u32,
+    pub(super) raw: Mutex<RawInode>,
+}
+
+impl Ext2Inode {
+    pub fn new(fs: Arc<Ext2Fs>, number: u32, raw: RawInode) -> Self {
+        Self {
+            fs,
+            number,
+            raw: Mutex::new(raw),
+        }
+    }
+
+    // new blocks and inodes go into the same group as their inode or directory
+    fn group(&self) -> u32 {
+        (self.number - 1) / self.fs.superblock.inodes_per_group
+    }
+
+    fn save(&self, raw: &RawInode) -> Result<(), FsError> {
+        self.fs.write_inode(self.number, raw)
+    }
+
+    fn per_block(&self) -> u64 {
+        u64::from(self.fs.block_size() / 4)
+    }
+
+    fn block_units(&self) -> u32 {
+        self.fs.block_size() / 512
+    }
+
+    fn read_pointer(&self, block: u32, index: usize) -> Result<u32, FsError> {
+        let mut pointer = [0; 4];
+        read_bytes(
+            &self.fs.device,
+            self.fs.block_offset(block) + index as u64 * 4,
+            &mut pointer,
+        )?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn write_pointer(&self, block: u32, index: usize, pointer: u32) -> Result<(), FsError> {
+        write_bytes(
+            &self.fs.device,
+            self.fs.block_offset(block) + index as u64 * 4,
+            &pointer.to_le_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The block on the disk that holds a block of the file, `None` for a hole.
+    fn map(&self, raw: &RawInode, index: u64) -> Result<Option<u32>, FsError> {
+        let Some((slot, indices)) = block_path(index, self.per_block()) else {
+            return Ok(None);
+        };
+        let mut block = raw.block(slot);
+        for index in indices {
+            if block == 0 {
+                break;
+            }
+            block = self.read_pointer(self.fs.check_block(block)?, index)?;
+        }
+        match block {
+            0 => Ok(None),
+            block => Ok(Some(self.fs.check_block(block)?)),
+        }
+    }
+
+    /// Like `map`, but fills holes with new blocks, and the indirect blocks on the way.
+    fn map_or_allocate(&self, raw: &mut RawInode, index: u64) -> Result<u32, FsError> {
+        let (slot, indices) = block_path(index, self.per_block()).ok_or(FsError::NoSpace)?;
+        let mut block = raw.block(slot);
+        if block == 0 {
+            block = self.fs.allocate_block(self.group())?;
+            raw.set_block(slot, block);
+            raw.set_sectors(raw.sectors() + self.block_units());
+        }
+        for index in indices {
+            let parent = self.fs.check_block(block)?;
+            block = self.read_pointer(parent, index)?;
+            if block == 0 {
+                block = self.fs.allocate_block(self.group())?;
+                self.write_pointer(parent, index, block)?;
+                raw.set_sectors(raw.sectors() + self.block_units());
+            }
+        }
+        self.fs.check_block(block)
+    }
+
+    /// Frees the blocks of the file from block `keep` on.
+    fn free_blocks(&self, raw: &mut RawInode, keep: u64) -> Result<(), FsError> {
+        for slot in keep.min(DIRECT_BLOCKS) as usize..DIRECT_BLOCKS as usize {
+            if raw.block(slot) != 0 {
+                self.fs.free_block(raw.block(slot))?;
+                raw.set_block(slot, 0);
+                raw.set_sectors(raw.sectors().saturating_sub(self.block_units()));
+            }
+        }
+        let mut first = DIRECT_BLOCKS;
+        let mut span = self.per_block();
+        for level in 0..3 {
+            let block = raw.block(INDIRECT + level);
+            if block != 0 && self.free_tree(raw, block, level as u32, first, keep)? {
+                raw.set_block(INDIRECT + level, 0);
+            }
+            first += span;
+            span *= self.per_block();
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks from `keep` on below an indirect block, whose pointers lead to the
+    /// blocks from `first` on, with `level` more indirect blocks in between. Returns whether
+    /// the indirect block was freed too because nothing is left below it.
+    fn free_tree(
+        &self,
+        raw: &mut RawInode,
+        block: u32,
+        level: u32,
+        first: u64,
+        keep: u64,
+    ) -> Result<bool, FsError> {
+        let mut data = vec![0; self.fs.block_size() as usize];
+        self.fs.read_block(self.fs.check_block(block)?, &mut data)?;
+        let span = self.per_block().pow(level);
+        let mut changed = false;
+        for (i, pointer) in data.chunks_exact_mut(4).enumerate() {
+            let child = u32::from_le_bytes(pointer.try_into().unwrap());
+            let child_first = first + i as u64 * span;
+            if child == 0 || child_first + span <= keep {
+                continue;
+            }
+            let freed = match level {
+                0 => {
+                    self.fs.free_block(child)?;
+                    raw.set_sectors(raw.sectors().saturating_sub(self.block_units()));
+                    true
+                }
+                _ => self.free_tree(raw, child, level - 1, child_first, keep)?,
+            };
+            if freed {
+                pointer.fill(0);
+                changed = true;
+            }
+        }
+        if data.iter().all(|&byte| byte == 0) {
+            self.fs.free_block(block)?;
+            raw.set_sectors(raw.sectors().saturating_sub(self.block_units()));
+            return Ok(true);
+        }
+        if changed {
+            self.fs.write_block(block, &data)?;
+        }
+        Ok(false)
+    }
+
+    /// Reads from the blocks of the file, whatever its size.
+    fn read_data(&self, raw: &RawInode, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let block_size = u64::from(self.fs.block_size());
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let in_block = position % block_size;
+            let length = ((block_size - in_block) as usize).min(buffer.len() - done);
+            let chunk = &mut buffer[done..][..length];
+            match self.map(raw, position / block_size)? {
+                Some(block) => read_bytes(
+                    &self.fs.device,
+                    self.fs.block_offset(block) + in_block,
+                    chunk,
+                )?,
+                None => chunk.fill(0),
+            }
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Writes into the blocks of the file, allocating them where there are holes.
+    fn write_data(&self, raw: &mut RawInode, offset: u64, buffer: &[u8]) -> Result<(), FsError> {
+        let block_size = u64::from(self.fs.block_size());
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let in_block = position % block_size;
+            let length = ((block_size - in_block) as usize).min(buffer.len() - done);
+            let block = self.map_or_allocate(raw, position / block_size)?;
+            write_bytes(
+                &self.fs.device,
+                self.fs.block_offset(block) + in_block,
+                &buffer[done..][..length],
+            )?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Zeros the rest of the last block behind the end of the file, which becomes part of
+    /// the file when it grows.
+    fn zero_tail(&self, raw: &RawInode) -> Result<(), FsError> {
+        let block_size = u64::from(self.fs.block_size());
+        let in_block = raw.size() % block_size;
+        if in_block == 0 {
+            return Ok(());
+        }
+        if let Some(block) = self.map(raw, raw.size() / block_size)? {
+            let zeros = vec![0; (block_size - in_block) as usize];
+            write_bytes(
+                &self.fs.device,
+                self.fs.block_offset(block) + in_block,
+                &zeros,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Locks a regular file, anything else can't be read or written like one.
+    fn file(&self) -> Result<MutexGuard<'_, RawInode>, FsError> {
+        let raw = self.raw.lock();
+        match raw.mode() & MODE_TYPE {
+            MODE_FILE => Ok(raw),
+            MODE_DIRECTORY => Err(FsError::IsADirectory),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    /// Locks a directory that still exists.
+    fn directory(&self) -> Result<MutexGuard<'_, RawInode>, FsError> {
+        let raw = self.raw.lock();
+        if !raw.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        if raw.links() == 0 {
+            return Err(FsError::NotFound);
+        }
+        Ok(raw)
+    }
+
+    /// All blocks of a directory, by their number on the disk.
+    fn directory_blocks(&self, raw: &RawInode) -> Result<Vec<(u32, Vec<u8>)>, FsError> {
+        let block_size = self.fs.block_size();
+        let mut blocks = Vec::new();
+        for index in 0..raw.size().div_ceil(u64::from(block_size)) {
+            let block = self
+                .map(raw, index)?
+                .ok_or(FsError::Corrupted("hole in a directory"))?;
+            let mut data = vec![0; block_size as usize];
+            self.fs.read_block(block, &mut data)?;
+            blocks.push((block, data));
+        }
+        Ok(blocks)
+    }
+
+    /// Finds an entry of a directory, `.` and `..` included.
+    pub(super) fn find(&self, raw: &RawInode, name: &str) -> Result<Option<Entry>, FsError> {
+        for (_, data) in self.directory_blocks(raw)? {
+            let entry = dir::parse(&data, self.fs.file_types())?
+                .into_iter()
+                .find(|entry| entry.inode != 0 && entry.name == name.as_bytes());
+            if entry.is_some() {
+                return Ok(entry);
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_empty(&self, raw: &RawInode) -> Result<bool, FsError> {
+        for (_, data) in self.directory_blocks(raw)? {
+            let entries = dir::parse(&data, self.fs.file_types())?;
+            if entries
+                .iter()
+                .any(|entry| entry.inode != 0 && !entry.is_dot())
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The hash tree of an indexed directory would miss the entries we change, so it is
+    /// dropped and the directory is read as a list again, like old drivers do.
+    fn drop_index(&self, raw: &mut RawInode) {
+        raw.set_flags(raw.flags() & !FLAG_INDEX);
+    }
+
+    /// Puts an entry into a directory, which grows by a block if it doesn't fit.
+    fn add_entry(
+        &self,
+        raw: &mut RawInode,
+        name: &str,
+        inode: u32,
+        kind: u8,
+    ) -> Result<(), FsError> {
+        let file_types = self.fs.file_types();
+        let name = name.as_bytes();
+        self.drop_index(raw);
+        for (block, mut data) in self.directory_blocks(raw)? {
+            let entries = dir::parse(&data, file_types)?;
+            if let Some(room) = dir::find_room(&entries, name.len()) {
+                dir::insert(&mut data, room, inode, kind, name, file_types);
+                self.fs.write_block(block, &data)?;
+                return self.save(raw);
+            }
+        }
+        let block_size = self.fs.block_size() as usize;
+        let block = self.map_or_allocate(raw, raw.size() / block_size as u64);
+        let result = block.and_then(|block| {
+            let mut data = vec![0; block_size];
+            dir::write(&mut data, 0, block_size, inode, kind, name, file_types);
+            self.fs.write_block(block, &data)?;
+            raw.set_size(raw.size() + block_size as u64);
+            Ok(())
+        });
+        // the allocated blocks have to be in the inode even if something failed
+        self.save(raw)?;
+        result
+    }
+
+    /// Takes an entry out of a directory and returns it.
+    fn remove_entry(&self, raw: &mut RawInode, name: &str) -> Result<Entry, FsError> {
+        for (block, mut data) in self.directory_blocks(raw)? {
+            let entries = dir::parse(&data, self.fs.file_types())?;
+            let index = entries
+                .iter()
+                .position(|entry| entry.inode != 0 && entry.name == name.as_bytes());
+            if let Some(index) = index {
+                dir::remove(&mut data, &entries, index);
+                self.fs.write_block(block, &data)?;
+                self.drop_index(raw);
+                self.save(raw)?;
+                return Ok(entries[index].clone());
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /// Points an existing entry at another inode.
+    fn replace_entry(
+        &self,
+        raw: &mut RawInode,
+        name: &str,
+        inode: u32,
+        kind: u8,
+    ) -> Result<(), FsError> {
+        let file_types = self.fs.file_types();
+        for (block, mut data) in self.directory_blocks(raw)? {
+            let entries = dir::parse(&data, file_types)?;
+            let entry = entries
+                .iter()
+                .find(|entry| entry.inode != 0 && entry.name == name.as_bytes());
+            if let Some(entry) = entry {
+                let (offset, length) = (entry.offset, entry.record_length);
+                dir::write(
+                    &mut data,
+                    offset,
+                    length,
+                    inode,
+                    kind,
+                    &entry.name,
+                    file_types,
+                );
+                return self.fs.write_block(block, &data);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /// Frees the blocks and the inode once nothing links to it anymore.
+    fn release(&self, raw: &mut RawInode) -> Result<(), FsError> {
+        if raw.has_blocks(self.fs.block_size()) {
+            self.free_blocks(raw, 0)?;
+        }
+        raw.set_size(0);
+        // there's no clock for the time of deletion, and small ones would be taken for the
+        // next inode of the list of orphans
+        let superblock = &self.fs.superblock;
+        raw.set_u32(20, superblock.write_time.max(superblock.inodes_count));
+        self.save(raw)?;
+        self.fs.free_inode(self.number, raw.is_dir())
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let raw = self.raw.get_mut();
+        if raw.links() == 0 && !self.fs.read_only {
+            let mut raw = core::mem::replace(raw, RawInode::new(0));
+            // there's no one left to tell, what's left is found when the disk is checked
+            let _ = self.release(&mut raw);
+        }
+        let mut inodes = self.fs.inodes.lock();
+        if inodes
+            .get(&self.number)
+            .is_some_and(|inode| inode.strong_count() == 0)
+        {
+            inodes.remove(&self.number);
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name.contains(['/', '\0']) || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+fn entry_type(directory: bool) -> u8 {
+    match directory {
+        true => TYPE_DIRECTORY,
+        false => TYPE_FILE,
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let raw = self.raw.lock();
+        Ok(Metadata {
+            inode: u64::from(self.number),
+            // symlinks and devices look like files until the VFS knows about them
+            kind: match raw.is_dir() {
+                true => FileType::Directory,
+                false => FileType::File,
+            },
+            size: raw.size(),
+            links: u32::from(raw.links()),
+            permissions: raw.mode() & MODE_PERMISSIONS,
+            uid: raw.uid(),
+            gid: raw.gid(),
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let raw = self.file()?;
+        let length = raw.size().saturating_sub(offset).min(buffer.len() as u64) as usize;
+        self.read_data(&raw, offset, &mut buffer[..length])?;
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.fs.check_writable()?;
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .filter(|&end| end <= self.fs.max_file_size())
+            .ok_or(FsError::NoSpace)?;
+        let mut raw = self.file()?;
+        if end > raw.size() {
+            self.zero_tail(&raw)?;
+        }
+        let result = self.write_data(&mut raw, offset, buffer);
+        if result.is_ok() && end > raw.size() {
+            raw.set_size(end);
+        }
+        // new blocks are in the inode even if not all of them could be written
+        self.save(&raw)?;
+        result.map(|()| buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.fs.check_writable()?;
+        if size > self.fs.max_file_size() {
+            return Err(FsError::NoSpace);
+        }
+        let mut raw = self.file()?;
+        if size > raw.size() {
+            // the file grows with a hole
+            self.zero_tail(&raw)?;
+            raw.set_size(size);
+            return self.save(&raw);
+        }
+        let keep = size.div_ceil(u64::from(self.fs.block_size()));
+        let result = self.free_blocks(&mut raw, keep);
+        raw.set_size(size);
+        // the blocks that were freed have to be gone from the inode even if something failed
+        self.save(&raw)?;
+        result
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _directories = self.fs.directories.lock();
+        let raw = self.directory()?;
+        let entry = self.find(&raw, name)?.ok_or(FsError::NotFound)?;
+        drop(raw);
+        Ok(self.fs.inode(entry.inode)?)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        check_name(name)?;
+        self.fs.check_writable()?;
+        let fs = &self.fs;
+        let _directories = fs.directories.lock();
+        let mut raw = self.directory()?;
+        if self.find(&raw, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let directory = kind == FileType::Directory;
+        let number = fs.allocate_inode(self.group(), directory)?;
+        let mut new = RawInode::new(fs.superblock.inode_size as usize);
+        // everything belongs to root until there are users
+        let (mode, links) = match directory {
+            true => (MODE_DIRECTORY | 0o755, 2),
+            false => (MODE_FILE | 0o644, 1),
+        };
+        new.set_mode(mode);
+        new.set_links(links);
+        if new.bytes().len() > 128 {
+            new.set_u16(128, EXTRA_SIZE);
+        }
+        let inode = match fs.new_inode(number, new) {
+            Ok(inode) => inode,
+            Err(err) => {
+                fs.free_inode(number, directory)?;
+                return Err(err);
+            }
+        };
+
+        let mut result = Ok(());
+        if directory {
+            // `.` and `..` fill the first block
+            let mut new = inode.raw.lock();
+            let block_size = fs.block_size() as usize;
+            result = inode.map_or_allocate(&mut new, 0).and_then(|block| {
+                let file_types = fs.file_types();
+                let mut data = vec![0; block_size];
+                let dot = dir::record_size(1);
+                dir::write(&mut data, 0, dot, number, TYPE_DIRECTORY, b".", file_types);
+                let rest = block_size - dot;
+                dir::write(
+                    &mut data,
+                    dot,
+                    rest,
+                    self.number,
+                    TYPE_DIRECTORY,
+                    b"..",
+                    file_types,
+                );
+                fs.write_block(block, &data)?;
+                new.set_size(block_size as u64);
+                Ok(())
+            });
+            inode.save(&new)?;
+        }
+        result =
+            result.and_then(|()| self.add_entry(&mut raw, name, number, entry_type(directory)));
+        if let Err(err) = result {
+            // the inode is freed with its last reference
+            inode.raw.lock().set_links(0);
+            return Err(err);
+        }
+        if directory {
+            // the `..` of the new directory
+            raw.add_link();
+            self.save(&raw)?;
+        }
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.fs.check_writable()?;
+        let _directories = self.fs.directories.lock();
+        let mut raw = self.directory()?;
+        let entry = self.find(&raw, name)?.ok_or(FsError::NotFound)?;
+        let inode = self.fs.inode(entry.inode)?;
+        let mut child = inode.raw.lock();
+        if child.is_dir() && !inode.is_empty(&child)? {
+            return Err(FsError::NotEmpty);
+        }
+        self.remove_entry(&mut raw, name)?;
+        if child.is_dir() {
+            // `.` goes along with the entry, and `..` with it
+            child.set_links(0);
+            raw.remove_link();
+            self.save(&raw)?;
+        } else {
+            child.remove_link();
+        }
+        inode.save(&child)
+    }
+
+    fn rename(&self, name: &str, target: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let target = target
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .filter(|target| Arc::ptr_eq(&target.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)?;
+        check_name(new_name)?;
+        self.fs.check_writable()?;
+        let fs = &self.fs;
+        let _directories = fs.directories.lock();
+
+        let entry = {
+            let raw = self.directory()?;
+            self.find(&raw, name)?.ok_or(FsError::NotFound)?
+        };
+        let moved = fs.inode(entry.inode)?;
+        let is_dir = moved.raw.lock().is_dir();
+        let kind = entry_type(is_dir);
+        let existing = {
+            let raw = target.directory()?;
+            target.find(&raw, new_name)?
+        };
+        match existing {
+            // the same file, maybe under another name
+            Some(existing) if existing.inode == entry.inode => return Ok(()),
+            Some(existing) => {
+                let replaced = fs.inode(existing.inode)?;
+                let mut replaced_raw = replaced.raw.lock();
+                match (is_dir, replaced_raw.is_dir()) {
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (true, true) if !replaced.is_empty(&replaced_raw)? => {
+                        return Err(FsError::NotEmpty)
+                    }
+                    _ => {}
+                }
+                let mut raw = target.raw.lock();
+                target.replace_entry(&mut raw, new_name, entry.inode, kind)?;
+                if replaced_raw.is_dir() {
+                    // the `..` of the replaced directory is gone
+                    raw.remove_link();
+                    target.save(&raw)?;
+                    replaced_raw.set_links(0);
+                } else {
+                    replaced_raw.remove_link();
+                }
+                replaced.save(&replaced_raw)?;
+            }
+            None => {
+                let mut raw = target.directory()?;
+                target.add_entry(&mut raw, new_name, entry.inode, kind)?;
+            }
+        }
+        self.remove_entry(&mut self.raw.lock(), name)?;
+
+        // `..` has to point to the new parent, which gets the link from the old one
+        if is_dir && self.number != target.number {
+            moved.replace_entry(&mut moved.raw.lock(), "..", target.number, TYPE_DIRECTORY)?;
+            let mut raw = self.raw.lock();
+            raw.remove_link();
+            self.save(&raw)?;
+            drop(raw);
+            let mut raw = target.raw.lock();
+            raw.add_link();
+            target.save(&raw)?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let _directories = self.fs.directories.lock();
+        let raw = self.directory()?;
+        let mut entries = Vec::new();
+        for (_, data) in self.directory_blocks(&raw)? {
+            for entry in dir::parse(&data, self.fs.file_types())? {
+                if entry.inode == 0 || entry.is_dot() {
+                    continue;
+                }
+                let is_dir = match entry.kind {
+                    TYPE_DIRECTORY => true,
+                    TYPE_FILE => false,
+                    // without types in the entries only the inode knows
+                    _ => {
+                        entry.inode != self.number
+                            && self.fs.inode(entry.inode)?.raw.lock().is_dir()
+                    }
+                };
+                entries.push(DirEntry {
+                    name: String::from_utf8_lossy(&entry.name).into(),
+                    inode: u64::from(entry.inode),
+                    kind: match is_dir {
+                        true => FileType::Directory,
+                        false => FileType::File,
+                    },
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn set_permissions(&self, permissions: u16) -> Result<(), FsError> {
+        self.fs.check_writable()?;
+        let mut raw = self.raw.lock();
+        let mode = (raw.mode() & !MODE_PERMISSIONS) | (permissions & MODE_PERMISSIONS);
+        raw.set_mode(mode);
+        self.save(&raw)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.device.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn block_paths() {
+        // with 1 KiB blocks, which have 256 pointers
+        assert_eq!(block_path(11, 256), Some((11, vec![])));
+        assert_eq!(block_path(12, 256), Some((INDIRECT, vec![0])));
+        assert_eq!(block_path(12 + 255, 256), Some((INDIRECT, vec![255])));
+        assert_eq!(block_path(12 + 256, 256), Some((INDIRECT + 1, vec![0, 0])));
+        assert_eq!(
+            block_path(12 + 256 + 256 * 3 + 5, 256),
+            Some((INDIRECT + 1, vec![3, 5]))
+        );
+        let triple = 12 + 256 + 256 * 256;
+        assert_eq!(block_path(triple, 256), Some((INDIRECT + 2, vec![0, 0, 0])));
+        assert_eq!(
+            block_path(triple + 256 * 256 * 255 + 256 * 255 + 255, 256),
+            Some((INDIRECT + 2, vec![255, 255, 255]))
+        );
+        assert_eq!(block_path(triple + 256 * 256 * 256, 256), None);
+    }
+
+    #[test_case]
+    fn names() {
+        assert_eq!(check_name("a name"), Ok(()));
+        assert_eq!(check_name(".."), Err(FsError::InvalidPath));
+        assert_eq!(check_name("a/b"), Err(FsError::InvalidPath));
+        assert_eq!(check_name(&"x".repeat(256)), Err(FsError::NameTooLong));
+    }
+}
@@ -0,0 +1,1331 @@
+//! The FAT filesystem in its 12, 16 and 32 bit variants, with long file names.
+//!
+//! FAT has no inodes, a file is described by its entry in the directory it's in. The inodes that
+//! are in use are kept in a table by the position of their entry, so there is only ever one for
+//! a file, which knows its current size and clusters. Directories only change with the
+//! filesystem locked.
+
+mod dir;
+
+use crate::block::{BlockDevice, CachedDevice};
+use crate::fs::{read_bytes, write_bytes};
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ops::Range;
+use dir::{Entry, ENTRY_SIZE};
+use spin::Mutex;
+
+// the first two entries of the table don't stand for clusters
+const FIRST_CLUSTER: u32 = 2;
+// FAT32 entries only have 28 bits, the others are reserved
+const FAT32_MASK: u32 = 0x0fff_ffff;
+// the signatures of the FSInfo sector of FAT32, and where the free cluster count is
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: u64 = 488;
+const UNKNOWN: u32 = 0xffff_ffff;
+// directories can't have more entries
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+// the root directory has no entry whose position could be its number, and entries are never
+// at odd offsets
+const ROOT_INODE: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn bits(self) -> u64 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    /// What the last cluster of a chain points to.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => FAT32_MASK,
+        }
+    }
+
+    // entries from here on mark the end of a chain, the one right before a bad cluster
+    fn end_marker(self) -> u32 {
+        self.end_of_chain() - 7
+    }
+}
+
+/// Where everything is, from the boot sector. Offsets are in bytes.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    kind: FatType,
+    cluster_size: u32,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u32,
+    // the fixed root directory of FAT12 and FAT16
+    root_start: u64,
+    root_size: u32,
+    // the first cluster of the root directory on FAT32
+    root_cluster: u32,
+    data_start: u64,
+    cluster_count: u32,
+    fs_info: Option<u64>,
+}
+
+impl Layout {
+    fn parse(sector: &[u8]) -> Result<Self, FsError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+        if sector[510..512] != [0x55, 0xaa] {
+            return Err(FsError::Corrupted("no boot sector signature"));
+        }
+        let sector_size = u64::from(u16_at(11));
+        let sectors_per_cluster = u64::from(sector[13]);
+        let reserved = u64::from(u16_at(14));
+        let fat_count = u32::from(sector[16]);
+        let root_entries = u64::from(u16_at(17));
+        let sectors = match u16_at(19) {
+            0 => u64::from(u32_at(32)),
+            sectors => u64::from(sectors),
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u64::from(u32_at(36)),
+            sectors => u64::from(sectors),
+        };
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Corrupted("not a FAT boot sector"));
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+        let root_sector = reserved + u64::from(fat_count) * fat_sectors;
+        let data_sector = root_sector + root_sectors;
+        if data_sector >= sectors {
+            return Err(FsError::Corrupted("no room for data"));
+        }
+        let clusters = (sectors - data_sector) / sectors_per_cluster;
+        // like Linux, we tell FAT32 apart by its boot sector and not by the number of
+        // clusters, so small filesystems can be FAT32 too
+        let kind = if u16_at(22) == 0 {
+            FatType::Fat32
+        } else if clusters < 4085 {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        };
+        if (kind == FatType::Fat32) != (root_entries == 0) {
+            return Err(FsError::Corrupted("not a FAT boot sector"));
+        }
+        // the table may not have room for all of them
+        let entries = fat_sectors * sector_size * 8 / kind.bits();
+        let cluster_count = clusters
+            .min(entries.saturating_sub(2))
+            .min(u64::from(kind.end_marker() - 1 - FIRST_CLUSTER))
+            as u32;
+        if cluster_count == 0 {
+            return Err(FsError::Corrupted("no clusters"));
+        }
+
+        let (root_cluster, fs_info) = match kind {
+            FatType::Fat32 => {
+                let root_cluster = u32_at(44) & FAT32_MASK;
+                if !(FIRST_CLUSTER..FIRST_CLUSTER + cluster_count).contains(&root_cluster) {
+                    return Err(FsError::Corrupted("bad root directory cluster"));
+                }
+                let fs_info = match u16_at(48) {
+                    0 | 0xffff => None,
+                    sector => Some(u64::from(sector) * sector_size),
+                };
+                (root_cluster, fs_info)
+            }
+            _ => (0, None),
+        };
+        Ok(Self {
+            kind,
+            cluster_size: (sector_size * sectors_per_cluster) as u32,
+            fat_start: reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fat_count,
+            root_start: root_sector * sector_size,
+            root_size: (root_entries * ENTRY_SIZE as u64) as u32,
+            root_cluster,
+            data_start: data_sector * sector_size,
+            cluster_count,
+            fs_info,
+        })
+    }
+}
+
+/// Where the entry of a file is. The directory is given by its first cluster, which is 0 for
+/// the fixed root directory of FAT12 and FAT16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    directory: u32,
+    offset: u32,
+}
+
+impl Position {
+    // the number of the inode, which changes when the file is moved like on other systems
+    // that don't have inodes either
+    fn number(self) -> u64 {
+        u64::from(self.directory) << 32 | u64::from(self.offset)
+    }
+}
+
+struct Allocation {
+    // where to look for a free cluster first
+    next_free: u32,
+    free: Option<u32>,
+}
+
+/// A FAT filesystem on a block device.
+pub struct FatFs {
+    device: CachedDevice,
+    layout: Layout,
+    this: Weak<FatFs>,
+    // the inodes in use by the position of their entry
+    inodes: Mutex<BTreeMap<Position, Weak<FatInode>>>,
+    root: Mutex<Weak<FatInode>>,
+    allocation: Mutex<Allocation>,
+    // held while directories change
+    directories: Mutex<()>,
+    // the FSInfo sector, if it has the right signatures
+    fs_info: Option<u64>,
+}
+
+impl FatFs {
+    /// Reads the filesystem from a device, which fails if it doesn't have one.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let device = CachedDevice::new(device);
+        let mut sector = [0; 512];
+        read_bytes(&device, 0, &mut sector)?;
+        let layout = Layout::parse(&sector)?;
+        let end =
+            layout.data_start + u64::from(layout.cluster_count) * u64::from(layout.cluster_size);
+        if end > device.block_count() * device.block_size() as u64 {
+            return Err(FsError::Corrupted("filesystem is larger than the device"));
+        }
+
+        // FAT32 keeps track of the free clusters so they don't have to be counted
+        let mut allocation = Allocation {
+            next_free: FIRST_CLUSTER,
+            free: None,
+        };
+        let mut fs_info = None;
+        if let Some(offset) = layout.fs_info {
+            let mut info = [0; 512];
+            read_bytes(&device, offset, &mut info)?;
+            let u32_at =
+                |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+            if u32_at(0) == FS_INFO_LEAD_SIGNATURE && u32_at(484) == FS_INFO_SIGNATURE {
+                fs_info = Some(offset);
+                allocation.free = Some(u32_at(488)).filter(|&free| free <= layout.cluster_count);
+                let next_free = u32_at(492);
+                if (FIRST_CLUSTER..FIRST_CLUSTER + layout.cluster_count).contains(&next_free) {
+                    allocation.next_free = next_free;
+                }
+            }
+        }
+
+        Ok(Arc::new_cyclic(|this| Self {
+            device,
+            layout,
+            this: this.clone(),
+            inodes: Mutex::new(BTreeMap::new()),
+            root: Mutex::new(Weak::new()),
+            allocation: Mutex::new(allocation),
+            directories: Mutex::new(()),
+            fs_info,
+        }))
+    }
+
+    pub fn kind(&self) -> FatType {
+        self.layout.kind
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.layout.cluster_size
+    }
+
+    /// Counts the free clusters, which means going through the whole allocation table.
+    pub fn free_clusters(&self) -> Result<u32, FsError> {
+        let mut allocation = self.allocation.lock();
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + self.layout.cluster_count {
+            if self.read_fat(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        allocation.free = Some(free);
+        Ok(free)
+    }
+
+    fn root_directory(&self) -> u32 {
+        self.layout.root_cluster
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.layout.data_start
+            + u64::from(cluster - FIRST_CLUSTER) * u64::from(self.layout.cluster_size)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.layout.cluster_count).contains(&cluster)
+    }
+
+    // where the entry of a cluster is in a table
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        u64::from(cluster) * self.layout.kind.bits() / 8
+    }
+
+    fn read_fat(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.layout.fat_start + self.fat_offset(cluster);
+        Ok(match self.layout.kind {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                read_bytes(&self.device, offset, &mut bytes)?;
+                let value = u32::from(u16::from_le_bytes(bytes));
+                // two entries share three bytes
+                if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                read_bytes(&self.device, offset, &mut bytes)?;
+                u32::from(u16::from_le_bytes(bytes))
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                read_bytes(&self.device, offset, &mut bytes)?;
+                u32::from_le_bytes(bytes) & FAT32_MASK
+            }
+        })
+    }
+
+    /// Changes the entry of a cluster in every copy of the table.
+    fn write_fat(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for copy in 0..u64::from(self.layout.fat_count) {
+            let offset =
+                self.layout.fat_start + copy * self.layout.fat_size + self.fat_offset(cluster);
+            match self.layout.kind {
+                FatType::Fat12 => {
+                    let mut bytes = [0; 2];
+                    read_bytes(&self.device, offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let new = if cluster & 1 == 1 {
+                        old & 0x000f | (value as u16) << 4
+                    } else {
+                        old & 0xf000 | value as u16
+                    };
+                    write_bytes(&self.device, offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => write_bytes(&self.device, offset, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    let mut bytes = [0; 4];
+                    read_bytes(&self.device, offset, &mut bytes)?;
+                    let new = u32::from_le_bytes(bytes) & !FAT32_MASK | value;
+                    write_bytes(&self.device, offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end of the chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let next = self.read_fat(cluster)?;
+        if next >= self.layout.kind.end_marker() {
+            Ok(None)
+        } else if self.is_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(FsError::Corrupted("broken cluster chain"))
+        }
+    }
+
+    /// All clusters of the chain that starts with `first`, which is 0 for an empty one.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut current = (first != 0).then_some(first);
+        while let Some(cluster) = current {
+            // chains that loop would never end
+            if !self.is_cluster(cluster) || clusters.len() == self.layout.cluster_count as usize {
+                return Err(FsError::Corrupted("broken cluster chain"));
+            }
+            clusters.push(cluster);
+            current = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Finds a free cluster, fills it with zeros and appends it to the chain that ends with
+    /// `last`.
+    fn allocate(&self, last: Option<u32>) -> Result<u32, FsError> {
+        let mut allocation = self.allocation.lock();
+        let count = self.layout.cluster_count;
+        for i in 0..count {
+            let cluster = FIRST_CLUSTER + (allocation.next_free - FIRST_CLUSTER + i) % count;
+            if self.read_fat(cluster)? != 0 {
+                continue;
+            }
+            // directories end at the zeros, and files that grow have to read as zeros
+            let zeros = vec![0; self.layout.cluster_size as usize];
+            write_bytes(&self.device, self.cluster_offset(cluster), &zeros)?;
+            self.write_fat(cluster, self.layout.kind.end_of_chain())?;
+            if let Some(last) = last {
+                self.write_fat(last, cluster)?;
+            }
+            allocation.next_free = FIRST_CLUSTER + (cluster + 1 - FIRST_CLUSTER) % count;
+            allocation.free = allocation.free.map(|free| free.saturating_sub(1));
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Marks the clusters of a chain as free.
+    fn free_chain(&self, first: u32) -> Result<(), FsError> {
+        let clusters = self.chain(first)?;
+        let mut allocation = self.allocation.lock();
+        for &cluster in &clusters {
+            self.write_fat(cluster, 0)?;
+        }
+        allocation.free = allocation.free.map(|free| free + clusters.len() as u32);
+        Ok(())
+    }
+
+    /// Splits a range of the clusters of a file into pieces that are contiguous on the disk,
+    /// as their offset on the disk and where they are in the range.
+    fn pieces(
+        &self,
+        clusters: &[u32],
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<(u64, Range<usize>)>, FsError> {
+        let cluster_size = u64::from(self.layout.cluster_size);
+        let mut pieces: Vec<(u64, Range<usize>)> = Vec::new();
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Corrupted("file is larger than its clusters"))?;
+            let in_cluster = position % cluster_size;
+            let chunk = ((cluster_size - in_cluster) as usize).min(length - done);
+            let disk_offset = self.cluster_offset(cluster) + in_cluster;
+            match pieces.last_mut() {
+                Some((start, range)) if *start + range.len() as u64 == disk_offset => {
+                    range.end += chunk;
+                }
+                _ => pieces.push((disk_offset, done..done + chunk)),
+            }
+            done += chunk;
+        }
+        Ok(pieces)
+    }
+
+    /// Reads all entries of a directory.
+    fn directory_data(&self, directory: u32) -> Result<Vec<u8>, FsError> {
+        if directory == 0 {
+            let mut data = vec![0; self.layout.root_size as usize];
+            read_bytes(&self.device, self.layout.root_start, &mut data)?;
+            return Ok(data);
+        }
+        let clusters = self.chain(directory)?;
+        let mut data = vec![0; clusters.len() * self.layout.cluster_size as usize];
+        for (offset, range) in self.pieces(&clusters, 0, data.len())? {
+            read_bytes(&self.device, offset, &mut data[range])?;
+        }
+        Ok(data)
+    }
+
+    /// Where an entry of a directory is on the disk.
+    fn entry_offset(&self, directory: u32, offset: u32) -> Result<u64, FsError> {
+        if directory == 0 {
+            return Ok(self.layout.root_start + u64::from(offset));
+        }
+        let mut cluster = directory;
+        for _ in 0..offset / self.layout.cluster_size {
+            cluster = self
+                .next_cluster(cluster)?
+                .ok_or(FsError::Corrupted("entry behind the end of its directory"))?;
+        }
+        Ok(self.cluster_offset(cluster) + u64::from(offset % self.layout.cluster_size))
+    }
+
+    fn read_entry(&self, directory: u32, offset: u32) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let mut raw = [0; ENTRY_SIZE];
+        read_bytes(
+            &self.device,
+            self.entry_offset(directory, offset)?,
+            &mut raw,
+        )?;
+        Ok(raw)
+    }
+
+    fn write_entry(
+        &self,
+        directory: u32,
+        offset: u32,
+        raw: &[u8; ENTRY_SIZE],
+    ) -> Result<(), FsError> {
+        write_bytes(&self.device, self.entry_offset(directory, offset)?, raw)?;
+        Ok(())
+    }
+
+    /// Puts entries into a directory, which grows if they don't fit. Returns the offset of
+    /// the first one.
+    fn add_entries(&self, directory: u32, entries: &[[u8; ENTRY_SIZE]]) -> Result<u32, FsError> {
+        loop {
+            let data = self.directory_data(directory)?;
+            if let Some(offset) = dir::find_free(&data, entries.len()) {
+                for (i, raw) in entries.iter().enumerate() {
+                    self.write_entry(directory, offset + (i * ENTRY_SIZE) as u32, raw)?;
+                }
+                return Ok(offset);
+            }
+            // the fixed root directory can't grow
+            if directory == 0 || data.len() >= MAX_DIRECTORY_ENTRIES * ENTRY_SIZE {
+                return Err(FsError::NoSpace);
+            }
+            let last = *self.chain(directory)?.last().unwrap();
+            self.allocate(Some(last))?;
+        }
+    }
+
+    /// Marks the entries of a file as deleted.
+    fn delete_entries(&self, directory: u32, entry: &Entry) -> Result<(), FsError> {
+        for offset in entry.offsets() {
+            let mut raw = self.read_entry(directory, offset)?;
+            raw[0] = dir::DELETED;
+            self.write_entry(directory, offset, &raw)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the entry of a file or empty directory. Its clusters are freed once its inode
+    /// is no longer used.
+    fn remove_entry(&self, directory: u32, entry: &Entry) -> Result<(), FsError> {
+        let first_cluster = self.first_cluster(entry)?;
+        if entry.is_dir() && !dir::parse(&self.directory_data(first_cluster)?).is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        self.delete_entries(directory, entry)?;
+        let position = Position {
+            directory,
+            offset: entry.offset,
+        };
+        let inode = self
+            .inodes
+            .lock()
+            .remove(&position)
+            .and_then(|inode| inode.upgrade());
+        match inode {
+            Some(inode) => inode.state.lock().unlinked = true,
+            None if first_cluster != 0 => self.free_chain(first_cluster)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// The first cluster of an entry, with the upper half ignored where it's reserved.
+    fn first_cluster(&self, entry: &Entry) -> Result<u32, FsError> {
+        let cluster = match self.layout.kind {
+            FatType::Fat32 => entry.first_cluster & FAT32_MASK,
+            _ => entry.first_cluster & 0xffff,
+        };
+        // 0 would be the fixed root directory
+        if entry.is_dir() && cluster == 0 {
+            return Err(FsError::Corrupted("directory without clusters"));
+        }
+        Ok(cluster)
+    }
+
+    /// Returns the inode of an entry, which is the one that is already in use if there is one.
+    fn inode(&self, directory: u32, entry: &Entry) -> Result<Arc<FatInode>, FsError> {
+        let position = Position {
+            directory,
+            offset: entry.offset,
+        };
+        let first_cluster = self.first_cluster(entry)?;
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&position).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let (kind, size) = match entry.is_dir() {
+            true => (FileType::Directory, 0),
+            false => (FileType::File, entry.size),
+        };
+        let inode = Arc::new(FatInode {
+            fs: self.this.upgrade().unwrap(),
+            kind,
+            state: Mutex::new(InodeState {
+                position: Some(position),
+                first_cluster,
+                size,
+                clusters: None,
+                unlinked: false,
+            }),
+        });
+        inodes.insert(position, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut root = self.root.lock();
+        if let Some(inode) = root.upgrade() {
+            return inode;
+        }
+        let inode = Arc::new(FatInode {
+            fs: self.this.upgrade().unwrap(),
+            kind: FileType::Directory,
+            state: Mutex::new(InodeState {
+                position: None,
+                first_cluster: self.root_directory(),
+                size: 0,
+                clusters: None,
+                unlinked: false,
+            }),
+        });
+        *root = Arc::downgrade(&inode);
+        inode
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        if let Some(offset) = self.fs_info {
+            let allocation = self.allocation.lock();
+            let mut info = [0; 8];
+            info[..4].copy_from_slice(&allocation.free.unwrap_or(UNKNOWN).to_le_bytes());
+            info[4..].copy_from_slice(&allocation.next_free.to_le_bytes());
+            write_bytes(&self.device, offset + FS_INFO_FREE_COUNT, &info)?;
+        }
+        self.device.flush()?;
+        Ok(())
+    }
+}
+
+struct InodeState {
+    // where the entry is, `None` for the root directory
+    position: Option<Position>,
+    first_cluster: u32,
+    size: u32,
+    // the clusters of a file, which are read when they are needed first
+    clusters: Option<Vec<u32>>,
+    // the entry is gone, and so are the clusters once the inode isn't used anymore
+    unlinked: bool,
+}
+
+impl InodeState {
+    fn number(&self) -> u64 {
+        self.position.map_or(ROOT_INODE, Position::number)
+    }
+
+    fn load_clusters(&mut self, fs: &FatFs) -> Result<(), FsError> {
+        if self.clusters.is_none() {
+            self.clusters = Some(fs.chain(self.first_cluster)?);
+        }
+        Ok(())
+    }
+}
+
+struct FatInode {
+    fs: Arc<FatFs>,
+    kind: FileType,
+    state: Mutex<InodeState>,
+}
+
+impl FatInode {
+    /// The first cluster of the directory, for finding its entries.
+    fn directory(&self) -> Result<u32, FsError> {
+        if self.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let state = self.state.lock();
+        if state.unlinked {
+            return Err(FsError::NotFound);
+        }
+        Ok(state.first_cluster)
+    }
+
+    fn is_root(&self) -> bool {
+        self.state.lock().position.is_none()
+    }
+
+    fn find(&self, directory: u32, name: &str) -> Result<Entry, FsError> {
+        dir::parse(&self.fs.directory_data(directory)?)
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Writes the size and first cluster back into the entry.
+    fn update_entry(&self, state: &InodeState) -> Result<(), FsError> {
+        let Some(position) = state.position.filter(|_| !state.unlinked) else {
+            return Ok(());
+        };
+        let mut raw = self.fs.read_entry(position.directory, position.offset)?;
+        dir::set_first_cluster(&mut raw, state.first_cluster);
+        dir::set_size(&mut raw, state.size);
+        self.fs
+            .write_entry(position.directory, position.offset, &raw)
+    }
+
+    /// Zeros the rest of the last cluster behind the end of the file, which becomes part of
+    /// the file when it grows.
+    fn zero_tail(&self, state: &InodeState) -> Result<(), FsError> {
+        let cluster_size = self.fs.layout.cluster_size;
+        let in_cluster = state.size % cluster_size;
+        if in_cluster == 0 {
+            return Ok(());
+        }
+        let clusters = state.clusters.as_ref().unwrap();
+        let cluster = *clusters
+            .get((state.size / cluster_size) as usize)
+            .ok_or(FsError::Corrupted("file is larger than its clusters"))?;
+        let zeros = vec![0; (cluster_size - in_cluster) as usize];
+        let offset = self.fs.cluster_offset(cluster) + u64::from(in_cluster);
+        write_bytes(&self.fs.device, offset, &zeros)?;
+        Ok(())
+    }
+
+    /// Adds clusters to the file until it has `count` of them.
+    fn grow(&self, state: &mut InodeState, count: usize) -> Result<(), FsError> {
+        loop {
+            let clusters = state.clusters.as_mut().unwrap();
+            if clusters.len() >= count {
+                return Ok(());
+            }
+            let cluster = self.fs.allocate(clusters.last().copied())?;
+            clusters.push(cluster);
+            if state.first_cluster == 0 {
+                // the entry has to know about the cluster even if there's no room for the rest
+                state.first_cluster = cluster;
+                self.update_entry(state)?;
+            }
+        }
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if state.unlinked {
+            if state.first_cluster != 0 {
+                // there's no one left to tell, the clusters stay lost until the disk is checked
+                let _ = self.fs.free_chain(state.first_cluster);
+            }
+        } else if let Some(position) = state.position {
+            let mut inodes = self.fs.inodes.lock();
+            if inodes
+                .get(&position)
+                .is_some_and(|inode| inode.strong_count() == 0)
+            {
+                inodes.remove(&position);
+            }
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let state = self.state.lock();
+        Ok(Metadata {
+            inode: state.number(),
+            kind: self.kind,
+            size: u64::from(state.size),
+            links: 1,
+            // FAT doesn't know about permissions or owners
+            permissions: match self.kind {
+                FileType::File => 0o644,
+                FileType::Directory => 0o755,
+            },
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if self.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let mut state = self.state.lock();
+        let length = u64::from(state.size)
+            .saturating_sub(offset)
+            .min(buffer.len() as u64) as usize;
+        state.load_clusters(&self.fs)?;
+        let clusters = state.clusters.as_ref().unwrap();
+        for (disk_offset, range) in self.fs.pieces(clusters, offset, length)? {
+            read_bytes(&self.fs.device, disk_offset, &mut buffer[range])?;
+        }
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        if self.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        // sizes only have 32 bits
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .filter(|&end| end <= u64::from(u32::MAX))
+            .ok_or(FsError::NoSpace)?;
+        let mut state = self.state.lock();
+        state.load_clusters(&self.fs)?;
+        if end > u64::from(state.size) {
+            self.zero_tail(&state)?;
+            let cluster_size = u64::from(self.fs.layout.cluster_size);
+            self.grow(&mut state, end.div_ceil(cluster_size) as usize)?;
+        }
+        let clusters = state.clusters.as_ref().unwrap();
+        for (disk_offset, range) in self.fs.pieces(clusters, offset, buffer.len())? {
+            write_bytes(&self.fs.device, disk_offset, &buffer[range])?;
+        }
+        if end > u64::from(state.size) {
+            state.size = end as u32;
+            self.update_entry(&state)?;
+        }
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if self.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if size > u64::from(u32::MAX) {
+            return Err(FsError::NoSpace);
+        }
+        let mut state = self.state.lock();
+        state.load_clusters(&self.fs)?;
+        let keep = size.div_ceil(u64::from(self.fs.layout.cluster_size)) as usize;
+        if size > u64::from(state.size) {
+            // FAT has no holes, the clusters are allocated right away
+            self.zero_tail(&state)?;
+            self.grow(&mut state, keep)?;
+        } else {
+            let clusters = state.clusters.as_mut().unwrap();
+            if keep < clusters.len() {
+                let freed = clusters.split_off(keep);
+                match clusters.last() {
+                    Some(&last) => self
+                        .fs
+                        .write_fat(last, self.fs.layout.kind.end_of_chain())?,
+                    None => state.first_cluster = 0,
+                }
+                self.fs.free_chain(freed[0])?;
+            }
+        }
+        state.size = size as u32;
+        self.update_entry(&state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let directory = self.directory()?;
+        let _directories = self.fs.directories.lock();
+        let entry = self.find(directory, name)?;
+        Ok(self.fs.inode(directory, &entry)?)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        dir::check_name(name)?;
+        let directory = self.directory()?;
+        let fs = &self.fs;
+        let _directories = fs.directories.lock();
+        let entries = dir::parse(&fs.directory_data(directory)?);
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (short_name, long_name) = dir::short_name(name, |short_name| {
+            entries.iter().any(|entry| &entry.short_name == short_name)
+        })
+        .ok_or(FsError::NoSpace)?;
+
+        let (attributes, first_cluster) = match kind {
+            FileType::File => (dir::ATTRIBUTE_ARCHIVE, 0),
+            FileType::Directory => {
+                let cluster = fs.allocate(None)?;
+                // `..` is 0 in the directories of the root, even if the root has clusters
+                let parent = if self.is_root() { 0 } else { directory };
+                let dots = dir::dot_entries(cluster, parent);
+                write_bytes(&fs.device, fs.cluster_offset(cluster), &dots)?;
+                (dir::ATTRIBUTE_DIRECTORY, cluster)
+            }
+        };
+        let raw = dir::encode(name, &short_name, long_name, attributes, first_cluster, 0);
+        let start = match fs.add_entries(directory, &raw) {
+            Ok(start) => start,
+            Err(err) => {
+                if first_cluster != 0 {
+                    fs.free_chain(first_cluster)?;
+                }
+                return Err(err);
+            }
+        };
+        let entry = Entry {
+            name: name.into(),
+            short_name,
+            attributes,
+            first_cluster,
+            size: 0,
+            offset: start + ((raw.len() - 1) * ENTRY_SIZE) as u32,
+            start,
+        };
+        Ok(fs.inode(directory, &entry)?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let directory = self.directory()?;
+        let _directories = self.fs.directories.lock();
+        let entry = self.find(directory, name)?;
+        self.fs.remove_entry(directory, &entry)
+    }
+
+    fn rename(&self, name: &str, target: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let target = target
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .filter(|target| Arc::ptr_eq(&target.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)?;
+        dir::check_name(new_name)?;
+        let source = self.directory()?;
+        let destination = target.directory()?;
+        let target_is_root = target.is_root();
+        let fs = &self.fs;
+        let _directories = fs.directories.lock();
+
+        let entry = self.find(source, name)?;
+        let existing = dir::parse(&fs.directory_data(destination)?)
+            .into_iter()
+            .find(|existing| existing.matches(new_name));
+        match existing {
+            // the same entry, with the case of the name changed or not
+            Some(existing) if source == destination && existing.offset == entry.offset => {
+                if existing.name == new_name {
+                    return Ok(());
+                }
+            }
+            Some(existing) => {
+                match (entry.is_dir(), existing.is_dir()) {
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, false) => return Err(FsError::NotADirectory),
+                    _ => {}
+                }
+                fs.remove_entry(destination, &existing)?;
+            }
+            None => {}
+        }
+
+        // the inode of the entry moves along with it, if it's in use
+        let position = Position {
+            directory: source,
+            offset: entry.offset,
+        };
+        let inode = fs.inodes.lock().get(&position).and_then(Weak::upgrade);
+        let mut state = inode.as_ref().map(|inode| inode.state.lock());
+        let (first_cluster, size) = match &state {
+            Some(state) => (state.first_cluster, state.size),
+            None => (fs.first_cluster(&entry)?, entry.size),
+        };
+
+        let old_entries = entry
+            .offsets()
+            .map(|offset| fs.read_entry(source, offset))
+            .collect::<Result<Vec<_>, _>>()?;
+        fs.delete_entries(source, &entry)?;
+        let entries = dir::parse(&fs.directory_data(destination)?);
+        let short_name = dir::short_name(new_name, |short_name| {
+            entries.iter().any(|entry| &entry.short_name == short_name)
+        });
+        let added = short_name
+            .ok_or(FsError::NoSpace)
+            .and_then(|(short_name, long_name)| {
+                let raw = dir::encode(
+                    new_name,
+                    &short_name,
+                    long_name,
+                    entry.attributes,
+                    first_cluster,
+                    size,
+                );
+                let start = fs.add_entries(destination, &raw)?;
+                Ok(start + ((raw.len() - 1) * ENTRY_SIZE) as u32)
+            });
+        let offset = match added {
+            Ok(offset) => offset,
+            Err(err) => {
+                // put the old entries back so the file isn't lost
+                for (offset, raw) in entry.offsets().zip(&old_entries) {
+                    fs.write_entry(source, offset, raw)?;
+                }
+                return Err(err);
+            }
+        };
+
+        // `..` has to point to the new parent
+        if entry.is_dir() && source != destination {
+            let parent = if target_is_root { 0 } else { destination };
+            let mut dot_dot = fs.read_entry(first_cluster, ENTRY_SIZE as u32)?;
+            dir::set_first_cluster(&mut dot_dot, parent);
+            fs.write_entry(first_cluster, ENTRY_SIZE as u32, &dot_dot)?;
+        }
+
+        let new_position = Position {
+            directory: destination,
+            offset,
+        };
+        if let (Some(inode), Some(state)) = (&inode, &mut state) {
+            state.position = Some(new_position);
+            let mut inodes = fs.inodes.lock();
+            inodes.remove(&position);
+            inodes.insert(new_position, Arc::downgrade(inode));
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let directory = self.directory()?;
+        let _directories = self.fs.directories.lock();
+        let data = self.fs.directory_data(directory)?;
+        Ok(dir::parse(&data)
+            .into_iter()
+            .map(|entry| DirEntry {
+                inode: Position {
+                    directory,
+                    offset: entry.offset,
+                }
+                .number(),
+                kind: match entry.is_dir() {
+                    true => FileType::Directory,
+                    false => FileType::File,
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.device.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block;
+    use crate::vfs;
+    use alloc::string::String;
+
+    // the images from `tests/images/build.py` are attached after the scratch disk
+    const DISKS: [&str; 3] = ["sata1", "sata2", "sata3"];
+
+    fn mount(disk: &str) -> Arc<FatFs> {
+        FatFs::new(block::get(disk).expect("FAT test image not attached")).unwrap()
+    }
+
+    fn filesystems() -> [Arc<FatFs>; 3] {
+        DISKS.map(mount)
+    }
+
+    fn lookup(fs: &FatFs, path: &str) -> Result<Arc<dyn Inode>, FsError> {
+        path.split('/')
+            .try_fold(fs.root(), |inode, name| inode.lookup(name))
+    }
+
+    fn read_all(inode: &Arc<dyn Inode>) -> Vec<u8> {
+        let mut data = vec![0; inode.metadata().unwrap().size as usize];
+        assert_eq!(inode.read_at(0, &mut data).unwrap(), data.len());
+        data
+    }
+
+    fn names(inode: &Arc<dyn Inode>) -> Vec<String> {
+        inode
+            .readdir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    #[test_case]
+    fn kinds() {
+        let kinds = filesystems().map(|fs| fs.kind());
+        assert_eq!(kinds, [FatType::Fat12, FatType::Fat16, FatType::Fat32]);
+        // not every disk has a filesystem
+        let disk = block::get("ata1").unwrap();
+        assert!(matches!(FatFs::new(disk), Err(FsError::Corrupted(_))));
+    }
+
+    #[test_case]
+    fn reads_host_files() {
+        for fs in filesystems() {
+            // neither the volume label nor the deleted file show up
+            assert_eq!(
+                names(&fs.root()),
+                ["HELLO.TXT", "A long file name.txt", "docs"]
+            );
+            let hello = lookup(&fs, "hello.txt").unwrap();
+            assert_eq!(read_all(&hello), b"Hello from the host!\n");
+
+            let long = lookup(&fs, "A long file name.txt").unwrap();
+            let expected: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+            assert_eq!(read_all(&long), expected);
+            let mut buffer = [0; 4];
+            assert_eq!(long.read_at(2998, &mut buffer).unwrap(), 2);
+            // the short name is another name for the same file
+            let alias = lookup(&fs, "ALONGF~1.TXT").unwrap();
+            assert_eq!(
+                alias.metadata().unwrap().inode,
+                long.metadata().unwrap().inode
+            );
+
+            assert_eq!(
+                read_all(&lookup(&fs, "DOCS/readme.md").unwrap()),
+                b"# docs\n"
+            );
+            let empty = lookup(&fs, "docs/empty").unwrap();
+            assert!(empty.metadata().unwrap().is_dir());
+            assert_eq!(names(&empty), [] as [String; 0]);
+            assert_eq!(lookup(&fs, "docs/missing").err(), Some(FsError::NotFound));
+            assert_eq!(hello.lookup("x").err(), Some(FsError::NotADirectory));
+        }
+    }
+
+    #[test_case]
+    fn writes_files() {
+        for disk in DISKS {
+            let fs = mount(disk);
+            let free = fs.free_clusters().unwrap();
+            let file = fs
+                .root()
+                .create("Written file.bin", FileType::File)
+                .unwrap();
+            let data: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+            // the gap in front of the data reads as zeros
+            file.write_at(1000, &data).unwrap();
+            let contents = read_all(&file);
+            assert_eq!(contents.len(), 4000);
+            assert!(contents[..1000].iter().all(|&byte| byte == 0));
+            assert_eq!(contents[1000..], data);
+            assert_eq!(file.write_at(u64::MAX, b"x"), Err(FsError::NoSpace));
+            let clusters = 4000u32.div_ceil(fs.cluster_size());
+            assert_eq!(fs.free_clusters().unwrap(), free - clusters);
+
+            // what is cut off doesn't come back when the file grows again
+            file.truncate(100).unwrap();
+            assert_eq!(fs.free_clusters().unwrap(), free - 1);
+            file.truncate(600).unwrap();
+            let contents = read_all(&file);
+            assert!(contents[100..].iter().all(|&byte| byte == 0));
+
+            // the file is still there for a new instance of the filesystem
+            fs.sync().unwrap();
+            let again = mount(disk);
+            let reread = again.root().lookup("WRITTE~1.BIN").unwrap();
+            assert_eq!(read_all(&reread), contents);
+            drop(reread);
+
+            // the short names don't collide with those of the host
+            let second = fs
+                .root()
+                .create("A long file name 2.txt", FileType::File)
+                .unwrap();
+            let entries = dir::parse(&fs.directory_data(fs.root_directory()).unwrap());
+            let entry = entries
+                .iter()
+                .find(|entry| entry.name == "A long file name 2.txt");
+            assert_eq!(&entry.unwrap().short_name, b"ALONGF~2TXT");
+            assert_eq!(
+                fs.root()
+                    .create("a LONG file name 2.TXT", FileType::File)
+                    .err(),
+                Some(FsError::AlreadyExists)
+            );
+            drop(second);
+
+            fs.root().unlink("Written file.bin").unwrap();
+            fs.root().unlink("a long file name 2.txt").unwrap();
+            assert_eq!(
+                fs.root().lookup("Written file.bin").err(),
+                Some(FsError::NotFound)
+            );
+            drop(file);
+            assert_eq!(fs.free_clusters().unwrap(), free);
+        }
+    }
+
+    #[test_case]
+    fn unlinked_files_stay_readable() {
+        let [_, fs, _] = filesystems();
+        let free = fs.free_clusters().unwrap();
+        let file = fs.root().create("doomed", FileType::File).unwrap();
+        file.write_at(0, b"still here").unwrap();
+        fs.root().unlink("doomed").unwrap();
+        assert_eq!(read_all(&file), b"still here");
+        // the clusters are freed with the last reference
+        assert_eq!(fs.free_clusters().unwrap(), free - 1);
+        drop(file);
+        assert_eq!(fs.free_clusters().unwrap(), free);
+    }
+
+    #[test_case]
+    fn directories() {
+        for fs in filesystems() {
+            let free = fs.free_clusters().unwrap();
+            let root = fs.root();
+            let directory = root.create("new directory", FileType::Directory).unwrap();
+            let sub = directory.create("sub", FileType::Directory).unwrap();
+            // enough long names that the directory needs more than one cluster
+            for i in 0..20 {
+                let name = alloc::format!("file number {i}.txt");
+                directory.create(&name, FileType::File).unwrap();
+            }
+            assert_eq!(directory.readdir().unwrap().len(), 21);
+            let cluster = directory
+                .as_any()
+                .downcast_ref::<FatInode>()
+                .unwrap()
+                .directory()
+                .unwrap();
+            assert!(fs.chain(cluster).unwrap().len() > 1);
+            assert_eq!(root.unlink("new directory"), Err(FsError::NotEmpty));
+
+            // `.` and `..`, which is 0 for the root directory
+            let sub_cluster = sub
+                .as_any()
+                .downcast_ref::<FatInode>()
+                .unwrap()
+                .directory()
+                .unwrap();
+            let dots = fs.directory_data(sub_cluster).unwrap();
+            assert_eq!(
+                dots[..ENTRY_SIZE],
+                dir::dot_entries(sub_cluster, cluster)[..ENTRY_SIZE]
+            );
+            assert_eq!(
+                dots[ENTRY_SIZE..2 * ENTRY_SIZE],
+                dir::dot_entries(sub_cluster, cluster)[ENTRY_SIZE..]
+            );
+            let dots = fs.directory_data(cluster).unwrap();
+            assert_eq!(
+                dots[ENTRY_SIZE..2 * ENTRY_SIZE],
+                dir::dot_entries(cluster, 0)[ENTRY_SIZE..]
+            );
+
+            for i in 0..20 {
+                directory
+                    .unlink(&alloc::format!("FILE NUMBER {i}.TXT"))
+                    .unwrap();
+            }
+            directory.unlink("sub").unwrap();
+            root.unlink("new directory").unwrap();
+            drop((directory, sub));
+            assert_eq!(fs.free_clusters().unwrap(), free);
+        }
+    }
+
+    #[test_case]
+    fn renames() {
+        for fs in filesystems() {
+            let root = fs.root();
+            let docs = root.lookup("docs").unwrap();
+            let file = root.create("a.txt", FileType::File).unwrap();
+            file.write_at(0, b"moved").unwrap();
+
+            root.rename("a.txt", &docs, "Renamed File.txt").unwrap();
+            assert_eq!(root.lookup("a.txt").err(), Some(FsError::NotFound));
+            // the open inode follows its entry
+            file.write_at(5, b" around").unwrap();
+            let renamed = docs.lookup("renamed file.txt").unwrap();
+            assert_eq!(read_all(&renamed), b"moved around");
+            drop(renamed);
+
+            // only the case of the name changes
+            docs.rename("renamed file.txt", &docs, "RENAMED FILE.TXT")
+                .unwrap();
+            assert!(names(&docs).iter().any(|name| name == "RENAMED FILE.TXT"));
+
+            // replacing files, but not directories with files
+            docs.create("other", FileType::File).unwrap();
+            docs.rename("other", &docs, "RENAMED FILE.TXT").unwrap();
+            assert_eq!(
+                docs.lookup("RENAMED FILE.TXT")
+                    .unwrap()
+                    .metadata()
+                    .unwrap()
+                    .size,
+                0
+            );
+            assert_eq!(
+                docs.rename("RENAMED FILE.TXT", &docs, "empty"),
+                Err(FsError::IsADirectory)
+            );
+            assert_eq!(
+                docs.rename("empty", &docs, "RENAMED FILE.TXT"),
+                Err(FsError::NotADirectory)
+            );
+            docs.unlink("RENAMED FILE.TXT").unwrap();
+            drop(file);
+
+            // directories take their `..` along
+            docs.rename("empty", &root, "moved").unwrap();
+            let moved = root.lookup("moved").unwrap();
+            let cluster = moved
+                .as_any()
+                .downcast_ref::<FatInode>()
+                .unwrap()
+                .directory()
+                .unwrap();
+            let dot_dot = fs.read_entry(cluster, ENTRY_SIZE as u32).unwrap();
+            assert_eq!(dot_dot, dir::dot_entries(cluster, 0)[ENTRY_SIZE..]);
+            root.rename("moved", &docs, "empty").unwrap();
+            assert_eq!(names(&docs), ["readme.md", "empty"]);
+        }
+    }
+
+    #[test_case]
+    fn through_the_vfs() {
+        let [_, _, fs] = filesystems();
+        vfs::mkdir("/fat").unwrap();
+        vfs::mount("/fat", fs).unwrap();
+        assert_eq!(vfs::read("/fat/docs/readme.md").unwrap(), b"# docs\n");
+        vfs::write("/fat/docs/from the kernel.txt", b"hello host").unwrap();
+        vfs::rename("/fat/docs/from the kernel.txt", "/fat/kernel.txt").unwrap();
+        assert_eq!(vfs::read("/fat/KERNEL.TXT").unwrap(), b"hello host");
+        // files can't move between filesystems
+        assert_eq!(
+            vfs::rename("/fat/kernel.txt", "/kernel.txt"),
+            Err(FsError::CrossDevice)
+        );
+        vfs::remove("/fat/kernel.txt").unwrap();
+        vfs::unmount("/fat").unwrap();
+        vfs::remove("/fat").unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+//! Directory entries: the 8.3 short entries and the long name entries in front of them.
+
+use crate::vfs::{FsError, MAX_NAME_LENGTH};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub(super) const ENTRY_SIZE: usize = 32;
+
+pub(super) const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub(super) const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+// read only, hidden, system and volume id at once, which old systems skip
+const ATTRIBUTE_LONG_NAME: u8 = 0x0f;
+
+/// The first byte of a deleted entry.
+pub(super) const DELETED: u8 = 0xe5;
+// the first byte of the entry after the last one
+const END: u8 = 0;
+// a short name starting with 0xe5 starts with this instead
+const ESCAPED_DELETED: u8 = 0x05;
+
+// Windows marks short names that are all lower case in the reserved byte instead of giving
+// them a long name
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+// a long name entry holds 13 UTF-16 units, spread over three fields
+const LONG_NAME_UNITS: usize = 13;
+const UNIT_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LAST_LONG_ENTRY: u8 = 0x40;
+const ORDINAL_MASK: u8 = 0x1f;
+
+// the 1st of January 1980, the earliest date there is, as we don't know the time
+const DEFAULT_DATE: u16 = 0x21;
+
+// the characters short names can have besides letters and digits
+const SHORT_NAME_SPECIAL: &str = "$%'-_@~`!(){}^#&";
+// and those long names can't have besides control characters
+const LONG_NAME_INVALID: &str = "\"*/:<>?\\|";
+
+/// A file or directory as its directory describes it.
+#[derive(Debug, Clone)]
+pub(super) struct Entry {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Where the short entry is in the directory.
+    pub offset: u32,
+    /// Where the first long name entry is, or the short entry if there are none.
+    pub start: u32,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    /// Whether `name` is the long or the short name of the entry, ignoring case like FAT does.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || format_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+
+    /// The offsets of all the entries that make up this one.
+    pub fn offsets(&self) -> impl Iterator<Item = u32> {
+        (self.start..=self.offset).step_by(ENTRY_SIZE)
+    }
+}
+
+// the parts of a long name collected so far
+struct LongName {
+    units: Vec<u16>,
+    // the ordinal of the entry that has to come next, the parts go backwards down to 1
+    next: u8,
+    checksum: u8,
+    start: u32,
+}
+
+impl LongName {
+    fn name(&self) -> String {
+        let end = self.units.iter().position(|&unit| unit == 0);
+        let units = &self.units[..end.unwrap_or(self.units.len())];
+        char::decode_utf16(units.iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
+
+// adds a long name entry to the ones before it, or starts over if it doesn't fit to them
+fn long_name_part(current: Option<LongName>, raw: &[u8], offset: u32) -> Option<LongName> {
+    let ordinal = raw[0] & ORDINAL_MASK;
+    let mut long_name = if raw[0] & LAST_LONG_ENTRY != 0 {
+        LongName {
+            units: vec![0; usize::from(ordinal) * LONG_NAME_UNITS],
+            next: ordinal,
+            checksum: raw[13],
+            start: offset,
+        }
+    } else {
+        current.filter(|long_name| long_name.checksum == raw[13])?
+    };
+    if ordinal == 0 || ordinal != long_name.next {
+        return None;
+    }
+    let first = (usize::from(ordinal) - 1) * LONG_NAME_UNITS;
+    for (i, &position) in UNIT_OFFSETS.iter().enumerate() {
+        long_name.units[first + i] = u16::from_le_bytes([raw[position], raw[position + 1]]);
+    }
+    long_name.next -= 1;
+    Some(long_name)
+}
+
+/// The checksum of a short name, which long name entries use to tell that they belong to it.
+pub(super) fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Turns `NAME    EXT` into `NAME.EXT`.
+fn format_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .map(|&byte| {
+                // short names are in an OEM code page, we treat them as Latin-1
+                let c = char::from(byte);
+                if lower {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                }
+            })
+            .collect::<String>()
+            .trim_end()
+            .into()
+    };
+    let mut base = short_name[..8].to_vec();
+    if base[0] == ESCAPED_DELETED {
+        base[0] = DELETED;
+    }
+    let base = part(&base, case & LOWER_CASE_BASE != 0);
+    let extension = part(&short_name[8..], case & LOWER_CASE_EXTENSION != 0);
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{base}.{extension}")
+    }
+}
+
+/// Reads the entries of a directory, except for `.`, `..` and the volume label.
+pub(super) fn parse(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long_name = None;
+    for (index, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = (index * ENTRY_SIZE) as u32;
+        match raw[0] {
+            END => break,
+            DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = raw[11];
+        if attributes & 0x3f == ATTRIBUTE_LONG_NAME {
+            long_name = long_name_part(long_name.take(), raw, offset);
+            continue;
+        }
+        let long_name = long_name.take();
+        if attributes & ATTRIBUTE_VOLUME_ID != 0 || raw[0] == b'.' {
+            continue;
+        }
+
+        let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+        // long names that don't belong to the entry were left behind by a system that
+        // doesn't know them
+        let (name, start) = match long_name {
+            Some(long_name)
+                if long_name.next == 0 && long_name.checksum == checksum(&short_name) =>
+            {
+                (long_name.name(), long_name.start)
+            }
+            _ => (format_short_name(&short_name, raw[12]), offset),
+        };
+        let cluster_high = u16::from_le_bytes([raw[20], raw[21]]);
+        let cluster_low = u16::from_le_bytes([raw[26], raw[27]]);
+        entries.push(Entry {
+            name,
+            short_name,
+            attributes,
+            first_cluster: u32::from(cluster_high) << 16 | u32::from(cluster_low),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            offset,
+            start,
+        });
+    }
+    entries
+}
+
+/// Finds `count` free entries in a row and returns the offset of the first one.
+pub(super) fn find_free(data: &[u8], count: usize) -> Option<u32> {
+    let mut run = 0;
+    let mut ended = false;
+    for (index, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        // everything after the end is free
+        ended |= raw[0] == END;
+        if ended || raw[0] == DELETED {
+            run += 1;
+            if run == count {
+                return Some(((index + 1 - count) * ENTRY_SIZE) as u32);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+/// Fails for names that FAT can't store.
+pub(super) fn check_name(name: &str) -> Result<(), FsError> {
+    if name.encode_utf16().count() > MAX_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+    // Windows drops trailing dots and spaces, so such names couldn't be opened there
+    if name
+        .chars()
+        .any(|c| c < ' ' || LONG_NAME_INVALID.contains(c))
+        || name.ends_with(['.', ' '])
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// Picks the short name of a new entry, along with whether it also needs a long name because
+/// the short one doesn't say the same. Short names for which `taken` is true are skipped, and
+/// there is none if all of them are.
+pub(super) fn short_name(
+    name: &str,
+    taken: impl Fn(&[u8; 11]) -> bool,
+) -> Option<([u8; 11], bool)> {
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    // characters that were dropped or replaced need a number at the end of the short name,
+    // lower case letters just a long name
+    let mut lossy = trimmed.len() != name.len();
+    let mut lower_case = false;
+    let mut convert = |part: &str, limit: usize| -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for c in part.chars() {
+            let byte = match c {
+                ' ' | '.' => {
+                    lossy = true;
+                    continue;
+                }
+                'A'..='Z' | '0'..='9' => c as u8,
+                c if SHORT_NAME_SPECIAL.contains(c) => c as u8,
+                'a'..='z' => {
+                    lower_case = true;
+                    c.to_ascii_uppercase() as u8
+                }
+                _ => {
+                    lossy = true;
+                    b'_'
+                }
+            };
+            if bytes.len() == limit {
+                lossy = true;
+                break;
+            }
+            bytes.push(byte);
+        }
+        bytes
+    };
+    let base = convert(base, 8);
+    let extension = convert(extension, 3);
+
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + extension.len()].copy_from_slice(&extension);
+    if !lossy && !base.is_empty() {
+        short_name[..base.len()].copy_from_slice(&base);
+        if !taken(&short_name) {
+            return Some((short_name, lower_case));
+        }
+    }
+    // `NAME~1.EXT`, `NAME~2.EXT` and so on, the number takes the place of the end of the name
+    for number in 1..1_000_000 {
+        let tail = format!("~{number}");
+        let keep = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short_name) {
+            return Some((short_name, true));
+        }
+    }
+    None
+}
+
+/// A short entry.
+pub(super) fn short_entry(
+    short_name: &[u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short_name);
+    raw[11] = attributes;
+    // created, accessed and modified
+    raw[16..18].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    raw[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    raw[24..26].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    set_first_cluster(&mut raw, first_cluster);
+    set_size(&mut raw, size);
+    raw
+}
+
+pub(super) fn set_first_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+pub(super) fn set_size(raw: &mut [u8], size: u32) {
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// The entries for a file called `name`: its long name entries if it needs them and then the
+/// short entry.
+pub(super) fn encode(
+    name: &str,
+    short_name: &[u8; 11],
+    long_name: bool,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut entries = Vec::new();
+    if long_name {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        // the name ends with a 0 unless it fills the last entry, the rest is padding
+        let length = units.len().next_multiple_of(LONG_NAME_UNITS);
+        if length != units.len() {
+            units.push(0);
+        }
+        units.resize(length, 0xffff);
+        let count = units.len() / LONG_NAME_UNITS;
+        let checksum = checksum(short_name);
+        for number in (1..=count).rev() {
+            let mut raw = [0; ENTRY_SIZE];
+            raw[0] = number as u8;
+            if number == count {
+                raw[0] |= LAST_LONG_ENTRY;
+            }
+            raw[11] = ATTRIBUTE_LONG_NAME;
+            raw[13] = checksum;
+            let part = &units[(number - 1) * LONG_NAME_UNITS..][..LONG_NAME_UNITS];
+            for (&unit, &position) in part.iter().zip(UNIT_OFFSETS.iter()) {
+                raw[position..position + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entries.push(raw);
+        }
+    }
+    entries.push(short_entry(short_name, attributes, first_cluster, size));
+    entries
+}
+
+/// The `.` and `..` entries at the start of a directory. `parent` is 0 for the root directory.
+pub(super) fn dot_entries(cluster: u32, parent: u32) -> [u8; 2 * ENTRY_SIZE] {
+    let mut raw = [0; 2 * ENTRY_SIZE];
+    raw[..ENTRY_SIZE].copy_from_slice(&short_entry(
+        b".          ",
+        ATTRIBUTE_DIRECTORY,
+        cluster,
+        0,
+    ));
+    raw[ENTRY_SIZE..].copy_from_slice(&short_entry(b"..         ", ATTRIBUTE_DIRECTORY, parent, 0));
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(entries: &[[u8; ENTRY_SIZE]]) -> Vec<u8> {
+        let mut data: Vec<u8> = entries.iter().flatten().copied().collect();
+        data.resize(16 * ENTRY_SIZE, 0);
+        data
+    }
+
+    #[test_case]
+    fn short_names() {
+        let free = |_: &[u8; 11]| false;
+        assert_eq!(
+            short_name("README.TXT", free),
+            Some((*b"README  TXT", false))
+        );
+        assert_eq!(short_name("Makefile", free), Some((*b"MAKEFILE   ", true)));
+        assert_eq!(
+            short_name("archive.tar.gz", free),
+            Some((*b"ARCHIV~1GZ ", true))
+        );
+        assert_eq!(short_name(".config", free), Some((*b"CONFIG~1   ", true)));
+        assert_eq!(short_name("a+b.c", free), Some((*b"A_B~1   C  ", true)));
+
+        // the numbers go up until one is free
+        let taken = |short_name: &[u8; 11]| {
+            short_name.starts_with(b"ALONGF~1") || short_name.starts_with(b"LONG    ")
+        };
+        assert_eq!(
+            short_name("A long file.txt", taken),
+            Some((*b"ALONGF~2TXT", true))
+        );
+        assert_eq!(short_name("LONG", taken), Some((*b"LONG~1     ", true)));
+    }
+
+    #[test_case]
+    fn long_names() {
+        // 13 units fit into an entry, the name needs exactly two without the 0 at the end
+        let name = "twenty six characters.long";
+        let (short_name, long_name) = short_name(name, |_| false).unwrap();
+        let entries = encode(name, &short_name, long_name, ATTRIBUTE_ARCHIVE, 7, 42);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0][0], 2 | LAST_LONG_ENTRY);
+
+        let data = directory(&entries);
+        let parsed = parse(&data);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].name, name);
+        assert_eq!((parsed[0].first_cluster, parsed[0].size), (7, 42));
+        assert_eq!((parsed[0].start, parsed[0].offset), (0, 64));
+        assert!(parsed[0].matches("TWENTY~1.LON"));
+        assert!(parsed[0].matches("Twenty Six Characters.LONG"));
+
+        // the long name no longer belongs to the short entry once that changes
+        let mut entries = entries;
+        entries[2][0] = b'X';
+        let parsed = parse(&directory(&entries));
+        assert_eq!(parsed[0].name, "XWENTY~1.LON");
+        assert_eq!(parsed[0].start, 64);
+
+        // and names that aren't ASCII survive
+        let entries = encode("grüße ✓", &short_name, true, ATTRIBUTE_ARCHIVE, 0, 0);
+        assert_eq!(parse(&directory(&entries))[0].name, "grüße ✓");
+    }
+
+    #[test_case]
+    fn entries() {
+        let mut lower_case = short_entry(b"LOWER   TXT", ATTRIBUTE_ARCHIVE, 0x12_3456, 0);
+        lower_case[12] = LOWER_CASE_BASE;
+        let mut deleted = short_entry(b"GONE       ", ATTRIBUTE_ARCHIVE, 0, 0);
+        deleted[0] = DELETED;
+        let entries = [
+            short_entry(b"VOLUME     ", ATTRIBUTE_VOLUME_ID, 0, 0),
+            dot_entries(5, 0)[..ENTRY_SIZE].try_into().unwrap(),
+            deleted,
+            short_entry(b"DIR        ", ATTRIBUTE_DIRECTORY, 9, 0),
+            lower_case,
+        ];
+        let parsed = parse(&directory(&entries));
+        let names: Vec<_> = parsed.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["DIR", "lower.TXT"]);
+        assert!(parsed[0].is_dir());
+        assert_eq!(parsed[1].first_cluster, 0x12_3456);
+
+        // the deleted entry and everything after the end can be reused
+        let data = directory(&entries);
+        assert_eq!(find_free(&data, 1), Some(64));
+        assert_eq!(find_free(&data, 2), Some(160));
+        assert_eq!(find_free(&data, 12), None);
+    }
+
+    #[test_case]
+    fn invalid_names() {
+        assert_eq!(check_name("a:b"), Err(FsError::InvalidPath));
+        assert_eq!(check_name("trailing."), Err(FsError::InvalidPath));
+        assert_eq!(check_name(&"x".repeat(256)), Err(FsError::NameTooLong));
+        assert_eq!(check_name("fine name.txt"), Ok(()));
+    }
+}
@@ -0,0 +1,515 @@
+//! A filesystem that only lives in memory.
+//!
+//! File contents are kept in pages that are only allocated once something is written to them,
+//! so files with holes don't take up memory for the holes.
+
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use spin::Mutex;
+
+const PAGE_SIZE: usize = 4096;
+
+type Page = Box<[u8; PAGE_SIZE]>;
+
+/// An in-memory filesystem.
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        let next_inode = Arc::new(AtomicU64::new(1));
+        Arc::new(Self {
+            root: TmpInode::new(&next_inode, FileType::Directory),
+        })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Content {
+    File {
+        size: u64,
+        // only the pages that were written to
+        pages: BTreeMap<u64, Page>,
+    },
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+}
+
+struct TmpInode {
+    number: u64,
+    // where the numbers of new inodes come from, shared by the whole filesystem
+    next_inode: Arc<AtomicU64>,
+    permissions: AtomicU16,
+    content: Mutex<Content>,
+}
+
+impl TmpInode {
+    fn new(next_inode: &Arc<AtomicU64>, kind: FileType) -> Arc<Self> {
+        let (content, permissions) = match kind {
+            FileType::File => (
+                Content::File {
+                    size: 0,
+                    pages: BTreeMap::new(),
+                },
+                0o644,
+            ),
+            FileType::Directory => (Content::Directory(BTreeMap::new()), 0o755),
+        };
+        Arc::new(Self {
+            number: next_inode.fetch_add(1, Ordering::Relaxed),
+            next_inode: next_inode.clone(),
+            permissions: AtomicU16::new(permissions),
+            content: Mutex::new(content),
+        })
+    }
+
+    fn kind(&self) -> FileType {
+        match *self.content.lock() {
+            Content::File { .. } => FileType::File,
+            Content::Directory(_) => FileType::Directory,
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&*self.content.lock(), Content::Directory(entries) if entries.is_empty())
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let (kind, size, links) = match &*self.content.lock() {
+            Content::File { size, .. } => (FileType::File, *size, 1),
+            // its entry in the parent, its own `.` and the `..` of every subdirectory
+            Content::Directory(entries) => (
+                FileType::Directory,
+                0,
+                2 + entries
+                    .values()
+                    .filter(|entry| entry.kind() == FileType::Directory)
+                    .count() as u32,
+            ),
+        };
+        // everything belongs to root
+        Ok(Metadata {
+            inode: self.number,
+            kind,
+            size,
+            links,
+            permissions: self.permissions.load(Ordering::Relaxed),
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let content = self.content.lock();
+        let Content::File { size, pages } = &*content else {
+            return Err(FsError::IsADirectory);
+        };
+        let length = size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let in_page = position as usize % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - in_page).min(length - done);
+            let target = &mut buffer[done..][..chunk];
+            match pages.get(&(position / PAGE_SIZE as u64)) {
+                Some(page) => target.copy_from_slice(&page[in_page..][..chunk]),
+                // holes read as zeros
+                None => target.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut content = self.content.lock();
+        let Content::File { size, pages } = &mut *content else {
+            return Err(FsError::IsADirectory);
+        };
+        // like on the other filesystems, a file can't grow past the largest offset
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .ok_or(FsError::NoSpace)?;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let in_page = position as usize % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - in_page).min(buffer.len() - done);
+            let page = pages
+                .entry(position / PAGE_SIZE as u64)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            page[in_page..][..chunk].copy_from_slice(&buffer[done..][..chunk]);
+            done += chunk;
+        }
+        *size = (*size).max(end);
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let Content::File { size, pages } = &mut *content else {
+            return Err(FsError::IsADirectory);
+        };
+        if new_size < *size {
+            // whole pages go away, the rest of the last one is zeroed so growing the file
+            // again doesn't bring the old data back
+            let first_unused = new_size.div_ceil(PAGE_SIZE as u64);
+            pages.retain(|&index, _| index < first_unused);
+            let in_page = new_size as usize % PAGE_SIZE;
+            if in_page != 0 {
+                if let Some(page) = pages.get_mut(&(new_size / PAGE_SIZE as u64)) {
+                    page[in_page..].fill(0);
+                }
+            }
+        }
+        // growing only changes the size, the new part is a hole
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let content = self.content.lock();
+        let Content::Directory(entries) = &*content else {
+            return Err(FsError::NotADirectory);
+        };
+        match entries.get(name) {
+            Some(inode) => Ok(inode.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let mut content = self.content.lock();
+        let Content::Directory(entries) = &mut *content else {
+            return Err(FsError::NotADirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = TmpInode::new(&self.next_inode, kind);
+        entries.insert(String::from(name), inode.clone());
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let Content::Directory(entries) = &mut *content else {
+            return Err(FsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        if inode.kind() == FileType::Directory && !inode.is_empty_directory() {
+            return Err(FsError::NotEmpty);
+        }
+        // open handles keep the inode alive until they are closed
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, target: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let target = target
+            .as_any()
+            .downcast_ref::<TmpInode>()
+            .ok_or(FsError::CrossDevice)?;
+        let inode = self.lookup_entry(name)?;
+        if core::ptr::eq(self, target) && name == new_name {
+            return Ok(());
+        }
+        // checked before the entry is taken out of `self`, where it would get lost otherwise
+        if let Content::File { .. } = &*target.content.lock() {
+            return Err(FsError::NotADirectory);
+        }
+
+        // an existing entry can only be replaced by one of the same kind
+        if let Ok(existing) = target.lookup_entry(new_name) {
+            match (inode.kind(), existing.kind()) {
+                (FileType::File, FileType::Directory) => return Err(FsError::IsADirectory),
+                (FileType::Directory, FileType::File) => return Err(FsError::NotADirectory),
+                (FileType::Directory, FileType::Directory) if !existing.is_empty_directory() => {
+                    return Err(FsError::NotEmpty)
+                }
+                _ => {}
+            }
+        }
+        if let Content::Directory(entries) = &mut *self.content.lock() {
+            entries.remove(name);
+        }
+        if let Content::Directory(entries) = &mut *target.content.lock() {
+            entries.insert(String::from(new_name), inode);
+        }
+        Ok(())
+    }
+
+    fn set_permissions(&self, permissions: u16) -> Result<(), FsError> {
+        self.permissions.store(permissions, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let content = self.content.lock();
+        let Content::Directory(entries) = &*content else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(entries
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                inode: inode.number,
+                kind: inode.kind(),
+            })
+            .collect())
+    }
+}
+
+impl TmpInode {
+    fn lookup_entry(&self, name: &str) -> Result<Arc<TmpInode>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => entries.get(name).cloned().ok_or(FsError::NotFound),
+            Content::File { .. } => Err(FsError::NotADirectory),
+        }
+    }
+
+    #[cfg(test)]
+    fn allocated_pages(&self) -> usize {
+        match &*self.content.lock() {
+            Content::File { pages, .. } => pages.len(),
+            Content::Directory(_) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{self, OpenFlags, SeekFrom};
+    use alloc::vec;
+
+    fn file(fs: &TmpFs, name: &str) -> Arc<dyn Inode> {
+        fs.root().create(name, FileType::File).unwrap()
+    }
+
+    #[test_case]
+    fn files() {
+        let fs = TmpFs::new();
+        let inode = file(&fs, "a");
+        assert_eq!(inode.write_at(0, b"hello world").unwrap(), 11);
+        assert_eq!(inode.write_at(6, b"tmpfs").unwrap(), 5);
+        let mut buffer = vec![0; 32];
+        let length = inode.read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"hello tmpfs");
+        assert_eq!(inode.read_at(100, &mut buffer).unwrap(), 0);
+        assert_eq!(
+            fs.root().create("a", FileType::File).err(),
+            Some(FsError::AlreadyExists)
+        );
+        assert_eq!(inode.lookup("x").err(), Some(FsError::NotADirectory));
+    }
+
+    #[test_case]
+    fn sparse_files() {
+        let fs = TmpFs::new();
+        let inode = file(&fs, "sparse");
+        // a single byte far into the file only needs a single page
+        inode.write_at(10 * PAGE_SIZE as u64 + 5, b"x").unwrap();
+        assert_eq!(inode.metadata().unwrap().size, 10 * PAGE_SIZE as u64 + 6);
+        let tmp = inode.as_any().downcast_ref::<TmpInode>().unwrap();
+        assert_eq!(tmp.allocated_pages(), 1);
+
+        let mut buffer = vec![0xff; PAGE_SIZE + 6];
+        inode.read_at(9 * PAGE_SIZE as u64, &mut buffer).unwrap();
+        assert!(buffer[..PAGE_SIZE + 5].iter().all(|&byte| byte == 0));
+        assert_eq!(buffer[PAGE_SIZE + 5], b'x');
+
+        // growing by truncation doesn't allocate either
+        inode.truncate(1 << 30).unwrap();
+        assert_eq!(inode.metadata().unwrap().size, 1 << 30);
+        assert_eq!(tmp.allocated_pages(), 1);
+
+        // the end of the write doesn't fit into an offset
+        assert_eq!(inode.write_at(u64::MAX - 1, b"xy"), Err(FsError::NoSpace));
+    }
+
+    #[test_case]
+    fn truncation() {
+        let fs = TmpFs::new();
+        let inode = file(&fs, "t");
+        inode.write_at(0, &[7; 3 * PAGE_SIZE]).unwrap();
+        inode.truncate(PAGE_SIZE as u64 + 10).unwrap();
+        let tmp = inode.as_any().downcast_ref::<TmpInode>().unwrap();
+        assert_eq!(tmp.allocated_pages(), 2);
+
+        // what was cut off is gone for good
+        inode.truncate(2 * PAGE_SIZE as u64).unwrap();
+        let mut buffer = vec![0; PAGE_SIZE];
+        inode.read_at(PAGE_SIZE as u64, &mut buffer).unwrap();
+        assert!(buffer[..10].iter().all(|&byte| byte == 7));
+        assert!(buffer[10..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test_case]
+    fn directories() {
+        let fs = TmpFs::new();
+        let root = fs.root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        dir.create("file", FileType::File).unwrap();
+        assert_eq!(root.metadata().unwrap().links, 3);
+        assert_eq!(dir.metadata().unwrap().permissions, 0o755);
+        dir.set_permissions(0o700).unwrap();
+        assert_eq!(dir.metadata().unwrap().permissions, 0o700);
+        assert_eq!(root.unlink("dir"), Err(FsError::NotEmpty));
+
+        let names: Vec<_> = dir
+            .readdir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["file"]);
+        dir.unlink("file").unwrap();
+        root.unlink("dir").unwrap();
+        assert_eq!(root.lookup("dir").err(), Some(FsError::NotFound));
+        assert_eq!(root.unlink("dir"), Err(FsError::NotFound));
+    }
+
+    #[test_case]
+    fn renames() {
+        let fs = TmpFs::new();
+        let root = fs.root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        file(&fs, "a").write_at(0, b"a").unwrap();
+        file(&fs, "b").write_at(0, b"b").unwrap();
+
+        // replaces `b`
+        root.rename("a", &root, "b").unwrap();
+        let mut buffer = [0; 1];
+        root.lookup("b").unwrap().read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"a");
+        assert_eq!(root.lookup("a").err(), Some(FsError::NotFound));
+
+        root.rename("b", &dir, "c").unwrap();
+        assert!(dir.lookup("c").is_ok());
+        assert_eq!(root.rename("dir", &dir, "c"), Err(FsError::NotADirectory));
+        assert_eq!(dir.rename("c", &root, "dir"), Err(FsError::IsADirectory));
+
+        // a file can't take entries, and the entry stays where it was
+        let c = dir.lookup("c").unwrap();
+        assert_eq!(root.rename("dir", &c, "d"), Err(FsError::NotADirectory));
+        assert!(root.lookup("dir").is_ok());
+    }
+
+    #[test_case]
+    fn mounted_at_root() {
+        // the tests run with a tmpfs at `/` just like the kernel
+        assert_eq!(vfs::mounts()[0].filesystem.name(), "tmpfs");
+        vfs::mkdir("/tmpfs-test").unwrap();
+        vfs::mkdir("/tmpfs-test/dir").unwrap();
+        vfs::write("/tmpfs-test/dir/file", b"contents").unwrap();
+        vfs::set_current_directory("/tmpfs-test/dir").unwrap();
+        assert_eq!(vfs::read("file").unwrap(), b"contents");
+        assert_eq!(vfs::read("../dir/./file").unwrap(), b"contents");
+        vfs::set_current_directory("/").unwrap();
+
+        vfs::rename("/tmpfs-test/dir/file", "/tmpfs-test/moved").unwrap();
+        assert_eq!(vfs::stat("/tmpfs-test/moved").unwrap().size, 8);
+        vfs::set_permissions("/tmpfs-test/moved", 0o600).unwrap();
+        assert_eq!(vfs::stat("/tmpfs-test/moved").unwrap().permissions, 0o600);
+        assert_eq!(
+            vfs::set_permissions("/tmpfs-test/moved", 0o10000),
+            Err(FsError::InvalidArgument)
+        );
+        assert_eq!(
+            vfs::stat("/tmpfs-test/dir/file").err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(
+            vfs::rename("/tmpfs-test", "/tmpfs-test/dir/inside"),
+            Err(FsError::InvalidPath)
+        );
+
+        let file = vfs::open("/tmpfs-test/moved", OpenFlags::READ | OpenFlags::WRITE).unwrap();
+        file.seek(SeekFrom::Start(100)).unwrap();
+        file.write(b"!").unwrap();
+        assert_eq!(file.stat().unwrap().size, 101);
+        file.set_len(3).unwrap();
+        assert_eq!(vfs::read("/tmpfs-test/moved").unwrap(), b"con");
+
+        // a second tmpfs mounted inside the first one
+        vfs::mount("/tmpfs-test/dir", TmpFs::new()).unwrap();
+        vfs::write("/tmpfs-test/dir/inner", b"x").unwrap();
+        assert_eq!(
+            vfs::rename("/tmpfs-test/dir/inner", "/tmpfs-test/inner"),
+            Err(FsError::CrossDevice)
+        );
+        vfs::unmount("/tmpfs-test/dir").unwrap();
+        assert_eq!(vfs::read_dir("/tmpfs-test/dir").unwrap(), []);
+
+        vfs::remove("/tmpfs-test/moved").unwrap();
+        vfs::remove("/tmpfs-test/dir").unwrap();
+        vfs::remove("/tmpfs-test").unwrap();
+    }
+
+    #[test_case]
+    fn permissions_are_enforced() {
+        use crate::vfs::Credentials;
+
+        vfs::mkdir("/permissions-test").unwrap();
+        vfs::mkdir("/permissions-test/private").unwrap();
+        vfs::set_permissions("/permissions-test/private", 0o700).unwrap();
+        vfs::write("/permissions-test/file", b"file").unwrap();
+        vfs::write("/permissions-test/shared", b"shared").unwrap();
+        vfs::set_permissions("/permissions-test/shared", 0o666).unwrap();
+
+        // everything is owned by root, so someone else gets the bits for others
+        vfs::set_credentials(Credentials {
+            uid: 1000,
+            gid: 1000,
+        });
+        let denied = Err(FsError::PermissionDenied);
+        assert_eq!(vfs::read("/permissions-test/file").unwrap(), b"file");
+        assert_eq!(vfs::write("/permissions-test/file", b"x"), denied);
+        vfs::write("/permissions-test/shared", b"mine").unwrap();
+        assert_eq!(vfs::write("/permissions-test/new", b"x"), denied);
+        assert_eq!(vfs::mkdir("/permissions-test/dir"), denied);
+        assert_eq!(vfs::remove("/permissions-test/file"), denied);
+        assert_eq!(
+            vfs::rename("/permissions-test/file", "/permissions-test/moved"),
+            denied
+        );
+        assert_eq!(
+            vfs::stat("/permissions-test/private/x").err(),
+            Some(FsError::PermissionDenied)
+        );
+        assert_eq!(
+            vfs::set_permissions("/permissions-test/file", 0o666),
+            denied
+        );
+
+        // root may do all of it
+        vfs::set_credentials(Credentials::ROOT);
+        assert_eq!(vfs::read("/permissions-test/shared").unwrap(), b"mine");
+        vfs::remove("/permissions-test/private").unwrap();
+        vfs::remove("/permissions-test/shared").unwrap();
+        vfs::remove("/permissions-test/file").unwrap();
+        vfs::remove("/permissions-test").unwrap();
+    }
+}
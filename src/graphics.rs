@@ -0,0 +1,318 @@
+//! 2D drawing primitives rendering into an off-screen back buffer, which is then flushed to a
+//! [`FrameBuffer`] one dirty rectangle at a time.
+
+use crate::framebuffer::{FrameBuffer, Rgb};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// An axis aligned rectangle. `x` and `y` may be negative, everything outside of the
+/// canvas gets clipped away when drawing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The x coordinate just past the right edge.
+    pub fn right(&self) -> i32 {
+        self.x + self.width as i32
+    }
+
+    /// The y coordinate just past the bottom edge.
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+
+    /// Returns the area covered by both rectangles, or `None` if they don't overlap.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rect::new(x, y, (right - x) as u32, (bottom - y) as u32))
+    }
+
+    /// Returns the smallest rectangle covering both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+    }
+}
+
+/// An off-screen image that can be drawn on and copied to a framebuffer.
+///
+/// Every drawing operation grows the dirty rectangle of the canvas, [`Canvas::flush`] then only
+/// copies that part to the screen.
+pub struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+    // drawing outside of this rectangle has no effect, it always lies within the canvas
+    clip: Rect,
+    // the area that changed since the last flush
+    dirty: Option<Rect>,
+}
+
+impl Canvas {
+    /// Creates a `width`x`height` canvas filled with black.
+    pub fn new(width: usize, height: usize) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![Rgb::BLACK; width * height],
+            clip: Rect::new(0, 0, width as u32, height as u32),
+            dirty: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the rectangle covering the whole canvas.
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width as u32, self.height as u32)
+    }
+
+    /// Restricts all drawing to `clip`, or to the whole canvas again if `clip` is `None`.
+    pub fn set_clip(&mut self, clip: Option<Rect>) {
+        let bounds = self.bounds();
+        self.clip = match clip {
+            // a clip rectangle that lies outside of the canvas doesn't let anything through
+            Some(clip) => bounds.intersection(&clip).unwrap_or(Rect::new(0, 0, 0, 0)),
+            None => bounds,
+        };
+    }
+
+    /// Returns the color of the pixel at `(x, y)`, or `None` if it's outside of the canvas.
+    pub fn pixel(&self, x: i32, y: i32) -> Option<Rgb> {
+        if !self.bounds().contains(x, y) {
+            return None;
+        }
+        Some(self.pixels[y as usize * self.width + x as usize])
+    }
+
+    /// Returns all pixels of the canvas, one row after the other.
+    pub fn pixels(&self) -> &[Rgb] {
+        &self.pixels
+    }
+
+    /// Returns the area that has been drawn to since the last flush.
+    pub fn dirty_rect(&self) -> Option<Rect> {
+        self.dirty
+    }
+
+    /// Sets the pixel at `(x, y)`.
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: Rgb) {
+        if self.plot(x, y, color) {
+            self.mark_dirty(Rect::new(x, y, 1, 1));
+        }
+    }
+
+    /// Fills the whole canvas, ignoring the clip rectangle.
+    pub fn clear(&mut self, color: Rgb) {
+        self.pixels.fill(color);
+        self.mark_dirty(self.bounds());
+    }
+
+    /// Draws a line from `(x0, y0)` to `(x1, y1)`, including both end points.
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Rgb) {
+        // https://en.wikipedia.org/wiki/Bresenham%27s_line_algorithm
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+
+        loop {
+            self.plot(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+
+        let left = x0.min(x1);
+        let top = y0.min(y1);
+        self.mark_dirty(Rect::new(left, top, dx as u32 + 1, (-dy) as u32 + 1));
+    }
+
+    /// Draws the outline of `rect`.
+    pub fn draw_rect(&mut self, rect: Rect, color: Rgb) {
+        if rect.is_empty() {
+            return;
+        }
+        let (right, bottom) = (rect.right() - 1, rect.bottom() - 1);
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), color);
+    }
+
+    /// Fills `rect` with `color`.
+    pub fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        let Some(area) = self.clip.intersection(&rect) else {
+            return;
+        };
+        for y in area.y..area.bottom() {
+            let start = y as usize * self.width + area.x as usize;
+            self.pixels[start..start + area.width as usize].fill(color);
+        }
+        self.mark_dirty(area);
+    }
+
+    /// Draws the outline of a circle around `(cx, cy)`.
+    pub fn draw_circle(&mut self, cx: i32, cy: i32, radius: u32, color: Rgb) {
+        // https://en.wikipedia.org/wiki/Midpoint_circle_algorithm
+        self.for_each_octant_point(radius, |canvas, x, y| {
+            for (px, py) in [(x, y), (y, x), (-y, x), (-x, y)] {
+                canvas.plot(cx + px, cy + py, color);
+                canvas.plot(cx - px, cy - py, color);
+            }
+        });
+        self.mark_dirty(Self::circle_bounds(cx, cy, radius));
+    }
+
+    /// Draws a filled circle around `(cx, cy)`.
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: u32, color: Rgb) {
+        // fill the horizontal spans between the points the outline would have
+        self.for_each_octant_point(radius, |canvas, x, y| {
+            for (half_width, dy) in [(x, y), (y, x)] {
+                let span = Rect::new(cx - half_width, 0, 2 * half_width as u32 + 1, 1);
+                canvas.fill_rect(Rect { y: cy + dy, ..span }, color);
+                canvas.fill_rect(Rect { y: cy - dy, ..span }, color);
+            }
+        });
+    }
+
+    /// Copies all of `src` to this canvas with its top left corner at `(x, y)`.
+    pub fn blit(&mut self, x: i32, y: i32, src: &Canvas) {
+        let target = Rect::new(x, y, src.width as u32, src.height as u32);
+        let Some(area) = self.clip.intersection(&target) else {
+            return;
+        };
+        for row in area.y..area.bottom() {
+            let src_start = (row - y) as usize * src.width + (area.x - x) as usize;
+            let dst_start = row as usize * self.width + area.x as usize;
+            let len = area.width as usize;
+            self.pixels[dst_start..dst_start + len]
+                .copy_from_slice(&src.pixels[src_start..src_start + len]);
+        }
+        self.mark_dirty(area);
+    }
+
+    /// Copies everything that changed since the last flush to `framebuffer`.
+    pub fn flush(&mut self, framebuffer: &mut FrameBuffer) {
+        if let Some(dirty) = self.dirty.take() {
+            self.copy_to(framebuffer, dirty);
+        }
+    }
+
+    /// Copies the whole canvas to `framebuffer`, whether it changed or not.
+    pub fn flush_all(&mut self, framebuffer: &mut FrameBuffer) {
+        self.dirty = None;
+        self.copy_to(framebuffer, self.bounds());
+    }
+
+    fn copy_to(&self, framebuffer: &mut FrameBuffer, area: Rect) {
+        let info = framebuffer.info();
+        let screen = Rect::new(0, 0, info.width as u32, info.height as u32);
+        let Some(area) = area.intersection(&screen) else {
+            return;
+        };
+        for y in area.y..area.bottom() {
+            for x in area.x..area.right() {
+                let color = self.pixels[y as usize * self.width + x as usize];
+                framebuffer.set_pixel(x as usize, y as usize, color);
+            }
+        }
+    }
+
+    /// Sets a single pixel if it's inside of the clip rectangle, without marking it dirty.
+    /// Returns whether the pixel was set.
+    fn plot(&mut self, x: i32, y: i32, color: Rgb) -> bool {
+        if !self.clip.contains(x, y) {
+            return false;
+        }
+        self.pixels[y as usize * self.width + x as usize] = color;
+        true
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        // only the part within the canvas ever has to be flushed
+        let Some(rect) = self.bounds().intersection(&rect) else {
+            return;
+        };
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(&rect),
+            None => rect,
+        });
+    }
+
+    /// Calls `f` with every point `(x, y)` of the first octant of a circle with `radius`
+    /// around the origin, where `x >= y >= 0`.
+    fn for_each_octant_point(&mut self, radius: u32, mut f: impl FnMut(&mut Canvas, i32, i32)) {
+        let (mut x, mut y) = (radius as i32, 0);
+        let mut error = 1 - x;
+        while x >= y {
+            f(self, x, y);
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    fn circle_bounds(cx: i32, cy: i32, radius: u32) -> Rect {
+        let r = radius as i32;
+        Rect::new(cx - r, cy - r, 2 * radius + 1, 2 * radius + 1)
+    }
+}
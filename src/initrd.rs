@@ -0,0 +1,245 @@
+//! The initial ramdisk, which ships files with the kernel before there is a disk to read them
+//! from. `build.rs` packs the directory `initrd` into a newc cpio archive that is built into
+//! the kernel, and it is unpacked into the root filesystem at boot.
+
+use crate::vfs::{self, FsError};
+use alloc::format;
+use alloc::vec::Vec;
+
+/// The archive that is built into the kernel.
+pub static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.cpio"));
+
+// every entry starts with a header of 13 fields of 8 hexadecimal digits after the magic
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+// the name of the entry that ends the archive
+const TRAILER: &str = "TRAILER!!!";
+// the kind of file is in the upper bits of the mode
+const KIND: u32 = 0o170000;
+const FILE: u32 = 0o100000;
+const DIRECTORY: u32 = 0o040000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    Fs(FsError),
+    /// The archive isn't a newc cpio archive, or it ends in the middle.
+    Invalid(&'static str),
+}
+
+impl From<FsError> for InitrdError {
+    fn from(err: FsError) -> Self {
+        InitrdError::Fs(err)
+    }
+}
+
+/// A file of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & KIND == DIRECTORY
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & KIND == FILE
+    }
+
+    pub fn permissions(&self) -> u16 {
+        (self.mode & 0o7777) as u16
+    }
+}
+
+/// Reads the entries of an archive up to its trailer.
+pub fn parse(archive: &[u8]) -> Result<Vec<Entry<'_>>, InitrdError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(InitrdError::Invalid("no trailer"))?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(InitrdError::Invalid("not a newc cpio archive"));
+        }
+        let field = |index: usize| {
+            let digits = &header[MAGIC.len() + index * 8..][..8];
+            core::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or(InitrdError::Invalid("bad header field"))
+        };
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // the name ends with a zero, and the name and the data start at multiples of 4
+        let name_start = offset + HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .and_then(|name| name.strip_suffix(&[0]))
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(InitrdError::Invalid("bad name"))?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(InitrdError::Invalid("entry runs past the end"))?;
+        offset = (data_start + size).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(entries);
+        }
+        entries.push(Entry { name, mode, data });
+    }
+}
+
+/// Unpacks the files and directories of an archive into the directory `root`, replacing the
+/// files that are there already. Other kinds of files, like symlinks, are skipped since the
+/// VFS has nowhere to put them. Returns how many entries were unpacked.
+pub fn unpack(archive: &[u8], root: &str) -> Result<usize, InitrdError> {
+    let mut count = 0;
+    for entry in parse(archive)? {
+        // names are relative to the root of the archive, which may be in there as `.`
+        let name = entry.name.strip_prefix("./").unwrap_or(entry.name);
+        let name = name.trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        let path = format!("{}/{}", root.trim_end_matches('/'), name);
+        if entry.is_dir() {
+            match vfs::mkdir(&path) {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(err) => return Err(err.into()),
+            }
+        } else if entry.is_file() {
+            vfs::write(&path, entry.data)?;
+        } else {
+            continue;
+        }
+        vfs::set_permissions(&path, entry.permissions())?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Unpacks the archive that is built into the kernel into the root filesystem.
+pub fn init() {
+    unpack(ARCHIVE, "/").expect("the initrd can't be unpacked");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // packs entries the way `build.rs` does
+    fn archive(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for &(name, mode, data) in entries.iter().chain([&(TRAILER, 0, &[][..])]) {
+            archive.extend_from_slice(MAGIC);
+            let fields = [1, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+            for field in fields.iter().chain(&[name.len() as u32 + 1, 0]) {
+                archive.extend_from_slice(format!("{field:08x}").as_bytes());
+            }
+            archive.extend_from_slice(name.as_bytes());
+            archive.push(0);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+        }
+        archive
+    }
+
+    #[test_case]
+    fn parses_archives() {
+        let data = archive(&[
+            (".", DIRECTORY | 0o755, b""),
+            ("hello.txt", FILE | 0o644, b"hello"),
+            ("link", 0o120777, b"hello.txt"),
+        ]);
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[1].name, "hello.txt");
+        assert_eq!(
+            (entries[1].data, entries[1].permissions()),
+            (&b"hello"[..], 0o644)
+        );
+        assert!(!entries[2].is_file() && !entries[2].is_dir());
+
+        // anything behind the trailer doesn't count
+        let mut longer = data.clone();
+        longer.extend_from_slice(&archive(&[("ignored", FILE, b"")]));
+        assert_eq!(parse(&longer).unwrap().len(), 3);
+
+        // the trailer takes 124 bytes, and the target of the link is in the 12 before it
+        let trailer = data.len() - 124;
+        assert_eq!(
+            parse(&data[..trailer]),
+            Err(InitrdError::Invalid("no trailer"))
+        );
+        assert_eq!(
+            parse(&data[..trailer - 4]),
+            Err(InitrdError::Invalid("entry runs past the end"))
+        );
+        // a whole header, so it's the magic that gets rejected
+        let mut ancient = b"070707 an old format".to_vec();
+        ancient.resize(HEADER_SIZE, b'0');
+        assert_eq!(
+            parse(&ancient),
+            Err(InitrdError::Invalid("not a newc cpio archive"))
+        );
+        let mut old = data.clone();
+        old[5] = b'7';
+        assert_eq!(
+            parse(&old),
+            Err(InitrdError::Invalid("not a newc cpio archive"))
+        );
+    }
+
+    #[test_case]
+    fn unpacks_into_a_directory() {
+        let data = archive(&[
+            (".", DIRECTORY | 0o755, b""),
+            ("./bin", DIRECTORY | 0o755, b""),
+            ("./bin/hello", FILE | 0o755, b"#!hello"),
+            ("./etc", DIRECTORY | 0o700, b""),
+            ("./etc/secret", FILE | 0o600, &[7; 5000]),
+            ("./etc/link", 0o120777, b"secret"),
+        ]);
+        vfs::mkdir("/initrd-test").unwrap();
+        assert_eq!(unpack(&data, "/initrd-test/"), Ok(4));
+        assert_eq!(vfs::read("/initrd-test/bin/hello").unwrap(), b"#!hello");
+        assert_eq!(vfs::read("/initrd-test/etc/secret").unwrap(), vec![7; 5000]);
+        assert_eq!(
+            vfs::stat("/initrd-test/etc/secret").unwrap().permissions,
+            0o600
+        );
+        assert_eq!(vfs::stat("/initrd-test/etc").unwrap().permissions, 0o700);
+        assert_eq!(
+            vfs::stat("/initrd-test/etc/link").err(),
+            Some(FsError::NotFound)
+        );
+
+        // unpacking again replaces the files
+        assert_eq!(unpack(&data, "/initrd-test"), Ok(4));
+        assert_eq!(vfs::read_dir("/initrd-test/etc").unwrap().len(), 1);
+        for path in ["bin/hello", "bin", "etc/secret", "etc"] {
+            vfs::remove(&format!("/initrd-test/{path}")).unwrap();
+        }
+        vfs::remove("/initrd-test").unwrap();
+    }
+
+    #[test_case]
+    fn unpacked_at_boot() {
+        assert!(parse(ARCHIVE).unwrap()[0].is_dir());
+        assert_eq!(
+            vfs::read("/etc/motd").unwrap(),
+            include_bytes!("../initrd/etc/motd")
+        );
+        assert_eq!(vfs::read("/etc/hostname").unwrap(), b"wally\n");
+    }
+}
@@ -1,7 +1,10 @@
-use crate::gdt;
+use crate::{gdt, process};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
+use spin::{self, Mutex};
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -21,28 +24,80 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // the hardware interrupts all go through `dispatch_irq`, which calls whatever
+        // handlers have been registered at runtime
+        for (irq, &entry_point) in IRQ_ENTRY_POINTS.iter().enumerate() {
+            idt[irq_vector(irq as u8).into()].set_handler_fn(entry_point);
+        }
+        // the same goes for the vectors that are handed out for message signalled interrupts
+        for (index, &entry_point) in VECTOR_ENTRY_POINTS.iter().enumerate() {
+            idt[usize::from(DYNAMIC_VECTOR_START) + index].set_handler_fn(entry_point);
+        }
+        idt[APIC_SPURIOUS_VECTOR.into()].set_handler_fn(apic_spurious_handler);
         idt
     };
 }
 
-#[derive(Debug, Clone, Copy)]
-// ensure that the enum is "C-like", meaning that all members are just numbers (u8 in this case) under the hood.
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET, // 32
-    Keyboard,             // 32 + 1 = 33
-}
+// the legacy IRQ lines of the PICs
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+/// The line the second PIC is chained to.
+pub const IRQ_CASCADE: u8 = 2;
+pub const IRQ_COM1: u8 = 4;
+pub const IRQ_MOUSE: u8 = 12;
+pub const IRQ_PRIMARY_ATA: u8 = 14;
+pub const IRQ_SECONDARY_ATA: u8 = 15;
+/// The number of IRQ lines of both PICs together.
+pub const IRQ_COUNT: usize = 16;
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
+/// The first vector handed out by [`allocate_vector`], right after the ones of the PICs.
+pub const DYNAMIC_VECTOR_START: u8 = PIC_2_OFFSET + 8;
+/// How many vectors can be handed out by [`allocate_vector`].
+pub const DYNAMIC_VECTOR_COUNT: usize = 32;
+/// The vector the local APIC raises when an interrupt went away before it could be delivered.
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xff;
+
+/// How many handlers can share a single IRQ line.
+const MAX_SHARED_HANDLERS: usize = 4;
+/// How many closures can wait to be freed after being unregistered from an interrupt handler.
+const MAX_RETIRED: usize = 8;
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+// the PICs report a spurious interrupt on the lowest priority line of the respective chip
+const SPURIOUS_MASTER_IRQ: u8 = 7;
+const SPURIOUS_SLAVE_IRQ: u8 = 15;
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const READ_ISR: u8 = 0x0b;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+// the handlers registered for each IRQ line
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: Mutex<[Option<IrqHandler>; MAX_SHARED_HANDLERS]> =
+    Mutex::new([None, None, None, None]);
+static IRQ_HANDLERS: [Mutex<[Option<IrqHandler>; MAX_SHARED_HANDLERS]>; IRQ_COUNT] =
+    [NO_HANDLERS; IRQ_COUNT];
+// the handlers of the vectors handed out by `allocate_vector`
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_VECTOR: Mutex<Option<IrqHandler>> = Mutex::new(None);
+static VECTOR_HANDLERS: [Mutex<Option<IrqHandler>>; DYNAMIC_VECTOR_COUNT] =
+    [FREE_VECTOR; DYNAMIC_VECTOR_COUNT];
+// closures that were removed while an interrupt handler ran, which might still be calling
+// them and must not touch the heap anyway. The next registration outside of it frees them.
+static RETIRED: Mutex<[Option<Closure>; MAX_RETIRED]> = Mutex::new([None; MAX_RETIRED]);
+// whether the handlers of an interrupt are being called right now
+static DISPATCHING: AtomicBool = AtomicBool::new(false);
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the interrupt vector the PICs raise for an IRQ line.
+pub fn irq_vector(irq: u8) -> u8 {
+    if irq < 8 {
+        PIC_1_OFFSET + irq
+    } else {
+        PIC_2_OFFSET + irq - 8
     }
 }
 
@@ -50,65 +105,382 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    // breakpoint interrupts are what most debuggers use in order to stop execution of code at a specified location.
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Identifies a registered handler so it can be unregistered again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    irq: u8,
+    id: u64,
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // https://en.wikipedia.org/wiki/Intel_8253
-    // the hardware timer prints a dot asynchronously every tick.
-    print!(".");
-    unsafe {
-        // tell the PICs that we are at the end of the timer interrupt.
-        // this is done in order for the cpu to know when to continue to the next event.
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // here we use the pc_keyboard crate to help us interpret scancodes coming from the keyboard controller.
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    // initialize a static mutable keyboard object
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key, // US keyboard layout
-                HandleControl::Ignore // ignore mapping things like `ctrl+[a-z]` to their unicode representations
-            ));
-    }
-
-    // lock the keyboard mutex
-    let mut keyboard = KEYBOARD.lock();
-    // 0x60 is the port number of the PS/2 controller.
-    // PS/2 is an old standard that was used before USB peripherals were a thing,
-    // and while new hardware won't have an actual PS/2 controller, most still emulate one.
-    let mut port = Port::new(0x60);
-
-    // read the scancode of the pressed key from the PS/2 controller.
-    // This is unsafe because the I/O port could have side effects that violate memory safety.
-    let scancode: u8 = unsafe { port.read() };
-    // add the scancode byte to the keyboard object which then generates a `KeyEvent` if successful
-    if let Ok(Some(key_evt)) = keyboard.add_byte(scancode) {
-        // process the above key event
-        if let Some(key) = keyboard.process_keyevent(key_evt) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{character}"),
-                DecodedKey::RawKey(key) => print!("{key:?}"),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// There is no IRQ line with this number.
+    InvalidIrq(u8),
+    /// The IRQ line already has as many handlers as it can take.
+    TooManyHandlers(u8),
+    /// All vectors for message signalled interrupts are in use.
+    NoFreeVectors,
+}
+
+#[derive(Clone, Copy)]
+struct IrqHandler {
+    id: u64,
+    function: HandlerFunction,
+}
+
+// the interrupt handlers copy the handlers out of the tables, so this has to be plain data
+#[derive(Clone, Copy)]
+enum HandlerFunction {
+    Pointer(fn()),
+    Closure(Closure),
+}
+
+// a boxed closure that was leaked when it was registered and is freed again by `retire`
+type Closure = &'static (dyn Fn() + Send + Sync);
+
+impl HandlerFunction {
+    fn closure(handler: impl Fn() + Send + Sync + 'static) -> Self {
+        HandlerFunction::Closure(Box::leak(Box::new(handler)))
+    }
+}
+
+impl IrqHandler {
+    fn call(&self) {
+        match &self.function {
+            HandlerFunction::Pointer(function) => function(),
+            HandlerFunction::Closure(closure) => closure(),
+        }
+    }
+}
+
+/// Calls `handler` every time the IRQ line `irq` fires and unmasks the line.
+///
+/// Handlers run with interrupts disabled and must not block. The end of interrupt is sent to
+/// the PICs after all handlers of the line have run. Several handlers can share a line, in
+/// which case every one of them gets called and has to check whether its device needs attention.
+pub fn register_irq(irq: u8, handler: fn()) -> Result<IrqHandle, IrqError> {
+    add_handler(irq, HandlerFunction::Pointer(handler))
+}
+
+/// Like [`register_irq`], but for closures. Needs the heap.
+pub fn register_irq_closure(
+    irq: u8,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<IrqHandle, IrqError> {
+    add_handler(irq, HandlerFunction::closure(handler))
+}
+
+/// Removes a handler again. The IRQ line gets masked once it has no handlers left.
+pub fn unregister_irq(handle: IrqHandle) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS[usize::from(handle.irq)].lock();
+        let removed = handlers
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|handler| handler.id == handle.id))
+            .and_then(Option::take);
+        if handlers.iter().all(Option::is_none) {
+            set_masked(handle.irq, true);
+        }
+        drop(handlers);
+        if let Some(handler) = removed {
+            retire(handler.function);
+        }
+    })
+}
+
+/// Reserves an interrupt vector that isn't tied to an IRQ line and calls `handler` whenever
+/// it is raised. This is what message signalled interrupts of PCI devices use.
+///
+/// The vectors are delivered by the local APIC, which gets its end of interrupt
+/// after the handler returns.
+pub fn allocate_vector(handler: fn()) -> Result<u8, IrqError> {
+    add_vector_handler(HandlerFunction::Pointer(handler))
+}
+
+/// Like [`allocate_vector`], but for closures. Needs the heap.
+pub fn allocate_vector_closure(handler: impl Fn() + Send + Sync + 'static) -> Result<u8, IrqError> {
+    add_vector_handler(HandlerFunction::closure(handler))
+}
+
+/// Gives back a vector handed out by [`allocate_vector`].
+pub fn free_vector(vector: u8) {
+    use x86_64::instructions::interrupts;
+
+    let Some(index) = usize::from(vector).checked_sub(DYNAMIC_VECTOR_START.into()) else {
+        return;
+    };
+    if let Some(handler) = VECTOR_HANDLERS.get(index) {
+        interrupts::without_interrupts(|| {
+            let removed = handler.lock().take();
+            if let Some(handler) = removed {
+                retire(handler.function);
+            }
+        });
+    }
+}
+
+fn add_vector_handler(function: HandlerFunction) -> Result<u8, IrqError> {
+    use x86_64::instructions::interrupts;
+
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    let vector = interrupts::without_interrupts(|| {
+        free_retired();
+        for (index, handler) in VECTOR_HANDLERS.iter().enumerate() {
+            let mut handler = handler.lock();
+            if handler.is_none() {
+                *handler = Some(IrqHandler { id, function });
+                return Ok(DYNAMIC_VECTOR_START + index as u8);
             }
         }
+        Err(IrqError::NoFreeVectors)
+    });
+    if vector.is_err() {
+        retire(function);
     }
+    vector
+}
 
-    // let the CPU know we're done
+/// The number of spurious interrupts the PICs raised so far.
+pub fn spurious_irqs() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+fn add_handler(irq: u8, function: HandlerFunction) -> Result<IrqHandle, IrqError> {
+    use x86_64::instructions::interrupts;
+
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    let handle = interrupts::without_interrupts(|| {
+        if usize::from(irq) >= IRQ_COUNT || irq == IRQ_CASCADE {
+            return Err(IrqError::InvalidIrq(irq));
+        }
+        free_retired();
+        let mut handlers = IRQ_HANDLERS[usize::from(irq)].lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers(irq))?;
+        *slot = Some(IrqHandler { id, function });
+        set_masked(irq, false);
+        Ok(IrqHandle { irq, id })
+    });
+    // the closure of a handler that didn't make it into the table isn't needed anymore
+    if handle.is_err() {
+        retire(function);
+    }
+    handle
+}
+
+/// Frees the closure of a handler that was removed from the tables. Inside an interrupt
+/// handler it is put aside instead, since freeing it needs the heap, which the interrupted
+/// code might hold.
+fn retire(function: HandlerFunction) {
+    let HandlerFunction::Closure(closure) = function else {
+        return;
+    };
+    if !DISPATCHING.load(Ordering::Relaxed) {
+        free_retired();
+        unsafe { free_closure(closure) };
+        return;
+    }
+    // without room left the closure is leaked, which is still better than a deadlock
+    if let Some(slot) = RETIRED.lock().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(closure);
+    }
+}
+
+/// Frees the closures [`retire`] put aside, unless an interrupt handler is running.
+fn free_retired() {
+    if DISPATCHING.load(Ordering::Relaxed) {
+        return;
+    }
+    let retired = core::mem::replace(&mut *RETIRED.lock(), [None; MAX_RETIRED]);
+    for closure in retired.into_iter().flatten() {
+        unsafe { free_closure(closure) };
+    }
+}
+
+/// Frees a closure leaked by `HandlerFunction::closure`, which nothing may call afterwards.
+unsafe fn free_closure(closure: Closure) {
+    drop(Box::from_raw(
+        closure as *const (dyn Fn() + Send + Sync) as *mut (dyn Fn() + Send + Sync),
+    ));
+}
+
+/// Masks or unmasks an IRQ line. Lines of the second PIC also need the cascade to be unmasked.
+fn set_masked(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
+    let mut masks = unsafe { pics.read_masks() };
+    let (chip, bit) = (usize::from(irq / 8), irq % 8);
+    if masked {
+        masks[chip] |= 1 << bit;
+    } else {
+        masks[chip] &= !(1 << bit);
+        if chip == 1 {
+            masks[0] &= !(1 << IRQ_CASCADE);
+        }
+    }
+    unsafe { pics.write_masks(masks[0], masks[1]) };
+}
+
+/// Reads the in-service register of the PIC that handles `irq`.
+fn in_service(irq: u8) -> u8 {
+    let mut command = Port::<u8>::new(if irq < 8 {
+        PIC_1_COMMAND
+    } else {
+        PIC_2_COMMAND
+    });
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        command.write(READ_ISR);
+        command.read()
+    }
+}
+
+/// Calls the handlers of an IRQ line and sends the end of interrupt.
+fn dispatch_irq(irq: u8) {
+    // https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+    // a spurious interrupt isn't in service, so it must not get an end of interrupt either.
+    // For the second PIC the first one doesn't know that though and still needs one.
+    if (irq == SPURIOUS_MASTER_IRQ || irq == SPURIOUS_SLAVE_IRQ) && in_service(irq) & 1 << 7 == 0 {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        if irq == SPURIOUS_SLAVE_IRQ {
+            unsafe { Port::<u8>::new(PIC_1_COMMAND).write(END_OF_INTERRUPT) };
+        }
+        return;
+    }
+
+    // the handlers are copied out and called without holding the lock, so that they can
+    // register and unregister handlers themselves
+    let handlers = *IRQ_HANDLERS[usize::from(irq)].lock();
+    DISPATCHING.store(true, Ordering::Relaxed);
+    for handler in handlers.iter().flatten() {
+        handler.call();
+    }
+    DISPATCHING.store(false, Ordering::Relaxed);
+
+    // let the CPU know we're done
+    unsafe { PICS.lock().notify_end_of_interrupt(irq_vector(irq)) };
+}
+
+/// Calls the handler of a vector handed out by `allocate_vector`.
+fn dispatch_vector(index: usize) {
+    let handler = *VECTOR_HANDLERS[index].lock();
+    if let Some(handler) = handler {
+        DISPATCHING.store(true, Ordering::Relaxed);
+        handler.call();
+        DISPATCHING.store(false, Ordering::Relaxed);
     }
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    // spurious interrupts must not get an end of interrupt
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+}
+
+// one entry point per IRQ line, since the handler functions don't know which vector they were called for
+macro_rules! irq_entry_points {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        const IRQ_ENTRY_POINTS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] =
+            [$($name),*];
+    };
+}
+
+irq_entry_points! {
+    0 => irq0_handler,
+    1 => irq1_handler,
+    2 => irq2_handler,
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
+}
+
+macro_rules! vector_entry_points {
+    ($($index:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_vector($index);
+            }
+        )*
+
+        const VECTOR_ENTRY_POINTS: [extern "x86-interrupt" fn(InterruptStackFrame);
+            DYNAMIC_VECTOR_COUNT] = [$($name),*];
+    };
+}
+
+vector_entry_points! {
+    0 => vector0_handler,
+    1 => vector1_handler,
+    2 => vector2_handler,
+    3 => vector3_handler,
+    4 => vector4_handler,
+    5 => vector5_handler,
+    6 => vector6_handler,
+    7 => vector7_handler,
+    8 => vector8_handler,
+    9 => vector9_handler,
+    10 => vector10_handler,
+    11 => vector11_handler,
+    12 => vector12_handler,
+    13 => vector13_handler,
+    14 => vector14_handler,
+    15 => vector15_handler,
+    16 => vector16_handler,
+    17 => vector17_handler,
+    18 => vector18_handler,
+    19 => vector19_handler,
+    20 => vector20_handler,
+    21 => vector21_handler,
+    22 => vector22_handler,
+    23 => vector23_handler,
+    24 => vector24_handler,
+    25 => vector25_handler,
+    26 => vector26_handler,
+    27 => vector27_handler,
+    28 => vector28_handler,
+    29 => vector29_handler,
+    30 => vector30_handler,
+    31 => vector31_handler,
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    // breakpoint interrupts are what most debuggers use in order to stop execution of code at a specified location.
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+/// How long it takes the timer to tick, it runs at its default rate of about 18.2 Hz.
+pub const TICK_MICROSECONDS: u64 = 54_925;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// How often the timer ticked since [`crate::init`].
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Registered for the timer IRQ by [`crate::init`].
+pub(crate) fn timer_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // https://en.wikipedia.org/wiki/Intel_8253
+    // the hardware timer prints a dot asynchronously every tick.
+    print!(".");
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -118,6 +490,20 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame)
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    // a process doing something it isn't allowed to gets stopped, the kernel doing it is a bug
+    if process::from_user(stack_frame.code_segment) {
+        process::stop(process::Stop::GeneralProtectionFault {
+            rip: stack_frame.instruction_pointer,
+            error_code,
+        });
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({error_code:#x})\n{stack_frame:#?}")
+}
+
 use crate::hlt_loop;
 use x86_64::structures::idt::PageFaultErrorCode;
 
@@ -127,6 +513,12 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    if process::from_user(stack_frame.code_segment) {
+        process::stop(process::Stop::PageFault {
+            rip: stack_frame.instruction_pointer,
+            address: Cr2::read(),
+        });
+    }
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {error_code:?}");
@@ -136,8 +528,130 @@ extern "x86-interrupt" fn page_fault_handler(
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::arch::asm;
+    use core::sync::atomic::AtomicUsize;
+
     #[test_case]
     fn breakpoint_exception() {
         x86_64::instructions::interrupts::int3();
     }
+
+    #[test_case]
+    fn shared_irq_handlers() {
+        // nothing in qemu uses IRQ 5, so we raise it ourselves
+        let calls = Arc::new(AtomicUsize::new(0));
+        let first = calls.clone();
+        let first = register_irq_closure(5, move || {
+            first.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        let second = calls.clone();
+        let second = register_irq_closure(5, move || {
+            second.fetch_add(10, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        unsafe { asm!("int 37") };
+        assert_eq!(calls.load(Ordering::SeqCst), 11);
+
+        unregister_irq(first);
+        unsafe { asm!("int 37") };
+        assert_eq!(calls.load(Ordering::SeqCst), 21);
+
+        unregister_irq(second);
+        unsafe { asm!("int 37") };
+        assert_eq!(calls.load(Ordering::SeqCst), 21);
+    }
+
+    #[test_case]
+    fn handlers_unregister_themselves() {
+        static HANDLE: Mutex<Option<IrqHandle>> = Mutex::new(None);
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        fn once() {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+            if let Some(handle) = HANDLE.lock().take() {
+                unregister_irq(handle);
+            }
+        }
+
+        *HANDLE.lock() = Some(register_irq(5, once).unwrap());
+        unsafe { asm!("int 37") };
+        unsafe { asm!("int 37") };
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn closures_are_freed_outside_of_the_handler() {
+        static HANDLE: Mutex<Option<IrqHandle>> = Mutex::new(None);
+        fn nothing() {}
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let handle = register_irq_closure(5, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if let Some(handle) = HANDLE.lock().take() {
+                unregister_irq(handle);
+            }
+        });
+        *HANDLE.lock() = Some(handle.unwrap());
+        unsafe { asm!("int 37") };
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(Arc::strong_count(&calls), 2);
+
+        // the next registration outside of the interrupt handler cleans up
+        unregister_irq(register_irq(5, nothing).unwrap());
+        assert_eq!(Arc::strong_count(&calls), 1);
+    }
+
+    #[test_case]
+    fn handler_limit() {
+        fn nothing() {}
+
+        let handles: [_; MAX_SHARED_HANDLERS] = core::array::from_fn(|_| register_irq(5, nothing));
+        assert_eq!(register_irq(5, nothing), Err(IrqError::TooManyHandlers(5)));
+        for handle in handles {
+            unregister_irq(handle.unwrap());
+        }
+        assert_eq!(register_irq(16, nothing), Err(IrqError::InvalidIrq(16)));
+    }
+
+    #[test_case]
+    fn dynamic_vectors() {
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        fn handler() {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let vector = allocate_vector(handler).unwrap();
+        assert!(vector >= DYNAMIC_VECTOR_START);
+        // `int` needs the vector in the instruction itself, so we pretend the interrupt came in
+        dispatch_vector(usize::from(vector - DYNAMIC_VECTOR_START));
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+
+        // the vector is only handed out once
+        let other = allocate_vector(handler).unwrap();
+        assert_ne!(vector, other);
+        free_vector(vector);
+        free_vector(other);
+        assert_eq!(allocate_vector(handler), Ok(vector));
+        free_vector(vector);
+    }
+
+    #[test_case]
+    fn spurious_irq() {
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        fn handler() {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        // an IRQ 7 that the PIC doesn't have in service is spurious
+        let handle = register_irq(7, handler).unwrap();
+        let spurious = spurious_irqs();
+        unsafe { asm!("int 39") };
+        assert_eq!(spurious_irqs(), spurious + 1);
+        assert_eq!(CALLED.load(Ordering::SeqCst), 0);
+        unregister_irq(handle);
+    }
 }
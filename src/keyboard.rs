@@ -0,0 +1,389 @@
+use crate::ps2::{self, Leds};
+use crate::ring_buffer::RingBuffer;
+use crate::terminal;
+use lazy_static::lazy_static;
+use pc_keyboard::layouts::{self, AnyLayout};
+use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+
+pub use pc_keyboard::{KeyCode, KeyState};
+
+/// The number of key events that are buffered for `read_event` before new ones get dropped.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<KeyDecoder> = Mutex::new(KeyDecoder::new(Layout::Us104));
+}
+
+// key events waiting to be picked up by `read_event`
+static EVENTS: Mutex<RingBuffer<KeyEvent, EVENT_QUEUE_SIZE>> = Mutex::new(RingBuffer::new());
+
+/// The keyboard layouts that can be selected at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// US 104 key QWERTY
+    Us104,
+    /// UK 105 key QWERTY
+    Uk105,
+    /// German 105 key QWERTZ
+    De105,
+    /// French 105 key AZERTY
+    Azerty,
+    /// US 104 key Dvorak
+    Dvorak104,
+    /// US 104 key Programmer Dvorak
+    DvorakProgrammer104,
+    Colemak,
+    /// Japanese 109 key
+    Jis109,
+}
+
+impl Layout {
+    fn to_any(self) -> AnyLayout {
+        match self {
+            Layout::Us104 => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk105 => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::De105 => AnyLayout::De105Key(layouts::De105Key),
+            Layout::Azerty => AnyLayout::Azerty(layouts::Azerty),
+            Layout::Dvorak104 => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Layout::DvorakProgrammer104 => AnyLayout::DVP104Key(layouts::DVP104Key),
+            Layout::Colemak => AnyLayout::Colemak(layouts::Colemak),
+            Layout::Jis109 => AnyLayout::Jis109Key(layouts::Jis109Key),
+        }
+    }
+}
+
+/// The state of the modifier keys at the time of a key event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// The left alt key
+    pub alt: bool,
+    /// The right alt key, which some layouts use to type additional characters
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// What a key press means once the layout and modifiers are taken into account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A key that types a character.
+    Unicode(char),
+    /// A letter pressed while holding down ctrl, e.g. `Ctrl('c')` for Ctrl+C.
+    Ctrl(char),
+    /// A key that doesn't type anything, like the arrow or function keys.
+    Raw(KeyCode),
+}
+
+/// A key being pressed or released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The physical key, independent of the layout.
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    /// The decoded key, only present when a key is pressed.
+    pub key: Option<Key>,
+}
+
+/// Turns scancodes into key events using the selected layout.
+struct KeyDecoder {
+    keyboard: Keyboard<AnyLayout, ScancodeSet1>,
+    layout: Layout,
+    // pc_keyboard keeps its modifiers to itself, so we follow the keys the same way it does
+    held: pc_keyboard::Modifiers,
+    // and it doesn't keep track of the left alt key and scroll lock at all
+    alt: bool,
+    scroll_lock: bool,
+}
+
+impl KeyDecoder {
+    fn new(layout: Layout) -> Self {
+        Self {
+            keyboard: Self::keyboard(layout),
+            layout,
+            // num lock starts out on, like in the decoder of pc_keyboard
+            held: pc_keyboard::Modifiers {
+                numlock: true,
+                ..Default::default()
+            },
+            alt: false,
+            scroll_lock: false,
+        }
+    }
+
+    fn keyboard(layout: Layout) -> Keyboard<AnyLayout, ScancodeSet1> {
+        Keyboard::new(
+            ScancodeSet1::new(),
+            layout.to_any(),
+            // we turn ctrl combinations into `Key::Ctrl` ourselves
+            HandleControl::Ignore,
+        )
+    }
+
+    /// Switches to `layout` without losing the lock keys and the modifiers that are held down.
+    fn change_layout(&mut self, layout: Layout) {
+        let mut keyboard = Self::keyboard(layout);
+        // the new decoder only knows about a key after it has seen it, so the keys that differ
+        // from how it starts out are pressed once more
+        let held = &self.held;
+        let pressed = [
+            (held.lshift, KeyCode::LShift),
+            (held.rshift, KeyCode::RShift),
+            (held.lctrl, KeyCode::LControl),
+            (held.rctrl, KeyCode::RControl),
+            (held.alt_gr, KeyCode::RAltGr),
+            (held.capslock, KeyCode::CapsLock),
+            (!held.numlock, KeyCode::NumpadLock),
+        ];
+        for (_, code) in pressed.into_iter().filter(|(pressed, _)| *pressed) {
+            keyboard.process_keyevent(pc_keyboard::KeyEvent::new(code, KeyState::Down));
+        }
+        self.keyboard = keyboard;
+        self.layout = layout;
+    }
+
+    /// Feeds a scancode byte to the decoder, returning an event once a key has been completed.
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let raw = self.keyboard.add_byte(scancode).ok()??;
+        self.track_modifiers(raw.code, raw.state);
+
+        let decoded = self.keyboard.process_keyevent(raw.clone());
+        let modifiers = self.modifiers();
+        let key = decoded.map(|decoded| match decoded {
+            DecodedKey::Unicode(c) if modifiers.ctrl && c.is_ascii_alphabetic() => {
+                Key::Ctrl(c.to_ascii_lowercase())
+            }
+            DecodedKey::Unicode(c) => Key::Unicode(c),
+            DecodedKey::RawKey(code) => Key::Raw(code),
+        });
+
+        Some(KeyEvent {
+            code: raw.code,
+            state: raw.state,
+            modifiers,
+            key,
+        })
+    }
+
+    /// Updates the modifier keys that are held down and the lock keys.
+    fn track_modifiers(&mut self, code: KeyCode, state: KeyState) {
+        let down = match state {
+            KeyState::Down => true,
+            KeyState::Up => false,
+            KeyState::SingleShot => return,
+        };
+        let held = &mut self.held;
+        match code {
+            KeyCode::LShift => held.lshift = down,
+            KeyCode::RShift => held.rshift = down,
+            KeyCode::LControl => held.lctrl = down,
+            KeyCode::RControl => held.rctrl = down,
+            KeyCode::RControl2 => held.rctrl2 = down,
+            KeyCode::RAltGr => held.alt_gr = down,
+            KeyCode::LAlt => self.alt = down,
+            KeyCode::CapsLock if down => held.capslock = !held.capslock,
+            // Pause is the hidden right control key followed by num lock
+            KeyCode::NumpadLock if down && !held.rctrl2 => held.numlock = !held.numlock,
+            KeyCode::ScrollLock if down => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.held.is_shifted(),
+            ctrl: self.held.is_ctrl(),
+            alt: self.alt,
+            alt_gr: self.held.alt_gr,
+            caps_lock: self.held.capslock,
+            num_lock: self.held.numlock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+}
+
+/// Switches to a different keyboard layout.
+pub fn set_layout(layout: Layout) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        KEYBOARD.lock().change_layout(layout);
+    })
+}
+
+/// Returns the keyboard layout currently in use.
+pub fn layout() -> Layout {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| KEYBOARD.lock().layout)
+}
+
+/// Takes the oldest key event out of the queue, if there is one.
+pub fn read_event() -> Option<KeyEvent> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Handles a scancode read from the keyboard controller.
+///
+/// Completed key events are queued up for [`read_event`] and passed to the active terminal.
+/// Called from the keyboard interrupt handler.
+pub(crate) fn add_scancode(scancode: u8) {
+    let Some(event) = KEYBOARD.lock().add_byte(scancode) else {
+        return;
+    };
+    // there is nothing sensible we can do if nobody picks up the events
+    let _ = EVENTS.lock().push(event);
+    if event.state == KeyState::Down {
+        if let KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock = event.code {
+            update_leds(event.modifiers);
+        }
+    }
+    handle_console_keys(event);
+}
+
+/// Makes the keyboard LEDs match the lock keys.
+fn update_leds(modifiers: Modifiers) {
+    let leds = Leds {
+        scroll_lock: modifiers.scroll_lock,
+        num_lock: modifiers.num_lock,
+        caps_lock: modifiers.caps_lock,
+    };
+    // this runs in the keyboard interrupt handler, which can't wait for the keyboard to answer
+    ps2::queue_leds(leds);
+}
+
+/// Implements the keys for switching terminals, scrolling and typing into the active terminal.
+fn handle_console_keys(event: KeyEvent) {
+    use core::fmt::Write;
+
+    let Some(key) = event.key else {
+        return;
+    };
+    let modifiers = event.modifiers;
+    // keyboard input always goes to whichever terminal is on the screen
+    let term = terminal::active();
+    match key {
+        // Shift+PageUp/PageDown scroll through the console history by half a screen
+        Key::Raw(KeyCode::PageUp) if modifiers.shift => {
+            let mut writer = term.writer();
+            let half = writer.size().0 / 2;
+            writer.scroll_up(half)
+        }
+        Key::Raw(KeyCode::PageDown) if modifiers.shift => {
+            let mut writer = term.writer();
+            let half = writer.size().0 / 2;
+            writer.scroll_down(half)
+        }
+        // Alt+F1..F6 switch between the virtual terminals
+        Key::Raw(code)
+            if (modifiers.alt || modifiers.alt_gr) && terminal::index_for_key(code).is_some() =>
+        {
+            terminal::switch_to(terminal::index_for_key(code).unwrap())
+        }
+        Key::Unicode(character) => {
+            term.push_input(character);
+            // echo what was typed
+            let _ = write!(term.writer(), "{character}");
+        }
+        Key::Ctrl(letter) => {
+            // deliver the matching ASCII control character, e.g. 0x03 for Ctrl+C
+            term.push_input((letter as u8 & 0x1f) as char);
+            let _ = write!(term.writer(), "^{}", letter.to_ascii_uppercase());
+        }
+        Key::Raw(code) => {
+            let _ = write!(term.writer(), "{code:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds all `scancodes` to `decoder` and returns the last event.
+    fn feed(decoder: &mut KeyDecoder, scancodes: &[u8]) -> Option<KeyEvent> {
+        scancodes
+            .iter()
+            .fold(None, |_, &scancode| decoder.add_byte(scancode))
+    }
+
+    #[test_case]
+    fn key_down_and_up() {
+        let mut decoder = KeyDecoder::new(Layout::Us104);
+        // the `A` key
+        let down = feed(&mut decoder, &[0x1e]).unwrap();
+        assert_eq!(down.code, KeyCode::A);
+        assert_eq!(down.state, KeyState::Down);
+        assert_eq!(down.key, Some(Key::Unicode('a')));
+
+        let up = feed(&mut decoder, &[0x9e]).unwrap();
+        assert_eq!(up.state, KeyState::Up);
+        assert_eq!(up.key, None);
+    }
+
+    #[test_case]
+    fn modifiers() {
+        let mut decoder = KeyDecoder::new(Layout::Us104);
+        // left shift + `A`
+        let event = feed(&mut decoder, &[0x2a, 0x1e]).unwrap();
+        assert!(event.modifiers.shift);
+        assert_eq!(event.key, Some(Key::Unicode('A')));
+
+        // left ctrl + `C` after releasing shift
+        let event = feed(&mut decoder, &[0xaa, 0x1d, 0x2e]).unwrap();
+        assert!(event.modifiers.ctrl);
+        assert!(!event.modifiers.shift);
+        assert_eq!(event.key, Some(Key::Ctrl('c')));
+
+        // left alt + F1 after releasing ctrl
+        let event = feed(&mut decoder, &[0x9d, 0x38, 0x3b]).unwrap();
+        assert!(event.modifiers.alt);
+        assert_eq!(event.key, Some(Key::Raw(KeyCode::F1)));
+    }
+
+    #[test_case]
+    fn layouts() {
+        // the key right of `T` types a different letter depending on the layout
+        let mut decoder = KeyDecoder::new(Layout::Us104);
+        assert_eq!(
+            feed(&mut decoder, &[0x15]).unwrap().key,
+            Some(Key::Unicode('y'))
+        );
+
+        let mut decoder = KeyDecoder::new(Layout::De105);
+        assert_eq!(
+            feed(&mut decoder, &[0x15]).unwrap().key,
+            Some(Key::Unicode('z'))
+        );
+
+        let mut decoder = KeyDecoder::new(Layout::Dvorak104);
+        assert_eq!(
+            feed(&mut decoder, &[0x15]).unwrap().key,
+            Some(Key::Unicode('f'))
+        );
+    }
+
+    #[test_case]
+    fn changing_layouts_keeps_locks() {
+        let mut decoder = KeyDecoder::new(Layout::Us104);
+        // caps lock and num lock, which starts out on
+        feed(&mut decoder, &[0x3a, 0xba, 0x45, 0xc5]);
+        decoder.change_layout(Layout::De105);
+
+        let event = feed(&mut decoder, &[0x15]).unwrap();
+        assert!(event.modifiers.caps_lock);
+        assert!(!event.modifiers.num_lock);
+        assert_eq!(event.key, Some(Key::Unicode('Z')));
+        // keypad 7 is Home with num lock off
+        assert_eq!(
+            feed(&mut decoder, &[0x47]).unwrap().key,
+            Some(Key::Raw(KeyCode::Home))
+        );
+    }
+}
@@ -6,13 +6,38 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
 #[macro_use]
 pub mod serial;
 #[macro_use]
 pub mod vga_buffer;
+#[macro_use]
+pub mod terminal;
+pub mod acpi;
+pub mod ahci;
+pub mod allocator;
+pub mod apic;
+pub mod ata;
+pub mod block;
+pub mod driver;
+pub mod framebuffer;
+pub mod fs;
 pub mod gdt;
+pub mod graphics;
+pub mod initrd;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
+pub mod mouse;
+pub mod pci;
+pub mod process;
+pub mod ps2;
+pub mod ring_buffer;
+pub mod syscall;
+pub mod task;
+pub mod vfs;
+pub mod virtio;
 
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
@@ -93,8 +118,23 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
     init();
+    // some of the unit tests need a heap
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe {
+        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+    });
+    let mut frame_allocator = memory::GlobalFrameAllocator;
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    pci::init();
+    apic::init();
+    driver::init();
+    vfs::init();
+    initrd::init();
     test_main();
     hlt_loop()
 }
@@ -107,8 +147,11 @@ fn panic(info: &PanicInfo) -> ! {
 
 pub fn init() {
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::register_irq(interrupts::IRQ_TIMER, interrupts::timer_tick)
+        .expect("failed to register the timer interrupt");
     x86_64::instructions::interrupts::enable();
 }
 
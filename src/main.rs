@@ -10,7 +10,6 @@
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use wally_os::println;
-use x86_64::structures::paging::Size4KiB;
 
 // we have to implement our own panic handler since we no longer have access to the standard library
 #[cfg(not(test))]
@@ -163,24 +162,44 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     ///////////////////////////////////////////////
 
     ///////////////////////////////////////////////
-    use wally_os::memory;
-    use x86_64::{structures::paging::Page, VirtAddr};
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
     // this now works because we are able to create page tables
     // with our frame allocator :D
-    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(0xdeadbeaf0000));
+    // use x86_64::structures::paging::Page;
+    // let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(0xdeadbeaf0000));
     // WARN: can cause undefined behavior, do NOT use!
     // memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
 
     // write the string `New!` to the screen through the new mapping
-    let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
-    unsafe { page_ptr.offset(400).write_volatile(0xf021_f077_f065_f04e) };
+    // let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
+    // unsafe { page_ptr.offset(400).write_volatile(0xf021_f077_f065_f04e) };
     ///////////////////////////////////////////////
 
+    use wally_os::{allocator, apic, driver, framebuffer, initrd, memory, pci, vfs};
+    use x86_64::VirtAddr;
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe {
+        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+    });
+    let mut frame_allocator = memory::GlobalFrameAllocator;
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    pci::init();
+    // needed for message signalled interrupts
+    apic::init();
+    // this is where the keyboard and mouse start working
+    driver::init();
+    // a root filesystem in memory with the files that are built into the kernel
+    vfs::init();
+    initrd::init();
+
+    // draw the terminals on a framebuffer console if the graphics card supports it, which
+    // fits more rows and columns than the 80x25 VGA text buffer. Otherwise we just keep
+    // using the text buffer.
+    if let Err(err) = framebuffer::init(&mut mapper, &mut frame_allocator) {
+        println!("no framebuffer console: {err:?}");
+    }
+
     #[cfg(test)]
     test_main();
 
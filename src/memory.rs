@@ -1,14 +1,195 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+// where the bootloader mapped the complete physical memory, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// hands out the frames for everything after boot, set by `init_frame_allocator`
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+// the frames given back to `GlobalFrameAllocator`, each one holds the next one in its first bytes
+static FREE_FRAMES: Mutex<Option<PhysFrame>> = Mutex::new(None);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returns the virtual address through which the physical address `addr` can be accessed.
+///
+/// The bootloader maps everything up to the highest address in the memory map, which also
+/// covers the registers of the local APIC and the BARs of the PCI devices qemu emulates.
+///
+/// Only valid after [`init`] has been called.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    debug_assert_ne!(offset, 0, "memory::init has not been called yet");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// Makes `allocator` the allocator behind [`GlobalFrameAllocator`] and [`DmaBuffer`].
+pub fn init_frame_allocator(allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Allocates `count` physically contiguous frames and returns the first one.
+///
+/// Frames that get skipped because they don't fit in front of the next one are given to
+/// [`GlobalFrameAllocator`], which hands them out again one by one.
+pub fn allocate_contiguous_frames(count: usize) -> Option<PhysFrame> {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard
+        .as_mut()
+        .expect("memory::init_frame_allocator has not been called");
+    let mut first = allocator.allocate_frame()?;
+    let mut found = 1;
+    while found < count {
+        let Some(frame) = allocator.allocate_frame() else {
+            unsafe { free_frames(first, found) };
+            return None;
+        };
+        if frame == first + found as u64 {
+            found += 1;
+        } else {
+            unsafe { free_frames(first, found) };
+            first = frame;
+            found = 1;
+        }
+    }
+    Some(first)
+}
+
+/// Gives `count` frames starting at `first` back to [`GlobalFrameAllocator`].
+unsafe fn free_frames(first: PhysFrame, count: usize) {
+    for frame in PhysFrame::range(first, first + count as u64) {
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+}
+
+/// A frame allocator that can be used from anywhere once [`init_frame_allocator`] has been
+/// called. Frames that are given back are handed out again before any new ones.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let mut free = FREE_FRAMES.lock();
+        if let Some(frame) = *free {
+            let link = phys_to_virt(frame.start_address()).as_ptr::<Option<PhysFrame>>();
+            *free = unsafe { link.read() };
+            return Some(frame);
+        }
+        drop(free);
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .expect("memory::init_frame_allocator has not been called")
+            .allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    /// Gives back a frame. The caller has to make sure that nothing uses it anymore.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let mut free = FREE_FRAMES.lock();
+        let link = phys_to_virt(frame.start_address()).as_mut_ptr::<Option<PhysFrame>>();
+        link.write(*free);
+        *free = Some(frame);
+    }
+}
+
+/// Zeroed, physically contiguous memory that devices can access directly.
+///
+/// The frames are given back when the buffer is dropped, so the device has to be done with it
+/// by then.
+pub struct DmaBuffer {
+    start: PhysAddr,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `size` bytes, aligned to a frame.
+    pub fn new(size: usize) -> Option<Self> {
+        let frames = size.div_ceil(4096).max(1);
+        let start = allocate_contiguous_frames(frames)?.start_address();
+        let size = frames * 4096;
+        unsafe { core::ptr::write_bytes(phys_to_virt(start).as_mut_ptr::<u8>(), 0, size) };
+        Some(Self { start, size })
+    }
+
+    /// The physical address the device has to be told about.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// A pointer to `offset` bytes into the buffer.
+    pub fn as_mut_ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size);
+        (phys_to_virt(self.start) + offset as u64).as_mut_ptr()
+    }
+
+    /// The contents of the buffer.
+    ///
+    /// The device can change the memory behind our back, so this should only be looked at
+    /// while the device isn't using it.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(0), self.size) }
+    }
+
+    /// Copies the bytes at `offset` into `buffer`, for buffers that are shared.
+    pub fn read_at(&self, offset: usize, buffer: &mut [u8]) {
+        assert!(offset + buffer.len() <= self.size);
+        // no reference to the memory is handed out, so the buffer doesn't have to be borrowed
+        // mutably for this to be sound
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.as_mut_ptr::<u8>(0).add(offset),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            )
+        };
+    }
+
+    /// Copies `data` to `offset` bytes into the buffer, for buffers that are shared.
+    pub fn write_at(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.size);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.as_mut_ptr::<u8>(0).add(offset),
+                data.len(),
+            )
+        };
+    }
+
+    /// Keeps the memory from being given back, for when the device can't be stopped and
+    /// might still use it.
+    pub fn leak(&mut self) {
+        self.size = 0;
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let first = PhysFrame::containing_address(self.start);
+        unsafe { free_frames(first, self.size / 4096) };
+    }
+}
+
 // 1. This is unsafe because the caller must guarantee that the complete physical memory is mapped
 //    to virtual memory at the passed offset.
 // 2. This function must only be called once to avoid aliasing `&mut` references which is
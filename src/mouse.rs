@@ -0,0 +1,379 @@
+//! Driver for PS/2 mice attached to the second port of the controller.
+//!
+//! See <https://wiki.osdev.org/PS/2_Mouse> for the packet format.
+
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::interrupts::{register_irq, unregister_irq, IrqHandle, IRQ_MOUSE};
+use crate::ps2::{self, Channel, Ps2Error};
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// The number of mouse events that are buffered before new ones get dropped.
+const EVENT_QUEUE_SIZE: usize = 100;
+
+// mouse commands
+const GET_ID: u8 = 0xf2;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_REPORTING: u8 = 0xf4;
+const DISABLE_REPORTING: u8 = 0xf5;
+const SET_DEFAULTS: u8 = 0xf6;
+
+/// The id a mouse reports once its scroll wheel has been enabled.
+const INTELLIMOUSE_ID: u8 = 3;
+
+// bits of the first byte of a packet
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+// always set, which lets us find the start of a packet again if we lost track
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new(false));
+static EVENT_QUEUE: OnceCell<ArrayQueue<MouseEvent>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+pub static DRIVER: MouseDriver = MouseDriver;
+static MOUSE_IRQ: Mutex<Option<IrqHandle>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// A change in the position, scroll wheel or buttons of the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    /// Horizontal movement, positive to the right.
+    pub dx: i16,
+    /// Vertical movement, positive downwards like screen coordinates.
+    pub dy: i16,
+    /// Scroll wheel movement, positive when scrolling down. Always 0 for mice without a wheel.
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+/// Puts together the bytes the mouse sends into events.
+pub struct PacketDecoder {
+    packet: [u8; 4],
+    received: usize,
+    wheel: bool,
+}
+
+impl PacketDecoder {
+    /// Mice with a scroll wheel send 4 byte packets instead of 3.
+    pub const fn new(wheel: bool) -> Self {
+        Self {
+            packet: [0; 4],
+            received: 0,
+            wheel,
+        }
+    }
+
+    pub fn packet_size(&self) -> usize {
+        if self.wheel {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// Adds a byte sent by the mouse, returning an event once a packet is complete.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // drop bytes until we see something that looks like the start of a packet
+        if self.received == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size() {
+            return None;
+        }
+        self.received = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let [flags, x, y, z] = self.packet;
+        // the movement is a 9 bit two's complement number, with the sign bit in the flags.
+        // When it overflowed there's no telling how far the mouse actually moved.
+        let movement = |value: u8, sign: u8, overflow: u8| match flags & overflow {
+            0 => i16::from(value) - (i16::from(flags & sign != 0) << 8),
+            _ => 0,
+        };
+        MouseEvent {
+            dx: movement(x, X_SIGN, X_OVERFLOW),
+            // the mouse counts upwards movement as positive
+            dy: -movement(y, Y_SIGN, Y_OVERFLOW),
+            wheel: if self.wheel {
+                // the lower 4 bits are a signed number, the rest are extra buttons we don't support
+                ((z << 4) as i8) >> 4
+            } else {
+                0
+            },
+            buttons: Buttons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+            },
+        }
+    }
+}
+
+/// Sets up the mouse on the second PS/2 port.
+///
+/// Has to be called after [`ps2::init`], the driver model takes care of that.
+pub fn init() -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    let wheel = interrupts::without_interrupts(|| {
+        let mut controller = ps2::CONTROLLER.lock();
+        controller.device_command(Channel::Second, SET_DEFAULTS, None)?;
+
+        // the magic knock that enables the scroll wheel of IntelliMouse compatible mice
+        for rate in [200, 100, 80] {
+            controller.device_command(Channel::Second, SET_SAMPLE_RATE, Some(rate))?;
+        }
+        controller.device_command(Channel::Second, GET_ID, None)?;
+        let wheel = controller.read_data()? == INTELLIMOUSE_ID;
+
+        controller.device_command(Channel::Second, ENABLE_REPORTING, None)?;
+        Ok(wheel)
+    })?;
+    interrupts::without_interrupts(|| *DECODER.lock() = PacketDecoder::new(wheel));
+    Ok(())
+}
+
+/// The driver for the mouse. Relies on the keyboard driver to set up the controller.
+pub struct MouseDriver;
+
+impl Driver for MouseDriver {
+    fn name(&self) -> &'static str {
+        "ps2-mouse"
+    }
+
+    fn match_table(&self) -> &'static [Match] {
+        &[Match::Isa("PNP0F13")]
+    }
+
+    fn probe(&self, _device: &Device) -> Result<(), DriverError> {
+        let irq = register_irq(IRQ_MOUSE, mouse_irq)
+            .map_err(|_| DriverError::Hardware("IRQ 12 is taken"))?;
+        match init() {
+            Ok(()) => {
+                *MOUSE_IRQ.lock() = Some(irq);
+                Ok(())
+            }
+            Err(err) => {
+                unregister_irq(irq);
+                Err(err.into())
+            }
+        }
+    }
+
+    fn remove(&self, _device: &Device) {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut controller = ps2::CONTROLLER.lock();
+            let _ = controller.device_command(Channel::Second, DISABLE_REPORTING, None);
+            let _ = controller.disable_port(Channel::Second);
+        });
+        if let Some(irq) = MOUSE_IRQ.lock().take() {
+            unregister_irq(irq);
+        }
+    }
+
+    fn suspend(&self, _device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| ps2::CONTROLLER.lock().disable_port(Channel::Second))?;
+        Ok(())
+    }
+
+    fn resume(&self, _device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| ps2::CONTROLLER.lock().enable_port(Channel::Second))?;
+        Ok(())
+    }
+}
+
+/// Whether the mouse has a scroll wheel.
+pub fn has_wheel() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| DECODER.lock().wheel)
+}
+
+/// How many events were dropped because the queue of the [`MouseStream`] was full.
+pub fn dropped_events() -> u64 {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+fn mouse_irq() {
+    // the mouse shares the data port of the PS/2 controller with the keyboard
+    ps2::handle_data();
+}
+
+/// Handles a byte read from the mouse. Called from the PS/2 interrupt handlers.
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    let Some(event) = DECODER.lock().add_byte(byte) else {
+        return;
+    };
+    if let Ok(queue) = EVENT_QUEUE.try_get() {
+        if queue.push(event).is_err() {
+            // printing from here could deadlock on the serial port
+            DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WAKER.wake();
+        }
+    }
+    // events are dropped until someone creates a `MouseStream`
+}
+
+/// The events of the mouse as an asynchronous stream.
+pub struct MouseStream {
+    _private: (),
+}
+
+impl MouseStream {
+    /// # Panics
+    /// There can only be one stream, since every event is only delivered once.
+    pub fn new() -> Self {
+        EVENT_QUEUE
+            .try_init_once(|| ArrayQueue::new(EVENT_QUEUE_SIZE))
+            .expect("MouseStream::new should only be called once");
+        Self { _private: () }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<MouseEvent>> {
+        let queue = EVENT_QUEUE.try_get().expect("event queue not initialized");
+
+        // fast path
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        // an event might arrive between the first check and registering the waker
+        WAKER.register(context.waker());
+        match queue.pop() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::task::noop_waker_ref;
+
+    /// Feeds all `bytes` to `decoder` and collects the events.
+    fn feed(decoder: &mut PacketDecoder, bytes: &[u8]) -> alloc::vec::Vec<MouseEvent> {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.add_byte(byte))
+            .collect()
+    }
+
+    #[test_case]
+    fn movement() {
+        let mut decoder = PacketDecoder::new(false);
+        // 5 to the right and 3 up
+        let events = feed(&mut decoder, &[0x08, 5, 3]);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].dx, events[0].dy), (5, -3));
+
+        // 2 to the left and 256 down, which is as far as a packet goes
+        let events = feed(&mut decoder, &[0x08 | X_SIGN | Y_SIGN, 0xfe, 0x00]);
+        assert_eq!((events[0].dx, events[0].dy), (-2, 256));
+
+        // overflowed movement gets dropped
+        let events = feed(&mut decoder, &[0x08 | X_OVERFLOW, 0x10, 0x01]);
+        assert_eq!((events[0].dx, events[0].dy), (0, -1));
+    }
+
+    #[test_case]
+    fn buttons() {
+        let mut decoder = PacketDecoder::new(false);
+        let events = feed(&mut decoder, &[0x08 | LEFT_BUTTON | MIDDLE_BUTTON, 0, 0]);
+        assert_eq!(
+            events[0].buttons,
+            Buttons {
+                left: true,
+                right: false,
+                middle: true,
+            }
+        );
+    }
+
+    #[test_case]
+    fn resynchronizes() {
+        let mut decoder = PacketDecoder::new(false);
+        // the stray zero can't be the start of a packet, so it gets dropped
+        let events = feed(&mut decoder, &[0x00, 0x08 | RIGHT_BUTTON, 1, 1]);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].buttons.right);
+    }
+
+    #[test_case]
+    fn scroll_wheel() {
+        let mut decoder = PacketDecoder::new(true);
+        assert_eq!(decoder.packet_size(), 4);
+        // three bytes aren't a complete packet anymore
+        assert!(feed(&mut decoder, &[0x08, 0, 0]).is_empty());
+        let events = feed(&mut decoder, &[0x0f, 0x08, 0, 0, 0x01]);
+        assert_eq!(events[0].wheel, -1);
+        assert_eq!(events[1].wheel, 1);
+    }
+
+    #[test_case]
+    fn stream() {
+        let mut stream = MouseStream::new();
+        let mut context = Context::from_waker(noop_waker_ref());
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+
+        // pretend the mouse moved
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            // finish whatever packet the decoder is in the middle of
+            while DECODER.lock().received != 0 {
+                add_byte(0);
+            }
+            for byte in [0x08, 1, 0] {
+                add_byte(byte);
+            }
+        });
+        match Pin::new(&mut stream).poll_next(&mut context) {
+            Poll::Ready(Some(event)) => assert_eq!(event.dx, 1),
+            other => panic!("expected a mouse event, got {other:?}"),
+        }
+    }
+}
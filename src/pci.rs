@@ -0,0 +1,677 @@
+//! Discovers and configures the devices on the PCI bus.
+//!
+//! See <https://wiki.osdev.org/PCI> and <https://wiki.osdev.org/PCI_Express>.
+
+use crate::acpi;
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+pub mod msi;
+
+// the legacy configuration mechanism: write an address to the first port, then access the
+// selected register through the second one
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// offsets in the configuration space header
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+/// The vendor id that reads back when there is no device.
+const NO_VENDOR: u16 = 0xffff;
+const MULTI_FUNCTION: u8 = 0x80;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+// bits of the command register
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+// bits of a base address register
+const BAR_IO: u32 = 1 << 0;
+const BAR_64BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Capability ids, see the PCI Code and ID Assignment Specification.
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAP_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// A malformed capability list could otherwise loop forever.
+const MAX_CAPABILITIES: usize = 48;
+
+static CONFIG_PORTS: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+// memory mapped configuration space, if the firmware told us about one
+static ECAM: Mutex<Option<Ecam>> = Mutex::new(None);
+
+/// A memory mapped configuration space region described by the ACPI MCFG table.
+#[derive(Debug, Clone, Copy)]
+struct Ecam {
+    base: PhysAddr,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl Ecam {
+    /// Looks for the region of the first PCI segment in the MCFG table.
+    fn from_acpi() -> Option<Self> {
+        // the entries follow the header and 8 reserved bytes
+        const ENTRIES: usize = acpi::SDT_HEADER_SIZE + 8;
+        const ENTRY_SIZE: usize = 16;
+
+        let mcfg = acpi::find_table(b"MCFG")?;
+        let entries = (acpi::table_length(mcfg) - ENTRIES) / ENTRY_SIZE;
+        (0..entries)
+            .map(|i| mcfg + (ENTRIES + i * ENTRY_SIZE) as u64)
+            .find(|&entry| unsafe { acpi::read::<u16>(entry + 8u64) } == 0)
+            .map(|entry| unsafe {
+                Ecam {
+                    base: PhysAddr::new(acpi::read(entry)),
+                    start_bus: acpi::read(entry + 10u64),
+                    end_bus: acpi::read(entry + 11u64),
+                }
+            })
+    }
+
+    /// Returns the physical address of a register if the device's bus is in this region.
+    fn register(&self, address: PciAddress, offset: u16) -> Option<PhysAddr> {
+        if !(self.start_bus..=self.end_bus).contains(&address.bus) {
+            return None;
+        }
+        let bus = u64::from(address.bus - self.start_bus);
+        let device = u64::from(address.device);
+        let function = u64::from(address.function);
+        Some(self.base + (bus << 20 | device << 15 | function << 12 | u64::from(offset)))
+    }
+}
+
+/// Switches to memory mapped configuration space access when ACPI describes it,
+/// which also makes the extended configuration space of PCI Express devices available.
+///
+/// Has to be called after [`crate::memory::init`]. Until then, and on machines without
+/// the MCFG table, the legacy I/O ports are used.
+pub fn init() {
+    let ecam = Ecam::from_acpi();
+    *ECAM.lock() = ecam;
+}
+
+/// The location of a function on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Reads a 32 bit register from the configuration space of a function.
+/// `offset` gets rounded down to a multiple of 4.
+pub fn read_config(address: PciAddress, offset: u16) -> u32 {
+    use x86_64::instructions::interrupts;
+
+    let offset = offset & !0b11;
+    interrupts::without_interrupts(|| {
+        if let Some(register) = ECAM.lock().and_then(|ecam| ecam.register(address, offset)) {
+            let register = phys_to_virt(register).as_ptr::<u32>();
+            return unsafe { register.read_volatile() };
+        }
+        // the legacy mechanism only reaches the first 256 bytes
+        if offset >= 0x100 {
+            return u32::MAX;
+        }
+        let mut ports = CONFIG_PORTS.lock();
+        unsafe {
+            ports.0.write(legacy_address(address, offset));
+            ports.1.read()
+        }
+    })
+}
+
+/// Writes a 32 bit register in the configuration space of a function.
+/// `offset` gets rounded down to a multiple of 4.
+pub fn write_config(address: PciAddress, offset: u16, value: u32) {
+    use x86_64::instructions::interrupts;
+
+    let offset = offset & !0b11;
+    interrupts::without_interrupts(|| {
+        if let Some(register) = ECAM.lock().and_then(|ecam| ecam.register(address, offset)) {
+            let register = phys_to_virt(register).as_mut_ptr::<u32>();
+            unsafe { register.write_volatile(value) };
+            return;
+        }
+        if offset >= 0x100 {
+            return;
+        }
+        let mut ports = CONFIG_PORTS.lock();
+        unsafe {
+            ports.0.write(legacy_address(address, offset));
+            ports.1.write(value);
+        }
+    })
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    let bus = u32::from(address.bus);
+    let device = u32::from(address.device);
+    let function = u32::from(address.function);
+    // the top bit enables the access
+    0x8000_0000 | bus << 16 | device << 11 | function << 8 | u32::from(offset)
+}
+
+/// What a base address register decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+        /// 64 bit BARs take up two registers.
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                is_64bit,
+            } => {
+                let width = if is_64bit { 64 } else { 32 };
+                let prefetchable = if prefetchable {
+                    "prefetchable"
+                } else {
+                    "non-prefetchable"
+                };
+                write!(
+                    f,
+                    "Memory at {:x} ({width}-bit, {prefetchable}) [size=",
+                    address.as_u64()
+                )?;
+                write_size(f, size)?;
+                write!(f, "]")
+            }
+            Bar::Io { port, size } => write!(f, "I/O ports at {port:04x} [size={size}]"),
+        }
+    }
+}
+
+/// Writes a size like lspci does, e.g. `16M`.
+fn write_size(f: &mut fmt::Formatter<'_>, size: u64) -> fmt::Result {
+    const UNITS: [&str; 4] = ["", "K", "M", "G"];
+    let mut size = size;
+    let mut unit = 0;
+    while size >= 1024 && size & 1023 == 0 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    write!(f, "{size}{}", UNITS[unit])
+}
+
+/// An entry in the capability list of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in the configuration space.
+    pub offset: u16,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            CAP_POWER_MANAGEMENT => "Power Management",
+            CAP_MSI => "MSI",
+            CAP_VENDOR_SPECIFIC => "Vendor Specific Information",
+            CAP_EXPRESS => "Express",
+            CAP_MSIX => "MSI-X",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Iterates over the capability list of a function.
+pub struct Capabilities {
+    address: PciAddress,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // the bottom two bits are reserved
+        let offset = u16::from(self.next & !0b11);
+        if offset == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = read_config(self.address, offset);
+        self.next = (header >> 8) as u8;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+/// A function of a device on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// The layout of the rest of the header, 0 for regular devices and 1 for PCI-to-PCI bridges.
+    pub header_type: u8,
+}
+
+impl PciDevice {
+    /// Reads the header of a function, if it exists.
+    pub fn new(address: PciAddress) -> Option<Self> {
+        let id = read_config(address, VENDOR_ID);
+        let vendor_id = id as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+        let class = read_config(address, REVISION);
+        let header_type = (read_config(address, HEADER_TYPE & !0b11) >> 16) as u8;
+        Some(Self {
+            address,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: header_type & !MULTI_FUNCTION,
+        })
+    }
+
+    pub fn read(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    pub fn write(&self, offset: u16, value: u32) {
+        write_config(self.address, offset, value)
+    }
+
+    /// Reads a 16 bit register, `offset` has to be a multiple of 2.
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read(offset) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    /// Writes a 16 bit register, `offset` has to be a multiple of 2.
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 0b10) * 8;
+        let register = self.read(offset) & !(0xffff << shift);
+        self.write(offset, register | u32::from(value) << shift)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read(offset) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        // the status register next to it is cleared by writing ones, so only write zeros to it
+        self.write(COMMAND, u32::from(command))
+    }
+
+    pub fn status(&self) -> u16 {
+        self.read_u16(STATUS)
+    }
+
+    /// Lets the device respond to accesses of its BARs and do DMA.
+    pub fn enable(&self) {
+        self.set_command(
+            self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        )
+    }
+
+    /// The legacy IRQ the firmware routed the device's interrupt to.
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u8(INTERRUPT_LINE)
+    }
+
+    /// Which of the INTA#..INTD# pins the device uses, or 0 if it doesn't use any.
+    pub fn interrupt_pin(&self) -> u8 {
+        self.read_u8(INTERRUPT_PIN)
+    }
+
+    pub fn is_multi_function(&self) -> bool {
+        read_config(self.address, HEADER_TYPE & !0b11) >> 16 & u32::from(MULTI_FUNCTION) != 0
+    }
+
+    /// The number of BAR registers in the header.
+    pub fn bar_count(&self) -> usize {
+        match self.header_type {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Decodes the BAR register at `index` and finds out its size.
+    ///
+    /// Returns `None` for unused registers. Calling this for the upper half of a 64 bit BAR
+    /// returns garbage, [`PciDevice::bars`] skips those.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        if index >= self.bar_count() {
+            return None;
+        }
+        let offset = BAR0 + index as u16 * 4;
+        let value = self.read(offset);
+
+        // the device must not decode addresses while we write all ones to its BARs
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let bar = if value & BAR_IO != 0 {
+            let mask = self.probe(offset) & !0b11;
+            let size = (!mask as u16).wrapping_add(1);
+            (mask != 0).then_some(Bar::Io {
+                port: (value & !0b11) as u16,
+                size,
+            })
+        } else {
+            let is_64bit = value & BAR_64BIT != 0;
+            let mut address = u64::from(value & !0xf);
+            let mut mask = u64::from(self.probe(offset) & !0xf) | 0xffff_ffff_0000_0000;
+            if is_64bit {
+                address |= u64::from(self.read(offset + 4)) << 32;
+                mask = mask & 0xffff_ffff | u64::from(self.probe(offset + 4)) << 32;
+            }
+            (mask as u32 != 0).then_some(Bar::Memory {
+                address: PhysAddr::new(address),
+                size: (!mask).wrapping_add(1),
+                prefetchable: value & BAR_PREFETCHABLE != 0,
+                is_64bit,
+            })
+        };
+        self.set_command(command);
+        bar
+    }
+
+    /// Writes all ones to a BAR register and returns which bits stuck, which tells us the size
+    /// of the region. The original value gets restored afterwards.
+    fn probe(&self, offset: u16) -> u32 {
+        let original = self.read(offset);
+        self.write(offset, u32::MAX);
+        let mask = self.read(offset);
+        self.write(offset, original);
+        mask
+    }
+
+    /// Returns all BARs the device uses along with their register index.
+    pub fn bars(&self) -> impl Iterator<Item = (usize, Bar)> + '_ {
+        let mut index = 0;
+        core::iter::from_fn(move || {
+            while index < self.bar_count() {
+                let current = index;
+                let bar = self.bar(current);
+                index += match bar {
+                    Some(Bar::Memory { is_64bit: true, .. }) => 2,
+                    _ => 1,
+                };
+                if let Some(bar) = bar {
+                    return Some((current, bar));
+                }
+            }
+            None
+        })
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        let first = if self.status() & STATUS_CAPABILITIES != 0 {
+            self.read_u8(CAPABILITIES_POINTER)
+        } else {
+            0
+        };
+        Capabilities {
+            address: self.address,
+            next: first,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Returns the offset of the first capability with the given id.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// A human readable name of the device class, like lspci shows it.
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x00, 0x01) => "VGA compatible unclassified device",
+            (0x00, _) => "Non-VGA unclassified device",
+            (0x01, 0x00) => "SCSI storage controller",
+            (0x01, 0x01) => "IDE interface",
+            (0x01, 0x05) => "ATA controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "Non-Volatile memory controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA compatible controller",
+            (0x03, _) => "Display controller",
+            (0x04, 0x03) => "Audio device",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x09, _) => "Input device controller",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus",
+            (0x0c, _) => "Serial bus controller",
+            _ => "Unclassified device",
+        }
+    }
+}
+
+impl fmt::Display for PciDevice {
+    /// Formats the device like a line of `lspci -nn`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            self.address,
+            self.class_name(),
+            self.class,
+            self.subclass,
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )
+    }
+}
+
+/// Finds all functions on all PCI buses.
+pub fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    // bus 0 always exists, the others are found through the bridges on it
+    scan_bus(0, &mut devices);
+    devices
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let Some(first) = PciDevice::new(PciAddress::new(bus, device, 0)) else {
+            continue;
+        };
+        let functions = if first.is_multi_function() { 8 } else { 1 };
+        for function in 0..functions {
+            let Some(function) = PciDevice::new(PciAddress::new(bus, device, function)) else {
+                continue;
+            };
+            devices.push(function);
+            if function.header_type == 1 {
+                let secondary_bus = function.read_u8(SECONDARY_BUS);
+                // a bridge that hasn't been configured by the firmware doesn't lead anywhere
+                if secondary_bus > bus {
+                    scan_bus(secondary_bus, devices);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the first function with the given vendor and device id.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    scan()
+        .into_iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// Prints all devices along with their BARs and capabilities, like `lspci -vnn`.
+pub fn dump() {
+    for device in scan() {
+        println!("{device}");
+        if device.interrupt_pin() != 0 {
+            println!(
+                "\tInterrupt: pin {} routed to IRQ {}",
+                (b'A' + device.interrupt_pin() - 1) as char,
+                device.interrupt_line()
+            );
+        }
+        for (_, bar) in device.bars() {
+            println!("\t{bar}");
+        }
+        for capability in device.capabilities() {
+            println!(
+                "\tCapabilities: [{:02x}] {}",
+                capability.offset,
+                capability.name()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    // qemu's default machine is an i440FX board with a PIIX3 southbridge
+
+    #[test_case]
+    fn finds_default_devices() {
+        let devices = scan();
+        let host_bridge = devices
+            .iter()
+            .find(|device| device.address == PciAddress::new(0, 0, 0))
+            .expect("no host bridge");
+        assert_eq!(
+            (host_bridge.vendor_id, host_bridge.device_id),
+            (0x8086, 0x1237)
+        );
+        assert_eq!(host_bridge.class_name(), "Host bridge");
+        assert!(host_bridge
+            .to_string()
+            .starts_with("00:00.0 Host bridge [0600]: 8086:1237"));
+
+        // the PIIX3 has an ISA bridge, an IDE controller and an ACPI function
+        let piix = devices.iter().filter(|device| device.address.device == 1);
+        assert!(piix.count() >= 3);
+    }
+
+    #[test_case]
+    fn memory_bar() {
+        let vga = find(0x1234, 0x1111).expect("no VGA card");
+        match vga.bar(0) {
+            Some(Bar::Memory {
+                size, prefetchable, ..
+            }) => {
+                assert_eq!(size, 16 * 1024 * 1024);
+                assert!(prefetchable);
+            }
+            other => panic!("unexpected BAR0: {other:?}"),
+        }
+        // probing must not change where the BAR points
+        assert_eq!(vga.bar(0), vga.bar(0));
+    }
+
+    #[test_case]
+    fn io_bar() {
+        let ide = find(0x8086, 0x7010).expect("no IDE controller");
+        // the bus master registers
+        match ide.bar(4) {
+            Some(Bar::Io { size, .. }) => assert_eq!(size, 16),
+            other => panic!("unexpected BAR4: {other:?}"),
+        }
+        assert!(ide.bars().all(|(index, _)| index < 6));
+    }
+
+    #[test_case]
+    fn capability_lists() {
+        for device in scan() {
+            for capability in device.capabilities() {
+                // capabilities live after the standard header
+                assert!(capability.offset >= 0x40);
+                assert_eq!(capability.offset % 4, 0);
+            }
+        }
+    }
+
+    #[test_case]
+    fn bar_sizes() {
+        let bar = Bar::Memory {
+            address: PhysAddr::new(0xfd00_0000),
+            size: 16 * 1024 * 1024,
+            prefetchable: true,
+            is_64bit: false,
+        };
+        assert_eq!(
+            bar.to_string(),
+            "Memory at fd000000 (32-bit, prefetchable) [size=16M]"
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Message signalled interrupts, which let PCI devices raise an interrupt vector of their own
+//! by writing to the local APIC instead of sharing the legacy IRQ lines.
+//!
+//! See <https://wiki.osdev.org/PCI#Message_Signaled_Interrupts>.
+
+use super::{Bar, PciDevice, CAP_MSI, CAP_MSIX, COMMAND_INTERRUPT_DISABLE, COMMAND_MEMORY_SPACE};
+use crate::apic;
+use crate::interrupts::{self, IrqError};
+use crate::memory::phys_to_virt;
+use x86_64::VirtAddr;
+
+// offsets in the MSI capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+// the data register moves back when the address is 64 bits wide
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0c;
+
+// bits of the MSI message control register
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_CAPABLE: u16 = 0b111 << 1;
+const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+// offsets in the MSI-X capability
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+
+// bits of the MSI-X message control register
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+// layout of an MSI-X table entry
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS: u64 = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: u64 = 0x4;
+const MSIX_ENTRY_DATA: u64 = 0x8;
+const MSIX_ENTRY_CONTROL: u64 = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device has neither an MSI nor an MSI-X capability.
+    Unsupported,
+    /// The MSI-X table isn't inside a memory BAR.
+    InvalidTable,
+    /// The MSI-X table doesn't have an entry with this index.
+    InvalidEntry(usize),
+    Irq(IrqError),
+}
+
+impl From<IrqError> for MsiError {
+    fn from(err: IrqError) -> Self {
+        MsiError::Irq(err)
+    }
+}
+
+/// What a device writes where to raise an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// A message that raises `vector` on the CPU whose local APIC has the id `apic_id`,
+    /// edge triggered and with fixed delivery.
+    pub fn new(apic_id: u8, vector: u8) -> Self {
+        Self {
+            address: apic::MSI_ADDRESS | u64::from(apic_id) << 12,
+            data: u32::from(vector),
+        }
+    }
+}
+
+/// The MSI capability of a device.
+///
+/// Devices can ask for several vectors through MSI, but they have to be contiguous and
+/// aligned, so we only ever give them one.
+pub struct Msi {
+    device: PciDevice,
+    offset: u16,
+}
+
+impl Msi {
+    pub fn new(device: PciDevice) -> Option<Self> {
+        let offset = device.find_capability(CAP_MSI)?;
+        Some(Self { device, offset })
+    }
+
+    /// How many vectors the device would like to have.
+    pub fn requested_vectors(&self) -> usize {
+        1 << ((self.control() & MSI_MULTIPLE_CAPABLE) >> 1)
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.control() & MSI_64BIT != 0
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control() & MSI_ENABLE != 0
+    }
+
+    /// Programs the message and switches the device from its legacy IRQ line over to MSI.
+    pub fn enable(&self, message: MsiMessage) {
+        let device = &self.device;
+        device.write(self.offset + MSI_ADDRESS, message.address as u32);
+        if self.is_64bit() {
+            device.write(
+                self.offset + MSI_ADDRESS_HIGH,
+                (message.address >> 32) as u32,
+            );
+        }
+        device.write_u16(self.data_offset(), message.data as u16);
+
+        let control = self.control() & !MSI_MULTIPLE_ENABLE | MSI_ENABLE;
+        device.write_u16(self.offset + MSI_CONTROL, control);
+        device.set_command(device.command() | COMMAND_INTERRUPT_DISABLE);
+    }
+
+    /// Switches the device back to its legacy IRQ line.
+    pub fn disable(&self) {
+        let device = &self.device;
+        device.write_u16(self.offset + MSI_CONTROL, self.control() & !MSI_ENABLE);
+        device.set_command(device.command() & !COMMAND_INTERRUPT_DISABLE);
+    }
+
+    /// Reads back the programmed message.
+    pub fn message(&self) -> MsiMessage {
+        let device = &self.device;
+        let mut address = u64::from(device.read(self.offset + MSI_ADDRESS));
+        if self.is_64bit() {
+            address |= u64::from(device.read(self.offset + MSI_ADDRESS_HIGH)) << 32;
+        }
+        MsiMessage {
+            address,
+            data: device.read_u16(self.data_offset()).into(),
+        }
+    }
+
+    fn control(&self) -> u16 {
+        self.device.read_u16(self.offset + MSI_CONTROL)
+    }
+
+    fn data_offset(&self) -> u16 {
+        self.offset
+            + if self.is_64bit() {
+                MSI_DATA_64
+            } else {
+                MSI_DATA_32
+            }
+    }
+}
+
+/// The MSI-X capability of a device, which gives every interrupt source of the device
+/// an entry in a table inside one of its BARs.
+pub struct MsiX {
+    device: PciDevice,
+    offset: u16,
+    table: VirtAddr,
+}
+
+impl MsiX {
+    pub fn new(device: PciDevice) -> Result<Self, MsiError> {
+        let offset = device
+            .find_capability(CAP_MSIX)
+            .ok_or(MsiError::Unsupported)?;
+        // the lower 3 bits select the BAR, the rest is the offset into it
+        let table = device.read(offset + MSIX_TABLE);
+        let address = match device.bar((table & 0b111) as usize) {
+            Some(Bar::Memory { address, .. }) => address + u64::from(table & !0b111),
+            _ => return Err(MsiError::InvalidTable),
+        };
+        Ok(Self {
+            device,
+            offset,
+            // the BARs are in the range the bootloader mapped along with the physical memory
+            table: phys_to_virt(address),
+        })
+    }
+
+    /// The number of entries in the table.
+    pub fn table_size(&self) -> usize {
+        usize::from(self.control() & MSIX_TABLE_SIZE) + 1
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control() & MSIX_ENABLE != 0
+    }
+
+    /// Programs an entry of the table and unmasks it.
+    ///
+    /// The table is only reachable while the device decodes its memory BARs, see [`enable`].
+    ///
+    /// [`enable`]: MsiX::enable
+    pub fn set_entry(&self, index: usize, message: MsiMessage) -> Result<(), MsiError> {
+        self.write_message(index, message)?;
+        self.set_masked(index, false)
+    }
+
+    // programs the message of an entry without touching its mask
+    fn write_message(&self, index: usize, message: MsiMessage) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        unsafe {
+            write(entry + MSIX_ENTRY_ADDRESS, message.address as u32);
+            write(
+                entry + MSIX_ENTRY_ADDRESS_HIGH,
+                (message.address >> 32) as u32,
+            );
+            write(entry + MSIX_ENTRY_DATA, message.data);
+        }
+        Ok(())
+    }
+
+    /// Reads back the message of an entry and whether it's masked.
+    pub fn get_entry(&self, index: usize) -> Result<(MsiMessage, bool), MsiError> {
+        let entry = self.entry(index)?;
+        unsafe {
+            let address = u64::from(read(entry + MSIX_ENTRY_ADDRESS))
+                | u64::from(read(entry + MSIX_ENTRY_ADDRESS_HIGH)) << 32;
+            let data = read(entry + MSIX_ENTRY_DATA);
+            let masked = read(entry + MSIX_ENTRY_CONTROL) & MSIX_ENTRY_MASKED != 0;
+            Ok((MsiMessage { address, data }, masked))
+        }
+    }
+
+    /// Keeps an entry from raising interrupts without disabling the others.
+    pub fn set_masked(&self, index: usize, masked: bool) -> Result<(), MsiError> {
+        let control = self.entry(index)? + MSIX_ENTRY_CONTROL;
+        unsafe {
+            let value = read(control) & !MSIX_ENTRY_MASKED;
+            write(control, value | u32::from(masked));
+        }
+        Ok(())
+    }
+
+    /// Switches the device from its legacy IRQ line over to MSI-X, with the whole function
+    /// masked so that nothing is raised before the table has been programmed.
+    pub fn enable(&self) {
+        let device = &self.device;
+        // the table is only reachable while the device decodes its memory BARs
+        device.set_command(device.command() | COMMAND_MEMORY_SPACE | COMMAND_INTERRUPT_DISABLE);
+        let control = self.control() | MSIX_FUNCTION_MASK | MSIX_ENABLE;
+        device.write_u16(self.offset + MSIX_CONTROL, control);
+    }
+
+    /// Masks or unmasks all entries at once. An entry only raises interrupts when neither it
+    /// nor the function is masked.
+    pub fn set_function_masked(&self, masked: bool) {
+        let control = match masked {
+            true => self.control() | MSIX_FUNCTION_MASK,
+            false => self.control() & !MSIX_FUNCTION_MASK,
+        };
+        self.device.write_u16(self.offset + MSIX_CONTROL, control);
+    }
+
+    /// Switches the device back to its legacy IRQ line.
+    pub fn disable(&self) {
+        let device = &self.device;
+        device.write_u16(self.offset + MSIX_CONTROL, self.control() & !MSIX_ENABLE);
+        device.set_command(device.command() & !COMMAND_INTERRUPT_DISABLE);
+    }
+
+    fn control(&self) -> u16 {
+        self.device.read_u16(self.offset + MSIX_CONTROL)
+    }
+
+    fn entry(&self, index: usize) -> Result<VirtAddr, MsiError> {
+        if index >= self.table_size() {
+            return Err(MsiError::InvalidEntry(index));
+        }
+        Ok(self.table + index as u64 * MSIX_ENTRY_SIZE)
+    }
+}
+
+unsafe fn read(register: VirtAddr) -> u32 {
+    register.as_ptr::<u32>().read_volatile()
+}
+
+unsafe fn write(register: VirtAddr, value: u32) {
+    register.as_mut_ptr::<u32>().write_volatile(value)
+}
+
+/// Allocates a vector for `handler` and has the device raise it, through the first MSI-X
+/// entry if the device supports MSI-X and through MSI otherwise.
+///
+/// Returns the vector, which has to be given back with [`interrupts::free_vector`] after
+/// disabling MSI again.
+pub fn enable_interrupt(
+    device: PciDevice,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<u8, MsiError> {
+    let msix = MsiX::new(device);
+    let msi = Msi::new(device);
+    if let (Err(MsiError::Unsupported), None) = (&msix, &msi) {
+        return Err(MsiError::Unsupported);
+    }
+
+    let vector = interrupts::allocate_vector_closure(handler)?;
+    let message = MsiMessage::new(apic::id(), vector);
+    let result = match (msix, msi) {
+        (Ok(msix), _) => {
+            msix.enable();
+            let result = msix.set_entry(0, message);
+            match result {
+                Ok(()) => msix.set_function_masked(false),
+                Err(_) => msix.disable(),
+            }
+            result
+        }
+        (Err(_), Some(msi)) => {
+            msi.enable(message);
+            Ok(())
+        }
+        (Err(err), None) => Err(err),
+    };
+    result
+        .map(|()| vector)
+        .inspect_err(|_| interrupts::free_vector(vector))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci;
+
+    #[test_case]
+    fn messages() {
+        let message = MsiMessage::new(1, 0x30);
+        assert_eq!(message.address, 0xfee0_1000);
+        assert_eq!(message.data, 0x30);
+    }
+
+    #[test_case]
+    fn program_msi() {
+        // the AHCI controller the tests run with supports MSI
+        let ahci = pci::scan()
+            .into_iter()
+            .find(|device| device.class == 0x01 && device.subclass == 0x06)
+            .expect("no AHCI controller");
+        let msi = Msi::new(ahci).expect("no MSI capability");
+        // the AHCI driver uses the capability too, so we have to put things back afterwards
+        let previous = msi.is_enabled().then(|| msi.message());
+        let vector = interrupts::allocate_vector(|| {}).unwrap();
+        let message = MsiMessage::new(apic::id(), vector);
+
+        msi.enable(message);
+        assert!(msi.is_enabled());
+        assert_eq!(msi.message(), message);
+        assert_ne!(ahci.command() & COMMAND_INTERRUPT_DISABLE, 0);
+
+        msi.disable();
+        assert!(!msi.is_enabled());
+        interrupts::free_vector(vector);
+        if let Some(message) = previous {
+            msi.enable(message);
+        }
+    }
+
+    #[test_case]
+    fn program_msix() {
+        // not every machine has a device with MSI-X
+        let Some((device, msix)) = pci::scan()
+            .into_iter()
+            .find_map(|device| MsiX::new(device).ok().map(|msix| (device, msix)))
+        else {
+            return;
+        };
+        // the table is only reachable while memory decoding is on
+        device.set_command(device.command() | COMMAND_MEMORY_SPACE);
+        // the driver of the device might use the entry, so we put it back afterwards
+        let (previous, was_masked) = msix.get_entry(0).unwrap();
+        let vector = interrupts::allocate_vector(|| {}).unwrap();
+        let message = MsiMessage::new(apic::id(), vector);
+
+        msix.set_entry(0, message).unwrap();
+        assert_eq!(msix.get_entry(0), Ok((message, false)));
+        msix.set_masked(0, true).unwrap();
+        assert_eq!(msix.get_entry(0), Ok((message, true)));
+        assert_eq!(
+            msix.set_entry(msix.table_size(), message),
+            Err(MsiError::InvalidEntry(msix.table_size()))
+        );
+
+        // the entry is still masked while the old message goes back in
+        msix.write_message(0, previous).unwrap();
+        msix.set_masked(0, was_masked).unwrap();
+        interrupts::free_vector(vector);
+    }
+}
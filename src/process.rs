@@ -0,0 +1,435 @@
+//! Processes run code in ring 3, in an address space of their own. The kernel switches to one
+//! with [`Process::run`] and gets back control when the process stops.
+
+use crate::terminal::{self, Terminal};
+use crate::vfs::File;
+use crate::{gdt, interrupts, syscall};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+pub mod address_space;
+
+pub use address_space::{AddressSpace, AddressSpaceError, USER_END, USER_START};
+
+/// Where [`Process::new`] puts the code.
+pub const CODE_START: u64 = USER_START;
+/// Where the stack of a process made by [`Process::new`] ends, it grows down from the end of
+/// user space.
+pub const STACK_TOP: u64 = USER_END;
+pub const STACK_SIZE: u64 = 4096 * 4;
+/// Where `mmap` puts memory when the process doesn't ask for an address.
+pub const MMAP_START: u64 = USER_START + 0x100_0000_0000;
+/// How many files a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+// the stack the interrupts and exceptions of a process run on
+const KERNEL_STACK_SIZE: usize = 4096 * 8;
+
+// where `Process::run` left off while a process is running
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+// where `pause` left off the running process
+static PROCESS_RSP: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// the resources of the process `Process::run` is running, for the system calls
+static CURRENT: Mutex<Option<Arc<Mutex<Resources>>>> = Mutex::new(None);
+// why the running process stopped
+static STOP: Mutex<Option<Stop>> = Mutex::new(None);
+
+/// Why a process stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The process ran a privileged instruction or loaded a segment it isn't allowed to.
+    GeneralProtectionFault { rip: VirtAddr, error_code: u64 },
+    /// The process touched memory that isn't mapped for ring 3.
+    PageFault { rip: VirtAddr, address: VirtAddr },
+    /// The process called `exit`.
+    Exited(i32),
+    /// The process called `yield`, it goes on when it runs again.
+    Yielded,
+    /// The process called `sleep`, it goes on once the timer reached the tick `until`.
+    Sleeping { until: u64 },
+}
+
+impl Stop {
+    /// Whether the process is gone for good.
+    pub fn is_final(&self) -> bool {
+        !matches!(self, Stop::Yielded | Stop::Sleeping { .. })
+    }
+}
+
+/// What a file descriptor of a process refers to.
+pub enum Descriptor {
+    /// A virtual terminal, which reads what is typed into it.
+    Terminal(&'static Terminal),
+    File(File),
+}
+
+pub struct Process {
+    // shared with the system calls, which run while `run` has the process borrowed
+    resources: Arc<Mutex<Resources>>,
+    // `u64`s keep the stack aligned
+    kernel_stack: Vec<u64>,
+    // where `switch_stacks` continues the process
+    rsp: u64,
+    // why the process stopped last, the final reasons keep it from running again
+    stop: Option<Stop>,
+}
+
+/// What the system calls of a process work with.
+pub struct Resources {
+    address_space: AddressSpace,
+    // indexed by the file descriptor
+    files: Vec<Option<Descriptor>>,
+    // where `mmap` looks for room next
+    mmap_next: u64,
+}
+
+impl Process {
+    /// Creates a process that runs `code` from [`CODE_START`] with a stack below
+    /// [`STACK_TOP`]. The code can't write to itself.
+    pub fn new(code: &[u8]) -> Result<Self, AddressSpaceError> {
+        let mut address_space = AddressSpace::new()?;
+        let code_start = VirtAddr::new(CODE_START);
+        address_space.map(code_start, code.len() as u64, false)?;
+        address_space.write(code_start, code)?;
+        address_space.map(VirtAddr::new(STACK_TOP - STACK_SIZE), STACK_SIZE, true)?;
+        Ok(Self::with_address_space(
+            address_space,
+            code_start,
+            VirtAddr::new(STACK_TOP),
+        ))
+    }
+
+    /// Creates a process that starts at `entry` with its stack pointer at `stack`, which both
+    /// have to be mapped in `address_space` already.
+    pub fn with_address_space(
+        address_space: AddressSpace,
+        entry: VirtAddr,
+        stack: VirtAddr,
+    ) -> Self {
+        let selectors = gdt::selectors();
+        // the first switch to the process pops the registers `switch_stacks` saves and returns
+        // to `enter_user`, which leaves the frame for `iretq` on the stack
+        let frame = [
+            // r15, r14, r13, r12, rbx and rbp
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            enter_user as *const () as u64,
+            entry.as_u64(),
+            u64::from(selectors.user_code_selector.0),
+            RFlags::INTERRUPT_FLAG.bits(),
+            stack.as_u64(),
+            u64::from(selectors.user_data_selector.0),
+        ];
+        // the terminal of the kernel console is standard input, output and error
+        let console = terminal::get(0).unwrap();
+        let resources = Resources {
+            address_space,
+            files: (0..3)
+                .map(|_| Some(Descriptor::Terminal(console)))
+                .collect(),
+            mmap_next: MMAP_START,
+        };
+        let mut process = Self {
+            resources: Arc::new(Mutex::new(resources)),
+            kernel_stack: vec![0; KERNEL_STACK_SIZE / 8],
+            rsp: 0,
+            stop: None,
+        };
+        let base = process.kernel_stack.as_ptr() as u64;
+        let end = (process.kernel_stack_top().as_u64() - base) as usize / 8;
+        let start = end - frame.len();
+        process.kernel_stack[start..end].copy_from_slice(&frame);
+        process.rsp = base + start as u64 * 8;
+        process
+    }
+
+    /// The address space and the open files of the process. The process can't run while they
+    /// are locked.
+    pub fn resources(&self) -> MutexGuard<'_, Resources> {
+        self.resources.lock()
+    }
+
+    // the end of the kernel stack, aligned to 16 bytes as the calling convention wants
+    fn kernel_stack_top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.kernel_stack.as_ptr_range().end).align_down(16u64)
+    }
+
+    /// Runs the process until it stops and returns why. A process that stopped for good
+    /// returns the same reason again, and so does a sleeping one until its tick has come. It
+    /// is up to the caller to do something else in the meantime.
+    ///
+    /// The process runs with interrupts enabled, their handlers and the system calls use its
+    /// kernel stack. Only one process can run at a time.
+    pub fn run(&mut self) -> Stop {
+        match self.stop {
+            Some(stop) if stop.is_final() => return stop,
+            Some(stop @ Stop::Sleeping { until }) if interrupts::ticks() < until => return stop,
+            _ => {}
+        }
+        assert!(
+            !RUNNING.swap(true, Ordering::SeqCst),
+            "another process is running already"
+        );
+        let level_4_frame = self.resources().address_space.level_4_frame();
+        *CURRENT.lock() = Some(self.resources.clone());
+        let stop = x86_64::instructions::interrupts::without_interrupts(|| {
+            let (kernel_table, flags) = Cr3::read();
+            gdt::set_kernel_stack(self.kernel_stack_top());
+            syscall::set_kernel_stack(self.kernel_stack_top());
+            unsafe {
+                Cr3::write(level_4_frame, flags);
+                switch_stacks(KERNEL_RSP.as_ptr(), self.rsp);
+                Cr3::write(kernel_table, flags);
+            }
+            STOP.lock()
+                .take()
+                .expect("the process stopped without a reason")
+        });
+        *CURRENT.lock() = None;
+        RUNNING.store(false, Ordering::SeqCst);
+        // only `pause` comes back to where it left off
+        if !stop.is_final() {
+            self.rsp = PROCESS_RSP.load(Ordering::SeqCst);
+        }
+        self.stop = Some(stop);
+        stop
+    }
+}
+
+impl Resources {
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    /// The open file behind a file descriptor.
+    pub fn file(&self, fd: usize) -> Option<&Descriptor> {
+        self.files.get(fd)?.as_ref()
+    }
+
+    /// Gives an open file the lowest file descriptor that is free, or returns `None` if the
+    /// process has [`MAX_FILES`] open already.
+    pub fn add_file(&mut self, descriptor: Descriptor) -> Option<usize> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[fd] = Some(descriptor);
+        Some(fd)
+    }
+
+    /// Takes a file descriptor away, which closes the file.
+    pub fn remove_file(&mut self, fd: usize) -> Option<Descriptor> {
+        self.files.get_mut(fd)?.take()
+    }
+
+    /// Maps `size` bytes of zeroed pages where `mmap` puts memory the process didn't ask for
+    /// an address for: behind everything it handed out so far, and past the pages that are
+    /// in use already.
+    pub fn map_anywhere(
+        &mut self,
+        size: u64,
+        writable: bool,
+    ) -> Result<VirtAddr, AddressSpaceError> {
+        let size = size
+            .checked_next_multiple_of(4096)
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let mut start = self.mmap_next;
+        loop {
+            // the range has to end below the stack
+            start
+                .checked_add(size)
+                .filter(|&end| end <= STACK_TOP - STACK_SIZE)
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            // the search goes on behind the last page in the way
+            let taken = (0..size / 4096)
+                .rev()
+                .map(|index| VirtAddr::new(start + index * 4096))
+                .find(|&page| self.address_space.translate(page).is_some());
+            match taken {
+                Some(page) => start = page.as_u64() + 4096,
+                None => break,
+            }
+        }
+        self.address_space
+            .map(VirtAddr::new(start), size, writable)?;
+        // the range only counts as handed out once it is mapped
+        self.mmap_next = start + size;
+        Ok(VirtAddr::new(start))
+    }
+}
+
+/// Calls `f` with the resources of the process that is running, for the system calls. `f`
+/// must not stop the process.
+pub(crate) fn with_current<R>(f: impl FnOnce(&mut Resources) -> R) -> R {
+    let resources = CURRENT.lock().clone().expect("no process is running");
+    let mut resources = resources.lock();
+    f(&mut resources)
+}
+
+/// Whether the code that was interrupted ran in ring 3, judging by the code segment the CPU
+/// saved.
+pub fn from_user(code_segment: u64) -> bool {
+    code_segment & 3 == 3
+}
+
+/// Stops the running process for good and continues in [`Process::run`]. This is how the
+/// exception handlers deal with faults in ring 3, and how `exit` works.
+pub(crate) fn stop(stop: Stop) -> ! {
+    assert!(RUNNING.load(Ordering::SeqCst), "no process is running");
+    // `run` expects interrupts to be disabled, which system calls don't have
+    x86_64::instructions::interrupts::disable();
+    *STOP.lock() = Some(stop);
+    // nothing ever switches back to the kernel stack of the process
+    let mut rsp = 0;
+    unsafe { switch_stacks(&mut rsp, KERNEL_RSP.load(Ordering::SeqCst)) };
+    unreachable!("a stopped process was continued");
+}
+
+/// Stops the running process for a while and continues in [`Process::run`], which returns
+/// `stop`. Returns when the process runs again.
+pub(crate) fn pause(stop: Stop) {
+    use x86_64::instructions::interrupts;
+
+    assert!(!stop.is_final());
+    assert!(RUNNING.load(Ordering::SeqCst), "no process is running");
+    *STOP.lock() = Some(stop);
+    // `run` switches with interrupts disabled, and switches back that way
+    interrupts::without_interrupts(|| unsafe {
+        switch_stacks(PROCESS_RSP.as_ptr(), KERNEL_RSP.load(Ordering::SeqCst))
+    });
+}
+
+extern "C" {
+    /// Saves the registers the caller doesn't, stores the stack pointer in `from` and
+    /// continues on the stack `to`, where an earlier call left off.
+    fn switch_stacks(from: *mut u64, to: u64);
+    /// Enters ring 3 with the frame `iretq` expects on the stack.
+    fn enter_user();
+}
+
+global_asm!(
+    ".global switch_stacks",
+    "switch_stacks:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    ".global enter_user",
+    "enter_user:",
+    // nothing of the kernel should be left in the registers, the saved ones are zero already
+    "xor eax, eax",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "iretq",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr::addr_of;
+
+    // programs for ring 3, which are copied out of the kernel
+    global_asm!(
+        ".global test_privileged_start, test_privileged_hlt, test_privileged_end",
+        "test_privileged_start:",
+        "mov rax, 0x1234",
+        "push rax",
+        "test_privileged_hlt:",
+        "hlt",
+        "test_privileged_end:",
+        ".global test_kernel_memory_start, test_kernel_memory_end",
+        "test_kernel_memory_start:",
+        // the start of the kernel heap
+        "movabs rax, 0x444444440000",
+        "mov rax, [rax]",
+        "test_kernel_memory_end:",
+    );
+
+    extern "C" {
+        static test_privileged_start: u8;
+        static test_privileged_hlt: u8;
+        static test_privileged_end: u8;
+        static test_kernel_memory_start: u8;
+        static test_kernel_memory_end: u8;
+    }
+
+    fn program(start: *const u8, end: *const u8) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+    }
+
+    #[test_case]
+    fn privileged_instructions_fault() {
+        let (start, hlt, end) = (
+            addr_of!(test_privileged_start),
+            addr_of!(test_privileged_hlt),
+            addr_of!(test_privileged_end),
+        );
+        let mut process = Process::new(program(start, end)).unwrap();
+        let rip = VirtAddr::new(CODE_START + (hlt as usize - start as usize) as u64);
+        let stop = Stop::GeneralProtectionFault { rip, error_code: 0 };
+        assert_eq!(process.run(), stop);
+
+        // everything up to the `hlt` did run, on the stack of the process
+        let mut pushed = [0; 8];
+        let top = VirtAddr::new(STACK_TOP - 8);
+        process
+            .resources()
+            .address_space()
+            .read(top, &mut pushed)
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(pushed), 0x1234);
+        assert_eq!(gdt::kernel_stack(), process.kernel_stack_top());
+        assert!(x86_64::instructions::interrupts::are_enabled());
+        assert_eq!(process.run(), stop);
+    }
+
+    #[test_case]
+    fn kernel_memory_is_out_of_reach() {
+        let code = program(
+            addr_of!(test_kernel_memory_start),
+            addr_of!(test_kernel_memory_end),
+        );
+        // the same addresses as in the test above, but a different process
+        let mut process = Process::new(code).unwrap();
+        assert_eq!(
+            process.run(),
+            Stop::PageFault {
+                rip: VirtAddr::new(CODE_START + 10),
+                address: VirtAddr::new(0x4444_4444_0000),
+            }
+        );
+    }
+}
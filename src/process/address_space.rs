@@ -0,0 +1,382 @@
+//! The page tables of a process. The kernel is mapped into every address space the same way
+//! it is mapped at boot, but only the pages of user space can be reached from ring 3.
+
+use crate::memory::{phys_to_virt, GlobalFrameAllocator};
+use core::ops::Range;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Where user space starts. It takes the entries 128 to 135 of the level 4 table, which the
+/// bootloader leaves alone and which end before the kernel heap.
+pub const USER_START: u64 = 0x4000_0000_0000;
+/// Where user space ends, this address is the first one that isn't part of it.
+pub const USER_END: u64 = 0x4400_0000_0000;
+
+// the entries of the level 4 table that belong to user space
+const USER_ENTRIES: Range<usize> = 128..136;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// There are no frames left for the pages or the page tables.
+    OutOfMemory,
+    /// The address isn't in user space.
+    NotUserSpace(VirtAddr),
+    /// There already is a page at the address.
+    AlreadyMapped(VirtAddr),
+    /// There is no page at the address.
+    NotMapped(VirtAddr),
+    /// The page at the address can't be written from ring 3.
+    ReadOnly(VirtAddr),
+}
+
+/// A level 4 table with the kernel in it and pages of user space that belong to one process.
+///
+/// Dropping it gives back the pages of user space and the tables, so it must not be the
+/// active address space then.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user space.
+    ///
+    /// The kernel's entries of the level 4 table are copied, so the kernel has to map anything
+    /// it needs from inside the process into an entry it already uses.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let level_4_frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let (kernel_frame, _) = Cr3::read();
+        let kernel_table: &PageTable =
+            unsafe { &*phys_to_virt(kernel_frame.start_address()).as_ptr() };
+        let table: &mut PageTable =
+            unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr() };
+        table.zero();
+        for (index, entry) in kernel_table.iter().enumerate() {
+            // `new` may be called from inside another process, whose user space stays there
+            if !USER_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(Self { level_4_frame })
+    }
+
+    /// The frame that has to go into CR3 to switch to the address space.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Maps zeroed pages for the `size` bytes at `start`, which can always be read from ring 3
+    /// and written if `writable` is set. If that fails, none of the pages stay mapped.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        writable: bool,
+    ) -> Result<(), AddressSpaceError> {
+        let end = check_range(start, size)?;
+        if size == 0 {
+            return Ok(());
+        }
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        // the tables above the pages need the same flags, the CPU checks every level
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let first = Page::containing_address(start);
+        for page in Page::range_inclusive(first, Page::containing_address(end - 1u64)) {
+            if let Err(err) = self.map_page(page, flags, parent_flags) {
+                self.unmap_pages(Page::range(first, page));
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the pages in the `size` bytes at `start` and gives back their frames. Pages that
+    /// aren't mapped are skipped.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let end = check_range(start, size)?;
+        if size == 0 {
+            return Ok(());
+        }
+        self.unmap_pages(Page::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end - 1u64),
+        ));
+        Ok(())
+    }
+
+    /// Looks up the frame behind an address of user space and the flags of its page.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        if !(USER_START..USER_END).contains(&addr.as_u64()) {
+            return None;
+        }
+        let mut table: &PageTable =
+            unsafe { &*phys_to_virt(self.level_4_frame.start_address()).as_ptr() };
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+        for (level, index) in indices.into_iter().enumerate() {
+            let entry = &table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            if level == indices.len() - 1 {
+                let phys = entry.addr() + u64::from(addr.page_offset());
+                return Some((phys, entry.flags()));
+            }
+            // user space is only ever mapped with 4 KiB pages
+            table = unsafe { &*phys_to_virt(entry.addr()).as_ptr() };
+        }
+        None
+    }
+
+    /// Checks that ring 3 can read the `size` bytes at `start`, and write them if `writable`
+    /// is set. This is how the kernel makes sure that the pointers it gets from a process point
+    /// to the process' own memory.
+    pub fn check_access(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        writable: bool,
+    ) -> Result<(), AddressSpaceError> {
+        let end = check_range(start, size)?;
+        let mut page = start.align_down(4096u64);
+        while page < end {
+            // the first page starts before `start`, which is the address that faults then
+            let addr = page.max(start);
+            let (_, flags) = self
+                .translate(addr)
+                .ok_or(AddressSpaceError::NotMapped(addr))?;
+            if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                return Err(AddressSpaceError::NotMapped(addr));
+            }
+            if writable && !flags.contains(PageTableFlags::WRITABLE) {
+                return Err(AddressSpaceError::ReadOnly(addr));
+            }
+            page += 4096u64;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` into user space, no matter whether the pages are writable.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        self.copy(addr, data.len(), |phys, offset, length| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), phys, length)
+        })
+    }
+
+    /// Copies bytes out of user space.
+    pub fn read(&self, addr: VirtAddr, buffer: &mut [u8]) -> Result<(), AddressSpaceError> {
+        self.copy(addr, buffer.len(), |phys, offset, length| unsafe {
+            core::ptr::copy_nonoverlapping(phys, buffer[offset..].as_mut_ptr(), length)
+        })
+    }
+
+    // calls `copy` with the kernel's view of each piece of the range that is on one page
+    fn copy(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+        mut copy: impl FnMut(*mut u8, usize, usize),
+    ) -> Result<(), AddressSpaceError> {
+        check_range(addr, size as u64)?;
+        let mut offset = 0;
+        while offset < size {
+            let current = addr + offset;
+            let (phys, _) = self
+                .translate(current)
+                .ok_or(AddressSpaceError::NotMapped(current))?;
+            let length = (4096 - usize::from(current.page_offset())).min(size - offset);
+            copy(phys_to_virt(phys).as_mut_ptr(), offset, length);
+            offset += length;
+        }
+        Ok(())
+    }
+
+    fn map_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+        parent_flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        unsafe {
+            core::ptr::write_bytes(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                4096,
+            )
+        };
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                parent_flags,
+                &mut GlobalFrameAllocator,
+            )
+        };
+        match result {
+            // the page might be in the TLB if the address space is the active one
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                return Err(match err {
+                    MapToError::FrameAllocationFailed => AddressSpaceError::OutOfMemory,
+                    _ => AddressSpaceError::AlreadyMapped(page.start_address()),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_pages(&mut self, pages: impl Iterator<Item = Page>) {
+        let mut mapper = self.mapper();
+        for page in pages {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            }
+        }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { &mut *phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr() };
+        unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let table: &PageTable =
+            unsafe { &*phys_to_virt(self.level_4_frame.start_address()).as_ptr() };
+        // the other entries are the kernel's, which every address space shares
+        for index in USER_ENTRIES {
+            unsafe { free_entry(&table[index], 3) };
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+// gives back the frame behind `entry` and, if it is a table of the given level, the frames
+// behind its entries first
+unsafe fn free_entry(entry: &PageTableEntry, level: usize) {
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    if level > 0 {
+        let table: &PageTable = &*phys_to_virt(entry.addr()).as_ptr();
+        for child in table.iter() {
+            free_entry(child, level - 1);
+        }
+    }
+    GlobalFrameAllocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+}
+
+/// Checks that the `size` bytes at `start` are all in user space and returns where they end.
+pub fn check_range(start: VirtAddr, size: u64) -> Result<VirtAddr, AddressSpaceError> {
+    let end = start
+        .as_u64()
+        .checked_add(size)
+        .ok_or(AddressSpaceError::NotUserSpace(start))?;
+    if start.as_u64() < USER_START {
+        return Err(AddressSpaceError::NotUserSpace(start));
+    }
+    if end > USER_END {
+        return Err(AddressSpaceError::NotUserSpace(VirtAddr::new(USER_END)));
+    }
+    Ok(VirtAddr::new(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn user_pages() {
+        let mut space = AddressSpace::new().unwrap();
+        let start = VirtAddr::new(USER_START + 0x1ff0);
+        space.map(start, 0x20, false).unwrap();
+        assert_eq!(
+            space.map(start + 0x1000u64, 1, true),
+            Err(AddressSpaceError::AlreadyMapped(VirtAddr::new(
+                USER_START + 0x2000
+            )))
+        );
+
+        // the two pages aren't next to each other in physical memory, but it looks like it
+        space.write(start, b"across two pages").unwrap();
+        let mut buffer = [0; 16];
+        space.read(start, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"across two pages");
+        let (_, flags) = space.translate(start).unwrap();
+        assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+        assert_eq!(space.check_access(start, 0x20, false), Ok(()));
+        assert_eq!(
+            space.check_access(start + 0x10u64, 8, true),
+            Err(AddressSpaceError::ReadOnly(start + 0x10u64))
+        );
+        // the page in front of them isn't mapped
+        let before = VirtAddr::new(USER_START + 0xff8);
+        assert_eq!(
+            space.check_access(before, 16, false),
+            Err(AddressSpaceError::NotMapped(before))
+        );
+
+        // a range that runs into a page that is mapped already isn't mapped at all
+        assert_eq!(
+            space.map(VirtAddr::new(USER_START), 0x2000, true),
+            Err(AddressSpaceError::AlreadyMapped(VirtAddr::new(
+                USER_START + 0x1000
+            )))
+        );
+        assert_eq!(space.translate(VirtAddr::new(USER_START)), None);
+        assert_eq!(
+            space.read(VirtAddr::new(USER_START + 0x3000), &mut buffer),
+            Err(AddressSpaceError::NotMapped(VirtAddr::new(
+                USER_START + 0x3000
+            )))
+        );
+        assert_eq!(
+            space.map(VirtAddr::new(USER_END - 8), 16, true),
+            Err(AddressSpaceError::NotUserSpace(VirtAddr::new(USER_END)))
+        );
+        assert_eq!(
+            space.write(VirtAddr::new(0x1000), b"kernel"),
+            Err(AddressSpaceError::NotUserSpace(VirtAddr::new(0x1000)))
+        );
+
+        space.unmap(start, 0x20).unwrap();
+        assert_eq!(space.translate(start), None);
+        assert_eq!(space.translate(start + 0x10u64), None);
+    }
+
+    #[test_case]
+    fn frames_are_given_back() {
+        let mut space = AddressSpace::new().unwrap();
+        space.map(VirtAddr::new(USER_START), 0x3000, true).unwrap();
+        let level_4_frame = space.level_4_frame();
+        drop(space);
+        // the level 4 table is given back last, so it is the first frame to be used again
+        let space = AddressSpace::new().unwrap();
+        assert_eq!(space.level_4_frame(), level_4_frame);
+    }
+}
@@ -0,0 +1,682 @@
+//! Driver for the 8042 PS/2 controller and the keyboard attached to it.
+//!
+//! See <https://wiki.osdev.org/%228042%22_PS/2_Controller> for how the controller works.
+
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::interrupts::{register_irq, ticks, unregister_irq, IrqHandle, IRQ_KEYBOARD};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+const DATA_PORT: u16 = 0x60;
+// reading this port returns the status register, writing to it sends a command to the controller
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+// bits of the status register
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// the byte in the output buffer came from the second port
+const STATUS_AUX_OUTPUT_FULL: u8 = 1 << 5;
+
+// bits of the controller configuration byte
+const CONFIG_FIRST_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_INTERRUPT: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const TEST_CONTROLLER: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+const WRITE_SECOND_PORT: u8 = 0xd4;
+
+// controller responses
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// device commands
+const SET_LEDS: u8 = 0xed;
+const SCANCODE_SET: u8 = 0xf0;
+const SET_TYPEMATIC: u8 = 0xf3;
+const ENABLE_SCANNING: u8 = 0xf4;
+const DISABLE_SCANNING: u8 = 0xf5;
+const RESET: u8 = 0xff;
+
+// device responses
+const DEVICE_TEST_PASSED: u8 = 0xaa;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+/// How often the status register is polled before giving up on the controller.
+const TIMEOUT: u32 = 100_000;
+/// Resetting a device takes a lot longer than anything else.
+const RESET_TIMEOUT: u32 = 10 * TIMEOUT;
+/// How often a device command is sent again if the device asks for it.
+const RETRIES: usize = 3;
+/// How many timer ticks the keyboard gets to acknowledge a byte of an LED update, about a
+/// quarter of a second.
+const LED_TIMEOUT_TICKS: u64 = 5;
+
+lazy_static! {
+    pub static ref CONTROLLER: Mutex<Controller> = Mutex::new(unsafe { Controller::new() });
+}
+
+pub static DRIVER: KeyboardDriver = KeyboardDriver;
+static KEYBOARD_IRQ: Mutex<Option<IrqHandle>> = Mutex::new(None);
+// the LED update the keyboard interrupt handler is in the middle of sending
+static LED_UPDATE: Mutex<LedUpdate> = Mutex::new(LedUpdate::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller didn't respond in time.
+    Timeout,
+    /// The controller self-test returned something other than `0x55`.
+    ControllerTestFailed(u8),
+    /// The interface test of a port failed with the given error code.
+    PortTestFailed(Channel, u8),
+    /// A device failed its self-test after a reset.
+    DeviceTestFailed(Channel),
+    /// There is no working device on the port.
+    NoDevice(Channel),
+    /// A device kept asking for a command to be sent again.
+    Resend,
+    /// A device responded with something we didn't expect.
+    UnexpectedResponse(u8),
+}
+
+impl From<Ps2Error> for DriverError {
+    fn from(err: Ps2Error) -> Self {
+        match err {
+            // without a controller nothing responds at all
+            Ps2Error::Timeout | Ps2Error::NoDevice(_) => DriverError::NoDevice,
+            Ps2Error::ControllerTestFailed(_) => {
+                DriverError::Hardware("controller self-test failed")
+            }
+            Ps2Error::PortTestFailed(..) => DriverError::Hardware("port test failed"),
+            Ps2Error::DeviceTestFailed(_) => DriverError::Hardware("device self-test failed"),
+            Ps2Error::Resend | Ps2Error::UnexpectedResponse(_) => {
+                DriverError::Hardware("unexpected response")
+            }
+        }
+    }
+}
+
+/// The two ports of the controller. The keyboard is usually connected to the first one
+/// and the mouse to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    First,
+    Second,
+}
+
+/// The keyboard LEDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn as_u8(self) -> u8 {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+}
+
+/// How fast a held down key repeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    rate: u8,
+    delay: u8,
+}
+
+impl Typematic {
+    /// `rate` goes from 0 (30 repeats per second) to 31 (2 repeats per second) and
+    /// `delay` from 0 (250ms before the first repeat) to 3 (1000ms). Larger values are clamped.
+    pub fn new(rate: u8, delay: u8) -> Self {
+        Self {
+            rate: rate.min(0x1f),
+            delay: delay.min(3),
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        self.delay << 5 | self.rate
+    }
+}
+
+impl Default for Typematic {
+    /// About 10 repeats per second after half a second, which is what keyboards start out with.
+    fn default() -> Self {
+        Self::new(0x0b, 1)
+    }
+}
+
+/// Sends the command for the LEDs one byte at a time. The acknowledgements arrive in the keyboard
+/// interrupt handler, which sends the next byte, so nothing has to wait for the keyboard.
+struct LedUpdate {
+    state: LedState,
+    // the LEDs to set once the update that is being sent is done
+    next: Option<Leds>,
+    // the tick the last byte was sent at
+    sent_at: u64,
+    retries: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedState {
+    Idle,
+    Command(Leds),
+    Data(Leds),
+}
+
+impl LedUpdate {
+    const fn new() -> Self {
+        Self {
+            state: LedState::Idle,
+            next: None,
+            sent_at: 0,
+            retries: 0,
+        }
+    }
+
+    fn queue(&mut self, controller: &mut Controller, leds: Leds) {
+        // an update the keyboard never answered doesn't hold up the ones after it
+        let waiting = ticks().saturating_sub(self.sent_at) <= LED_TIMEOUT_TICKS;
+        if self.state != LedState::Idle && waiting {
+            self.next = Some(leds);
+        } else {
+            self.next = None;
+            self.start(controller, leds);
+        }
+    }
+
+    fn start(&mut self, controller: &mut Controller, leds: Leds) {
+        self.retries = 0;
+        self.send(controller, LedState::Command(leds));
+    }
+
+    fn send(&mut self, controller: &mut Controller, state: LedState) {
+        let byte = match state {
+            LedState::Idle => return,
+            LedState::Command(_) => SET_LEDS,
+            LedState::Data(leds) => leds.as_u8(),
+        };
+        // the controller takes the byte right away, it's only the acknowledgement that takes long
+        if !controller.first_port || controller.write_data(byte).is_err() {
+            self.state = LedState::Idle;
+            return;
+        }
+        self.state = state;
+        self.sent_at = ticks();
+    }
+
+    /// Handles a byte from the keyboard and returns whether it belonged to the LED update.
+    fn receive(&mut self, controller: &mut Controller, byte: u8) -> bool {
+        match (self.state, byte) {
+            (LedState::Idle, _) => return false,
+            (LedState::Command(leds), ACK) => {
+                self.retries = 0;
+                self.send(controller, LedState::Data(leds));
+            }
+            (state, RESEND) if self.retries < RETRIES => {
+                self.retries += 1;
+                self.send(controller, state);
+            }
+            (LedState::Data(_), ACK) | (_, RESEND) => {
+                self.state = LedState::Idle;
+                if let Some(leds) = self.next.take() {
+                    self.start(controller, leds);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+pub struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    first_port: bool,
+    second_port: bool,
+}
+
+impl Controller {
+    /// # Safety
+    /// There must only be one `Controller`, since it talks to the hardware through fixed I/O ports.
+    pub const unsafe fn new() -> Self {
+        Self {
+            data: Port::new(DATA_PORT),
+            status: PortReadOnly::new(STATUS_PORT),
+            command: PortWriteOnly::new(COMMAND_PORT),
+            first_port: false,
+            second_port: false,
+        }
+    }
+
+    /// Sets up the controller and the keyboard from scratch instead of relying on the firmware.
+    ///
+    /// Interrupts of the controller stay off until everything is set up, so this has to finish
+    /// before the keyboard works.
+    pub fn init(&mut self) -> Result<(), Ps2Error> {
+        self.first_port = false;
+        self.second_port = false;
+
+        // keep the devices from sending anything while we set up the controller
+        self.send_command(DISABLE_FIRST_PORT)?;
+        self.send_command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_FIRST_INTERRUPT | CONFIG_SECOND_INTERRUPT);
+        self.write_config(config)?;
+
+        self.send_command(TEST_CONTROLLER)?;
+        match self.read_data()? {
+            CONTROLLER_TEST_PASSED => {}
+            response => return Err(Ps2Error::ControllerTestFailed(response)),
+        }
+        // some controllers reset themselves during the self-test
+        self.write_config(config)?;
+
+        // the clock of the second port only turns on when enabling it if there actually is one
+        let mut dual_channel = false;
+        if config & CONFIG_SECOND_CLOCK_DISABLED != 0 {
+            self.send_command(ENABLE_SECOND_PORT)?;
+            dual_channel = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            self.send_command(DISABLE_SECOND_PORT)?;
+        }
+
+        self.first_port = self.test_port(Channel::First).is_ok();
+        self.second_port = dual_channel && self.test_port(Channel::Second).is_ok();
+        if !self.first_port && !self.second_port {
+            return Err(Ps2Error::NoDevice(Channel::First));
+        }
+
+        if self.first_port {
+            self.send_command(ENABLE_FIRST_PORT)?;
+            config |= CONFIG_FIRST_INTERRUPT;
+        }
+        if self.second_port {
+            self.send_command(ENABLE_SECOND_PORT)?;
+            config |= CONFIG_SECOND_INTERRUPT;
+        }
+
+        // a port without a device attached to it is useless
+        if self.first_port && self.reset_device(Channel::First).is_err() {
+            self.first_port = false;
+            self.send_command(DISABLE_FIRST_PORT)?;
+            config &= !CONFIG_FIRST_INTERRUPT;
+        }
+        if self.second_port && self.reset_device(Channel::Second).is_err() {
+            self.second_port = false;
+            self.send_command(DISABLE_SECOND_PORT)?;
+            config &= !CONFIG_SECOND_INTERRUPT;
+        }
+
+        if self.first_port {
+            // the keyboard decoder expects scancode set 1, which the controller translates set 2 into
+            config |= CONFIG_TRANSLATION;
+            self.device_command(Channel::First, DISABLE_SCANNING, None)?;
+            self.set_scancode_set(2)?;
+            self.set_typematic(Typematic::default())?;
+            self.set_leds(Leds::default())?;
+            self.device_command(Channel::First, ENABLE_SCANNING, None)?;
+        }
+
+        self.write_config(config)
+    }
+
+    /// Whether there is a working device on the first port.
+    pub fn has_first_port(&self) -> bool {
+        self.first_port
+    }
+
+    /// Whether the controller has a second port with a working device on it.
+    pub fn has_second_port(&self) -> bool {
+        self.second_port
+    }
+
+    pub fn enable_port(&mut self, channel: Channel) -> Result<(), Ps2Error> {
+        self.send_command(match channel {
+            Channel::First => ENABLE_FIRST_PORT,
+            Channel::Second => ENABLE_SECOND_PORT,
+        })
+    }
+
+    pub fn disable_port(&mut self, channel: Channel) -> Result<(), Ps2Error> {
+        self.send_command(match channel {
+            Channel::First => DISABLE_FIRST_PORT,
+            Channel::Second => DISABLE_SECOND_PORT,
+        })
+    }
+
+    /// Runs the interface test of a port.
+    pub fn test_port(&mut self, channel: Channel) -> Result<(), Ps2Error> {
+        self.send_command(match channel {
+            Channel::First => TEST_FIRST_PORT,
+            Channel::Second => TEST_SECOND_PORT,
+        })?;
+        match self.read_data()? {
+            PORT_TEST_PASSED => Ok(()),
+            response => Err(Ps2Error::PortTestFailed(channel, response)),
+        }
+    }
+
+    /// Resets the device on a port and waits for its self-test to finish.
+    pub fn reset_device(&mut self, channel: Channel) -> Result<(), Ps2Error> {
+        self.device_command(channel, RESET, None)?;
+        match self.read_data_with_timeout(RESET_TIMEOUT)? {
+            DEVICE_TEST_PASSED => {}
+            _ => return Err(Ps2Error::DeviceTestFailed(channel)),
+        }
+        // mice send their device id after the self-test
+        if channel == Channel::Second {
+            self.read_data()?;
+        }
+        Ok(())
+    }
+
+    /// Selects the scancode set (1, 2 or 3) the keyboard sends.
+    pub fn set_scancode_set(&mut self, set: u8) -> Result<(), Ps2Error> {
+        self.device_command(Channel::First, SCANCODE_SET, Some(set))
+    }
+
+    pub fn set_leds(&mut self, leds: Leds) -> Result<(), Ps2Error> {
+        self.device_command(Channel::First, SET_LEDS, Some(leds.as_u8()))
+    }
+
+    pub fn set_typematic(&mut self, typematic: Typematic) -> Result<(), Ps2Error> {
+        self.device_command(Channel::First, SET_TYPEMATIC, Some(typematic.as_u8()))
+    }
+
+    /// Sends a command with an optional data byte to a device and waits for it to be acknowledged.
+    pub fn device_command(
+        &mut self,
+        channel: Channel,
+        command: u8,
+        data: Option<u8>,
+    ) -> Result<(), Ps2Error> {
+        let present = match channel {
+            Channel::First => self.first_port,
+            Channel::Second => self.second_port,
+        };
+        if !present {
+            return Err(Ps2Error::NoDevice(channel));
+        }
+
+        self.send_to_device(channel, command)?;
+        if let Some(data) = data {
+            self.send_to_device(channel, data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a byte a device sent, if there is one, together with the port it came from.
+    pub fn try_read(&mut self) -> Option<(Channel, u8)> {
+        let status = self.status();
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let channel = match status & STATUS_AUX_OUTPUT_FULL {
+            0 => Channel::First,
+            _ => Channel::Second,
+        };
+        Some((channel, unsafe { self.data.read() }))
+    }
+
+    /// Waits for a byte from the controller or one of the devices.
+    pub fn read_data(&mut self) -> Result<u8, Ps2Error> {
+        self.read_data_with_timeout(TIMEOUT)
+    }
+
+    /// Sends a single byte to a device and waits for the acknowledgement.
+    fn send_to_device(&mut self, channel: Channel, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RETRIES {
+            if channel == Channel::Second {
+                self.send_command(WRITE_SECOND_PORT)?;
+            }
+            self.write_data(byte)?;
+            match self.read_data()? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                response => return Err(Ps2Error::UnexpectedResponse(response)),
+            }
+        }
+        Err(Ps2Error::Resend)
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.send_command(READ_CONFIG)?;
+        self.read_data()
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.send_command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_for(|status| status & STATUS_INPUT_FULL == 0, TIMEOUT)?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait_for(|status| status & STATUS_INPUT_FULL == 0, TIMEOUT)?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    fn read_data_with_timeout(&mut self, timeout: u32) -> Result<u8, Ps2Error> {
+        self.wait_for(|status| status & STATUS_OUTPUT_FULL != 0, timeout)?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    /// Throws away anything that is still waiting in the output buffer.
+    fn flush(&mut self) {
+        while self.try_read().is_some() {}
+    }
+
+    fn wait_for(&mut self, condition: impl Fn(u8) -> bool, timeout: u32) -> Result<(), Ps2Error> {
+        for _ in 0..timeout {
+            if condition(self.status()) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+}
+
+/// Initializes the PS/2 controller.
+pub fn init() -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    // the interrupt handlers would steal the responses of the devices otherwise
+    interrupts::without_interrupts(|| CONTROLLER.lock().init())
+}
+
+/// The driver for the keyboard, which is also responsible for setting up the controller.
+pub struct KeyboardDriver;
+
+impl Driver for KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "ps2-keyboard"
+    }
+
+    fn match_table(&self) -> &'static [Match] {
+        &[Match::Isa("PNP0303")]
+    }
+
+    fn probe(&self, _device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::interrupts;
+
+        // the handler has to be in place before the controller starts raising interrupts,
+        // otherwise the first scancode would never get read and block all the ones after it
+        let irq = register_irq(IRQ_KEYBOARD, keyboard_irq)
+            .map_err(|_| DriverError::Hardware("IRQ 1 is taken"))?;
+        // there might only be a mouse
+        let result = init().map_err(DriverError::from).and_then(|()| {
+            match interrupts::without_interrupts(|| CONTROLLER.lock().has_first_port()) {
+                true => Ok(()),
+                false => Err(DriverError::NoDevice),
+            }
+        });
+        match result {
+            Ok(()) => *KEYBOARD_IRQ.lock() = Some(irq),
+            Err(_) => unregister_irq(irq),
+        }
+        result
+    }
+
+    fn remove(&self, device: &Device) {
+        let _ = self.suspend(device);
+        if let Some(irq) = KEYBOARD_IRQ.lock().take() {
+            unregister_irq(irq);
+        }
+    }
+
+    fn suspend(&self, _device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| CONTROLLER.lock().disable_port(Channel::First))?;
+        Ok(())
+    }
+
+    fn resume(&self, _device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| CONTROLLER.lock().enable_port(Channel::First))?;
+        Ok(())
+    }
+}
+
+fn keyboard_irq() {
+    // PS/2 is an old standard that was used before USB peripherals were a thing,
+    // and while new hardware won't have an actual PS/2 controller, most still emulate one.
+    handle_data();
+}
+
+/// Reads a byte from the controller and hands it to the driver of the device that sent it.
+///
+/// Both interrupt handlers call this, since the keyboard and the mouse share the data port and
+/// either handler can find the byte of the other device waiting.
+pub(crate) fn handle_data() {
+    // The interrupt can fire without a byte waiting when a device acknowledged a command
+    // which has already been read, so we have to check first.
+    let data = {
+        let mut controller = CONTROLLER.lock();
+        match controller.try_read() {
+            // the acknowledgements of a queued LED update arrive here too
+            Some((Channel::First, byte)) if LED_UPDATE.lock().receive(&mut controller, byte) => {
+                None
+            }
+            data => data,
+        }
+    };
+    match data {
+        // the keyboard module turns the scancodes into key events
+        Some((Channel::First, scancode)) => crate::keyboard::add_scancode(scancode),
+        Some((Channel::Second, byte)) => crate::mouse::add_byte(byte),
+        None => {}
+    }
+}
+
+/// Turns the keyboard LEDs on or off.
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    // the keyboard interrupt handler uses the controller too
+    interrupts::without_interrupts(|| CONTROLLER.lock().set_leds(leds))
+}
+
+/// Starts turning the keyboard LEDs on or off without waiting for the keyboard, so it can be
+/// called from interrupt handlers. The keyboard interrupt handler sends the rest of the command.
+pub fn queue_leds(leds: Leds) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        LED_UPDATE.lock().queue(&mut controller, leds);
+    })
+}
+
+/// Changes how fast held down keys repeat.
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| CONTROLLER.lock().set_typematic(typematic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::instructions::interrupts;
+
+    #[test_case]
+    fn typematic_byte() {
+        assert_eq!(Typematic::new(0, 0).as_u8(), 0);
+        assert_eq!(Typematic::default().as_u8(), 0x2b);
+        // out of range values get clamped
+        assert_eq!(Typematic::new(0xff, 0xff).as_u8(), 0x7f);
+    }
+
+    #[test_case]
+    fn led_byte() {
+        let leds = Leds {
+            caps_lock: true,
+            ..Leds::default()
+        };
+        assert_eq!(leds.as_u8(), 0b100);
+    }
+
+    #[test_case]
+    fn keyboard_detected() {
+        // qemu always emulates a keyboard and a mouse
+        interrupts::without_interrupts(|| {
+            let controller = CONTROLLER.lock();
+            assert!(controller.has_first_port());
+            assert!(controller.has_second_port());
+        });
+    }
+
+    #[test_case]
+    fn keyboard_commands() {
+        let leds = Leds {
+            num_lock: true,
+            ..Leds::default()
+        };
+        assert_eq!(set_leds(leds), Ok(()));
+        assert_eq!(set_leds(Leds::default()), Ok(()));
+        assert_eq!(set_typematic(Typematic::default()), Ok(()));
+    }
+
+    #[test_case]
+    fn queued_leds() {
+        queue_leds(Leds {
+            caps_lock: true,
+            ..Leds::default()
+        });
+        queue_leds(Leds::default());
+        // the keyboard interrupt handler sends both updates
+        let start = ticks();
+        while interrupts::without_interrupts(|| LED_UPDATE.lock().state != LedState::Idle) {
+            assert!(ticks() - start <= 2 * LED_TIMEOUT_TICKS);
+            x86_64::instructions::hlt();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+/// A fixed size first-in first-out queue that never allocates,
+/// which makes it usable from interrupt handlers.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    // index of the oldest item in `items`
+    start: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            items: [None; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends `item` to the queue.
+    /// Returns `false` if the queue is full and the item was dropped.
+    pub fn push(&mut self, item: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.items[(self.start + self.len) % N] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    /// Takes the oldest item out of the queue.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.start].take();
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    /// Returns the item at `index`, where 0 is the oldest item in the queue.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+        self.items[(self.start + index) % N].as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+
+    #[test_case]
+    fn push_and_pop() {
+        let mut queue: RingBuffer<char, 4> = RingBuffer::new();
+        assert_eq!(queue.pop(), None);
+        for c in "abc".chars() {
+            assert!(queue.push(c));
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some('a'));
+        assert_eq!(queue.pop(), Some('b'));
+        assert_eq!(queue.pop(), Some('c'));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test_case]
+    fn full_queue() {
+        let mut queue: RingBuffer<u8, 4> = RingBuffer::new();
+        // fill it up past the wrap around point
+        queue.push(0);
+        queue.pop();
+        for i in 0..4 {
+            assert!(queue.push(i));
+        }
+        assert!(!queue.push(4));
+        assert_eq!(queue.pop(), Some(0));
+        queue.clear();
+        assert!(queue.is_empty());
+    }
+
+    #[test_case]
+    fn indexing() {
+        let mut queue: RingBuffer<u8, 3> = RingBuffer::new();
+        // the oldest item isn't at the start of the array anymore
+        queue.push(0);
+        queue.pop();
+        for i in 1..4 {
+            queue.push(i);
+        }
+        assert_eq!(queue.get(0), Some(&1));
+        assert_eq!(queue.get(2), Some(&3));
+        assert_eq!(queue.get(3), None);
+    }
+}
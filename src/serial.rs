@@ -1,3 +1,4 @@
+use crate::driver::{Device, Driver, DriverError, Match};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
@@ -10,6 +11,44 @@ lazy_static! {
     };
 }
 
+pub static DRIVER: SerialDriver = SerialDriver;
+
+pub struct SerialDriver;
+
+impl Driver for SerialDriver {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn match_table(&self) -> &'static [Match] {
+        &[Match::Isa("PNP0501")]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::port::Port;
+
+        // only the first port is wired up to `serial_print!`
+        let Device::Isa(device) = device else {
+            return Err(DriverError::Unsupported);
+        };
+        if device.io_base != 0x3f8 {
+            return Err(DriverError::Unsupported);
+        }
+
+        // every 16550 has a scratch register that keeps what we write to it
+        let mut scratch = Port::<u8>::new(device.io_base + 7);
+        let present = unsafe {
+            scratch.write(0x5a);
+            scratch.read() == 0x5a
+        };
+        if !present {
+            return Err(DriverError::NoDevice);
+        }
+        lazy_static::initialize(&SERIAL1);
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -0,0 +1,607 @@
+//! System calls through `syscall` and `sysret`.
+//!
+//! A process puts the number of the call into `rax` and up to six arguments into `rdi`, `rsi`,
+//! `rdx`, `r10`, `r8` and `r9`, like on Linux. The result comes back in `rax`, errors as the
+//! negated [`Errno`]. `rcx` and `r11` are lost, every other register is kept.
+
+use crate::gdt;
+use crate::interrupts::{self, TICK_MICROSECONDS};
+use crate::process::{self, AddressSpace, AddressSpaceError, Descriptor, Stop};
+use crate::terminal::Terminal;
+use crate::vfs::{self, FsError, OpenFlags};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// `write(fd, buffer, length)` writes to an open file and returns how much it wrote.
+pub const WRITE: u64 = 0;
+/// `read(fd, buffer, length)` reads from an open file and returns how much it read, 0 at
+/// the end of the file. Reading a terminal waits until something was typed.
+pub const READ: u64 = 1;
+/// `exit(code)` ends the process.
+pub const EXIT: u64 = 2;
+/// `yield()` lets the kernel do something else before the process goes on.
+pub const YIELD: u64 = 3;
+/// `sleep(milliseconds)` lets the process wait, in steps of the timer.
+pub const SLEEP: u64 = 4;
+/// `mmap(address, length, protection)` maps zeroed memory and returns where. The kernel
+/// picks the address if it is 0.
+pub const MMAP: u64 = 5;
+/// `open(path, length, flags)` opens a file with the [`OpenFlags`] of the VFS and returns
+/// its file descriptor. The path doesn't need a zero at the end.
+pub const OPEN: u64 = 6;
+/// `close(fd)` closes a file descriptor.
+pub const CLOSE: u64 = 7;
+
+/// `mmap` maps the memory writable with this, it can always be read.
+pub const PROT_WRITE: u64 = 2;
+// the other bit `mmap` knows, the pages can always be executed as well
+const PROT_READ: u64 = 1;
+
+// reads and writes move at most this much at once, so they don't need all of the heap
+const MAX_TRANSFER: usize = 64 * 1024;
+// the longest path `open` takes, like `PATH_MAX` on Linux
+const MAX_PATH: u64 = 4096;
+
+/// The errors of the system calls, with the numbers Linux uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    NoEntry = 2,
+    Io = 5,
+    BadFile = 9,
+    NoMemory = 12,
+    Fault = 14,
+    Busy = 16,
+    Exists = 17,
+    CrossDevice = 18,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    Invalid = 22,
+    TooManyFiles = 24,
+    NoSpace = 28,
+    ReadOnly = 30,
+    NameTooLong = 36,
+    NoSys = 38,
+    NotEmpty = 39,
+    NotSupported = 95,
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Errno::NoEntry,
+            FsError::AlreadyExists => Errno::Exists,
+            FsError::NotADirectory => Errno::NotADirectory,
+            FsError::IsADirectory => Errno::IsADirectory,
+            FsError::NotEmpty => Errno::NotEmpty,
+            FsError::InvalidPath | FsError::InvalidArgument => Errno::Invalid,
+            FsError::NameTooLong => Errno::NameTooLong,
+            FsError::Busy => Errno::Busy,
+            FsError::CrossDevice => Errno::CrossDevice,
+            // the file wasn't opened for reading or writing
+            FsError::PermissionDenied => Errno::BadFile,
+            FsError::ReadOnly => Errno::ReadOnly,
+            FsError::NoSpace => Errno::NoSpace,
+            FsError::Unsupported => Errno::NotSupported,
+            FsError::Corrupted(_) | FsError::Io(_) => Errno::Io,
+        }
+    }
+}
+
+impl From<AddressSpaceError> for Errno {
+    fn from(err: AddressSpaceError) -> Self {
+        match err {
+            AddressSpaceError::OutOfMemory => Errno::NoMemory,
+            AddressSpaceError::AlreadyMapped(_) => Errno::Exists,
+            _ => Errno::Fault,
+        }
+    }
+}
+
+type SystemCall = fn([u64; 6]) -> Result<u64, Errno>;
+
+// indexed by the number of the call
+static SYSTEM_CALLS: [SystemCall; 8] = [write, read, exit, yield_now, sleep, mmap, open, close];
+
+// the stack of the running process that the system calls run on, set by `Process::run`
+static KERNEL_STACK: AtomicU64 = AtomicU64::new(0);
+// where the stack pointer of the process goes until it is on the kernel stack
+static USER_STACK: AtomicU64 = AtomicU64::new(0);
+
+/// Turns on `syscall` and `sysret`. Needs the GDT.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.kernel_code_selector,
+        selectors.kernel_data_selector,
+    )
+    .expect("the GDT has its segments in the wrong order for syscall");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // the entry point has to switch stacks before an interrupt can come in
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Sets the stack the system calls of the process that runs next use.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    KERNEL_STACK.store(top.as_u64(), Ordering::SeqCst);
+}
+
+// the registers the entry point saves, in the order they are on the stack
+#[repr(C)]
+struct Registers {
+    number: u64,
+    arguments: [u64; 6],
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+extern "C" {
+    fn syscall_entry();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_stack}], rsp",
+    "mov rsp, [rip + {kernel_stack}]",
+    // `syscall` put the instruction pointer into rcx and the flags into r11
+    "push qword ptr [rip + {user_stack}]",
+    "push rcx",
+    "push r11",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    // with 10 registers on it the stack is still aligned to 16 bytes
+    "mov rdi, rsp",
+    "sti",
+    "call {dispatch}",
+    "cli",
+    "add rsp, 8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_stack = sym USER_STACK,
+    kernel_stack = sym KERNEL_STACK,
+    dispatch = sym dispatch,
+);
+
+extern "C" fn dispatch(registers: &Registers) -> u64 {
+    let result = match SYSTEM_CALLS.get(registers.number as usize) {
+        Some(call) => call(registers.arguments),
+        None => Err(Errno::NoSys),
+    };
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+// copies `length` bytes out of the memory of a process, an empty buffer may be anywhere
+fn copy_from_user(space: &AddressSpace, addr: u64, length: usize) -> Result<Vec<u8>, Errno> {
+    if length == 0 {
+        return Ok(Vec::new());
+    }
+    let addr = user_address(addr)?;
+    space.check_access(addr, length as u64, false)?;
+    let mut data = vec![0; length];
+    space.read(addr, &mut data)?;
+    Ok(data)
+}
+
+// checks that a process can write `length` bytes at `addr`, an empty buffer may be anywhere
+fn check_user_buffer(space: &AddressSpace, addr: u64, length: usize) -> Result<VirtAddr, Errno> {
+    if length == 0 {
+        return Ok(VirtAddr::zero());
+    }
+    let addr = user_address(addr)?;
+    space.check_access(addr, length as u64, true)?;
+    Ok(addr)
+}
+
+fn user_address(addr: u64) -> Result<VirtAddr, Errno> {
+    VirtAddr::try_new(addr).map_err(|_| Errno::Fault)
+}
+
+fn fd(argument: u64) -> usize {
+    usize::try_from(argument).unwrap_or(usize::MAX)
+}
+
+fn write([fd_argument, buffer, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let length = (length as usize).min(MAX_TRANSFER);
+    process::with_current(|resources| {
+        let data = copy_from_user(resources.address_space(), buffer, length)?;
+        let written = match resources.file(fd(fd_argument)).ok_or(Errno::BadFile)? {
+            Descriptor::Terminal(terminal) => {
+                x86_64::instructions::interrupts::without_interrupts(|| {
+                    terminal
+                        .writer()
+                        .write_string(&String::from_utf8_lossy(&data))
+                });
+                data.len()
+            }
+            Descriptor::File(file) => file.write(&data)?,
+        };
+        Ok(written as u64)
+    })
+}
+
+fn read([fd_argument, buffer, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let length = (length as usize).min(MAX_TRANSFER);
+    process::with_current(|resources| {
+        let addr = check_user_buffer(resources.address_space(), buffer, length)?;
+        let mut data = vec![0; length];
+        let read = match resources.file(fd(fd_argument)).ok_or(Errno::BadFile)? {
+            Descriptor::Terminal(terminal) => read_terminal(terminal, &mut data),
+            Descriptor::File(file) => file.read(&mut data)?,
+        };
+        if read > 0 {
+            resources.address_space().write(addr, &data[..read])?;
+        }
+        Ok(read as u64)
+    })
+}
+
+// waits for the first character and takes the ones that were typed after it as long as they
+// fit, the rest stays for the next read
+fn read_terminal(terminal: &Terminal, buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+    let mut length = 0;
+    while length + 4 <= buffer.len() || length == 0 {
+        let Some(c) = terminal.read_char() else {
+            if length > 0 {
+                break;
+            }
+            x86_64::instructions::hlt();
+            continue;
+        };
+        let mut encoded = [0; 4];
+        let encoded = c.encode_utf8(&mut encoded).as_bytes();
+        // a character that doesn't fit into a tiny buffer is cut off
+        let fits = encoded.len().min(buffer.len() - length);
+        buffer[length..length + fits].copy_from_slice(&encoded[..fits]);
+        length += fits;
+        if length == buffer.len() {
+            break;
+        }
+    }
+    length
+}
+
+fn exit([code, ..]: [u64; 6]) -> Result<u64, Errno> {
+    process::stop(Stop::Exited(code as i32))
+}
+
+fn yield_now(_: [u64; 6]) -> Result<u64, Errno> {
+    process::pause(Stop::Yielded);
+    Ok(0)
+}
+
+fn sleep([milliseconds, ..]: [u64; 6]) -> Result<u64, Errno> {
+    if milliseconds == 0 {
+        return Ok(0);
+    }
+    let ticks = milliseconds
+        .saturating_mul(1000)
+        .div_ceil(TICK_MICROSECONDS);
+    let until = interrupts::ticks().saturating_add(ticks);
+    process::pause(Stop::Sleeping { until });
+    Ok(0)
+}
+
+fn mmap([addr, length, protection, ..]: [u64; 6]) -> Result<u64, Errno> {
+    if length == 0 || addr & 0xfff != 0 || protection & !(PROT_READ | PROT_WRITE) != 0 {
+        return Err(Errno::Invalid);
+    }
+    let writable = protection & PROT_WRITE != 0;
+    process::with_current(|resources| {
+        if addr == 0 {
+            let start = resources.map_anywhere(length, writable)?;
+            return Ok(start.as_u64());
+        }
+        let start = user_address(addr).map_err(|_| Errno::NoMemory)?;
+        process::address_space::check_range(start, length).map_err(|_| Errno::NoMemory)?;
+        // nothing that is there already gets replaced
+        let space = resources.address_space();
+        let mut page = start;
+        while page < start + length {
+            if space.translate(page).is_some() {
+                return Err(Errno::Exists);
+            }
+            page += 4096u64;
+        }
+        space.map(start, length, writable)?;
+        Ok(start.as_u64())
+    })
+}
+
+fn open([path, length, flags, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let flags = u32::try_from(flags).map_err(|_| Errno::Invalid)?;
+    if !OpenFlags::ALL.contains(OpenFlags::from_bits(flags)) {
+        return Err(Errno::Invalid);
+    }
+    if length > MAX_PATH {
+        return Err(Errno::NameTooLong);
+    }
+    process::with_current(|resources| {
+        let path = copy_from_user(resources.address_space(), path, length as usize)?;
+        let path = core::str::from_utf8(&path).map_err(|_| Errno::Invalid)?;
+        let file = vfs::open(path, OpenFlags::from_bits(flags))?;
+        let fd = resources
+            .add_file(Descriptor::File(file))
+            .ok_or(Errno::TooManyFiles)?;
+        Ok(fd as u64)
+    })
+}
+
+fn close([fd_argument, ..]: [u64; 6]) -> Result<u64, Errno> {
+    process::with_current(|resources| match resources.remove_file(fd(fd_argument)) {
+        Some(_) => Ok(0),
+        None => Err(Errno::BadFile),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{Process, CODE_START, MMAP_START, STACK_SIZE, STACK_TOP};
+    use crate::terminal;
+    use core::ptr::addr_of;
+
+    // runs the system calls on its stack one after the other, each one is the number and six
+    // arguments, and puts the results where the numbers were
+    global_asm!(
+        ".global test_script_start, test_script_end",
+        "test_script_start:",
+        "mov rax, [rsp]",
+        "mov rdi, [rsp + 8]",
+        "mov rsi, [rsp + 16]",
+        "mov rdx, [rsp + 24]",
+        "mov r10, [rsp + 32]",
+        "mov r8, [rsp + 40]",
+        "mov r9, [rsp + 48]",
+        "syscall",
+        "mov [rsp], rax",
+        "add rsp, 56",
+        "jmp test_script_start",
+        "test_script_end:",
+    );
+
+    extern "C" {
+        static test_script_start: u8;
+        static test_script_end: u8;
+    }
+
+    // the calls start at the bottom of the stack, data for them goes above
+    const CALLS: u64 = STACK_TOP - STACK_SIZE;
+    const DATA: u64 = STACK_TOP - 4096;
+
+    fn script(calls: &[[u64; 7]], data: &[u8]) -> Process {
+        let (start, end) = (addr_of!(test_script_start), addr_of!(test_script_end));
+        let code = unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) };
+        let calls: Vec<u8> = calls
+            .iter()
+            .flatten()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .map(VirtAddr::new(CODE_START), code.len() as u64, false)
+            .unwrap();
+        space.write(VirtAddr::new(CODE_START), code).unwrap();
+        space.map(VirtAddr::new(CALLS), STACK_SIZE, true).unwrap();
+        space.write(VirtAddr::new(CALLS), &calls).unwrap();
+        space.write(VirtAddr::new(DATA), data).unwrap();
+        Process::with_address_space(space, VirtAddr::new(CODE_START), VirtAddr::new(CALLS))
+    }
+
+    fn results(process: &mut Process, count: usize) -> Vec<i64> {
+        let mut results = Vec::new();
+        for index in 0..count {
+            let mut result = [0; 8];
+            let addr = VirtAddr::new(CALLS + index as u64 * 56);
+            process
+                .resources()
+                .address_space()
+                .read(addr, &mut result)
+                .unwrap();
+            results.push(i64::from_le_bytes(result));
+        }
+        results
+    }
+
+    fn errno(errno: Errno) -> i64 {
+        -(errno as i64)
+    }
+
+    #[test_case]
+    fn console_and_errors() {
+        let kernel = crate::allocator::HEAP_START as u64;
+        let mut process = script(
+            &[
+                [WRITE, 1, DATA, 13, 0, 0, 0],
+                [WRITE, 1, kernel, 8, 0, 0, 0],
+                [WRITE, 1, DATA - 0x10_0000, 8, 0, 0, 0],
+                [WRITE, 1, u64::MAX - 3, 8, 0, 0, 0],
+                [WRITE, 9, DATA, 1, 0, 0, 0],
+                // the code can't be written to
+                [READ, 0, CODE_START, 1, 0, 0, 0],
+                [99, 0, 0, 0, 0, 0, 0],
+                // nothing is read or written, so the buffer isn't checked
+                [WRITE, 1, 0, 0, 0, 0, 0],
+                [READ, 0, 0, 0, 0, 0, 0],
+                [EXIT, 42, 0, 0, 0, 0, 0],
+            ],
+            b"hello, world\n",
+        );
+        assert_eq!(process.run(), Stop::Exited(42));
+        assert_eq!(
+            results(&mut process, 9),
+            [
+                13,
+                errno(Errno::Fault),
+                errno(Errno::Fault),
+                errno(Errno::Fault),
+                errno(Errno::BadFile),
+                errno(Errno::Fault),
+                errno(Errno::NoSys),
+                0,
+                0,
+            ]
+        );
+        assert_eq!(process.run(), Stop::Exited(42));
+    }
+
+    #[test_case]
+    fn files_and_memory() {
+        let path = b"/syscall-test";
+        let mut data = [0; 64];
+        data[..path.len()].copy_from_slice(path);
+        data[32..42].copy_from_slice(b"from ring3");
+        let (read, write, create) = (
+            OpenFlags::READ.bits() as u64,
+            (OpenFlags::WRITE | OpenFlags::CREATE).bits() as u64,
+            (OpenFlags::CREATE | OpenFlags::EXCLUSIVE).bits() as u64,
+        );
+        let path_length = path.len() as u64;
+        let mut process = script(
+            &[
+                [OPEN, DATA, path_length, write, 0, 0, 0],
+                [WRITE, 3, DATA + 32, 10, 0, 0, 0],
+                [CLOSE, 3, 0, 0, 0, 0, 0],
+                [CLOSE, 3, 0, 0, 0, 0, 0],
+                [OPEN, DATA, path_length, create, 0, 0, 0],
+                [OPEN, DATA, path_length, read, 0, 0, 0],
+                [MMAP, 0, 8192, PROT_READ | PROT_WRITE, 0, 0, 0],
+                [READ, 3, MMAP_START + 4090, 100, 0, 0, 0],
+                [READ, 3, MMAP_START, 100, 0, 0, 0],
+                [WRITE, 3, DATA, 1, 0, 0, 0],
+                [MMAP, MMAP_START + 4096, 4096, 0, 0, 0, 0],
+                [MMAP, MMAP_START + 1, 4096, 0, 0, 0, 0],
+                [MMAP, MMAP_START + 0x10000, 4096, PROT_READ, 0, 0, 0],
+                [READ, 3, MMAP_START + 0x10000, 1, 0, 0, 0],
+                // the next memory the kernel picks goes behind what is in the way
+                [MMAP, MMAP_START + 0x2000, 4096, PROT_READ, 0, 0, 0],
+                [MMAP, 0, 4096, PROT_READ, 0, 0, 0],
+                [OPEN, DATA, MAX_PATH + 1, read, 0, 0, 0],
+                [EXIT, 0, 0, 0, 0, 0, 0],
+            ],
+            &data,
+        );
+        assert_eq!(process.run(), Stop::Exited(0));
+        assert_eq!(
+            results(&mut process, 17),
+            [
+                3,
+                10,
+                0,
+                errno(Errno::BadFile),
+                errno(Errno::Exists),
+                3,
+                MMAP_START as i64,
+                10,
+                0,
+                errno(Errno::BadFile),
+                errno(Errno::Exists),
+                errno(Errno::Invalid),
+                MMAP_START as i64 + 0x10000,
+                errno(Errno::Fault),
+                MMAP_START as i64 + 0x2000,
+                MMAP_START as i64 + 0x3000,
+                errno(Errno::NameTooLong),
+            ]
+        );
+        // the read ran across two pages
+        let mut buffer = [0; 10];
+        let addr = VirtAddr::new(MMAP_START + 4090);
+        process
+            .resources()
+            .address_space()
+            .read(addr, &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer, b"from ring3");
+        assert_eq!(vfs::read("/syscall-test").unwrap(), b"from ring3");
+        vfs::remove("/syscall-test").unwrap();
+    }
+
+    #[test_case]
+    fn terminal_input() {
+        let console = terminal::get(0).unwrap();
+        for c in "hé!".chars() {
+            console.push_input(c);
+        }
+        let mut process = script(
+            &[
+                // the rest of the buffer can't take a whole character
+                [READ, 0, DATA, 2, 0, 0, 0],
+                [READ, 0, DATA + 1, 64, 0, 0, 0],
+                [EXIT, 0, 0, 0, 0, 0, 0],
+            ],
+            &[],
+        );
+        assert_eq!(process.run(), Stop::Exited(0));
+        assert_eq!(results(&mut process, 2), [1, 3]);
+        let mut buffer = [0; 4];
+        process
+            .resources()
+            .address_space()
+            .read(VirtAddr::new(DATA), &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer, "hé!".as_bytes());
+    }
+
+    #[test_case]
+    fn yield_and_sleep() {
+        let mut process = script(
+            &[
+                [YIELD, 0, 0, 0, 0, 0, 0],
+                [SLEEP, 100, 0, 0, 0, 0, 0],
+                [SLEEP, 0, 0, 0, 0, 0, 0],
+                [EXIT, 7, 0, 0, 0, 0, 0],
+            ],
+            &[],
+        );
+        assert_eq!(process.run(), Stop::Yielded);
+        let start = interrupts::ticks();
+        let Stop::Sleeping { until } = process.run() else {
+            panic!("the process didn't sleep");
+        };
+        // 100 ms are two ticks
+        assert!((start + 2..=interrupts::ticks() + 2).contains(&until));
+        let mut stop = process.run();
+        while stop == (Stop::Sleeping { until }) {
+            x86_64::instructions::hlt();
+            stop = process.run();
+        }
+        assert_eq!(stop, Stop::Exited(7));
+        assert!(interrupts::ticks() >= until);
+        assert_eq!(results(&mut process, 3), [0, 0, 0]);
+    }
+}
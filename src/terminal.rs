@@ -0,0 +1,159 @@
+use crate::ring_buffer::RingBuffer;
+use crate::vga_buffer::VGAWriter;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pc_keyboard::KeyCode;
+use spin::{Mutex, MutexGuard};
+
+/// The number of virtual terminals, one for each of Alt+F1 to Alt+F6.
+pub const TERMINAL_COUNT: usize = 6;
+/// The number of characters a terminal buffers before it starts dropping input.
+const INPUT_QUEUE_SIZE: usize = 256;
+
+// all virtual terminals, the first one doubles as the kernel console which `print!` writes to.
+// these are plain statics (no `lazy_static`) so that the rather large writers never have to
+// be moved across the stack.
+pub(crate) static TERMINALS: [Terminal; TERMINAL_COUNT] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INACTIVE: Terminal = Terminal::new(VGAWriter::new());
+    let mut terminals = [INACTIVE; TERMINAL_COUNT];
+    terminals[0] = Terminal::new(VGAWriter::new_active());
+    terminals
+};
+
+/// The index of the terminal that is currently shown on the screen and receives keyboard input.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// A virtual terminal consisting of its own screen and a queue of keyboard input.
+pub struct Terminal {
+    pub(crate) writer: Mutex<VGAWriter>,
+    input: Mutex<RingBuffer<char, INPUT_QUEUE_SIZE>>,
+}
+
+impl Terminal {
+    const fn new(writer: VGAWriter) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            input: Mutex::new(RingBuffer::new()),
+        }
+    }
+
+    /// Locks and returns the writer of this terminal.
+    pub fn writer(&self) -> MutexGuard<'_, VGAWriter> {
+        self.writer.lock()
+    }
+
+    /// Queues up a character typed into this terminal.
+    /// Returns `false` if the queue is full and the character was dropped.
+    pub fn push_input(&self, c: char) -> bool {
+        self.input.lock().push(c)
+    }
+
+    /// Takes the oldest character typed into this terminal out of its queue.
+    pub fn read_char(&self) -> Option<char> {
+        use x86_64::instructions::interrupts;
+
+        // the keyboard interrupt handler pushes to the queue so we have to keep it out
+        // while we hold the lock.
+        interrupts::without_interrupts(|| self.input.lock().pop())
+    }
+}
+
+/// Returns the terminal at `index`, or `None` if there is no such terminal.
+pub fn get(index: usize) -> Option<&'static Terminal> {
+    TERMINALS.get(index)
+}
+
+/// Returns the index of the terminal currently shown on the screen.
+pub fn active_index() -> usize {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Returns the terminal currently shown on the screen.
+pub fn active() -> &'static Terminal {
+    &TERMINALS[active_index()]
+}
+
+/// Shows the terminal at `index` on the screen and directs keyboard input to it.
+pub fn switch_to(index: usize) {
+    use x86_64::instructions::interrupts;
+
+    if index >= TERMINAL_COUNT {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let previous = ACTIVE.swap(index, Ordering::SeqCst);
+        if previous == index {
+            return;
+        }
+        TERMINALS[previous].writer().deactivate();
+        TERMINALS[index].writer().activate();
+    })
+}
+
+/// Returns the terminal index that Alt+`code` switches to, if any.
+pub fn index_for_key(code: KeyCode) -> Option<usize> {
+    match code {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        KeyCode::F5 => Some(4),
+        KeyCode::F6 => Some(5),
+        _ => None,
+    }
+}
+
+// prints to the virtual terminal with the given index.
+#[macro_export]
+macro_rules! term_print {
+    ($index:expr, $($arg:tt)*) => ($crate::terminal::_print($index, format_args!($($arg)*)));
+}
+
+// prints to the virtual terminal with the given index, appending a newline.
+#[macro_export]
+macro_rules! term_println {
+    ($index:expr) => ($crate::term_print!($index, "\n"));
+    ($index:expr, $($arg:tt)*) => ($crate::term_print!($index, "{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(index: usize, args: fmt::Arguments) {
+    use fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    if let Some(terminal) = get(index) {
+        interrupts::without_interrupts(|| {
+            terminal.writer().write_fmt(args).unwrap();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn input_is_per_terminal() {
+        assert!(get(2).unwrap().push_input('q'));
+        assert_eq!(get(1).unwrap().read_char(), None);
+        assert_eq!(get(2).unwrap().read_char(), Some('q'));
+    }
+
+    #[test_case]
+    fn switching_terminals() {
+        term_print!(3, "hidden");
+        assert!(!get(3).unwrap().writer().is_active());
+
+        switch_to(3);
+        assert_eq!(active_index(), 3);
+        assert!(get(3).unwrap().writer().is_active());
+        assert!(!get(0).unwrap().writer().is_active());
+
+        switch_to(0);
+        assert_eq!(active_index(), 0);
+        assert!(get(0).unwrap().writer().is_active());
+        assert!(!get(3).unwrap().writer().is_active());
+    }
+}
@@ -0,0 +1,748 @@
+//! The virtual filesystem, which puts all mounted filesystems into a single tree of paths and
+//! gives access to their files through handles.
+//!
+//! Filesystems implement [`FileSystem`] and [`Inode`], the VFS takes care of resolving paths,
+//! mount points and the directory entry cache.
+
+use crate::block::BlockError;
+use crate::fs::TmpFs;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use spin::Mutex;
+
+pub mod dentry;
+pub mod file;
+pub mod path;
+
+pub use dentry::Dentry;
+pub use file::{File, OpenFlags, SeekFrom};
+
+/// The longest name a directory entry can have.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+// the root of the tree, which is where the first filesystem gets mounted
+static ROOT: Mutex<Option<Arc<Dentry>>> = Mutex::new(None);
+// where relative paths start
+static CURRENT_DIRECTORY: Mutex<Option<Arc<Dentry>>> = Mutex::new(None);
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+// who the permission bits are checked against
+static CREDENTIALS: Mutex<Credentials> = Mutex::new(Credentials::ROOT);
+
+// the kinds of access `check_access` can be asked for, in the position of the bits for others
+const MAY_READ: u16 = 0o4;
+const MAY_WRITE: u16 = 0o2;
+const MAY_EXECUTE: u16 = 0o1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    /// A directory that should be removed still has entries.
+    NotEmpty,
+    InvalidPath,
+    NameTooLong,
+    /// Something is mounted there, or it's the root of a mount.
+    Busy,
+    /// Renaming across filesystems.
+    CrossDevice,
+    /// The handle wasn't opened for this, or the permission bits don't allow it.
+    PermissionDenied,
+    /// Like seeking in front of the start of a file.
+    InvalidArgument,
+    ReadOnly,
+    NoSpace,
+    /// The filesystem can't do this.
+    Unsupported,
+    /// The data on the disk doesn't make sense.
+    Corrupted(&'static str),
+    Io(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        FsError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+/// What `stat` tells about a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// The number of the inode, unique within its filesystem.
+    pub inode: u64,
+    pub kind: FileType,
+    pub size: u64,
+    /// How many directory entries point at the inode.
+    pub links: u32,
+    /// The Unix permission bits, like `0o644`, which the VFS checks against the
+    /// [`Credentials`] when opening, creating and removing files.
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileType::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == FileType::File
+    }
+}
+
+/// The user and group the VFS checks the permission bits against.
+///
+/// Filesystems can't change the owner of an inode yet, so new files and directories belong
+/// to root no matter who creates them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    /// The superuser, whom the permission bits don't apply to.
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// A file or directory of a filesystem.
+///
+/// The methods for files fail with [`FsError::IsADirectory`] by default and those for
+/// directories with [`FsError::NotADirectory`], so inodes only implement what fits them.
+/// Names are never `.` or `..`, the VFS resolves those itself.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// Lets filesystems get their own type back, for the target directory of a rename.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Reads from `offset` on and returns how many bytes that were, which is 0 at the end.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Writes at `offset`, growing the file if necessary, and returns how many bytes were
+    /// written.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Cuts the file off at `size`, or grows it with zeros.
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Finds an entry of a directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Removes a file, or a directory that is empty.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Moves the entry `name` into the directory `target`, which is on the same filesystem,
+    /// as `new_name`. An existing file there is replaced.
+    fn rename(
+        &self,
+        _name: &str,
+        _target: &Arc<dyn Inode>,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Changes the permission bits, on filesystems that have them.
+    fn set_permissions(&self, _permissions: u16) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Writes changes of the inode back to the disk.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    /// The type of the filesystem, like `tmpfs`.
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes everything that changed back to the disk.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// An entry of the mount table.
+#[derive(Clone)]
+pub struct Mount {
+    pub path: String,
+    pub filesystem: Arc<dyn FileSystem>,
+    root: Arc<Dentry>,
+}
+
+fn root() -> Result<Arc<Dentry>, FsError> {
+    let root = ROOT.lock().clone().ok_or(FsError::NotFound)?;
+    Ok(Dentry::follow_mounts(root))
+}
+
+fn current_directory() -> Result<Arc<Dentry>, FsError> {
+    match CURRENT_DIRECTORY.lock().clone() {
+        Some(directory) => Ok(directory),
+        None => root(),
+    }
+}
+
+/// Checks that the current credentials allow the `wanted` kinds of access to a file, which
+/// are a combination of `MAY_READ`, `MAY_WRITE` and `MAY_EXECUTE`.
+fn check_access(dentry: &Dentry, wanted: u16) -> Result<(), FsError> {
+    let credentials = credentials();
+    if credentials.uid == Credentials::ROOT.uid {
+        return Ok(());
+    }
+    // only one set of bits applies, even if the others would allow more
+    let metadata = dentry.metadata()?;
+    let granted = if credentials.uid == metadata.uid {
+        metadata.permissions >> 6
+    } else if credentials.gid == metadata.gid {
+        metadata.permissions >> 3
+    } else {
+        metadata.permissions
+    };
+    match granted & wanted == wanted {
+        true => Ok(()),
+        false => Err(FsError::PermissionDenied),
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    match name.len() {
+        0 => Err(FsError::InvalidPath),
+        1..=MAX_NAME_LENGTH => Ok(()),
+        _ => Err(FsError::NameTooLong),
+    }
+}
+
+/// Finds the directory entry of a path. Relative paths start at the current directory.
+pub fn lookup(path: &str) -> Result<Arc<Dentry>, FsError> {
+    let start = match path::is_absolute(path) {
+        true => root()?,
+        false => current_directory()?,
+    };
+    lookup_from(start, path)
+}
+
+/// Resolves the components of `path` one after the other, beginning at `start`.
+fn lookup_from(start: Arc<Dentry>, path: &str) -> Result<Arc<Dentry>, FsError> {
+    let mut dentry = start;
+    for name in path::components(path) {
+        check_name(name)?;
+        // looking into a directory needs its execute bit
+        check_access(&dentry, MAY_EXECUTE)?;
+        dentry = match name {
+            // the root is its own parent
+            ".." => dentry.parent().unwrap_or(dentry),
+            _ => dentry.child(name)?,
+        };
+    }
+    Ok(dentry)
+}
+
+/// Finds the directory a new entry goes into, and the name of the entry.
+fn lookup_parent(path: &str) -> Result<(Arc<Dentry>, &str), FsError> {
+    let (directory, name) = path::split_last(path).ok_or(FsError::InvalidPath)?;
+    check_name(name)?;
+    let directory = lookup(directory)?;
+    if !directory.metadata()?.is_dir() {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((directory, name))
+}
+
+/// Returns who the permission bits are currently checked against.
+pub fn credentials() -> Credentials {
+    *CREDENTIALS.lock()
+}
+
+/// Changes who the permission bits are checked against.
+pub fn set_credentials(credentials: Credentials) {
+    *CREDENTIALS.lock() = credentials;
+}
+
+/// Mounts an empty tmpfs at `/`, so there is a tree to mount other filesystems into.
+pub fn init() {
+    mount("/", TmpFs::new()).expect("the root filesystem is already mounted");
+}
+
+/// Mounts a filesystem at `path`, which has to be an existing directory. The first
+/// filesystem has to be mounted at `/`.
+pub fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let mut root = ROOT.lock();
+    let dentry = match root.as_ref() {
+        None if path == "/" => {
+            let dentry = Dentry::root(filesystem.clone());
+            *root = Some(dentry.clone());
+            dentry
+        }
+        None => return Err(FsError::NotFound),
+        Some(_) => {
+            drop(root);
+            let mount_point = lookup(path)?;
+            if !mount_point.metadata()?.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            mount_point.mount(filesystem.clone())?
+        }
+    };
+    MOUNTS.lock().push(Mount {
+        path: dentry.path(),
+        filesystem,
+        root: dentry,
+    });
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted at `path` after syncing it.
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let dentry = lookup(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| Arc::ptr_eq(&mount.root, &dentry))
+        .ok_or(FsError::InvalidPath)?;
+    // the root stays, and so do filesystems that have others mounted in them
+    let Some(parent) = dentry.parent() else {
+        return Err(FsError::Busy);
+    };
+    if mounts
+        .iter()
+        .any(|mount| !Arc::ptr_eq(&mount.root, &dentry) && mount.root.is_below(&dentry))
+    {
+        return Err(FsError::Busy);
+    }
+    mounts[index].filesystem.sync()?;
+    parent.unmount_child(dentry.name())?;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Returns the mount table.
+pub fn mounts() -> Vec<Mount> {
+    MOUNTS.lock().clone()
+}
+
+/// Syncs all mounted filesystems.
+pub fn sync() -> Result<(), FsError> {
+    for mount in mounts() {
+        mount.filesystem.sync()?;
+    }
+    Ok(())
+}
+
+/// Opens a file or directory.
+pub fn open(path: &str, flags: OpenFlags) -> Result<File, FsError> {
+    let dentry = match lookup(path) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(FsError::AlreadyExists)
+        }
+        Ok(dentry) => {
+            let mut wanted = 0;
+            if flags.contains(OpenFlags::READ) {
+                wanted |= MAY_READ;
+            }
+            if flags.contains(OpenFlags::WRITE) {
+                wanted |= MAY_WRITE;
+            }
+            check_access(&dentry, wanted)?;
+            dentry
+        }
+        // a file we just created can be opened however we like
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (directory, name) = lookup_parent(path)?;
+            check_access(&directory, MAY_WRITE | MAY_EXECUTE)?;
+            directory.create(name, FileType::File)?
+        }
+        Err(err) => return Err(err),
+    };
+    File::open(dentry, flags)
+}
+
+/// Creates a file, or empties it if it exists, and opens it for writing.
+pub fn create(path: &str) -> Result<File, FsError> {
+    open(
+        path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+    )
+}
+
+/// Creates a directory.
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    let (directory, name) = lookup_parent(path)?;
+    check_access(&directory, MAY_WRITE | MAY_EXECUTE)?;
+    directory.create(name, FileType::Directory).map(|_| ())
+}
+
+/// Removes a file or an empty directory.
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (directory, name) = lookup_parent(path)?;
+    check_access(&directory, MAY_WRITE | MAY_EXECUTE)?;
+    directory.remove(name)
+}
+
+/// Moves a file or directory, replacing the file at `new_path` if there is one.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_directory, old_name) = lookup_parent(old_path)?;
+    let (new_directory, new_name) = lookup_parent(new_path)?;
+    check_access(&old_directory, MAY_WRITE | MAY_EXECUTE)?;
+    check_access(&new_directory, MAY_WRITE | MAY_EXECUTE)?;
+    let dentry = old_directory.child(old_name)?;
+    // a directory can't be moved into itself
+    if new_directory.is_below(&dentry) {
+        return Err(FsError::InvalidPath);
+    }
+    old_directory.rename(old_name, &new_directory, new_name)
+}
+
+/// Returns the metadata of a file or directory.
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    lookup(path)?.metadata()
+}
+
+/// Changes the permission bits of a file or directory, which only its owner and root may do.
+pub fn set_permissions(path: &str, permissions: u16) -> Result<(), FsError> {
+    if permissions & !0o7777 != 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    let dentry = lookup(path)?;
+    let uid = credentials().uid;
+    if uid != Credentials::ROOT.uid && uid != dentry.metadata()?.uid {
+        return Err(FsError::PermissionDenied);
+    }
+    dentry.inode().set_permissions(permissions)
+}
+
+/// Returns the entries of a directory.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path)?.inode().readdir()
+}
+
+/// Reads a whole file.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    let file = open(path, OpenFlags::READ)?;
+    let mut data = alloc::vec![0; file.stat()?.size as usize];
+    let mut length = 0;
+    // filesystems can return less than asked for, only a read of nothing is the end
+    loop {
+        if length == data.len() {
+            // the file might have grown since we looked at its size
+            data.resize(length + 512, 0);
+        }
+        match file.read(&mut data[length..])? {
+            0 => break,
+            read => length += read,
+        }
+    }
+    data.truncate(length);
+    Ok(data)
+}
+
+/// Replaces the contents of a file, creating it if necessary.
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let file = create(path)?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            0 => return Err(FsError::NoSpace),
+            length => written += length,
+        }
+    }
+    Ok(())
+}
+
+/// Changes the directory relative paths start at.
+pub fn set_current_directory(path: &str) -> Result<(), FsError> {
+    let dentry = lookup(path)?;
+    if !dentry.metadata()?.is_dir() {
+        return Err(FsError::NotADirectory);
+    }
+    *CURRENT_DIRECTORY.lock() = Some(dentry);
+    Ok(())
+}
+
+/// The absolute path of the current directory.
+pub fn current_directory_path() -> Result<String, FsError> {
+    Ok(current_directory()?.path())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    /// A tiny read-only filesystem, so the VFS can be tested on its own.
+    pub struct TestFs {
+        root: Arc<dyn Inode>,
+    }
+
+    struct TestInode {
+        number: u64,
+        // `None` for files
+        children: Option<BTreeMap<String, Arc<dyn Inode>>>,
+        data: Vec<u8>,
+        // the most a single read returns
+        max_read: usize,
+    }
+
+    impl Inode for TestInode {
+        fn metadata(&self) -> Result<Metadata, FsError> {
+            Ok(Metadata {
+                inode: self.number,
+                kind: match self.children {
+                    Some(_) => FileType::Directory,
+                    None => FileType::File,
+                },
+                size: self.data.len() as u64,
+                links: 1,
+                permissions: 0o644,
+                uid: 0,
+                gid: 0,
+            })
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+            if self.children.is_some() {
+                return Err(FsError::IsADirectory);
+            }
+            let data = self.data.get(offset as usize..).unwrap_or(&[]);
+            let length = data.len().min(buffer.len()).min(self.max_read);
+            buffer[..length].copy_from_slice(&data[..length]);
+            Ok(length)
+        }
+
+        fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+            Err(FsError::ReadOnly)
+        }
+
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+            let children = self.children.as_ref().ok_or(FsError::NotADirectory)?;
+            children.get(name).cloned().ok_or(FsError::NotFound)
+        }
+
+        fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+            Err(FsError::ReadOnly)
+        }
+
+        fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+            let children = self.children.as_ref().ok_or(FsError::NotADirectory)?;
+            children
+                .iter()
+                .map(|(name, inode)| {
+                    let metadata = inode.metadata()?;
+                    Ok(DirEntry {
+                        name: name.clone(),
+                        inode: metadata.inode,
+                        kind: metadata.kind,
+                    })
+                })
+                .collect()
+        }
+    }
+
+    impl TestFs {
+        /// A filesystem with `dir/file` and `dir/sub/`, `file` contains `name`.
+        pub fn new(name: &str) -> Arc<Self> {
+            Self::with_max_read(name, usize::MAX)
+        }
+
+        /// Like [`TestFs::new`], but reading `file` never returns more than `max_read` bytes.
+        pub fn with_max_read(name: &str, max_read: usize) -> Arc<Self> {
+            let file: Arc<dyn Inode> = Arc::new(TestInode {
+                number: 3,
+                children: None,
+                data: name.as_bytes().to_vec(),
+                max_read,
+            });
+            let sub: Arc<dyn Inode> = Arc::new(TestInode {
+                number: 4,
+                children: Some(BTreeMap::new()),
+                data: Vec::new(),
+                max_read: usize::MAX,
+            });
+            let dir: Arc<dyn Inode> = Arc::new(TestInode {
+                number: 2,
+                children: Some(BTreeMap::from([
+                    ("file".to_string(), file),
+                    ("sub".to_string(), sub),
+                ])),
+                data: Vec::new(),
+                max_read: usize::MAX,
+            });
+            let root = Arc::new(TestInode {
+                number: 1,
+                children: Some(BTreeMap::from([("dir".to_string(), dir)])),
+                data: Vec::new(),
+                max_read: usize::MAX,
+            });
+            Arc::new(Self { root })
+        }
+    }
+
+    impl FileSystem for TestFs {
+        fn name(&self) -> &str {
+            "testfs"
+        }
+
+        fn root(&self) -> Arc<dyn Inode> {
+            self.root.clone()
+        }
+    }
+
+    #[test_case]
+    fn path_resolution() {
+        let root = Dentry::root(TestFs::new("a"));
+        let dir = lookup_from(root.clone(), "/dir").unwrap();
+        assert_eq!(
+            lookup_from(dir.clone(), "file").unwrap().path(),
+            "/dir/file"
+        );
+        assert_eq!(
+            lookup_from(dir.clone(), "./sub/../file").unwrap().path(),
+            "/dir/file"
+        );
+        assert_eq!(lookup_from(dir.clone(), "../../..").unwrap().path(), "/");
+        assert_eq!(
+            lookup_from(root.clone(), "/dir//sub/")
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .inode,
+            4
+        );
+        assert_eq!(
+            lookup_from(dir.clone(), "missing").err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(
+            lookup_from(dir.clone(), "file/more").err(),
+            Some(FsError::NotADirectory)
+        );
+        assert_eq!(
+            lookup_from(dir.clone(), &"x".repeat(MAX_NAME_LENGTH + 1)).err(),
+            Some(FsError::NameTooLong)
+        );
+    }
+
+    #[test_case]
+    fn mount_points() {
+        let root = Dentry::root(TestFs::new("a"));
+        let sub = lookup_from(root.clone(), "/dir/sub").unwrap();
+        let mounted = sub.mount(TestFs::new("b")).unwrap();
+        assert_eq!(mounted.path(), "/dir/sub");
+
+        // the path goes into the mounted filesystem, and `..` comes back out of it
+        let file = lookup_from(root.clone(), "/dir/sub/dir/file").unwrap();
+        let mut data = vec![0; 8];
+        let length = file.inode().read_at(0, &mut data).unwrap();
+        assert_eq!(&data[..length], b"b");
+        assert_eq!(
+            lookup_from(mounted.clone(), "../file").unwrap().path(),
+            "/dir/file"
+        );
+
+        // mount points can't be removed while something is mounted on them
+        let dir = lookup_from(root.clone(), "/dir").unwrap();
+        assert_eq!(dir.remove("sub"), Err(FsError::Busy));
+        dir.unmount_child("sub").unwrap();
+        assert_eq!(
+            lookup_from(root.clone(), "/dir/sub/dir").err(),
+            Some(FsError::NotFound)
+        );
+    }
+
+    #[test_case]
+    fn file_handles() {
+        let root = Dentry::root(TestFs::new("hello"));
+        let dentry = lookup_from(root.clone(), "/dir/file").unwrap();
+        let file = File::open(dentry.clone(), OpenFlags::READ).unwrap();
+        let mut buffer = [0; 3];
+        assert_eq!(file.read(&mut buffer).unwrap(), 3);
+        assert_eq!(&buffer, b"hel");
+        assert_eq!(file.seek(SeekFrom::End(-1)).unwrap(), 4);
+        assert_eq!(file.read(&mut buffer).unwrap(), 1);
+        assert_eq!(file.read(&mut buffer).unwrap(), 0);
+        assert_eq!(
+            file.seek(SeekFrom::Current(-10)),
+            Err(FsError::InvalidArgument)
+        );
+        assert_eq!(file.stat().unwrap().size, 5);
+        // opened for reading only
+        assert_eq!(file.write(b"x"), Err(FsError::PermissionDenied));
+        assert_eq!(
+            File::open(dentry, OpenFlags::READ | OpenFlags::DIRECTORY).err(),
+            Some(FsError::NotADirectory)
+        );
+
+        let dir = lookup_from(root.clone(), "/dir").unwrap();
+        let handle = File::open(dir, OpenFlags::READ).unwrap();
+        let names: Vec<_> = handle
+            .readdir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["file", "sub"]);
+        assert_eq!(handle.read(&mut buffer), Err(FsError::IsADirectory));
+    }
+
+    #[test_case]
+    fn short_reads() {
+        mkdir("/vfs-test").unwrap();
+        mount(
+            "/vfs-test",
+            TestFs::with_max_read("more than a few bytes", 3),
+        )
+        .unwrap();
+        assert_eq!(
+            read("/vfs-test/dir/file").unwrap(),
+            b"more than a few bytes"
+        );
+        unmount("/vfs-test").unwrap();
+        remove("/vfs-test").unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! Directory entries, which tie inodes to their names and parents and cache the lookups.
+
+use super::{FileSystem, FileType, FsError, Inode, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+/// A name in the tree of paths and the inode behind it.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    filesystem: Arc<dyn FileSystem>,
+    /// `None` only for the root of the whole tree.
+    parent: Option<Arc<Dentry>>,
+    // the entries that were looked up already
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    // the root of the filesystem mounted on top of this one
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    /// Creates the root of the tree, with `filesystem` mounted on it.
+    pub fn root(filesystem: Arc<dyn FileSystem>) -> Arc<Self> {
+        Self::new(String::new(), filesystem.root(), filesystem, None)
+    }
+
+    fn new(
+        name: String,
+        inode: Arc<dyn Inode>,
+        filesystem: Arc<dyn FileSystem>,
+        parent: Option<Arc<Dentry>>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            inode,
+            filesystem,
+            parent,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// The filesystem the inode belongs to.
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem> {
+        &self.filesystem
+    }
+
+    /// The directory this entry is in. For the root of a mounted filesystem that's the
+    /// directory the mount point is in.
+    pub fn parent(&self) -> Option<Arc<Dentry>> {
+        self.parent.clone()
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, FsError> {
+        self.inode.metadata()
+    }
+
+    /// The absolute path of the entry.
+    pub fn path(&self) -> String {
+        match &self.parent {
+            None => String::from("/"),
+            Some(parent) => {
+                let mut path = parent.path();
+                if !path.ends_with('/') {
+                    path.push('/');
+                }
+                path.push_str(&self.name);
+                path
+            }
+        }
+    }
+
+    /// Whether this is `other` or somewhere inside of it.
+    pub fn is_below(self: &Arc<Self>, other: &Arc<Dentry>) -> bool {
+        let mut current = Some(self.clone());
+        while let Some(dentry) = current {
+            if Arc::ptr_eq(&dentry, other) {
+                return true;
+            }
+            current = dentry.parent();
+        }
+        false
+    }
+
+    /// Returns the root of whatever is mounted on top of the entry, or the entry itself.
+    pub(super) fn follow_mounts(mut dentry: Arc<Dentry>) -> Arc<Dentry> {
+        loop {
+            let mounted = dentry.mounted.lock().clone();
+            match mounted {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// Looks up an entry of this directory.
+    pub fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        let mut children = self.children.lock();
+        if let Some(child) = children.get(name) {
+            return Ok(Self::follow_mounts(child.clone()));
+        }
+        let inode = self.inode.lookup(name)?;
+        let child = Self::new(
+            String::from(name),
+            inode,
+            self.filesystem.clone(),
+            Some(self.clone()),
+        );
+        children.insert(String::from(name), child.clone());
+        Ok(child)
+    }
+
+    /// Creates a file or directory in this directory.
+    pub fn create(self: &Arc<Self>, name: &str, kind: FileType) -> Result<Arc<Dentry>, FsError> {
+        if self.child(name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.inode.create(name, kind)?;
+        self.child(name)
+    }
+
+    fn is_mount_point(&self, name: &str) -> bool {
+        let children = self.children.lock();
+        children
+            .get(name)
+            .is_some_and(|child| child.mounted.lock().is_some())
+    }
+
+    /// Removes a file or empty directory from this directory.
+    pub fn remove(self: &Arc<Self>, name: &str) -> Result<(), FsError> {
+        if self.is_mount_point(name) {
+            return Err(FsError::Busy);
+        }
+        self.inode.unlink(name)?;
+        self.children.lock().remove(name);
+        Ok(())
+    }
+
+    /// Moves an entry of this directory to `target`, which has to be on the same filesystem.
+    pub fn rename(
+        self: &Arc<Self>,
+        name: &str,
+        target: &Arc<Dentry>,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        if !Arc::ptr_eq(&self.filesystem, &target.filesystem) {
+            return Err(FsError::CrossDevice);
+        }
+        if self.is_mount_point(name) || target.is_mount_point(new_name) {
+            return Err(FsError::Busy);
+        }
+        self.inode.rename(name, &target.inode, new_name)?;
+        // the cached entries would still have the old names and parents
+        self.children.lock().remove(name);
+        target.children.lock().remove(new_name);
+        Ok(())
+    }
+
+    /// Mounts a filesystem on top of this directory and returns its root.
+    pub fn mount(
+        self: &Arc<Self>,
+        filesystem: Arc<dyn FileSystem>,
+    ) -> Result<Arc<Dentry>, FsError> {
+        let mut mounted = self.mounted.lock();
+        if mounted.is_some() {
+            return Err(FsError::Busy);
+        }
+        let root = Self::new(
+            self.name.clone(),
+            filesystem.root(),
+            filesystem,
+            self.parent.clone(),
+        );
+        *mounted = Some(root.clone());
+        Ok(root)
+    }
+
+    /// Removes the filesystem that was mounted last on the entry `name` of this directory.
+    pub fn unmount_child(&self, name: &str) -> Result<(), FsError> {
+        let mut current = self
+            .children
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(FsError::NotFound)?;
+        loop {
+            let next = current.mounted.lock().clone().ok_or(FsError::InvalidPath)?;
+            if next.mounted.lock().is_none() {
+                *current.mounted.lock() = None;
+                return Ok(());
+            }
+            current = next;
+        }
+    }
+}
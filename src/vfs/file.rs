@@ -0,0 +1,152 @@
+//! Handles of open files.
+
+use super::{Dentry, DirEntry, FsError, Metadata};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use spin::Mutex;
+
+/// How a file is opened, the constants can be combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it doesn't exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Together with `CREATE`, fail if the file exists.
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 3);
+    /// Empty the file when opening it for writing.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+    /// Every write goes to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 5);
+    /// Fail unless it's a directory.
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 6);
+    /// Every flag there is, anything outside of it is invalid.
+    pub const ALL: OpenFlags = OpenFlags((1 << 7) - 1);
+
+    pub const fn from_bits(bits: u32) -> Self {
+        OpenFlags(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// Where a seek is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file or directory with its own position.
+pub struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    position: Mutex<u64>,
+}
+
+impl File {
+    /// Opens the file behind a directory entry.
+    pub fn open(dentry: Arc<Dentry>, flags: OpenFlags) -> Result<Self, FsError> {
+        let metadata = dentry.metadata()?;
+        if metadata.is_dir() && flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::IsADirectory);
+        }
+        if !metadata.is_dir() && flags.contains(OpenFlags::DIRECTORY) {
+            return Err(FsError::NotADirectory);
+        }
+        if flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE) {
+            dentry.inode().truncate(0)?;
+        }
+        Ok(Self {
+            dentry,
+            flags,
+            position: Mutex::new(0),
+        })
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// Reads from the current position on and moves past what was read.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut position = self.position.lock();
+        let length = self.dentry.inode().read_at(*position, buffer)?;
+        *position += length as u64;
+        Ok(length)
+    }
+
+    /// Writes at the current position, or at the end for files opened with `APPEND`.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut position = self.position.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *position = self.stat()?.size;
+        }
+        let length = self.dentry.inode().write_at(*position, buffer)?;
+        *position += length as u64;
+        Ok(length)
+    }
+
+    /// Moves the position and returns the new one. Seeking past the end is fine, writing
+    /// there leaves a hole.
+    pub fn seek(&self, from: SeekFrom) -> Result<u64, FsError> {
+        let mut position = self.position.lock();
+        let new = match from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.stat()?.size.checked_add_signed(offset),
+        };
+        *position = new.ok_or(FsError::InvalidArgument)?;
+        Ok(*position)
+    }
+
+    pub fn stat(&self) -> Result<Metadata, FsError> {
+        self.dentry.metadata()
+    }
+
+    /// Changes the size of the file.
+    pub fn set_len(&self, size: u64) -> Result<(), FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.dentry.inode().truncate(size)
+    }
+
+    /// Returns the entries of the directory.
+    pub fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.dentry.inode().readdir()
+    }
+
+    /// Writes the changes to the file back to the disk.
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.dentry.inode().sync()
+    }
+}
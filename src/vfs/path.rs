@@ -0,0 +1,54 @@
+//! Splitting paths into their components.
+
+/// Whether a path starts at the root rather than the current directory.
+pub fn is_absolute(path: &str) -> bool {
+    path.starts_with('/')
+}
+
+/// The names along a path, without the empty ones that repeated slashes produce and without
+/// `.`, which doesn't go anywhere. `..` is left for the resolution to deal with, since it
+/// depends on where mount points are.
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// Splits a path into the directory and the name of the last component, like `/a/b` into
+/// `/a` and `b`. Returns `None` if there's no last component, like for `/` or `a/..`.
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (directory, name) = match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => (".", path),
+    };
+    match name {
+        "" | "." | ".." => None,
+        _ => Some((directory, name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn path_components() {
+        let parts: Vec<_> = components("//usr/./lib/../bin/").collect();
+        assert_eq!(parts, ["usr", "lib", "..", "bin"]);
+        assert_eq!(components("/").count(), 0);
+        assert!(is_absolute("/usr"));
+        assert!(!is_absolute("usr"));
+    }
+
+    #[test_case]
+    fn split_paths() {
+        assert_eq!(split_last("/usr/bin"), Some(("/usr", "bin")));
+        assert_eq!(split_last("/usr/"), Some(("/", "usr")));
+        assert_eq!(split_last("file"), Some((".", "file")));
+        assert_eq!(split_last("a/b/c"), Some(("a/b", "c")));
+        assert_eq!(split_last("/"), None);
+        assert_eq!(split_last("a/.."), None);
+    }
+}
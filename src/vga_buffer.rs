@@ -1,19 +1,43 @@
+use crate::framebuffer::{self, FrameBufferWriter, Rgb};
+use crate::ring_buffer::RingBuffer;
 use core::fmt;
-use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
+
+pub mod cp437;
 
 // https://en.wikipedia.org/wiki/Code_page_437
 /// The width of the text buffer.
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
 /// The height of the text buffer.
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
+/// The most columns a writer can have, enough for a framebuffer console with the default
+/// resolution and an 8x16 font.
+pub const MAX_COLUMNS: usize = framebuffer::DEFAULT_WIDTH / 8;
+/// The most rows a writer can have, see [`MAX_COLUMNS`].
+pub const MAX_ROWS: usize = framebuffer::DEFAULT_HEIGHT / 16;
+/// The width of a tab stop in columns.
+const TAB_WIDTH: usize = 8;
+/// The most lines a writer can keep around after they have scrolled off the top of the screen.
+/// Each writer keeps this many unless it is told otherwise with
+/// [`VGAWriter::set_scrollback_lines`].
+pub const SCROLLBACK_LINES: usize = 200;
+
+/// The CRTC address register, used to select which CRTC register to access.
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+/// The CRTC data register, used to read or write the selected CRTC register.
+const CRTC_DATA_PORT: u16 = 0x3d5;
+/// CRTC register indices used to control the hardware cursor.
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
 
 // define a globally accessible reference to the a writer instance.
-lazy_static! {
-    // we have to wrap it in a mutex in order to be able to mutably borrow it for write operations.
-    pub static ref WRITER: Mutex<VGAWriter> = Mutex::new(VGAWriter::default());
-}
+// the kernel console is simply the first virtual terminal.
+// it is wrapped in a mutex in order to be able to mutably borrow it for write operations.
+pub static WRITER: &Mutex<VGAWriter> = &crate::terminal::TERMINALS[0].writer;
 
 /// Enum to represent all available colors for the VGA text buffer
 #[allow(dead_code)]
@@ -45,14 +69,42 @@ pub enum Color {
 #[repr(transparent)]
 struct ColorCode(u8);
 
+/// The RGB values of the 16 text mode colors, used when the terminals are drawn to a framebuffer.
+const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0x00, 0x00, 0xaa),
+    Rgb::new(0x00, 0xaa, 0x00),
+    Rgb::new(0x00, 0xaa, 0xaa),
+    Rgb::new(0xaa, 0x00, 0x00),
+    Rgb::new(0xaa, 0x00, 0xaa),
+    Rgb::new(0xaa, 0x55, 0x00),
+    Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xff),
+    Rgb::new(0x55, 0xff, 0x55),
+    Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0x55, 0x55),
+    Rgb::new(0xff, 0x55, 0xff),
+    Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0xff, 0xff, 0xff),
+];
+
 impl ColorCode {
-    fn new(fg: Color, bg: Color) -> ColorCode {
+    const fn new(fg: Color, bg: Color) -> ColorCode {
         // Let's say that the bg is LightGreen, i.e. 0xa/10u8, and we shift it 4 bits to
         // the left, we get 0xa0/160u8.
         // Let's also say that the fg color is Red, i.e. 0x4/4u8.
         // We then bitwise OR the bg and fg to get the final color code 0xa4/164u8.
         ColorCode((bg as u8) << 4 | (fg as u8))
     }
+
+    /// Returns the foreground and background color as they are shown on a framebuffer.
+    fn to_rgb(self) -> (Rgb, Rgb) {
+        (
+            PALETTE[usize::from(self.0 & 0xf)],
+            PALETTE[usize::from(self.0 >> 4)],
+        )
+    }
 }
 
 /// A character that can be drawn in the VGA text buffer.
@@ -69,21 +121,55 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+impl ScreenChar {
+    const fn blank(color_code: ColorCode) -> ScreenChar {
+        ScreenChar {
+            character: b' ',
+            color_code,
+        }
+    }
+}
+
+/// A single row of characters as it is kept in memory outside of the text buffer. Only the
+/// first `columns` characters of it are used.
+type Row = [ScreenChar; MAX_COLUMNS];
+
+const BLANK_ROW: Row = [ScreenChar::blank(ColorCode::new(Color::White, Color::Black)); MAX_COLUMNS];
+
+/// The framebuffer console, if there is one. It is locked once for every operation of a
+/// writer, not for every character.
+type Console = Option<FrameBufferWriter<'static>>;
+
 /// A safe wrapper around the [VGA Text Buffer](https://en.wikipedia.org/wiki/VGA_text_mode).
+///
+/// Every writer keeps its own copy of the screen, so several writers can exist at once
+/// and only the active one is drawn to the text buffer. Once there is a framebuffer console,
+/// the writers take on its size with [`VGAWriter::resize`] and the active one is drawn to the
+/// framebuffer instead.
 pub struct VGAWriter {
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    // the size of the screen, the one of the text buffer until there is a framebuffer console
+    rows: usize,
+    columns: usize,
+    // the live contents of the screen, kept up to date even while the writer isn't shown
+    screen: [Row; MAX_ROWS],
+    // whether this writer is the one being drawn to the text buffer
+    active: bool,
+    // set when the text buffer doesn't show `screen` yet and has to be redrawn completely
+    stale: bool,
+    // the most recent rows that scrolled off the screen
+    scrollback: RingBuffer<Row, SCROLLBACK_LINES>,
+    // how many rows `scrollback` may hold
+    scrollback_lines: usize,
+    // how many rows into the history we are currently looking, 0 means the live view
+    scroll_offset: usize,
 }
 
 impl Default for VGAWriter {
     fn default() -> Self {
-        Self {
-            column_position: 0,
-            color_code: ColorCode::new(Color::White, Color::Black),
-            // assign the buffer member a mutable pointer to the VGA text buffer
-            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-        }
+        Self::new()
     }
 }
 
@@ -95,61 +181,374 @@ impl fmt::Write for VGAWriter {
 }
 
 impl VGAWriter {
+    /// Creates an inactive writer with a blank screen.
+    pub const fn new() -> Self {
+        Self {
+            row_position: 0,
+            column_position: 0,
+            color_code: ColorCode::new(Color::White, Color::Black),
+            rows: BUFFER_HEIGHT,
+            columns: BUFFER_WIDTH,
+            screen: [BLANK_ROW; MAX_ROWS],
+            active: false,
+            stale: false,
+            scrollback: RingBuffer::new(),
+            scrollback_lines: SCROLLBACK_LINES,
+            scroll_offset: 0,
+        }
+    }
+
+    /// Creates a writer that owns the text buffer from the start.
+    pub const fn new_active() -> Self {
+        let mut writer = Self::new();
+        writer.active = true;
+        // get rid of whatever the BIOS and bootloader left on the screen on the first write
+        writer.stale = true;
+        writer
+    }
+
+    /// Makes this writer the one drawn to the text buffer and redraws the screen.
+    pub fn activate(&mut self) {
+        self.active = true;
+        self.render_view(&mut framebuffer::CONSOLE.lock());
+        self.update_cursor();
+    }
+
+    /// Stops drawing this writer to the text buffer, writes only go to its own screen from now on.
+    pub fn deactivate(&mut self) {
+        self.active = false;
+    }
+
+    /// Returns whether this writer is currently drawn to the text buffer.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns the size of the screen as `(rows, columns)`.
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    /// Changes the size of the screen, at most [`MAX_ROWS`] by [`MAX_COLUMNS`]. Whatever
+    /// doesn't fit anymore is cut off at the bottom and the right.
+    pub fn resize(&mut self, rows: usize, columns: usize) {
+        let (rows, columns) = (rows.clamp(1, MAX_ROWS), columns.clamp(1, MAX_COLUMNS));
+        let blank = ScreenChar::blank(self.color_code);
+        for row in self.screen.iter_mut() {
+            row[columns.min(self.columns)..].fill(blank);
+        }
+        for row in &mut self.screen[rows.min(self.rows)..] {
+            row.fill(blank);
+        }
+        self.rows = rows;
+        self.columns = columns;
+        self.row_position = self.row_position.min(rows - 1);
+        self.column_position = self.column_position.min(columns);
+        self.render_view(&mut framebuffer::CONSOLE.lock());
+        self.update_cursor();
+    }
+
     /// write a single byte to the text buffer
     pub fn write_byte(&mut self, byte: u8) {
+        let mut console = framebuffer::CONSOLE.lock();
+        self.return_to_live_view(&mut console);
+        self.put_byte(&mut console, byte);
+        self.update_cursor();
+    }
+
+    /// Write a string to the text buffer, translating each character to code page 437
+    pub fn write_string(&mut self, s: &str) {
+        let mut console = framebuffer::CONSOLE.lock();
+        self.return_to_live_view(&mut console);
+        for c in s.chars() {
+            match c {
+                // control characters that the writer knows how to handle
+                '\n' | '\r' | '\t' | '\u{8}' => self.put_byte(&mut console, c as u8),
+                // everything else is drawn as its glyph, or "■" if there is none
+                c => self.put_byte(&mut console, cp437::from_char_lossy(c)),
+            }
+        }
+        // only move the hardware cursor once the whole string has been written
+        self.update_cursor();
+    }
+
+    /// Move the cursor to `(row, col)` and write `s` from there.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        self.set_cursor_position(row, col);
+        self.write_string(s);
+    }
+
+    /// Returns the current cursor position as `(row, col)`.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /// Moves the cursor to `(row, col)`, clamping it to the bounds of the screen.
+    pub fn set_cursor_position(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(self.rows - 1);
+        self.column_position = col.min(self.columns - 1);
+        self.update_cursor();
+    }
+
+    /// Sets the foreground and background color used for subsequent writes.
+    pub fn set_color(&mut self, fg: Color, bg: Color) {
+        self.color_code = ColorCode::new(fg, bg);
+    }
+
+    /// Blanks the whole screen and moves the cursor to the top left corner.
+    pub fn clear_screen(&mut self) {
+        let mut console = framebuffer::CONSOLE.lock();
+        self.return_to_live_view(&mut console);
+        let blank = ScreenChar::blank(self.color_code);
+        for row in &mut self.screen[..self.rows] {
+            row.fill(blank);
+        }
+        match console.as_mut() {
+            // filling the framebuffer at once is a lot faster than drawing every blank
+            Some(console) if self.active => {
+                let (fg, bg) = self.color_code.to_rgb();
+                console.set_color(fg, bg);
+                console.clear_screen();
+                self.stale = false;
+            }
+            _ => self.render_view(&mut console),
+        }
+        drop(console);
+        self.set_cursor_position(0, 0);
+    }
+
+    /// Shows the hardware cursor, drawn from scanline `start` to scanline `end` of the cell.
+    pub fn enable_cursor(&mut self, start: u8, end: u8) {
+        unsafe {
+            let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+            let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+
+            // the upper bits of these registers are reserved so we have to preserve them
+            address.write(CRTC_CURSOR_START);
+            let current = data.read();
+            data.write((current & 0xc0) | (start & 0x1f));
+
+            address.write(CRTC_CURSOR_END);
+            let current = data.read();
+            data.write((current & 0xe0) | (end & 0x1f));
+        }
+    }
+
+    /// Hides the hardware cursor.
+    pub fn disable_cursor(&mut self) {
+        unsafe {
+            let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+            let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+
+            // bit 5 of the cursor start register disables the cursor
+            address.write(CRTC_CURSOR_START);
+            data.write(0x20);
+        }
+    }
+
+    /// Scrolls the view `lines` rows back into the history.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll_offset = (self.scroll_offset + lines).min(self.scrollback.len());
+        self.render_view(&mut framebuffer::CONSOLE.lock());
+    }
+
+    /// Scrolls the view `lines` rows forward towards the live view.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scroll_offset == 0 {
+            return;
+        }
+        self.scroll_offset = self.scroll_offset.saturating_sub(lines);
+        self.render_view(&mut framebuffer::CONSOLE.lock());
+    }
+
+    /// Sets how many rows are kept after they have scrolled off the screen, at most
+    /// [`SCROLLBACK_LINES`]. The oldest rows are forgotten if there are more already.
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.scrollback_lines = lines.min(SCROLLBACK_LINES);
+        while self.scrollback.len() > self.scrollback_lines {
+            self.scrollback.pop();
+        }
+        if self.scroll_offset > self.scrollback.len() {
+            self.scroll_offset = self.scrollback.len();
+            self.render_view(&mut framebuffer::CONSOLE.lock());
+        }
+    }
+
+    /// Returns how many rows are kept after they have scrolled off the screen.
+    pub fn scrollback_lines(&self) -> usize {
+        self.scrollback_lines
+    }
+
+    /// Returns how many rows the view is scrolled back into the history.
+    pub fn scroll_offset(&self) -> usize {
+        self.scroll_offset
+    }
+
+    /// Jumps back to the live view if we're currently looking at the history.
+    fn return_to_live_view(&mut self, console: &mut Console) {
+        if self.scroll_offset != 0 {
+            self.scroll_offset = 0;
+            self.render_view(console);
+        }
+    }
+
+    /// Draws the part of the history and live screen selected by `scroll_offset`.
+    fn render_view(&mut self, console: &mut Console) {
+        if !self.active {
+            return;
+        }
+        self.stale = false;
+
+        // think of the history and the live screen as one long list of rows,
+        // the view is then a window of `rows` rows into that list.
+        let first = self.scrollback.len() - self.scroll_offset;
+        for row in 0..self.rows {
+            let index = first + row;
+            let chars = match self.scrollback.get(index) {
+                Some(chars) => chars,
+                None => &self.screen[index - self.scrollback.len()],
+            };
+            for (col, &sc) in chars[..self.columns].iter().enumerate() {
+                Self::draw(console, row, col, sc);
+            }
+        }
+    }
+
+    /// Draws a character on whichever display shows the terminals.
+    fn draw(console: &mut Console, row: usize, col: usize, sc: ScreenChar) {
+        match console {
+            Some(console) => {
+                let (fg, bg) = sc.color_code.to_rgb();
+                console.draw_cell(row, col, cp437::to_char(sc.character), fg, bg);
+            }
+            None => {
+                if let Some(cell) = Self::text_buffer()
+                    .chars
+                    .get_mut(row)
+                    .and_then(|chars| chars.get_mut(col))
+                {
+                    cell.write(sc);
+                }
+            }
+        }
+    }
+
+    /// Returns the memory mapped VGA text buffer.
+    fn text_buffer() -> &'static mut Buffer {
+        // the VGA text buffer is identity mapped at 0xb8000 by the bootloader
+        unsafe { &mut *(0xb8000 as *mut Buffer) }
+    }
+
+    /// Sets the character at `(row, col)` on the screen and draws it if we're active.
+    fn put_char(&mut self, console: &mut Console, row: usize, col: usize, sc: ScreenChar) {
+        self.screen[row][col] = sc;
+        if self.stale {
+            self.render_view(console);
+        } else if self.active {
+            Self::draw(console, row, col, sc);
+        }
+    }
+
+    /// Writes a byte at the cursor without touching the hardware cursor.
+    fn put_byte(&mut self, console: &mut Console, byte: u8) {
         match byte {
-            b'\n' => self.new_line(),
+            b'\n' => self.new_line(console),
+            b'\r' => self.column_position = 0,
+            b'\t' => {
+                // pad with spaces up to the next tab stop
+                let next_stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < next_stop.min(self.columns) {
+                    self.put_byte(console, b' ');
+                }
+            }
+            // backspace
+            0x08 => self.backspace(console),
             byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+                if self.column_position >= self.columns {
+                    self.new_line(console);
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    character: byte,
-                    color_code,
-                });
+                self.put_char(
+                    console,
+                    row,
+                    col,
+                    ScreenChar {
+                        character: byte,
+                        color_code,
+                    },
+                );
                 self.column_position += 1;
             }
         }
     }
 
-    /// Write an ASCII string to the text buffer
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // only write supported bytes
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // for all other, unsupported bytes, print "■".
-                _ => self.write_byte(0xfe),
-            }
+    /// Moves the cursor one step back and erases the character there.
+    fn backspace(&mut self, console: &mut Console) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+        } else if self.row_position > 0 {
+            // wrap around to the end of the previous row
+            self.row_position -= 1;
+            self.column_position = self.columns - 1;
+        } else {
+            return;
         }
+
+        let blank = ScreenChar::blank(self.color_code);
+        self.put_char(console, self.row_position, self.column_position, blank);
     }
 
-    /// Creates a new line at the bottom of the text buffer
-    fn new_line(&mut self) {
-        // move all characters one row up
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                // no need for bounds-checking since the row range start from 1
-                self.buffer.chars[row - 1][col].write(character);
+    /// Moves the cursor to the start of the next line, scrolling the text buffer if needed
+    fn new_line(&mut self, console: &mut Console) {
+        self.column_position = 0;
+        if self.row_position < self.rows - 1 {
+            self.row_position += 1;
+            return;
+        }
+
+        // save the top row before it disappears off the screen
+        if self.scrollback_lines > 0 {
+            if self.scrollback.len() == self.scrollback_lines {
+                // the history is full so we drop the oldest row
+                self.scrollback.pop();
             }
+            self.scrollback.push(self.screen[0]);
+        }
+
+        // move all rows one row up
+        let blank = ScreenChar::blank(self.color_code);
+        self.screen[..self.rows].copy_within(1.., 0);
+        self.screen[self.rows - 1] = [blank; MAX_COLUMNS];
+        match console.as_mut() {
+            // the framebuffer can move its pixels itself, which beats drawing every glyph again
+            Some(console) if self.active && !self.stale => {
+                console.scroll_up(1, self.color_code.to_rgb().1);
+            }
+            _ => self.render_view(console),
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            character: b' ',
-            color_code: self.color_code,
-        };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+    /// Moves the blinking hardware cursor to the current cursor position.
+    fn update_cursor(&mut self) {
+        if !self.active {
+            return;
+        }
+        // the CRTC expects the cursor location as a linear offset into the text buffer
+        // (a full row leaves the column one past the end until the next byte wraps it)
+        let col = self.column_position.min(self.columns - 1);
+        let position = (self.row_position * BUFFER_WIDTH + col) as u16;
+        unsafe {
+            let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+            let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+
+            address.write(CRTC_CURSOR_LOCATION_LOW);
+            data.write((position & 0xff) as u8);
+            address.write(CRTC_CURSOR_LOCATION_HIGH);
+            data.write((position >> 8) as u8);
         }
     }
 }
@@ -173,6 +572,7 @@ pub fn _print(args: fmt::Arguments) {
     use fmt::Write;
     use x86_64::instructions::interrupts;
 
+    // the kernel console is drawn to the framebuffer instead once there is one
     interrupts::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
     })
@@ -194,7 +594,7 @@ mod tests {
 
     #[test_case]
     fn println_output() {
-        use super::{BUFFER_HEIGHT, WRITER};
+        use super::WRITER;
         use core::fmt::Write;
         use x86_64::instructions::interrupts;
 
@@ -203,13 +603,145 @@ mod tests {
             let mut writer = WRITER.lock();
             // write the string to the VGA test buffer
             writeln!(writer, "\n{s}").unwrap();
+            // the text ends up on the row right above the cursor
+            let (row, _) = writer.cursor_position();
             // iterate through each character
             for (i, c) in s.chars().enumerate() {
-                // grab the character in column `i` on the last row
-                let sc = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+                // grab the character in column `i` on the row of the text
+                let sc = writer.screen[row - 1][i];
                 // assert that they're equal
                 assert_eq!(char::from(sc.character), c);
             }
         })
     }
+
+    #[test_case]
+    fn write_at_position() {
+        use super::WRITER;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.write_at(3, 10, "hi");
+            assert_eq!(writer.cursor_position(), (3, 12));
+            assert_eq!(writer.screen[3][10].character, b'h');
+            assert_eq!(writer.screen[3][11].character, b'i');
+        })
+    }
+
+    #[test_case]
+    fn control_characters() {
+        use super::WRITER;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.set_cursor_position(5, 0);
+
+            // carriage return goes back to the start of the row and overwrites
+            writer.write_string("abc\rx");
+            assert_eq!(writer.screen[5][0].character, b'x');
+            assert_eq!(writer.cursor_position(), (5, 1));
+
+            // tab advances to the next tab stop
+            writer.write_string("\t");
+            assert_eq!(writer.cursor_position(), (5, 8));
+
+            // backspace erases the previous character
+            writer.write_string("\x08\x08");
+            assert_eq!(writer.cursor_position(), (5, 6));
+            assert_eq!(writer.screen[5][6].character, b' ');
+
+            // and wraps to the end of the previous row
+            writer.set_cursor_position(6, 0);
+            writer.write_string("\x08");
+            assert_eq!(writer.cursor_position(), (5, writer.size().1 - 1));
+        })
+    }
+
+    /// Reads what is actually shown on `row` of the text buffer.
+    fn read_row(row: usize) -> [super::ScreenChar; super::BUFFER_WIDTH] {
+        let mut chars = [super::BLANK_ROW[0]; super::BUFFER_WIDTH];
+        for (col, sc) in chars.iter_mut().enumerate() {
+            *sc = super::VGAWriter::text_buffer().chars[row][col].read();
+        }
+        chars
+    }
+
+    #[test_case]
+    fn scrollback() {
+        use super::{BUFFER_WIDTH, WRITER};
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            // make sure at least one row has scrolled off the screen
+            for _ in 0..writer.size().0 {
+                writer.write_string("scrollback\n");
+            }
+            let live_top = read_row(0);
+            let history_top = *writer.scrollback.get(writer.scrollback.len() - 1).unwrap();
+
+            // the newest history row appears at the top, pushing the live rows down
+            writer.scroll_up(1);
+            assert_eq!(writer.scroll_offset(), 1);
+            assert_eq!(read_row(0), history_top[..BUFFER_WIDTH]);
+            assert_eq!(read_row(1), live_top);
+
+            writer.scroll_down(1);
+            assert_eq!(writer.scroll_offset(), 0);
+            assert_eq!(read_row(0), live_top);
+
+            // new output always returns to the live view
+            writer.scroll_up(3);
+            writer.write_string("x");
+            assert_eq!(writer.scroll_offset(), 0);
+            assert_eq!(read_row(0), live_top);
+        })
+    }
+
+    #[test_case]
+    fn scrollback_limit() {
+        use super::SCROLLBACK_LINES;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            // a terminal that isn't shown, so nothing is drawn
+            let mut writer = crate::terminal::get(5).unwrap().writer();
+            writer.set_scrollback_lines(3);
+            for _ in 0..writer.size().0 + 10 {
+                writer.write_string("limited\n");
+            }
+            assert_eq!(writer.scrollback.len(), 3);
+            writer.scroll_up(10);
+            assert_eq!(writer.scroll_offset(), 3);
+
+            // shrinking the history takes the view along
+            writer.set_scrollback_lines(1);
+            assert_eq!(writer.scrollback.len(), 1);
+            assert_eq!(writer.scroll_offset(), 1);
+            writer.set_scrollback_lines(0);
+            writer.write_string("\n");
+            assert!(writer.scrollback.is_empty());
+
+            writer.set_scrollback_lines(usize::MAX);
+            assert_eq!(writer.scrollback_lines(), SCROLLBACK_LINES);
+        })
+    }
+
+    #[test_case]
+    fn unicode_output() {
+        use super::WRITER;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.write_at(7, 0, "é┼日x");
+            assert_eq!(writer.screen[7][0].character, 0x82);
+            assert_eq!(writer.screen[7][1].character, 0xc5);
+            // a multi-byte character without a glyph turns into a single box
+            assert_eq!(writer.screen[7][2].character, 0xfe);
+            assert_eq!(writer.screen[7][3].character, b'x');
+        })
+    }
 }
@@ -0,0 +1,116 @@
+// https://en.wikipedia.org/wiki/Code_page_437
+//! Mapping of Unicode characters to the glyphs of code page 437, the character set built into the
+//! ROM of VGA compatible graphics cards.
+
+/// The glyph used for characters that don't exist in code page 437, i.e. "■".
+pub const REPLACEMENT: u8 = 0xfe;
+
+/// The glyphs in the range `0x00..=0x1f`.
+/// These overlap with the ASCII control characters but the VGA hardware draws them as symbols.
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyph at `0x7f`.
+const HOUSE: char = '⌂';
+
+/// The glyphs in the range `0x80..=0xff`.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Characters that aren't in code page 437 but look close enough to one of its glyphs.
+const ALIASES: [(char, u8); 8] = [
+    ('β', 0xe1),        // drawn the same as ß
+    ('μ', 0xe6),        // greek mu vs. micro sign
+    ('\u{2126}', 0xea), // ohm sign vs. greek omega
+    ('∅', 0xed),
+    ('ϕ', 0xed),
+    ('∈', 0xee),
+    ('€', 0xee), // better than a box
+    ('⋅', 0xfa),
+];
+
+/// Returns the code page 437 byte for `c`, or `None` if there is no glyph for it.
+///
+/// ASCII control characters are never mapped since the writer gives some of them special meaning.
+pub fn from_char(c: char) -> Option<u8> {
+    match c {
+        // printable ASCII maps straight through
+        ' '..='~' => Some(c as u8),
+        '\0'..='\u{1f}' | '\u{7f}' => None,
+        HOUSE => Some(0x7f),
+        c => {
+            if let Some(i) = LOW.iter().skip(1).position(|&g| g == c) {
+                return Some(i as u8 + 1);
+            }
+            if let Some(i) = HIGH.iter().position(|&g| g == c) {
+                return Some(i as u8 + 0x80);
+            }
+            ALIASES
+                .iter()
+                .find(|&&(alias, _)| alias == c)
+                .map(|&(_, byte)| byte)
+        }
+    }
+}
+
+/// Like [`from_char`], but returns the [`REPLACEMENT`] glyph for unmappable characters.
+pub fn from_char_lossy(c: char) -> u8 {
+    from_char(c).unwrap_or(REPLACEMENT)
+}
+
+/// Returns the Unicode character drawn for the code page 437 byte `byte`.
+pub fn to_char(byte: u8) -> char {
+    match byte {
+        0x00..=0x1f => LOW[byte as usize],
+        0x7f => HOUSE,
+        0x80..=0xff => HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ascii_is_unchanged() {
+        for byte in 0x20..=0x7eu8 {
+            assert_eq!(from_char(byte as char), Some(byte));
+        }
+    }
+
+    #[test_case]
+    fn maps_symbols() {
+        assert_eq!(from_char('é'), Some(0x82));
+        assert_eq!(from_char('─'), Some(0xc4));
+        assert_eq!(from_char('╬'), Some(0xce));
+        assert_eq!(from_char('☺'), Some(0x01));
+        assert_eq!(from_char('⌂'), Some(0x7f));
+        assert_eq!(from_char('β'), Some(0xe1));
+    }
+
+    #[test_case]
+    fn unmappable_characters() {
+        assert_eq!(from_char('\n'), None);
+        assert_eq!(from_char('\0'), None);
+        assert_eq!(from_char('🦀'), None);
+        assert_eq!(from_char_lossy('日'), REPLACEMENT);
+    }
+
+    #[test_case]
+    fn round_trip() {
+        for byte in 0x01..=0xffu8 {
+            assert_eq!(from_char(to_char(byte)), Some(byte));
+        }
+    }
+}
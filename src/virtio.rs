@@ -0,0 +1,383 @@
+//! Virtio, the interface of the paravirtualized devices of qemu and other hypervisors.
+//!
+//! Both the legacy transport, which has its registers in an I/O BAR, and the modern one,
+//! which finds its memory mapped registers through vendor specific PCI capabilities, are
+//! supported. See <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>.
+
+use crate::driver::DriverError;
+use crate::memory::phys_to_virt;
+use crate::pci::msi::MsiX;
+use crate::pci::{Bar, PciDevice, CAP_VENDOR_SPECIFIC};
+use core::ptr;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+pub mod block;
+pub mod queue;
+
+pub use queue::VirtQueue;
+
+/// The PCI vendor id of all virtio devices.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// bits of the device status register
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// The feature that tells the device we speak the modern interface.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// The bit of the interrupt status register that signals used buffers in a queue.
+pub const ISR_QUEUE: u8 = 1 << 0;
+
+// registers of the legacy transport
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+// while MSI-X is enabled the vector registers come first and the device configuration
+// moves back behind them
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+const LEGACY_QUEUE_VECTOR: u16 = 0x16;
+const LEGACY_DEVICE_CONFIG_MSIX: u16 = 0x18;
+
+// the types of the vendor specific capabilities of modern devices
+const CAP_COMMON_CONFIG: u8 = 1;
+const CAP_NOTIFY_CONFIG: u8 = 2;
+const CAP_ISR_CONFIG: u8 = 3;
+const CAP_DEVICE_CONFIG: u8 = 4;
+
+// fields of the capabilities
+const CAP_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+
+// the common configuration structure of the modern transport
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFFSET: u64 = 0x1e;
+const COMMON_QUEUE_DESCRIPTORS: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// What the MSI-X vector registers read when an event doesn't raise an interrupt.
+const NO_VECTOR: u16 = 0xffff;
+
+/// The largest queue we set up, modern devices let us pick a smaller size than they support.
+const MAX_QUEUE_SIZE: u16 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device has neither a legacy I/O BAR nor the capabilities of the modern interface.
+    NoTransport,
+    /// The device doesn't accept the features we picked.
+    FeaturesRejected,
+    /// The queue doesn't exist.
+    NoQueue(u16),
+    OutOfMemory,
+    /// The device can't raise the MSI-X entry for a queue.
+    VectorRejected,
+}
+
+impl From<VirtioError> for DriverError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::NoTransport => DriverError::Unsupported,
+            VirtioError::FeaturesRejected => DriverError::Hardware("virtio features rejected"),
+            VirtioError::NoQueue(_) => DriverError::Hardware("virtio queue missing"),
+            VirtioError::OutOfMemory => DriverError::Hardware("out of DMA memory"),
+            VirtioError::VectorRejected => DriverError::Hardware("virtio MSI-X vector rejected"),
+        }
+    }
+}
+
+/// How to reach the registers of a virtio device.
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    /// Pre 1.0 devices, and transitional ones that are used like them. Where their device
+    /// configuration is depends on whether MSI-X is enabled.
+    Legacy { port: u16, pci: PciDevice },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        /// The notification address of a queue is its notify offset times this.
+        notify_multiplier: u32,
+        isr: VirtAddr,
+        device: VirtAddr,
+    },
+}
+
+impl Transport {
+    /// Finds the registers of a device, preferring the modern interface.
+    pub fn new(pci: PciDevice) -> Result<Self, VirtioError> {
+        if let Some(transport) = Self::modern(pci) {
+            return Ok(transport);
+        }
+        match pci.bar(0) {
+            Some(Bar::Io { port, .. }) => Ok(Transport::Legacy { port, pci }),
+            _ => Err(VirtioError::NoTransport),
+        }
+    }
+
+    fn modern(pci: PciDevice) -> Option<Self> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in pci.capabilities() {
+            if capability.id != CAP_VENDOR_SPECIFIC {
+                continue;
+            }
+            let bar = usize::from(pci.read_u8(capability.offset + CAP_BAR));
+            let offset = pci.read(capability.offset + CAP_OFFSET);
+            let Some(Bar::Memory { address, .. }) = pci.bar(bar) else {
+                continue;
+            };
+            let address = Some(phys_to_virt(address) + u64::from(offset));
+            // there can be several capabilities of a type, the first one is the preferred one
+            match pci.read_u8(capability.offset + CAP_TYPE) {
+                CAP_COMMON_CONFIG if common.is_none() => common = address,
+                CAP_NOTIFY_CONFIG if notify.is_none() => {
+                    notify = address;
+                    notify_multiplier = pci.read(capability.offset + CAP_NOTIFY_MULTIPLIER);
+                }
+                CAP_ISR_CONFIG if isr.is_none() => isr = address,
+                CAP_DEVICE_CONFIG if device.is_none() => device = address,
+                _ => {}
+            }
+        }
+        Some(Transport::Modern {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            isr: isr?,
+            device: device?,
+        })
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { port, .. } => unsafe { Port::new(port + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => read(common + COMMON_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { port, .. } => unsafe {
+                Port::new(port + LEGACY_STATUS).write(status)
+            },
+            Transport::Modern { common, .. } => write(common + COMMON_STATUS, status),
+        }
+    }
+
+    /// Resets the device, after which it forgets its queues and doesn't touch their memory
+    /// anymore.
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Resets the device and tells it that a driver is taking care of it.
+    pub fn begin_init(&self) {
+        self.reset();
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    }
+
+    /// Accepts the features in `wanted` that the device offers and returns them.
+    pub fn negotiate_features(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let features = match *self {
+            Transport::Legacy { port, .. } => {
+                // legacy devices only have 32 feature bits
+                let offered: u32 = unsafe { Port::new(port + LEGACY_DEVICE_FEATURES).read() };
+                let features = u64::from(offered) & wanted;
+                unsafe { Port::new(port + LEGACY_DRIVER_FEATURES).write(features as u32) };
+                return Ok(features);
+            }
+            Transport::Modern { common, .. } => {
+                let mut offered = 0;
+                for half in 0..2 {
+                    write(common + COMMON_DEVICE_FEATURE_SELECT, half as u32);
+                    offered |=
+                        u64::from(read::<u32>(common + COMMON_DEVICE_FEATURE)) << (half * 32);
+                }
+                // the modern interface only works if both sides agree on it
+                let features = offered & (wanted | FEATURE_VERSION_1);
+                if features & FEATURE_VERSION_1 == 0 {
+                    return Err(VirtioError::FeaturesRejected);
+                }
+                for half in 0..2 {
+                    write(common + COMMON_DRIVER_FEATURE_SELECT, half as u32);
+                    write(
+                        common + COMMON_DRIVER_FEATURE,
+                        (features >> (half * 32)) as u32,
+                    );
+                }
+                features
+            }
+        };
+        self.set_status(self.status() | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    /// Allocates the memory of a queue and hands it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<VirtQueue, VirtioError> {
+        match *self {
+            Transport::Legacy { port, .. } => unsafe {
+                Port::new(port + LEGACY_QUEUE_SELECT).write(index);
+                // legacy devices decide the size on their own
+                let size: u16 = Port::new(port + LEGACY_QUEUE_SIZE).read();
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let queue = VirtQueue::new(index, size, 0)?;
+                let frame = queue.descriptors_address().as_u64() >> 12;
+                Port::new(port + LEGACY_QUEUE_ADDRESS).write(frame as u32);
+                Ok(queue)
+            },
+            Transport::Modern { common, .. } => {
+                write(common + COMMON_QUEUE_SELECT, index);
+                let size = read::<u16>(common + COMMON_QUEUE_SIZE).min(MAX_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                write(common + COMMON_QUEUE_SIZE, size);
+                let notify_offset = read(common + COMMON_QUEUE_NOTIFY_OFFSET);
+                let queue = VirtQueue::new(index, size, notify_offset)?;
+                write_u64(
+                    common + COMMON_QUEUE_DESCRIPTORS,
+                    queue.descriptors_address().as_u64(),
+                );
+                write_u64(
+                    common + COMMON_QUEUE_DRIVER,
+                    queue.available_address().as_u64(),
+                );
+                write_u64(common + COMMON_QUEUE_DEVICE, queue.used_address().as_u64());
+                write(common + COMMON_QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            }
+        }
+    }
+
+    /// Has the device raise the MSI-X table entry `entry` when it used buffers of a queue.
+    /// MSI-X has to be enabled already, the registers for this only exist while it is.
+    pub fn set_queue_vector(&self, queue: u16, entry: u16) -> Result<(), VirtioError> {
+        // the device answers with `NO_VECTOR` when it can't use the entry
+        let vector = match *self {
+            Transport::Legacy { port, .. } => unsafe {
+                Port::new(port + LEGACY_QUEUE_SELECT).write(queue);
+                Port::new(port + LEGACY_QUEUE_VECTOR).write(entry);
+                Port::new(port + LEGACY_QUEUE_VECTOR).read()
+            },
+            Transport::Modern { common, .. } => {
+                write(common + COMMON_QUEUE_SELECT, queue);
+                write(common + COMMON_QUEUE_MSIX_VECTOR, entry);
+                read(common + COMMON_QUEUE_MSIX_VECTOR)
+            }
+        };
+        match vector {
+            NO_VECTOR => Err(VirtioError::VectorRejected),
+            _ => Ok(()),
+        }
+    }
+
+    /// Tells the device that the initialization is done.
+    pub fn finish_init(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device that there are new buffers in a queue.
+    pub fn notify(&self, queue: &VirtQueue) {
+        match *self {
+            Transport::Legacy { port, .. } => unsafe {
+                Port::new(port + LEGACY_QUEUE_NOTIFY).write(queue.index())
+            },
+            Transport::Modern {
+                notify,
+                notify_multiplier,
+                ..
+            } => {
+                let offset = u64::from(queue.notify_offset()) * u64::from(notify_multiplier);
+                write(notify + offset, queue.index());
+            }
+        }
+    }
+
+    /// Reads and thereby acknowledges the interrupt status.
+    pub fn read_isr(&self) -> u8 {
+        match *self {
+            Transport::Legacy { port, .. } => unsafe { Port::new(port + LEGACY_ISR).read() },
+            Transport::Modern { isr, .. } => read(isr),
+        }
+    }
+
+    /// Reads a byte of the device specific configuration.
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        match *self {
+            Transport::Legacy { port, pci } => unsafe {
+                Port::new(legacy_config(port, pci) + offset).read()
+            },
+            Transport::Modern { device, .. } => read(device + u64::from(offset)),
+        }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match *self {
+            Transport::Legacy { port, pci } => unsafe {
+                Port::new(legacy_config(port, pci) + offset).read()
+            },
+            Transport::Modern { device, .. } => read(device + u64::from(offset)),
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        // the halves are read separately, legacy devices don't have 64 bit ports
+        u64::from(self.read_config_u32(offset)) | u64::from(self.read_config_u32(offset + 4)) << 32
+    }
+}
+
+/// Where the device configuration of a legacy device starts.
+fn legacy_config(port: u16, pci: PciDevice) -> u16 {
+    match MsiX::new(pci) {
+        Ok(msix) if msix.is_enabled() => port + LEGACY_DEVICE_CONFIG_MSIX,
+        _ => port + LEGACY_DEVICE_CONFIG,
+    }
+}
+
+fn read<T>(address: VirtAddr) -> T {
+    unsafe { ptr::read_volatile(address.as_ptr()) }
+}
+
+fn write<T>(address: VirtAddr, value: T) {
+    unsafe { ptr::write_volatile(address.as_mut_ptr(), value) }
+}
+
+// the 64 bit fields are written in two halves, devices don't have to support wider accesses
+fn write_u64(address: VirtAddr, value: u64) {
+    write(address, value as u32);
+    write(address + 4u64, (value >> 32) as u32);
+}
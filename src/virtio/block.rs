@@ -0,0 +1,584 @@
+//! Driver for virtio block devices, the disks of `-drive if=virtio`.
+//!
+//! Requests are asynchronous: they complete when the device raises an interrupt, through
+//! MSI-X if the device has it, and the [`BlockDevice`] implementation simply waits for them.
+
+use super::queue::Buffer;
+use super::{Transport, VirtQueue, VirtioError, ISR_QUEUE, VENDOR_ID};
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::interrupts;
+use crate::memory::DmaBuffer;
+use crate::pci::msi::{self, MsiX};
+use crate::pci::PciDevice;
+use crate::task;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// the PCI device ids of block devices, transitional ones and those that only speak 1.0
+const LEGACY_DEVICE_ID: u16 = 0x1001;
+const MODERN_DEVICE_ID: u16 = 0x1042;
+
+// feature bits
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+// offsets in the device configuration
+const CONFIG_CAPACITY: u16 = 0;
+
+// request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+// what the device writes into the status byte
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// The number of requests that can be in flight at the same time.
+const SLOTS: usize = 4;
+/// The most sectors a single request transfers.
+const SLOT_SECTORS: usize = 128;
+// where things are in the memory of a slot: the request header and status byte share the
+// first frame, the data follows in the next ones
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+const DATA_OFFSET: usize = 4096;
+
+// the number of disks found so far, which decides the name of the next one
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+// the disks again, for those who want to use the asynchronous interface
+static DISKS: Mutex<Vec<Arc<VirtioBlock>>> = Mutex::new(Vec::new());
+
+pub static DRIVER: VirtioBlockDriver = VirtioBlockDriver;
+
+/// A virtio disk.
+pub struct VirtioBlock {
+    name: String,
+    transport: Transport,
+    queue: Mutex<VirtQueue>,
+    // the DMA memory of every slot
+    memory: Vec<DmaBuffer>,
+    slots: Mutex<Slots>,
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+}
+
+impl VirtioBlock {
+    fn new(transport: Transport) -> Result<Self, VirtioError> {
+        transport.begin_init();
+        let features = transport.negotiate_features(FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+        let memory = (0..SLOTS)
+            .map(|_| DmaBuffer::new(DATA_OFFSET + SLOT_SECTORS * SECTOR_SIZE))
+            .collect::<Option<_>>()
+            .ok_or(VirtioError::OutOfMemory)?;
+        // the device knows about the queue from here on, so nothing may fail before the disk
+        // exists and resets it when it's dropped
+        let queue = transport.setup_queue(0)?;
+        let number = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+        Ok(Self {
+            name: format!("virtio{number}"),
+            transport,
+            queue: Mutex::new(queue),
+            memory,
+            slots: Mutex::new(Slots {
+                states: [SlotState::Free; SLOTS],
+                waiting: Vec::new(),
+                next_ticket: 0,
+            }),
+            capacity: transport.read_config_u64(CONFIG_CAPACITY),
+            read_only: features & FEATURE_READ_ONLY != 0,
+            can_flush: features & FEATURE_FLUSH != 0,
+        })
+    }
+
+    /// Has the device raise a vector of its own through MSI-X instead of its legacy IRQ line.
+    /// Returns whether that worked.
+    fn enable_msix(self: &Arc<Self>, pci: PciDevice) -> bool {
+        // virtio only knows how to route its interrupts to MSI-X entries, not to plain MSI
+        let Ok(msix) = MsiX::new(pci) else {
+            return false;
+        };
+        let disk = self.clone();
+        let Ok(vector) = msi::enable_interrupt(pci, move || disk.finish_requests()) else {
+            return false;
+        };
+        if self.transport.set_queue_vector(0, 0).is_ok() {
+            return true;
+        }
+        msix.disable();
+        interrupts::free_vector(vector);
+        false
+    }
+
+    /// Acknowledges an interrupt of the legacy IRQ line, which other devices might share, and
+    /// finishes the requests the device is done with.
+    fn handle_interrupt(&self) {
+        if self.transport.read_isr() & ISR_QUEUE != 0 {
+            self.finish_requests();
+        }
+    }
+
+    /// Finishes the requests the device is done with. MSI-X interrupts come here directly,
+    /// since they belong to the queue alone and don't have to be acknowledged.
+    fn finish_requests(&self) {
+        without_interrupts(|| self.process_used(&mut self.queue.lock()));
+    }
+
+    /// Collects the requests the device is done with and updates their slots. This happens
+    /// with the queue locked, so that no new request gets the descriptors of a finished one
+    /// before its slot knows about it.
+    fn process_used(&self, queue: &mut VirtQueue) {
+        if !queue.process_used() {
+            return;
+        }
+        let mut slots = self.slots.lock();
+        for index in 0..SLOTS {
+            match slots.states[index] {
+                SlotState::InFlight(head) if queue.take_finished(head).is_some() => {
+                    slots.states[index] = SlotState::Finished;
+                }
+                SlotState::Abandoned(head) if queue.take_finished(head).is_some() => {
+                    slots.release(index);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the sectors starting at `start` into `buffer`.
+    pub async fn read(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, start, buffer.len())?;
+        for (i, chunk) in buffer.chunks_mut(SLOT_SECTORS * SECTOR_SIZE).enumerate() {
+            let slot = self.slot().await;
+            let sector = start + (i * SLOT_SECTORS) as u64;
+            self.request(&slot, REQUEST_IN, sector, chunk.len()).await?;
+            slot.memory().read_at(DATA_OFFSET, chunk);
+        }
+        Ok(())
+    }
+
+    /// Writes `buffer` to the sectors starting at `start`.
+    pub async fn write(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, start, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::Io("the disk is read-only"));
+        }
+        for (i, chunk) in buffer.chunks(SLOT_SECTORS * SECTOR_SIZE).enumerate() {
+            let slot = self.slot().await;
+            let sector = start + (i * SLOT_SECTORS) as u64;
+            slot.memory().write_at(DATA_OFFSET, chunk);
+            self.request(&slot, REQUEST_OUT, sector, chunk.len())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until everything written so far is on the disk.
+    pub async fn sync(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            // without the feature the device doesn't cache writes
+            return Ok(());
+        }
+        let slot = self.slot().await;
+        self.request(&slot, REQUEST_FLUSH, 0, 0).await
+    }
+
+    /// Waits for a free slot and claims it.
+    fn slot(&self) -> SlotFuture<'_> {
+        SlotFuture {
+            disk: self,
+            ticket: None,
+        }
+    }
+
+    /// Sends a request that transfers `length` bytes of the slot's data and waits for it.
+    async fn request(
+        &self,
+        slot: &Slot<'_>,
+        kind: u32,
+        sector: u64,
+        length: usize,
+    ) -> Result<(), BlockError> {
+        let memory = slot.memory();
+        unsafe {
+            ptr::write_volatile(memory.as_mut_ptr(HEADER_OFFSET), kind);
+            ptr::write_volatile(memory.as_mut_ptr(HEADER_OFFSET + 4), 0u32);
+            ptr::write_volatile(memory.as_mut_ptr(HEADER_OFFSET + 8), sector);
+            ptr::write_volatile(memory.as_mut_ptr(STATUS_OFFSET), 0xffu8);
+        }
+
+        let base = memory.phys_addr();
+        let header = Buffer {
+            address: base + HEADER_OFFSET as u64,
+            length: 16,
+            writable: false,
+        };
+        let data = Buffer {
+            address: base + DATA_OFFSET as u64,
+            length: length as u32,
+            writable: kind == REQUEST_IN,
+        };
+        let status = Buffer {
+            address: base + STATUS_OFFSET as u64,
+            length: 1,
+            writable: true,
+        };
+        let buffers = [header, data, status];
+        let buffers = if length > 0 {
+            &buffers[..]
+        } else {
+            &[header, status][..]
+        };
+
+        // every slot can have a request in flight, so the queue always has room for them
+        let head = without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            let head = queue.add(buffers).expect("virtio queue full");
+            self.slots.lock().states[slot.index] = SlotState::InFlight(head);
+            self.transport.notify(&queue);
+            head
+        });
+        RequestFuture {
+            disk: self,
+            index: slot.index,
+            head,
+        }
+        .await;
+
+        match unsafe { ptr::read_volatile(memory.as_mut_ptr::<u8>(STATUS_OFFSET)) } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::Io("request not supported")),
+            _ => Err(BlockError::Io("the disk reported an error")),
+        }
+    }
+}
+
+impl Drop for VirtioBlock {
+    fn drop(&mut self) {
+        // the memory of the queue and the slots is given back right after this
+        self.transport.reset();
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        task::block_on(self.read(start, buffer))
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        task::block_on(self.write(start, buffer))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        task::block_on(self.sync())
+    }
+}
+
+/// What a slot is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    Claimed,
+    /// The device has the request with this head.
+    InFlight(u16),
+    /// The device is done with the request, but whoever sent it hasn't looked yet.
+    Finished,
+    /// Whoever sent the request with this head went away before the device was done with it.
+    /// The slot stays taken until the device is, since it might still write into the memory.
+    Abandoned(u16),
+}
+
+/// The state of the slots and who waits for one of them.
+struct Slots {
+    states: [SlotState; SLOTS],
+    // the wakers of the `SlotFuture`s that are waiting, by their ticket
+    waiting: Vec<(u64, Waker)>,
+    next_ticket: u64,
+}
+
+impl Slots {
+    /// Frees a slot and wakes up everyone waiting for one, the first to look gets it.
+    ///
+    /// This can happen in the interrupt handler, so the wakers stay where they are and are
+    /// only dropped by their futures.
+    fn release(&mut self, index: usize) {
+        self.states[index] = SlotState::Free;
+        for (_, waker) in &self.waiting {
+            waker.wake_by_ref();
+        }
+    }
+
+    fn stop_waiting(&mut self, ticket: u64) {
+        self.waiting.retain(|(waiting, _)| *waiting != ticket);
+    }
+}
+
+/// A claimed slot, which is given back when it's dropped, or once the device is done with it
+/// if a request is still in flight.
+struct Slot<'a> {
+    disk: &'a VirtioBlock,
+    index: usize,
+}
+
+impl Slot<'_> {
+    fn memory(&self) -> &DmaBuffer {
+        &self.disk.memory[self.index]
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut slots = self.disk.slots.lock();
+            match slots.states[self.index] {
+                SlotState::InFlight(head) => slots.states[self.index] = SlotState::Abandoned(head),
+                _ => slots.release(self.index),
+            }
+        });
+    }
+}
+
+struct SlotFuture<'a> {
+    disk: &'a VirtioBlock,
+    // our place among the waiting futures, once we had to wait
+    ticket: Option<u64>,
+}
+
+impl<'a> Future for SlotFuture<'a> {
+    type Output = Slot<'a>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Slot<'a>> {
+        let this = self.get_mut();
+        let disk = this.disk;
+        without_interrupts(|| {
+            // slots of abandoned requests are only given back when someone looks at the queue
+            disk.process_used(&mut disk.queue.lock());
+            let mut slots = disk.slots.lock();
+            if let Some(index) = slots
+                .states
+                .iter()
+                .position(|state| *state == SlotState::Free)
+            {
+                slots.states[index] = SlotState::Claimed;
+                if let Some(ticket) = this.ticket.take() {
+                    slots.stop_waiting(ticket);
+                }
+                return Poll::Ready(Slot { disk, index });
+            }
+
+            let ticket = match this.ticket {
+                Some(ticket) => ticket,
+                None => {
+                    slots.next_ticket += 1;
+                    slots.next_ticket
+                }
+            };
+            this.ticket = Some(ticket);
+            let waker = context.waker().clone();
+            match slots
+                .waiting
+                .iter_mut()
+                .find(|(waiting, _)| *waiting == ticket)
+            {
+                Some((_, old)) => *old = waker,
+                None => slots.waiting.push((ticket, waker)),
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for SlotFuture<'_> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            without_interrupts(|| self.disk.slots.lock().stop_waiting(ticket));
+        }
+    }
+}
+
+/// Waits for the device to finish the request with the given head, which uses a slot.
+struct RequestFuture<'a> {
+    disk: &'a VirtioBlock,
+    index: usize,
+    head: u16,
+}
+
+impl Future for RequestFuture<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        without_interrupts(|| {
+            let mut queue = self.disk.queue.lock();
+            // with interrupts disabled we would wait forever, so we look ourselves
+            self.disk.process_used(&mut queue);
+            let mut slots = self.disk.slots.lock();
+            if slots.states[self.index] == SlotState::Finished {
+                slots.states[self.index] = SlotState::Claimed;
+                return Poll::Ready(());
+            }
+            queue.wake_when_finished(self.head, context.waker());
+            Poll::Pending
+        })
+    }
+}
+
+/// The driver for virtio block devices, which registers them as `virtio0`, `virtio1` and so
+/// on.
+pub struct VirtioBlockDriver;
+
+impl Driver for VirtioBlockDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn match_table(&self) -> &'static [Match] {
+        &[
+            Match::PciId {
+                vendor_id: VENDOR_ID,
+                device_id: LEGACY_DEVICE_ID,
+            },
+            Match::PciId {
+                vendor_id: VENDOR_ID,
+                device_id: MODERN_DEVICE_ID,
+            },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        let Device::Pci(pci) = device else {
+            return Err(DriverError::Unsupported);
+        };
+        let transport = Transport::new(*pci)?;
+        pci.enable();
+        let disk = Arc::new(VirtioBlock::new(transport)?);
+
+        if !disk.enable_msix(*pci) {
+            let handler = disk.clone();
+            interrupts::register_irq_closure(pci.interrupt_line(), move || {
+                handler.handle_interrupt()
+            })
+            .map_err(|_| DriverError::Hardware("no interrupt for the virtio device"))?;
+        }
+        transport.finish_init();
+        DISKS.lock().push(disk.clone());
+        block::register(disk);
+        Ok(())
+    }
+}
+
+/// Returns all virtio disks.
+pub fn disks() -> Vec<Arc<VirtioBlock>> {
+    DISKS.lock().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::executor::Executor;
+    use crate::task::Task;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    /// The test image is attached once through each transport.
+    fn test_disks() -> Vec<Arc<dyn BlockDevice>> {
+        let disks: Vec<_> = block::devices()
+            .into_iter()
+            .filter(|disk| disk.name().starts_with("virtio"))
+            .collect();
+        assert_eq!(disks.len(), 2, "virtio disks missing");
+        disks
+    }
+
+    #[test_case]
+    fn read_sectors() {
+        for disk in test_disks() {
+            assert_eq!(disk.block_count(), 2048);
+            let mut buffer = vec![0; 3 * SECTOR_SIZE];
+            disk.read_blocks(300, &mut buffer).unwrap();
+            for (i, sector) in buffer.chunks(SECTOR_SIZE).enumerate() {
+                let number = u64::from_le_bytes(sector[..8].try_into().unwrap());
+                assert_eq!(number, 300 + i as u64);
+            }
+        }
+    }
+
+    #[test_case]
+    fn write_sectors() {
+        for disk in test_disks() {
+            // more than a single request can transfer
+            let data: Vec<u8> = (0..150 * SECTOR_SIZE).map(|i| (i % 249) as u8).collect();
+            disk.write_blocks(1800, &data).unwrap();
+            disk.flush().unwrap();
+            let mut buffer = vec![0; data.len()];
+            disk.read_blocks(1800, &mut buffer).unwrap();
+            assert!(buffer == data);
+        }
+    }
+
+    #[test_case]
+    fn dropped_requests_keep_their_slot() {
+        let disk = disks()[0].clone();
+        let mut buffer = vec![0; SECTOR_SIZE];
+        let waker = futures_util::task::noop_waker();
+        let mut read = Box::pin(disk.read(40, &mut buffer));
+        // a single poll sends the request, which the device might not be done with yet
+        let _ = read.as_mut().poll(&mut Context::from_waker(&waker));
+        drop(read);
+        let state = without_interrupts(|| disk.slots.lock().states[0]);
+        assert!(matches!(state, SlotState::Abandoned(_) | SlotState::Free));
+
+        // the slot comes back once the device is done, and everything still works
+        for i in 0..2 * SLOTS as u64 {
+            disk.read_blocks(i, &mut buffer).unwrap();
+            assert_eq!(u64::from_le_bytes(buffer[..8].try_into().unwrap()), i);
+        }
+        let states = without_interrupts(|| disk.slots.lock().states);
+        assert_eq!(states, [SlotState::Free; SLOTS]);
+    }
+
+    #[test_case]
+    fn concurrent_requests() {
+        // the executor sleeps until the interrupt of the device wakes the tasks up
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+        DONE.store(0, Ordering::Relaxed);
+        let mut executor = Executor::new();
+        for task in 0..6u64 {
+            executor.spawn(Task::new(async move {
+                let disk = disks()[0].clone();
+                let mut buffer = vec![0; SECTOR_SIZE];
+                disk.read(task * 10, &mut buffer).await.unwrap();
+                assert_eq!(
+                    u64::from_le_bytes(buffer[..8].try_into().unwrap()),
+                    task * 10
+                );
+                DONE.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        while DONE.load(Ordering::Relaxed) < 6 {
+            executor.run_ready_tasks();
+        }
+    }
+}
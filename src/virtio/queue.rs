@@ -0,0 +1,303 @@
+//! Split virtqueues, the rings through which buffers are passed to a virtio device and back.
+
+use super::VirtioError;
+use crate::memory::DmaBuffer;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use core::task::Waker;
+use x86_64::PhysAddr;
+
+// bits of the descriptor flags
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+/// Legacy devices expect the used ring to start on a new page.
+const QUEUE_ALIGN: usize = 4096;
+
+/// A piece of physically contiguous memory that takes part in a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// The device writes to the buffer instead of reading it.
+    pub writable: bool,
+}
+
+/// A split virtqueue, which consists of a descriptor table, the available ring the driver
+/// puts descriptor chains into and the used ring the device returns them through.
+pub struct VirtQueue {
+    memory: DmaBuffer,
+    index: u16,
+    size: u16,
+    notify_offset: u16,
+    used_offset: usize,
+    // the unused descriptors are chained together through their next fields
+    free_head: u16,
+    free_count: u16,
+    // our copy of the index of the available ring
+    available_index: u16,
+    // how far we looked at the used ring
+    last_used: u16,
+    // the number of bytes the device wrote for every finished chain, by its head
+    finished: Vec<Option<u32>>,
+    // who waits for the chain with a head
+    wakers: Vec<Option<Waker>>,
+}
+
+impl VirtQueue {
+    /// Allocates a queue with `size` descriptors, which has to be a power of two.
+    pub fn new(index: u16, size: u16, notify_offset: u16) -> Result<Self, VirtioError> {
+        let entries = usize::from(size);
+        let available_end = entries * DESCRIPTOR_SIZE + 6 + 2 * entries;
+        let used_offset = available_end.next_multiple_of(QUEUE_ALIGN);
+        let used_size = 6 + USED_ELEMENT_SIZE * entries;
+        let memory = DmaBuffer::new(used_offset + used_size).ok_or(VirtioError::OutOfMemory)?;
+
+        let queue = Self {
+            memory,
+            index,
+            size,
+            notify_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            last_used: 0,
+            finished: vec![None; entries],
+            wakers: vec![None; entries],
+        };
+        for descriptor in 0..size {
+            queue.write_descriptor(descriptor, 0, 0, 0, (descriptor + 1) % size);
+        }
+        Ok(queue)
+    }
+
+    /// The number of the queue on its device.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn notify_offset(&self) -> u16 {
+        self.notify_offset
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    pub fn descriptors_address(&self) -> PhysAddr {
+        self.memory.phys_addr()
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.memory.phys_addr() + self.available_offset() as u64
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.memory.phys_addr() + self.used_offset as u64
+    }
+
+    fn available_offset(&self) -> usize {
+        usize::from(self.size) * DESCRIPTOR_SIZE
+    }
+
+    fn write_descriptor(&self, index: u16, address: u64, length: u32, flags: u16, next: u16) {
+        let offset = usize::from(index) * DESCRIPTOR_SIZE;
+        unsafe {
+            ptr::write_volatile(self.memory.as_mut_ptr(offset), address);
+            ptr::write_volatile(self.memory.as_mut_ptr(offset + 8), length);
+            ptr::write_volatile(self.memory.as_mut_ptr(offset + 12), flags);
+            ptr::write_volatile(self.memory.as_mut_ptr(offset + 14), next);
+        }
+    }
+
+    fn descriptor_flags(&self, index: u16) -> u16 {
+        let offset = usize::from(index) * DESCRIPTOR_SIZE + 12;
+        unsafe { ptr::read_volatile(self.memory.as_mut_ptr(offset)) }
+    }
+
+    fn descriptor_next(&self, index: u16) -> u16 {
+        let offset = usize::from(index) * DESCRIPTOR_SIZE + 14;
+        unsafe { ptr::read_volatile(self.memory.as_mut_ptr(offset)) }
+    }
+
+    /// Chains descriptors for `buffers` together and makes them available to the device.
+    ///
+    /// Returns the head of the chain, which identifies the request, or `None` if there aren't
+    /// enough free descriptors. The device still has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next = self.descriptor_next(current);
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            self.write_descriptor(current, buffer.address.as_u64(), buffer.length, flags, next);
+            self.free_count -= 1;
+            if i + 1 < buffers.len() {
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.finished[usize::from(head)] = None;
+
+        // the ring entry has to be visible before the device sees the new index
+        let slot = usize::from(self.available_index % self.size);
+        let available = self.available_offset();
+        unsafe { ptr::write_volatile(self.memory.as_mut_ptr(available + 4 + slot * 2), head) };
+        fence(Ordering::SeqCst);
+        self.available_index = self.available_index.wrapping_add(1);
+        unsafe { ptr::write_volatile(self.memory.as_mut_ptr(available + 2), self.available_index) };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Collects the chains the device is done with, frees their descriptors and wakes up
+    /// whoever waits for them. Returns whether there were any.
+    pub fn process_used(&mut self) -> bool {
+        let mut any = false;
+        loop {
+            let used_index: u16 =
+                unsafe { ptr::read_volatile(self.memory.as_mut_ptr(self.used_offset + 2)) };
+            if used_index == self.last_used {
+                return any;
+            }
+            fence(Ordering::SeqCst);
+            let slot = usize::from(self.last_used % self.size);
+            let element = self.used_offset + 4 + slot * USED_ELEMENT_SIZE;
+            let head: u32 = unsafe { ptr::read_volatile(self.memory.as_mut_ptr(element)) };
+            let length: u32 = unsafe { ptr::read_volatile(self.memory.as_mut_ptr(element + 4)) };
+            self.last_used = self.last_used.wrapping_add(1);
+
+            let head = head as u16;
+            self.free_chain(head);
+            self.finished[usize::from(head)] = Some(length);
+            if let Some(waker) = self.wakers[usize::from(head)].take() {
+                waker.wake();
+            }
+            any = true;
+        }
+    }
+
+    /// Puts the descriptors of a chain back onto the free list.
+    fn free_chain(&mut self, head: u16) {
+        let mut last = head;
+        self.free_count += 1;
+        while self.descriptor_flags(last) & DESCRIPTOR_NEXT != 0 {
+            last = self.descriptor_next(last);
+            self.free_count += 1;
+        }
+        let flags = 0;
+        let offset = usize::from(last) * DESCRIPTOR_SIZE;
+        unsafe {
+            ptr::write_volatile(self.memory.as_mut_ptr(offset + 12), flags);
+            ptr::write_volatile(self.memory.as_mut_ptr(offset + 14), self.free_head);
+        }
+        self.free_head = head;
+    }
+
+    /// Returns the number of bytes the device wrote if the chain with this head is finished,
+    /// and forgets about it.
+    pub fn take_finished(&mut self, head: u16) -> Option<u32> {
+        self.finished[usize::from(head)].take()
+    }
+
+    /// Has `waker` woken up once the chain with this head is finished.
+    pub fn wake_when_finished(&mut self, head: u16, waker: &Waker) {
+        self.wakers[usize::from(head)] = Some(waker.clone());
+    }
+
+    /// Returns the number of bytes the device wrote if the chain with this head is finished,
+    /// otherwise `waker` gets woken up once it is.
+    pub fn poll_finished(&mut self, head: u16, waker: &Waker) -> Option<u32> {
+        let finished = self.take_finished(head);
+        if finished.is_none() {
+            self.wake_when_finished(head, waker);
+        }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(address: u64, length: u32, writable: bool) -> Buffer {
+        Buffer {
+            address: PhysAddr::new(address),
+            length,
+            writable,
+        }
+    }
+
+    /// Plays the device and returns a chain through the used ring.
+    fn complete(queue: &VirtQueue, head: u16, length: u32) {
+        let used_index: u16 =
+            unsafe { ptr::read_volatile(queue.memory.as_mut_ptr(queue.used_offset + 2)) };
+        let slot = usize::from(used_index % queue.size);
+        let element = queue.used_offset + 4 + slot * USED_ELEMENT_SIZE;
+        unsafe {
+            ptr::write_volatile(queue.memory.as_mut_ptr(element), u32::from(head));
+            ptr::write_volatile(queue.memory.as_mut_ptr(element + 4), length);
+            ptr::write_volatile(
+                queue.memory.as_mut_ptr(queue.used_offset + 2),
+                used_index.wrapping_add(1),
+            );
+        }
+    }
+
+    #[test_case]
+    fn layout() {
+        let queue = VirtQueue::new(0, 256, 0).unwrap();
+        let base = queue.descriptors_address().as_u64();
+        assert_eq!(queue.available_address().as_u64() - base, 4096);
+        // 256 descriptors and the available ring need more than one page
+        assert_eq!(queue.used_address().as_u64() - base, 8192);
+    }
+
+    #[test_case]
+    fn descriptor_chains() {
+        let mut queue = VirtQueue::new(0, 4, 0).unwrap();
+        let head = queue
+            .add(&[buffer(0x1000, 16, false), buffer(0x2000, 512, true)])
+            .unwrap();
+        assert_eq!(queue.free_descriptors(), 2);
+        assert_eq!(queue.descriptor_flags(head), DESCRIPTOR_NEXT);
+        let second = queue.descriptor_next(head);
+        assert_eq!(queue.descriptor_flags(second), DESCRIPTOR_WRITE);
+
+        // not enough descriptors left for three buffers
+        let three = [buffer(0x3000, 1, false); 3];
+        assert_eq!(queue.add(&three), None);
+        let other = queue.add(&three[..2]).unwrap();
+        assert_eq!(queue.free_descriptors(), 0);
+
+        // chains can finish in any order
+        let waker = futures_util::task::noop_waker();
+        assert_eq!(queue.poll_finished(head, &waker), None);
+        complete(&queue, other, 0);
+        complete(&queue, head, 512);
+        assert!(queue.process_used());
+        assert!(!queue.process_used());
+        assert_eq!(queue.free_descriptors(), 4);
+        assert_eq!(queue.poll_finished(head, &waker), Some(512));
+        assert_eq!(queue.poll_finished(other, &waker), Some(0));
+
+        // the freed descriptors can be used again
+        assert!(queue.add(&[buffer(0x4000, 1, false); 4]).is_some());
+    }
+}
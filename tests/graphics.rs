@@ -0,0 +1,175 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wally_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use wally_os::framebuffer::{FrameBuffer, FrameBufferInfo, PixelFormat, Rgb};
+use wally_os::graphics::{Canvas, Rect};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wally_os::{allocator, memory};
+    use x86_64::VirtAddr;
+
+    wally_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    wally_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wally_os::test_panic_handler(info)
+}
+
+const RED: Rgb = Rgb::new(0xff, 0, 0);
+
+/// Asserts that exactly the pixels in `expected` are set to `color`, in `.`/`#` notation.
+fn assert_pixels(canvas: &Canvas, color: Rgb, expected: &[&str]) {
+    for (y, row) in expected.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+            let pixel = canvas.pixel(x as i32, y as i32).unwrap();
+            assert_eq!(pixel == color, c == '#', "pixel ({x}, {y})");
+        }
+    }
+}
+
+#[test_case]
+fn pixels_outside_are_clipped() {
+    let mut canvas = Canvas::new(4, 4);
+    canvas.set_pixel(1, 2, RED);
+    canvas.set_pixel(-1, 0, RED);
+    canvas.set_pixel(4, 4, RED);
+    assert_eq!(canvas.pixel(1, 2), Some(RED));
+    assert_eq!(canvas.pixel(4, 4), None);
+    assert_eq!(canvas.dirty_rect(), Some(Rect::new(1, 2, 1, 1)));
+}
+
+#[test_case]
+fn lines() {
+    let mut canvas = Canvas::new(5, 5);
+    canvas.draw_line(0, 0, 4, 4, RED);
+    canvas.draw_line(4, 0, 2, 0, RED);
+    assert_pixels(
+        &canvas,
+        RED,
+        &[
+            "#.###", //
+            ".#...", //
+            "..#..", //
+            "...#.", //
+            "....#",
+        ],
+    );
+}
+
+#[test_case]
+fn rectangles() {
+    let mut canvas = Canvas::new(6, 5);
+    canvas.draw_rect(Rect::new(0, 0, 4, 4), RED);
+    // sticks out of the bottom right corner
+    canvas.fill_rect(Rect::new(4, 3, 10, 10), RED);
+    assert_pixels(
+        &canvas,
+        RED,
+        &[
+            "####..", //
+            "#..#..", //
+            "#..#..", //
+            "######", //
+            "....##",
+        ],
+    );
+}
+
+#[test_case]
+fn circles() {
+    let mut canvas = Canvas::new(7, 7);
+    canvas.draw_circle(3, 3, 3, RED);
+    assert_pixels(
+        &canvas,
+        RED,
+        &[
+            "..###..", //
+            ".#...#.", //
+            "#.....#", //
+            "#.....#", //
+            "#.....#", //
+            ".#...#.", //
+            "..###..",
+        ],
+    );
+
+    let mut canvas = Canvas::new(5, 5);
+    canvas.fill_circle(2, 2, 2, RED);
+    assert_pixels(
+        &canvas,
+        RED,
+        &[
+            ".###.", //
+            "#####", //
+            "#####", //
+            "#####", //
+            ".###.",
+        ],
+    );
+}
+
+#[test_case]
+fn clip_rectangle() {
+    let mut canvas = Canvas::new(4, 4);
+    canvas.set_clip(Some(Rect::new(1, 1, 2, 2)));
+    canvas.fill_rect(canvas.bounds(), RED);
+    assert_pixels(&canvas, RED, &["....", ".##.", ".##.", "...."]);
+}
+
+#[test_case]
+fn blit() {
+    let mut sprite = Canvas::new(2, 2);
+    sprite.clear(RED);
+
+    let mut canvas = Canvas::new(4, 3);
+    canvas.blit(3, 2, &sprite);
+    canvas.blit(-1, -1, &sprite);
+    assert_pixels(&canvas, RED, &["#...", "....", "...#"]);
+}
+
+#[test_case]
+fn flush_dirty_rectangle() {
+    let info = FrameBufferInfo {
+        width: 8,
+        height: 8,
+        stride: 8,
+        bytes_per_pixel: 4,
+        format: PixelFormat::Bgr,
+    };
+    let mut memory = [0u8; 8 * 8 * 4];
+    let mut framebuffer = FrameBuffer::new(&mut memory, info);
+
+    let mut canvas = Canvas::new(8, 8);
+    canvas.fill_rect(Rect::new(1, 1, 2, 2), RED);
+    canvas.set_pixel(5, 4, RED);
+    assert_eq!(canvas.dirty_rect(), Some(Rect::new(1, 1, 5, 4)));
+
+    canvas.flush(&mut framebuffer);
+    assert_eq!(canvas.dirty_rect(), None);
+    for y in 0..8 {
+        for x in 0..8 {
+            assert_eq!(framebuffer.pixel(x, y), canvas.pixel(x as i32, y as i32));
+        }
+    }
+    // pixels in the framebuffer are stored as blue, green, red
+    let offset = (8 + 1) * 4; // pixel (1, 1)
+    assert_eq!(&memory[offset..offset + 3], &[0, 0, 0xff]);
+}
@@ -0,0 +1,378 @@
+#!/usr/bin/env python3
+"""Builds the disk images the kernel tests run against.
+
+Run it from anywhere, the images end up next to this script. It also puts a copy of
+the scratch disk at the root of the crate for `cargo run`.
+"""
+
+import os
+import shutil
+import struct
+import subprocess
+import tempfile
+import uuid
+import zlib
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+ROOT = os.path.dirname(os.path.dirname(HERE))
+SECTOR_SIZE = 512
+
+
+def scratch_disk(path, sectors=2048):
+    """A disk where every sector starts with its own number, so reads are easy to check."""
+    with open(path, "wb") as image:
+        for number in range(sectors):
+            image.write(struct.pack("<Q", number).ljust(SECTOR_SIZE, b"\0"))
+
+
+def mbr_entry(kind, start, count):
+    return struct.pack("<B3sB3sII", 0, b"", kind, b"", start, count)
+
+
+def boot_record(entries):
+    record = bytearray(SECTOR_SIZE)
+    for index, entry in enumerate(entries):
+        record[446 + index * 16 : 446 + (index + 1) * 16] = mbr_entry(*entry)
+    record[510:512] = b"\x55\xaa"
+    return record
+
+
+def mbr_disk(path, sectors=2048):
+    """A disk with a primary partition and two logical ones in an extended partition."""
+    disk = bytearray(sectors * SECTOR_SIZE)
+
+    def put(sector, data):
+        disk[sector * SECTOR_SIZE : sector * SECTOR_SIZE + len(data)] = data
+
+    put(0, boot_record([(0x83, 64, 512), (0x0F, 1024, 1024)]))
+    # the logical partitions are relative to their record, the links to the extended partition
+    put(1024, boot_record([(0x83, 32, 256), (0x05, 512, 512)]))
+    put(1536, boot_record([(0x0C, 32, 128)]))
+    with open(path, "wb") as image:
+        image.write(disk)
+
+
+LINUX_FILESYSTEM = uuid.UUID("0FC63DAF-8483-4772-8E79-3D69D8477DE4")
+
+
+def gpt_header(lba, alternate, entries_lba, sectors, entries):
+    header = struct.pack(
+        "<8sIIIIQQQQ16sQIII",
+        b"EFI PART",
+        0x00010000,
+        92,
+        0,
+        0,
+        lba,
+        alternate,
+        34,
+        sectors - 34,
+        uuid.UUID(int=0x1234).bytes_le,
+        entries_lba,
+        128,
+        128,
+        zlib.crc32(entries),
+    )
+    header = header[:16] + struct.pack("<I", zlib.crc32(header)) + header[20:]
+    return header.ljust(SECTOR_SIZE, b"\0")
+
+
+def gpt_disk(path, sectors=2048):
+    """A GPT disk with two partitions, including the backup header and entries at the end."""
+    disk = bytearray(sectors * SECTOR_SIZE)
+
+    def put(sector, data):
+        disk[sector * SECTOR_SIZE : sector * SECTOR_SIZE + len(data)] = data
+
+    put(0, boot_record([(0xEE, 1, sectors - 1)]))
+    entries = bytearray(128 * 128)
+    for index, (first, last, name) in enumerate([(64, 1023, "boot"), (1024, 2000, "root")]):
+        entries[index * 128 : (index + 1) * 128] = struct.pack(
+            "<16s16sQQQ72s",
+            LINUX_FILESYSTEM.bytes_le,
+            uuid.UUID(int=index + 1).bytes_le,
+            first,
+            last,
+            0,
+            name.encode("utf-16-le"),
+        )
+    entries = bytes(entries)
+    put(1, gpt_header(1, sectors - 1, 2, sectors, entries))
+    put(2, entries)
+    put(sectors - 33, entries)
+    put(sectors - 1, gpt_header(sectors - 1, 1, sectors - 33, sectors, entries))
+    with open(path, "wb") as image:
+        image.write(disk)
+
+
+def lfn_checksum(short_name):
+    total = 0
+    for byte in short_name:
+        total = (((total & 1) << 7) + (total >> 1) + byte) & 0xFF
+    return total
+
+
+def lfn_entries(name, short_name):
+    """The long name entries in front of a short entry, the last part of the name first."""
+    units = list(struct.unpack(f"<{len(name)}H", name.encode("utf-16-le")))
+    if len(units) % 13:
+        units.append(0)
+    units += [0xFFFF] * (-len(units) % 13)
+    count = len(units) // 13
+    checksum = lfn_checksum(short_name)
+    entries = []
+    for number in range(count, 0, -1):
+        part = units[(number - 1) * 13 : number * 13]
+        ordinal = number | (0x40 if number == count else 0)
+        entries.append(
+            struct.pack("<B5HBBB6HH2H", ordinal, *part[:5], 0x0F, 0, checksum, *part[5:11], 0, *part[11:])
+        )
+    return entries
+
+
+def short_entry(short_name, attributes, cluster, size):
+    # every date is the 1st of January 1980
+    return struct.pack("<11sBBBHHHHHHHI", short_name, attributes, 0, 0, 0, 0x21, 0x21, cluster >> 16, 0, 0x21, cluster & 0xFFFF, size)
+
+
+ATTRIBUTE_VOLUME_ID = 0x08
+ATTRIBUTE_DIRECTORY = 0x10
+ATTRIBUTE_ARCHIVE = 0x20
+
+# (long name, short name, contents), where the contents of a directory are a list
+FAT_FILES = [
+    (None, b"HELLO   TXT", b"Hello from the host!\n"),
+    ("A long file name.txt", b"ALONGF~1TXT", bytes(i % 251 for i in range(3000))),
+    (
+        "docs",
+        b"DOCS       ",
+        [
+            ("readme.md", b"README  MD ", b"# docs\n"),
+            ("empty", b"EMPTY      ", []),
+        ],
+    ),
+]
+
+
+class FatImage:
+    """Formats a FAT12, FAT16 or FAT32 filesystem with a sector per cluster.
+
+    Whether it's FAT32 depends on the layout of the boot sector rather than the number of
+    clusters, so the FAT32 image can be as small as the others.
+    """
+
+    def __init__(self, bits, sectors):
+        self.bits = bits
+        self.reserved = 32 if bits == 32 else 1
+        self.root_entries = {12: 224, 16: 512, 32: 0}[bits]
+        root_sectors = self.root_entries * 32 // SECTOR_SIZE
+        self.fat_sectors = 1
+        while True:
+            clusters = sectors - self.reserved - 2 * self.fat_sectors - root_sectors
+            needed = -(-((clusters + 2) * bits // 8) // SECTOR_SIZE)
+            if needed <= self.fat_sectors:
+                break
+            self.fat_sectors = needed
+        self.root_start = self.reserved + 2 * self.fat_sectors
+        self.data_start = self.root_start + root_sectors
+        self.clusters = clusters
+        self.sectors = sectors
+        self.image = bytearray(sectors * SECTOR_SIZE)
+        mask = (1 << min(bits, 28)) - 1
+        self.fat = [0] * (clusters + 2)
+        self.fat[0] = 0x0FFFFFF8 & mask
+        self.fat[1] = mask
+        self.end_of_chain = mask
+        self.next_free = 2
+
+    def allocate(self, count):
+        first = self.next_free
+        for cluster in range(first, first + count):
+            self.fat[cluster] = cluster + 1 if cluster + 1 < first + count else self.end_of_chain
+        self.next_free += count
+        return first
+
+    def put_cluster_data(self, first, data):
+        offset = (self.data_start + first - 2) * SECTOR_SIZE
+        self.image[offset : offset + len(data)] = data
+
+    def directory(self, files, parent, cluster):
+        entries = []
+        if cluster is not None:
+            entries.append(short_entry(b".          ", ATTRIBUTE_DIRECTORY, cluster, 0))
+            entries.append(short_entry(b"..         ", ATTRIBUTE_DIRECTORY, parent, 0))
+        for long_name, short_name, contents in files:
+            if long_name is not None:
+                entries += lfn_entries(long_name, short_name)
+            if isinstance(contents, list):
+                slots = 2 + sum(len(lfn_entries(n, s)) + 1 if n else 1 for n, s, _ in contents)
+                first = self.allocate(-(-slots * 32 // SECTOR_SIZE))
+                # `..` of the subdirectories of the root is 0, even on FAT32
+                self.put_cluster_data(first, self.directory(contents, cluster or 0, first))
+                entries.append(short_entry(short_name, ATTRIBUTE_DIRECTORY, first, 0))
+            else:
+                first = self.allocate(-(-len(contents) // SECTOR_SIZE)) if contents else 0
+                self.put_cluster_data(first, contents)
+                entries.append(short_entry(short_name, ATTRIBUTE_ARCHIVE, first, len(contents)))
+        return b"".join(entries)
+
+    def build(self, files):
+        root = [short_entry(b"WALLY OS   ", ATTRIBUTE_VOLUME_ID, 0, 0)]
+        # a deleted entry that has to be skipped
+        root.append(b"\xe5" + short_entry(b"ELETED TXT", ATTRIBUTE_ARCHIVE, 0, 0)[1:])
+        if self.bits == 32:
+            root_cluster = self.allocate(1)
+            root = b"".join(root) + self.directory(files, 0, None)
+            self.put_cluster_data(root_cluster, root)
+        else:
+            root = b"".join(root) + self.directory(files, 0, None)
+            offset = self.root_start * SECTOR_SIZE
+            self.image[offset : offset + len(root)] = root
+
+        self.image[0:SECTOR_SIZE] = self.boot_sector()
+        if self.bits == 32:
+            info = bytearray(SECTOR_SIZE)
+            struct.pack_into("<I", info, 0, 0x41615252)
+            free = self.clusters + 2 - self.next_free
+            struct.pack_into("<IIII", info, 484, 0x61417272, free, self.next_free, 0)
+            struct.pack_into("<I", info, 508, 0xAA550000)
+            self.image[SECTOR_SIZE : 2 * SECTOR_SIZE] = info
+            # the backups of both
+            self.image[6 * SECTOR_SIZE : 8 * SECTOR_SIZE] = self.image[0 : 2 * SECTOR_SIZE]
+
+        table = bytearray(self.fat_sectors * SECTOR_SIZE)
+        for cluster, value in enumerate(self.fat):
+            if self.bits == 12:
+                offset = cluster * 3 // 2
+                packed = struct.unpack_from("<H", table, offset)[0]
+                if cluster & 1:
+                    packed = (packed & 0x000F) | (value << 4)
+                else:
+                    packed = (packed & 0xF000) | value
+                struct.pack_into("<H", table, offset, packed)
+            elif self.bits == 16:
+                struct.pack_into("<H", table, cluster * 2, value)
+            else:
+                struct.pack_into("<I", table, cluster * 4, value)
+        for copy in range(2):
+            offset = (self.reserved + copy * self.fat_sectors) * SECTOR_SIZE
+            self.image[offset : offset + len(table)] = table
+        return self.image
+
+    def boot_sector(self):
+        sector = bytearray(SECTOR_SIZE)
+        struct.pack_into(
+            "<3s8sHBHBHHBHHHII",
+            sector,
+            0,
+            b"\xeb\x3c\x90",
+            b"WALLYOS ",
+            SECTOR_SIZE,
+            1,
+            self.reserved,
+            2,
+            self.root_entries,
+            self.sectors if self.sectors < 0x10000 else 0,
+            0xF8,
+            0 if self.bits == 32 else self.fat_sectors,
+            32,
+            2,
+            0,
+            self.sectors if self.sectors >= 0x10000 else 0,
+        )
+        extended = struct.pack("<BBBI11s8s", 0x80, 0, 0x29, 0x12345678, b"WALLY OS   ", f"FAT{self.bits}".ljust(8).encode())
+        if self.bits == 32:
+            struct.pack_into("<IHHIHH12s", sector, 36, self.fat_sectors, 0, 0, 2, 1, 6, b"")
+            sector[64 : 64 + len(extended)] = extended
+        else:
+            sector[36 : 36 + len(extended)] = extended
+        sector[510:512] = b"\x55\xaa"
+        return sector
+
+
+def fat_disk(path, bits, sectors):
+    """A FAT filesystem without a partition table, with the files of `FAT_FILES`."""
+    with open(path, "wb") as image:
+        image.write(FatImage(bits, sectors).build(FAT_FILES))
+
+
+# (path, permissions, contents), where directories have no contents
+EXT2_FILES = [
+    ("hello.txt", 0o644, b"Hello from ext2!\n"),
+    ("secret.txt", 0o600, b"top secret\n"),
+    # big enough for doubly indirect blocks with 1 KiB blocks
+    ("big.bin", 0o644, bytes(i % 253 for i in range(300 * 1024))),
+    ("docs", 0o755, None),
+    ("docs/readme.md", 0o644, b"# docs\n"),
+    ("docs/deep", 0o700, None),
+]
+
+
+def ext2_disk(path, block_size, blocks):
+    """An ext2 filesystem made by `mke2fs` with the files of `EXT2_FILES`, a sparse file and
+    several block groups."""
+    with tempfile.TemporaryDirectory() as source:
+        for name, permissions, contents in EXT2_FILES:
+            target = os.path.join(source, name)
+            if contents is None:
+                os.mkdir(target)
+            else:
+                with open(target, "wb") as file:
+                    file.write(contents)
+            os.chmod(target, permissions)
+        # a single byte behind a megabyte of nothing
+        with open(os.path.join(source, "sparse.bin"), "wb") as file:
+            file.seek(1 << 20)
+            file.write(b"x")
+        for name in [name for name, _, _ in EXT2_FILES] + ["sparse.bin"]:
+            os.utime(os.path.join(source, name), (1700000000, 1700000000))
+
+        with open(path, "wb") as image:
+            image.truncate(blocks * block_size)
+        # the same image every time
+        environment = dict(os.environ, E2FSPROGS_FAKE_TIME="1700000000")
+        subprocess.run(
+            [
+                "mke2fs", "-q", "-F", "-t", "ext2",
+                "-b", str(block_size),
+                "-g", "1024",
+                "-L", "wally",
+                "-U", "6c3b8d2e-5f4a-4e0b-9c1d-2a7e8f9b0c11",
+                "-E", "hash_seed=1d8f0a6b-3c2e-4b5a-8d7c-9e0f1a2b3c4d,root_owner=0:0",
+                "-d", source,
+                path, str(blocks),
+            ],
+            env=environment,
+            check=True,
+        )
+        # the change times come from the files in the temporary directory, which can't be
+        # set, and the access times of directories change when they are read
+        names = [name for name, _, _ in EXT2_FILES] + ["sparse.bin"]
+        commands = "".join(
+            f"set_inode_field /{name} {field} 1700000000\n" for name in names for field in ["atime", "ctime"]
+        )
+        subprocess.run(
+            ["debugfs", "-w", "-f", "-", path],
+            input=commands.encode(),
+            env=environment,
+            stdout=subprocess.DEVNULL,
+            stderr=subprocess.DEVNULL,
+            check=True,
+        )
+
+
+def main():
+    disk = os.path.join(HERE, "disk.img")
+    scratch_disk(disk)
+    shutil.copyfile(disk, os.path.join(ROOT, "disk.img"))
+    mbr_disk(os.path.join(HERE, "mbr.img"))
+    gpt_disk(os.path.join(HERE, "gpt.img"))
+    fat_disk(os.path.join(HERE, "fat12.img"), 12, 2048)
+    fat_disk(os.path.join(HERE, "fat16.img"), 16, 8192)
+    fat_disk(os.path.join(HERE, "fat32.img"), 32, 8192)
+    ext2_disk(os.path.join(HERE, "ext2-1k.img"), 1024, 4096)
+    ext2_disk(os.path.join(HERE, "ext2-4k.img"), 4096, 2048)
+
+
+if __name__ == "__main__":
+    main()